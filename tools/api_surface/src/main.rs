@@ -0,0 +1,313 @@
+use clap::Parser;
+use quote::ToTokens;
+use rust_util::collect::{FileCollector, ModPath};
+use rust_util::item_span::VisibilityFilter;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use syn::ext::IdentExt;
+
+/// Report the public API of a Rust crate as a sorted list of normalized signatures, one per line.
+/// Diffing the output of two runs shows how the API changed, without the noise of changes to
+/// function bodies, private items, comments, or formatting.
+#[derive(Parser)]
+struct Args {
+    /// Root Rust source file of the crate (`lib.rs` or `main.rs`).
+    src_root_path: PathBuf,
+
+    /// Where to write the output.  Default: stdout.
+    #[clap(short, long)]
+    output_path: Option<PathBuf>,
+}
+
+fn is_pub(vis: &syn::Visibility) -> bool {
+    VisibilityFilter::Public.matches(vis)
+}
+
+/// Render `x` as a normalized token string.  The spacing of the result depends only on the
+/// tokens, not on how the source was formatted.
+fn tokens<T: ToTokens>(x: &T) -> String {
+    x.to_token_stream().to_string()
+}
+
+fn name(ident: &syn::Ident) -> String {
+    ident.unraw().to_string()
+}
+
+/// Keep only the `#[derive]` attributes, which affect which traits a type implements.  Doc
+/// comments and other attributes are dropped.
+fn keep_derives(attrs: &[syn::Attribute]) -> String {
+    attrs.iter()
+        .filter(|attr| attr.path().is_ident("derive"))
+        .map(|attr| format!("{} ", tokens(attr)))
+        .collect()
+}
+
+/// Public fields of a struct or union.  Private named fields are collapsed into a trailing `..`,
+/// and private tuple fields are shown as `_` to keep the positions of the public ones.
+fn fields_signature(fields: &syn::Fields) -> String {
+    match *fields {
+        syn::Fields::Named(ref named) => {
+            let mut parts = named.named.iter()
+                .filter(|f| is_pub(&f.vis))
+                .map(|f| format!("{} {} : {}", tokens(&f.vis), tokens(&f.ident), tokens(&f.ty)))
+                .collect::<Vec<_>>();
+            if named.named.iter().any(|f| !is_pub(&f.vis)) {
+                parts.push("..".to_owned());
+            }
+            format!(" {{ {} }}", parts.join(", "))
+        },
+        syn::Fields::Unnamed(ref unnamed) => {
+            let parts = unnamed.unnamed.iter()
+                .map(|f| if is_pub(&f.vis) {
+                    format!("{} {}", tokens(&f.vis), tokens(&f.ty))
+                } else {
+                    "_".to_owned()
+                })
+                .collect::<Vec<_>>();
+            format!("({})", parts.join(", "))
+        },
+        syn::Fields::Unit => String::new(),
+    }
+}
+
+fn where_clause(generics: &syn::Generics) -> String {
+    match generics.where_clause {
+        Some(ref wc) => format!(" {}", tokens(wc)),
+        None => String::new(),
+    }
+}
+
+/// Return the name and signature of each public API element declared by `item`, which appears at
+/// the top level of a module.  Names are relative to the module; elements that have no name of
+/// their own, such as `pub use` declarations and trait impls, use an empty name.
+fn item_signatures(item: &syn::Item) -> Vec<(String, String)> {
+    match *item {
+        syn::Item::Fn(ref f) if is_pub(&f.vis) => {
+            vec![(name(&f.sig.ident), format!("{} {}", tokens(&f.vis), tokens(&f.sig)))]
+        },
+        syn::Item::Struct(ref s) if is_pub(&s.vis) => {
+            let sig = format!("{}{} struct {}{}{}{}", keep_derives(&s.attrs), tokens(&s.vis),
+                s.ident, tokens(&s.generics), fields_signature(&s.fields),
+                where_clause(&s.generics));
+            vec![(name(&s.ident), sig)]
+        },
+        syn::Item::Union(ref u) if is_pub(&u.vis) => {
+            let fields = syn::Fields::Named(u.fields.clone());
+            let sig = format!("{}{} union {}{}{}{}", keep_derives(&u.attrs), tokens(&u.vis),
+                u.ident, tokens(&u.generics), fields_signature(&fields),
+                where_clause(&u.generics));
+            vec![(name(&u.ident), sig)]
+        },
+        syn::Item::Enum(ref e) if is_pub(&e.vis) => {
+            // Variants and their fields are always public.
+            let variants = e.variants.iter().map(|v| {
+                let mut v = v.clone();
+                v.attrs.clear();
+                for f in v.fields.iter_mut() {
+                    f.attrs.clear();
+                }
+                tokens(&v)
+            }).collect::<Vec<_>>();
+            let sig = format!("{}{} enum {}{}{} {{ {} }}", keep_derives(&e.attrs), tokens(&e.vis),
+                e.ident, tokens(&e.generics), where_clause(&e.generics), variants.join(", "));
+            vec![(name(&e.ident), sig)]
+        },
+        syn::Item::Trait(ref t) if is_pub(&t.vis) => {
+            // Default method bodies and default values are implementation details.
+            let mut t = t.clone();
+            t.attrs.clear();
+            for ti in &mut t.items {
+                match *ti {
+                    syn::TraitItem::Fn(ref mut f) => {
+                        f.attrs.clear();
+                        if f.default.take().is_some() {
+                            f.semi_token = Some(Default::default());
+                        }
+                    },
+                    syn::TraitItem::Const(ref mut c) => {
+                        c.attrs.clear();
+                        if c.default.take().is_some() {
+                            c.semi_token = Default::default();
+                        }
+                    },
+                    syn::TraitItem::Type(ref mut ty) => {
+                        ty.attrs.clear();
+                        ty.default = None;
+                    },
+                    _ => {},
+                }
+            }
+            vec![(name(&t.ident), tokens(&t))]
+        },
+        syn::Item::Const(ref c) if is_pub(&c.vis) => {
+            let sig = format!("{} const {} : {}", tokens(&c.vis), c.ident, tokens(&c.ty));
+            vec![(name(&c.ident), sig)]
+        },
+        syn::Item::Static(ref s) if is_pub(&s.vis) => {
+            let sig = format!("{} static {} {} : {}", tokens(&s.vis), tokens(&s.mutability),
+                s.ident, tokens(&s.ty));
+            vec![(name(&s.ident), sig.replace("  ", " "))]
+        },
+        syn::Item::Type(ref ty) if is_pub(&ty.vis) => {
+            let mut ty = ty.clone();
+            ty.attrs.clear();
+            vec![(name(&ty.ident), tokens(&ty))]
+        },
+        syn::Item::Use(ref u) if is_pub(&u.vis) => {
+            let mut u = u.clone();
+            u.attrs.clear();
+            vec![(String::new(), tokens(&u))]
+        },
+        syn::Item::Impl(ref imp) => impl_signatures(imp),
+        _ => Vec::new(),
+    }
+}
+
+/// Signatures for an `impl` block.  An inherent impl contributes its public methods and
+/// constants, named `Type::item`.  A trait impl contributes only its header, since the trait
+/// determines the signatures of its items.
+fn impl_signatures(imp: &syn::ItemImpl) -> Vec<(String, String)> {
+    let self_ty = tokens(&*imp.self_ty);
+    if let Some((ref bang, ref trait_path, _)) = imp.trait_ {
+        let sig = format!("{} impl{} {}{} for {}{}", tokens(&imp.unsafety), tokens(&imp.generics),
+            tokens(bang), tokens(trait_path), self_ty, where_clause(&imp.generics));
+        return vec![(String::new(), sig.trim_start().to_owned())];
+    }
+    let context = format!("impl{} {}{}", tokens(&imp.generics), self_ty,
+        where_clause(&imp.generics));
+    imp.items.iter().filter_map(|ii| {
+        let (item_name, sig) = match *ii {
+            syn::ImplItem::Fn(ref f) if is_pub(&f.vis) => {
+                (name(&f.sig.ident), format!("{} {}", tokens(&f.vis), tokens(&f.sig)))
+            },
+            syn::ImplItem::Const(ref c) if is_pub(&c.vis) => {
+                (name(&c.ident), format!("{} const {} : {}", tokens(&c.vis), c.ident,
+                    tokens(&c.ty)))
+            },
+            _ => return None,
+        };
+        Some((format!("{self_ty}::{item_name}"), format!("{context} {{ {sig} }}")))
+    }).collect()
+}
+
+/// Collect the public API of the crate whose files have been parsed into `fc`.  Items in
+/// non-`pub` modules are skipped, since they can't be reached from outside the crate (unless
+/// they're re-exported, in which case the `pub use` appears in the output instead).
+fn api_surface(fc: &FileCollector) -> BTreeSet<String> {
+    let hidden_mods = fc.items()
+        .filter_map(|(mod_path, _, item, _)| match *item.item()? {
+            syn::Item::Mod(ref im) if !is_pub(&im.vis) => {
+                let mut path = mod_path;
+                path.push(name(&im.ident));
+                Some(path)
+            },
+            _ => None,
+        })
+        .collect::<Vec<ModPath>>();
+
+    let mut out = BTreeSet::new();
+    for (mod_path, _, item, _) in fc.items() {
+        // Members are reported along with their `impl` or `trait` block.
+        let Some(item) = item.item() else {
+            continue;
+        };
+        if hidden_mods.iter().any(|m| mod_path.starts_with(m)) {
+            continue;
+        }
+        for (item_name, sig) in item_signatures(item) {
+            let path = std::iter::once("crate")
+                .chain(mod_path.iter().map(|s| s as &str))
+                .chain((!item_name.is_empty()).then_some(&item_name as &str))
+                .collect::<Vec<_>>()
+                .join("::");
+            out.insert(format!("{path}: {sig}"));
+        }
+    }
+    out
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut fc = FileCollector::default();
+    fc.skip_test_mods = true;
+    fc.parse(&args.src_root_path, vec![], true).unwrap();
+
+    let mut w: Box<dyn Write> = match args.output_path {
+        Some(ref path) => Box::new(BufWriter::new(File::create(path).unwrap())),
+        None => Box::new(io::stdout().lock()),
+    };
+    for line in api_surface(&fc) {
+        writeln!(w, "{line}").unwrap();
+    }
+    w.flush().unwrap();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn surface(ast: syn::File) -> Vec<String> {
+        let mut fc = FileCollector::default();
+        fc.files.push((PathBuf::from("lib.rs"), vec![], ast));
+        api_surface(&fc).into_iter().collect()
+    }
+
+    #[test]
+    fn test_items() {
+        let lines = surface(syn::parse_quote! {
+            /// Docs.
+            pub fn f(x: i32) -> i32 { x + 1 }
+            fn private() {}
+            #[derive(Clone)]
+            pub struct S { pub a: i32, b: i32 }
+            pub struct T(pub u8, u8);
+            pub enum E { A, B(i32) }
+            pub trait Tr { fn m(&self) { } const C: i32 = 1; }
+            impl S { pub fn new() -> S { S { a: 0, b: 0 } } fn helper(&self) {} }
+            impl Tr for S {}
+            pub use self::m::g;
+        });
+        assert_eq!(lines, [
+            "crate: impl Tr for S",
+            "crate: pub use self :: m :: g ;",
+            "crate::E: pub enum E { A, B (i32) }",
+            "crate::S: # [derive (Clone)] pub struct S { pub a : i32, .. }",
+            "crate::S::new: impl S { pub fn new () -> S }",
+            "crate::T: pub struct T(pub u8, _)",
+            "crate::Tr: pub trait Tr { fn m (& self) ; const C : i32 ; }",
+            "crate::f: pub fn f (x : i32) -> i32",
+        ]);
+    }
+
+    #[test]
+    fn test_private_mods() {
+        let lines = surface(syn::parse_quote! {
+            pub mod a {
+                pub fn f() {}
+                mod b {
+                    pub fn g() {}
+                }
+            }
+            mod c {
+                pub fn h() {}
+            }
+        });
+        assert_eq!(lines, ["crate::a::f: pub fn f ()"]);
+    }
+
+    #[test]
+    fn test_bodies_ignored() {
+        let a = surface(syn::parse_quote! { pub fn f() -> i32 { 1 } });
+        let b = surface(syn::parse_quote! {
+            pub fn f()
+                -> i32
+            {
+                2
+            }
+        });
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,132 @@
+use clap::Parser;
+use rust_util::collect::FileCollector;
+use rust_util::item_span::item_spans;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// Extract the source of the functions that `find-unsafe` flagged as unsafe, as a JSON map from
+/// item paths to source text in the format `split_rust` emits.  After the functions are
+/// rewritten, the map can be passed back to `merge_rust` with `--update-only`.
+#[derive(Parser)]
+struct Args {
+    /// Root Rust source file of the crate (`lib.rs` or `main.rs`).
+    src_root_path: PathBuf,
+
+    /// Output of `find-unsafe --crate-root` for the same crate.  The functions are found by the
+    /// module-qualified names it reports.
+    find_unsafe_output: PathBuf,
+
+    /// Also extract functions that aren't unsafe themselves, but contain unsafe blocks.
+    #[clap(long)]
+    include_containing_unsafe: bool,
+
+    /// Where to write output JSON.  Default: stdout.
+    #[clap(short, long)]
+    output_path: Option<PathBuf>,
+}
+
+/// The parts of `find-unsafe` output for one file that are used here.
+#[derive(Deserialize)]
+struct FindUnsafeOutput {
+    #[serde(default)]
+    internal_unsafe_fns: Vec<String>,
+    #[serde(default)]
+    fns_containing_unsafe: Vec<String>,
+}
+
+/// Read the names of the functions to extract from the `find-unsafe` output at `path`.
+fn read_fn_names(path: &Path, include_containing_unsafe: bool) -> BTreeSet<String> {
+    let json = fs::read_to_string(path).unwrap_or_else(|e| {
+        panic!("failed to read {:?}: {}", path, e);
+    });
+    let outputs: BTreeMap<PathBuf, FindUnsafeOutput> = serde_json::from_str(&json)
+        .unwrap_or_else(|e| panic!("failed to parse {:?}: {}", path, e));
+    let mut names = BTreeSet::new();
+    for out in outputs.into_values() {
+        names.extend(out.internal_unsafe_fns);
+        if include_containing_unsafe {
+            names.extend(out.fns_containing_unsafe);
+        }
+    }
+    names
+}
+
+/// Get the source of each function in `names`.  Returns the extracted snippets, along with the
+/// names that don't correspond to any item, such as methods and functions declared inside other
+/// functions' bodies, which `item_spans` doesn't track.  A nested function is extracted along
+/// with the function containing it, if that's in `names` too.
+fn extract(
+    fc: &FileCollector,
+    names: &BTreeSet<String>,
+) -> (BTreeMap<String, String>, Vec<String>) {
+    let mut out = BTreeMap::new();
+    for (file_path, mod_path, ast) in &fc.files {
+        let src = &fc.sources[file_path];
+        for (item_path, lo, hi) in item_spans(mod_path.clone(), ast) {
+            let item_path = item_path.join("::");
+            if names.contains(&item_path) {
+                out.insert(item_path, src[lo..hi].to_owned());
+            }
+        }
+    }
+    let missing = names.iter().filter(|name| !out.contains_key(*name)).cloned().collect();
+    (out, missing)
+}
+
+fn main() {
+    let args = Args::parse();
+    let names = read_fn_names(&args.find_unsafe_output, args.include_containing_unsafe);
+
+    let mut fc = FileCollector::default();
+    fc.keep_sources = true;
+    fc.parse(&args.src_root_path, vec![], true).unwrap();
+    let (out, missing) = extract(&fc, &names);
+    for name in missing {
+        eprintln!("warning: couldn't find the source of {name}");
+    }
+    eprintln!("extracted {} functions", out.len());
+
+    if let Some(output_path) = args.output_path {
+        let f = BufWriter::new(File::create(output_path).unwrap());
+        serde_json::to_writer(f, &out).unwrap();
+    } else {
+        serde_json::to_writer(io::stdout(), &out).unwrap();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"),
+            "mod a;\nunsafe fn f() {}\nfn g() { unsafe fn inner() {} }\n").unwrap();
+        fs::write(dir.path().join("a.rs"), "// Comment.\nunsafe fn h(p: *const u8) -> u8 {\n    \
+            *p // Kept.\n}\n").unwrap();
+        fs::write(dir.path().join("out.json"), r#"{
+            "lib.rs": {"internal_unsafe_fns": ["f", "g::inner"], "fns_containing_unsafe": ["g"]},
+            "a.rs": {"internal_unsafe_fns": ["a::h", "a::S::m"], "unsafe_loc": 3}
+        }"#).unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.keep_sources = true;
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let names = read_fn_names(&dir.path().join("out.json"), false);
+        let (out, missing) = extract(&fc, &names);
+        assert_eq!(out, BTreeMap::from([
+            ("f".to_owned(), "unsafe fn f() {}".to_owned()),
+            ("a::h".to_owned(), "unsafe fn h(p: *const u8) -> u8 {\n    *p // Kept.\n}".to_owned()),
+        ]));
+        assert_eq!(missing, ["a::S::m", "g::inner"]);
+
+        let names = read_fn_names(&dir.path().join("out.json"), true);
+        let (out, _) = extract(&fc, &names);
+        assert_eq!(out["g"], "fn g() { unsafe fn inner() {} }");
+    }
+}
@@ -0,0 +1,2004 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+use std::iter;
+use std::mem;
+use std::path::PathBuf;
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use serde::{Deserialize, Serialize};
+use rust_util::collect::is_cfg_test;
+use rust_util::snippet::item_name;
+use syn::{
+    self, Attribute, ExprCall, ExprMethodCall, ExprPath, ExprUnary, ExprUnsafe, ImplItemConst,
+    ImplItemFn, ItemConst, ItemFn, ItemImpl, ItemMacro, ItemMod, ItemStatic, ItemTrait, Macro,
+    Meta, Path, Signature, StaticMutability, Token, TraitItemFn,
+};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+
+/// Analyze `ast` as a standalone file, reporting names without a module path.
+pub fn analyze_file(ast: &syn::File, options: Options) -> Output {
+    let mut v = options.visitor(None);
+    v.visit_file(ast);
+    v.out
+}
+
+// Include test files to ensure they compile.
+#[allow(warnings)]
+mod test_funcs;
+#[allow(warnings)]
+mod test_statics;
+#[allow(warnings)]
+mod test_macros;
+
+fn link_attr(attr: &Attribute) -> Option<String> {
+    link_attr_meta(&attr.meta)
+}
+
+/// If `meta` is a link attribute, return a description of it, such as `no_mangle` or
+/// `export_name = "foo"`.  The description omits any `unsafe(...)` wrapper.
+fn link_attr_meta(meta: &Meta) -> Option<String> {
+    match *meta {
+        Meta::Path(ref p) => is_link_attr_path(p).then(|| type_to_string(p)),
+        Meta::List(ref ml) => {
+            if ml.path.get_ident().is_some_and(|i| i == "unsafe") {
+                let sub_meta = syn::parse2::<Meta>(ml.tokens.clone()).ok()?;
+                link_attr_meta(&sub_meta)
+            } else {
+                is_link_attr_path(&ml.path).then(|| type_to_string(ml))
+            }
+        },
+        Meta::NameValue(ref mnv) => is_link_attr_path(&mnv.path).then(|| type_to_string(mnv)),
+    }
+}
+
+fn is_link_attr_path(path: &Path) -> bool {
+    match path.get_ident() {
+        Some(i) => {
+            i == "no_mangle" || i == "export_name"
+        },
+        None => false,
+    }
+}
+
+/// If the attributes include a link attribute such as `#[no_mangle]` that exports the item to
+/// other compilation units, returns a description of the first such attribute.
+fn exporting_attr(attrs: &[Attribute]) -> Option<String> {
+    attrs.iter().find_map(link_attr)
+}
+
+/// If the function with signature `sig` and attributes `attrs` looks like an FFI entry point,
+/// returns the reason: a link attribute (see `exporting_attr`), or otherwise a non-Rust ABI such
+/// as `extern "C"`.  Functions with a foreign ABI can be called from C through a function
+/// pointer even if they aren't exported by name.
+fn entry_point_reason(sig: &Signature, attrs: &[Attribute]) -> Option<String> {
+    if let Some(attr) = exporting_attr(attrs) {
+        return Some(attr);
+    }
+    sig.abi.as_ref().and_then(foreign_abi)
+}
+
+/// If `abi` is anything other than the Rust ABI, return a description of it, such as
+/// `extern "C"`.
+fn foreign_abi(abi: &syn::Abi) -> Option<String> {
+    match abi.name {
+        Some(ref name) if name.value() == "Rust" => None,
+        Some(ref name) => Some(format!("extern {:?}", name.value())),
+        // `extern fn` with no ABI string defaults to the C ABI.
+        None => Some("extern \"C\"".to_owned()),
+    }
+}
+
+/// If `attrs` include `#[repr(C)]` or `#[repr(packed)]`, return all the representation hints, such
+/// as `C` and `align (8)`, in the order they appear.
+fn repr_c_hints(attrs: &[Attribute]) -> Option<Vec<String>> {
+    let mut hints = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        // Malformed `repr` attributes are rejected by `rustc`, so they can be skipped here.
+        let Ok(metas) = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+        else {
+            continue;
+        };
+        hints.extend(metas.iter().map(type_to_string));
+    }
+    let is_repr_c = hints.iter().any(|h| h == "C" || h == "packed" || h.starts_with("packed "));
+    is_repr_c.then_some(hints)
+}
+
+/// Functions whose calls are high-risk, reported in `high_risk_calls`.  Calls are matched by the
+/// last segment of the path, so `slice::from_raw_parts` and `Vec::from_raw_parts` both count.
+const HIGH_RISK_FNS: &[&str] = &[
+    "transmute", "transmute_copy", "from_raw_parts", "from_raw_parts_mut",
+];
+
+/// Standard library types whose use means invariants are being managed manually.
+const MANUAL_INVARIANT_TYPES: &[&str] = &["MaybeUninit", "ManuallyDrop", "Pin"];
+
+fn token_stream_contains_unsafe(tokens: TokenStream) -> bool {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) if ident == "unsafe" => return true,
+            TokenTree::Group(group) if token_stream_contains_unsafe(group.stream()) => return true,
+            _ => { continue }
+        }
+    }
+    false
+}
+
+
+// Missing fields default to empty, so output from older versions can be read by `--baseline`.
+#[derive(Clone, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Output {
+    /// Functions that are not accessible from other compilation units and are also unsafe.  With
+    /// `--report-exported-unsafe`, exported unsafe functions are included as well.
+    pub internal_unsafe_fns: BTreeSet<String>,
+    /// Unsafe functions that were excluded from `internal_unsafe_fns` because they're FFI entry
+    /// points, mapped to the reason: the link attribute that exports them (e.g. `no_mangle`), or
+    /// their non-Rust ABI (e.g. `extern "C"`).
+    pub exported_unsafe_fns: BTreeMap<String, String>,
+    /// Functions that contain an unsafe block.
+    pub fns_containing_unsafe: BTreeSet<String>,
+    /// Statics that contain an unsafe block.  Constants that contain an unsafe block are also
+    /// reported here, as `const NAME`.
+    pub statics_containing_unsafe: BTreeSet<String>,
+    /// Static that are mutable, regardless of unsafe.
+    pub mutable_statics: BTreeSet<String>,
+    /// Macro invocations that contain an unsafe token, and don't belong to an item (ex global invocations).
+    pub global_macro_invocations_containing_unsafe: BTreeSet<String>,
+    /// Macro definitions (macro_rules!) that contain an unsafe token.
+    pub macro_definitions_containing_unsafe: BTreeSet<String>,
+    /// Total number of source lines spanned by unsafe blocks.  Blocks nested inside another unsafe
+    /// block are counted only once, as part of the outer block.
+    pub unsafe_loc: usize,
+    /// Number of source lines spanned by unsafe blocks in each function of
+    /// `fns_containing_unsafe`.
+    pub unsafe_loc_by_fn: BTreeMap<String, usize>,
+    /// For each function containing an unsafe block, the deepest nesting of blocks, match arms,
+    /// and closures at which an unsafe block appears.  An unsafe block directly in the function
+    /// body has depth 1, and one nested inside it has depth 2.  Deeply nested unsafe code is
+    /// harder to review, so this helps to triage functions.
+    pub max_unsafe_depth: BTreeMap<String, usize>,
+    /// Items that use `MaybeUninit`, `ManuallyDrop`, or `Pin`, in types or constructor calls such
+    /// as `MaybeUninit::uninit()`, mapped to the names of the types used.  These types signal that
+    /// the code manages invariants by hand, even where the unsafe code itself is elsewhere.
+    pub manual_invariant_types: BTreeMap<String, BTreeSet<String>>,
+    /// With `--detailed`, a breakdown of the unsafe operations in each function that is declared
+    /// unsafe or contains unsafe code.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fn_details: Option<BTreeMap<String, FnDetails>>,
+    /// Functions and structs whose signatures or fields mention function pointer types with a
+    /// non-Rust ABI, such as `extern "C" fn(i32)`, mapped to those types.  Calls through such
+    /// pointers are unsafe, but happen at the call site rather than in an `unsafe fn`.
+    pub fn_ptr_types: BTreeMap<String, BTreeSet<String>>,
+    /// Functions containing an `unsafe` block with no operation in it that might need `unsafe`,
+    /// making the block a candidate for removal.  This is conservative: any call, dereference,
+    /// field access, macro invocation, or use of a static (or anything named like one) counts as
+    /// an unsafe operation.
+    pub redundant_unsafe_blocks: BTreeSet<String>,
+    /// Functions declared `unsafe fn` whose bodies contain no operation that might need `unsafe`,
+    /// in the same conservative sense as `redundant_unsafe_blocks`.  Their unsafety doesn't
+    /// propagate from anything they do, so they're candidates for becoming safe functions, unless
+    /// they're unsafe to uphold an invariant that other code relies on.  Functions without a
+    /// body, such as trait method declarations, aren't included.
+    pub ceremonially_unsafe_fns: BTreeSet<String>,
+    /// With `--report-indexing`, the number of indexing expressions such as `a[i]` in each
+    /// function that has any.  These can panic, so they're candidates for checked access.
+    /// Indexing with an integer literal, as in `a[0]`, isn't counted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub indexing_sites: Option<BTreeMap<String, usize>>,
+    /// With `--include-source`, each unsafe block that isn't nested in another one, with its
+    /// source text, in the order they appear.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsafe_blocks: Option<Vec<UnsafeBlock>>,
+    /// Structs, enums, and unions with `#[repr(C)]` or `#[repr(packed)]`.  Their layout has to
+    /// match the C side exactly, so they're part of the FFI boundary along with the foreign
+    /// functions.
+    pub repr_c_types: BTreeMap<String, ReprCType>,
+    /// Number of high-risk operations in each item that has any: calls to `transmute`,
+    /// `transmute_copy`, `from_raw_parts`, or `from_raw_parts_mut`, and `asm!` and `naked_asm!`
+    /// invocations.  These are counted whether or not they're in unsafe code, and are checked by
+    /// `--max-high-risk`.
+    pub high_risk_calls: BTreeMap<String, usize>,
+    /// Line of the signature of each function in `internal_unsafe_fns`, counting from 1.
+    pub unsafe_fn_lines: BTreeMap<String, usize>,
+    /// Functions in `internal_unsafe_fns` or `fns_containing_unsafe` that are test code: marked
+    /// `#[test]` or `#[cfg(test)]`, or inside a `#[cfg(test)]` module or impl.  They're still
+    /// listed in the other fields, so they can be counted separately from production code.
+    pub test_only_fns: BTreeSet<String>,
+    /// With `--weights`, a single score for each function in `fn_details`: the sum of its
+    /// operation counts, each multiplied by the weight of its category.  This is computed after
+    /// the analysis, so it isn't cached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub severity_scores: Option<BTreeMap<String, usize>>,
+    /// For each function with unsafe code, the functions and methods it calls from that code, as
+    /// the full path of a called function, resolved against the caller's module and its `use`
+    /// imports, as for `m::read`, or the path as written if it names an item outside the crate,
+    /// as for `std::ptr::read`.  Method calls can't be resolved without type information, so
+    /// they're given as the method name after a dot, as for `.set_len`.  Calls to safe functions
+    /// are included as well.
+    pub unsafe_call_targets: BTreeMap<String, BTreeSet<String>>,
+    /// Functions whose unsafe code does something other than call a named function or method:
+    /// dereferences a pointer, uses a static, invokes a macro, or calls through a function
+    /// pointer.  Union field reads can't be told apart from struct field accesses, so they're not
+    /// counted.
+    pub direct_unsafe_fns: BTreeSet<String>,
+    /// With `--allow`, the functions whose unsafe code only calls allowlisted functions, so their
+    /// unsafety is fully delegated to reviewed code.  Calls are matched to the allowlist by their
+    /// full path, except that method calls match an allowed function of the same name.  This is
+    /// computed after the analysis, so it isn't cached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delegated_unsafe_fns: Option<BTreeSet<String>>,
+    /// In crate mode, the functions exported by `#[no_mangle]` or `#[export_name]` that nothing in
+    /// the crate refers to by name.  They may be called only from other compilation units, or not
+    /// at all, so they're candidates for pruning dead FFI surface.  This is computed across the
+    /// whole crate, so it isn't cached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub possibly_dead_exports: Option<BTreeSet<String>>,
+}
+
+/// A type reported in `repr_c_types`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct ReprCType {
+    /// `struct`, `enum`, or `union`.
+    pub kind: String,
+    /// All the hints in the type's `repr` attributes, such as `C` and `align (8)`.
+    pub repr: Vec<String>,
+    /// Number of fields.  For enums, this is the number of variants instead.
+    pub fields: usize,
+}
+
+/// An unsafe block reported by `--include-source`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct UnsafeBlock {
+    /// The function, static, or constant containing the block, if any.
+    pub item: Option<String>,
+    /// Lines of the start and end of the block, counting from 1.
+    pub start_line: usize,
+    pub end_line: usize,
+    /// Number of possibly unsafe operations in the block, in the sense of
+    /// `redundant_unsafe_blocks`, including those in nested blocks.  This is the sum of the
+    /// counts below.
+    pub ops: usize,
+    /// Number of `*` dereferences.
+    pub derefs: usize,
+    /// Number of function and method calls.
+    pub calls: usize,
+    /// Number of uses of `static mut`s and of paths that look like statics.
+    pub statics: usize,
+    /// Number of field accesses, which may read union fields.
+    pub field_accesses: usize,
+    /// Number of macro invocations, whose expansions might contain anything.
+    pub macros: usize,
+    /// Source text of the block, from `unsafe` through the closing brace, if it's available.
+    pub source: Option<String>,
+}
+
+/// The kinds of possibly unsafe operations counted in `UnsafeBlock`.
+#[derive(Clone, Copy, Debug)]
+enum UnsafeOp {
+    Deref,
+    Call,
+    Static,
+    FieldAccess,
+    Macro,
+}
+
+/// Counts of the possibly unsafe operations in an unsafe block, by kind.
+#[derive(Clone, Copy, Debug, Default)]
+struct OpCounts {
+    derefs: usize,
+    calls: usize,
+    statics: usize,
+    field_accesses: usize,
+    macros: usize,
+}
+
+impl OpCounts {
+    fn add(&mut self, op: UnsafeOp) {
+        let count = match op {
+            UnsafeOp::Deref => &mut self.derefs,
+            UnsafeOp::Call => &mut self.calls,
+            UnsafeOp::Static => &mut self.statics,
+            UnsafeOp::FieldAccess => &mut self.field_accesses,
+            UnsafeOp::Macro => &mut self.macros,
+        };
+        *count += 1;
+    }
+
+    fn total(&self) -> usize {
+        self.derefs + self.calls + self.statics + self.field_accesses + self.macros
+    }
+}
+
+/// Counts of the unsafe operations in a single function, reported by `--detailed`.  Without type
+/// information, operations are recognized syntactically, so some counts are approximate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct FnDetails {
+    /// The function itself is declared `unsafe fn`.
+    pub declared_unsafe: bool,
+    /// Number of `unsafe` blocks, including nested ones.
+    pub unsafe_blocks: usize,
+    /// Number of `*` dereferences in unsafe code.  These include dereferences of ordinary
+    /// references, which can't be told apart from raw pointer dereferences syntactically.
+    pub deref_count: usize,
+    /// Number of function and method calls in unsafe code, other than calls to `transmute`.  Calls
+    /// to safe functions are included as well.
+    pub unsafe_calls: usize,
+    /// Number of uses, in unsafe code, of `static mut`s defined in the same file.
+    pub static_mut_accesses: usize,
+    /// Number of calls to `transmute` or `transmute_copy`.
+    pub transmutes: usize,
+    /// Number of `asm!` and `naked_asm!` invocations.
+    pub asm_blocks: usize,
+}
+
+/// Weights of the categories of `FnDetails` for `severity_scores`, read from the `--weights`
+/// file.  Categories missing from the file keep their default weights.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Weights {
+    pub declared_unsafe: usize,
+    pub unsafe_blocks: usize,
+    pub deref_count: usize,
+    pub unsafe_calls: usize,
+    pub static_mut_accesses: usize,
+    pub transmutes: usize,
+    pub asm_blocks: usize,
+}
+
+impl Default for Weights {
+    fn default() -> Weights {
+        Weights {
+            declared_unsafe: 5,
+            unsafe_blocks: 1,
+            deref_count: 2,
+            unsafe_calls: 1,
+            static_mut_accesses: 3,
+            transmutes: 10,
+            asm_blocks: 20,
+        }
+    }
+}
+
+impl Weights {
+    pub fn score(&self, d: &FnDetails) -> usize {
+        self.declared_unsafe * d.declared_unsafe as usize
+            + self.unsafe_blocks * d.unsafe_blocks
+            + self.deref_count * d.deref_count
+            + self.unsafe_calls * d.unsafe_calls
+            + self.static_mut_accesses * d.static_mut_accesses
+            + self.transmutes * d.transmutes
+            + self.asm_blocks * d.asm_blocks
+    }
+}
+
+/// Fill in `severity_scores` for each of `outputs` from its `fn_details`, and return the total
+/// score of all functions.
+pub fn add_severity_scores(outputs: &mut BTreeMap<PathBuf, Output>, weights: &Weights) -> usize {
+    let mut total = 0;
+    for out in outputs.values_mut() {
+        let scores = out.fn_details.iter().flatten()
+            .map(|(name, d)| (name.clone(), weights.score(d)))
+            .collect::<BTreeMap<_, _>>();
+        total += scores.values().sum::<usize>();
+        out.severity_scores = Some(scores);
+    }
+    total
+}
+
+#[derive(Clone, Debug)]
+enum ItemKind {
+    Fn(String),
+    Static(String),
+    /// A `const` item or associated constant.  The name includes the `const ` prefix.
+    Const(String),
+}
+
+/// Tracks the enclosing `impl` or `trait` so methods can be reported with a qualified name.
+#[derive(Clone, Debug)]
+enum MethodScope {
+    /// `impl Type { ... }` — methods are reported as `Type::method`.
+    Inherent(String),
+    /// `impl Trait for Type { ... }` — methods are reported as `<Type as Trait>::method`.
+    TraitImpl { self_ty: String, trait_path: String },
+    /// `trait Trait { ... }` — default methods are reported as `Trait::method`.
+    TraitDef(String),
+}
+
+impl MethodScope {
+    fn qualify(&self, method: &syn::Ident) -> String {
+        match self {
+            MethodScope::Inherent(ty) => format!("{}::{}", ty, method),
+            MethodScope::TraitImpl { self_ty, trait_path } => {
+                format!("<{} as {}>::{}", self_ty, trait_path, method)
+            }
+            MethodScope::TraitDef(tr) => format!("{}::{}", tr, method),
+        }
+    }
+}
+
+fn type_to_string<T: ToTokens>(ty: &T) -> String {
+    ty.to_token_stream().to_string()
+}
+
+#[derive(Clone, Debug)]
+enum TraversalScope {
+    Item(ItemKind),
+    Method(MethodScope),
+}
+
+/// Names that paths in a module may start with, used to resolve the paths of called functions.
+#[derive(Clone, Debug, Default)]
+struct ModuleNames {
+    /// The module's `use` imports, mapped to the full paths they import.
+    imports: HashMap<String, Vec<String>>,
+    /// The names of the module's own items.
+    items: HashSet<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Visitor {
+    pub out: Output,
+    scopes: Vec<TraversalScope>,
+    /// Path of the module currently being visited, used to qualify reported names.  This is
+    /// `None` when analyzing standalone files, in which case names are reported unqualified.
+    mod_path: Option<Vec<String>>,
+    /// Module path of the crate root, used to resolve `crate::` paths.  This is the crate name
+    /// when names are qualified with it, and empty otherwise.
+    pub crate_path: Vec<String>,
+    /// The names of each enclosing module, innermost last.
+    module_names: Vec<ModuleNames>,
+    /// Number of unsafe blocks enclosing the current position.
+    unsafe_depth: usize,
+    /// Number of blocks, match arms, and closures enclosing the current position within the
+    /// current function, used for `max_unsafe_depth`.
+    block_depth: usize,
+    /// For each enclosing unsafe block, the possibly unsafe operations it contains, used for
+    /// `redundant_unsafe_blocks` and `unsafe_blocks`.
+    unsafe_block_ops: Vec<OpCounts>,
+    /// If the current function is declared unsafe, the number of possibly unsafe operations in
+    /// its body so far, used for `ceremonially_unsafe_fns`.
+    unsafe_fn_ops: Option<usize>,
+    /// If set, exported unsafe functions are also reported in `internal_unsafe_fns`, and their
+    /// bodies are analyzed.
+    report_exported_unsafe: bool,
+    /// Names of the `static mut`s defined in the current file.
+    static_muts: HashSet<String>,
+    /// Number of enclosing items that are test code, used for `test_only_fns`.  This starts at 1
+    /// for files of modules inside a `#[cfg(test)]` module.
+    pub test_depth: usize,
+}
+
+impl Visitor {
+    /// Create a visitor that qualifies every reported name with its module path, starting from
+    /// `mod_path`.
+    pub fn in_module(mod_path: Vec<String>) -> Visitor {
+        Visitor {
+            mod_path: Some(mod_path),
+            ..Visitor::default()
+        }
+    }
+
+    /// Qualify `name` with the current module path, if we're tracking module paths.  Methods in
+    /// a trait impl are reported as e.g. `foo::<H as J>::j`, using the module containing the impl.
+    fn qualify(&self, name: String) -> String {
+        match self.mod_path {
+            Some(ref mod_path) if !mod_path.is_empty() => {
+                format!("{}::{}", mod_path.join("::"), name)
+            },
+            _ => name,
+        }
+    }
+
+    /// Resolve `path` to a full path, like those produced by `qualify`, using the names of the
+    /// current module.  Paths that don't name an item of the crate are returned as written.
+    fn resolve_path(&self, names: Option<&ModuleNames>, path: &[String]) -> Vec<String> {
+        let mod_path = self.mod_path.as_deref().unwrap_or(&[]);
+        let (base, rest): (&[String], &[String]) = match path.first().map(|s| s.as_str()) {
+            Some("crate") => (&self.crate_path, &path[1..]),
+            Some("self") => (mod_path, &path[1..]),
+            Some("super") => {
+                let supers = path.iter().take_while(|s| *s == "super").count();
+                match mod_path.len().checked_sub(supers) {
+                    Some(len) if len >= self.crate_path.len() => {
+                        (&mod_path[..len], &path[supers..])
+                    },
+                    _ => (&[], path),
+                }
+            },
+            Some(first) => match names.and_then(|n| n.imports.get(first)) {
+                Some(import) => (import, &path[1..]),
+                None if names.is_some_and(|n| n.items.contains(first)) => (mod_path, path),
+                None => (&[], path),
+            },
+            None => (&[], path),
+        };
+        base.iter().chain(rest).cloned().collect()
+    }
+
+    /// Collect the names of the current module, whose contents are `items`.
+    fn module_names(&self, items: &[syn::Item]) -> ModuleNames {
+        let mut names = ModuleNames::default();
+        for item in items {
+            match *item {
+                syn::Item::ExternCrate(_) => {},
+                syn::Item::ForeignMod(ref x) => {
+                    names.items.extend(x.items.iter().filter_map(|i| match *i {
+                        syn::ForeignItem::Fn(ref f) => Some(f.sig.ident.to_string()),
+                        syn::ForeignItem::Static(ref s) => Some(s.ident.to_string()),
+                        _ => None,
+                    }));
+                },
+                _ => names.items.extend(item_name(item).map(|i| i.to_string())),
+            }
+        }
+        let mut imports = Vec::new();
+        for item in items {
+            if let syn::Item::Use(ref x) = *item {
+                self.collect_imports(&names, &mut vec![], &x.tree, &mut imports);
+            }
+        }
+        names.imports.extend(imports);
+        names
+    }
+
+    /// Collect the names imported by `tree`, whose path so far is `prefix`, into `imports`.  Glob
+    /// imports are ignored.
+    fn collect_imports(
+        &self,
+        names: &ModuleNames,
+        prefix: &mut Vec<String>,
+        tree: &syn::UseTree,
+        imports: &mut Vec<(String, Vec<String>)>,
+    ) {
+        let mut import = |ident: &syn::Ident, name: &syn::Ident| {
+            let path = if ident == "self" {
+                prefix.clone()
+            } else {
+                prefix.iter().cloned().chain([ident.to_string()]).collect()
+            };
+            let name = if name == "self" { prefix.last().cloned() } else { Some(name.to_string()) };
+            if let Some(name) = name.filter(|name| name != "_") {
+                imports.push((name, self.resolve_path(Some(names), &path)));
+            }
+        };
+        match *tree {
+            syn::UseTree::Path(ref x) => {
+                prefix.push(x.ident.to_string());
+                self.collect_imports(names, prefix, &x.tree, imports);
+                prefix.pop();
+            },
+            syn::UseTree::Name(ref x) => import(&x.ident, &x.ident),
+            syn::UseTree::Rename(ref x) => import(&x.ident, &x.rename),
+            syn::UseTree::Glob(_) => {},
+            syn::UseTree::Group(ref x) => {
+                for tree in &x.items {
+                    self.collect_imports(names, prefix, tree, imports);
+                }
+            },
+        }
+    }
+
+    /// Visit the contents of `item_mod`, resolving paths in an inline module against its names.
+    fn visit_module(&mut self, item_mod: &ItemMod) {
+        let Some((_, ref items)) = item_mod.content else {
+            visit::visit_item_mod(self, item_mod);
+            return;
+        };
+        let names = self.module_names(items);
+        self.module_names.push(names);
+        visit::visit_item_mod(self, item_mod);
+        self.module_names.pop();
+    }
+
+    /// Record the unsafe function `name`.  Returns `false` if the function's body should be
+    /// skipped, which is the case for FFI entry points unless `report_exported_unsafe` is set.
+    fn record_unsafe_fn(&mut self, name: &str, sig: &Signature, attrs: &[Attribute]) -> bool {
+        if let Some(reason) = entry_point_reason(sig, attrs) {
+            self.out.exported_unsafe_fns.insert(name.to_owned(), reason);
+            if !self.report_exported_unsafe {
+                // Ignore unsafety inside of FFI entry points, as it's often unavoidable.
+                return false;
+            }
+        }
+        self.out.internal_unsafe_fns.insert(name.to_owned());
+        self.out.unsafe_fn_lines.insert(name.to_owned(), sig.span().start().line);
+        true
+    }
+
+    /// Record the foreign function pointer types found by `visit` in `fn_ptr_types` under `name`.
+    fn record_fn_ptr_types(&mut self, name: &str, visit: impl FnOnce(&mut ForeignFnPtrCollector)) {
+        let mut c = ForeignFnPtrCollector::default();
+        visit(&mut c);
+        if !c.types.is_empty() {
+            self.out.fn_ptr_types.entry(name.to_owned()).or_default().extend(c.types);
+        }
+    }
+
+    /// Record the type `name` in `repr_c_types` if `attrs` make it `repr(C)` or `repr(packed)`.
+    fn record_repr_c_type(&mut self, name: String, kind: &str, attrs: &[Attribute], fields: usize) {
+        if let Some(repr) = repr_c_hints(attrs) {
+            self.out.repr_c_types.insert(name, ReprCType { kind: kind.to_owned(), repr, fields });
+        }
+    }
+
+    /// Count a high-risk operation in `high_risk_calls` for the current item, if any.
+    fn record_high_risk(&mut self) {
+        if let Some(ItemKind::Fn(name) | ItemKind::Static(name) | ItemKind::Const(name)) =
+            self.current_item()
+        {
+            *self.out.high_risk_calls.entry(name.clone()).or_default() += 1;
+        }
+    }
+
+    /// Enable the `--detailed` per-function breakdown in `fn_details`.
+    pub fn detailed(mut self) -> Visitor {
+        self.out.fn_details = Some(BTreeMap::new());
+        self
+    }
+
+    /// If `--detailed` is enabled and we're inside a function, apply `update` to its entry in
+    /// `fn_details`.
+    fn update_details(&mut self, update: impl FnOnce(&mut FnDetails)) {
+        let Some(ItemKind::Fn(name)) = self.current_item() else {
+            return;
+        };
+        let name = name.clone();
+        if let Some(ref mut fn_details) = self.out.fn_details {
+            update(fn_details.entry(name).or_default());
+        }
+    }
+
+    /// Record a possibly unsafe operation of kind `op` at the current position.  Operations in a
+    /// nested block count for the enclosing blocks as well, so none of them is reported as
+    /// redundant.
+    fn record_unsafe_op(&mut self, op: UnsafeOp) {
+        for ops in &mut self.unsafe_block_ops {
+            ops.add(op);
+        }
+        if let Some(ref mut ops) = self.unsafe_fn_ops {
+            *ops += 1;
+        }
+    }
+
+    /// Visit the function `name` with `visit`, which should traverse its signature and body (if
+    /// `has_body`).
+    fn visit_fn(
+        &mut self,
+        name: String,
+        sig: &Signature,
+        has_body: bool,
+        visit: impl FnOnce(&mut Self),
+    ) {
+        let is_unsafe = sig.unsafety.is_some();
+        let outer_ops = mem::replace(&mut self.unsafe_fn_ops, is_unsafe.then_some(0));
+        let outer_depth = mem::replace(&mut self.block_depth, 0);
+        self.with_scope(TraversalScope::Item(ItemKind::Fn(name.clone())), |v| {
+            if is_unsafe {
+                v.update_details(|d| d.declared_unsafe = true);
+            }
+            visit(v)
+        });
+        self.block_depth = outer_depth;
+        if self.test_depth > 0 && (is_unsafe || self.out.fns_containing_unsafe.contains(&name)) {
+            self.out.test_only_fns.insert(name.clone());
+        }
+        let ops = mem::replace(&mut self.unsafe_fn_ops, outer_ops);
+        if ops == Some(0) && has_body {
+            self.out.ceremonially_unsafe_fns.insert(name);
+        }
+    }
+
+    /// Check whether the current position is in unsafe code: inside an unsafe block, or in the
+    /// body of an unsafe function.
+    fn in_unsafe_code(&self) -> bool {
+        self.unsafe_depth > 0 || self.unsafe_fn_ops.is_some()
+    }
+
+    /// If we're in unsafe code in a function, record a call to `target` in `unsafe_call_targets`,
+    /// or an operation other than a named call in `direct_unsafe_fns` if `target` is `None`.
+    fn record_unsafe_call(&mut self, target: Option<String>) {
+        if !self.in_unsafe_code() {
+            return;
+        }
+        let Some(ItemKind::Fn(name)) = self.current_item() else {
+            return;
+        };
+        let name = name.clone();
+        match target {
+            Some(target) => {
+                self.out.unsafe_call_targets.entry(name).or_default().insert(target);
+            },
+            None => {
+                self.out.direct_unsafe_fns.insert(name);
+            },
+        }
+    }
+
+    /// Visit a nested block, match arm, or closure with `visit`, counting it for
+    /// `max_unsafe_depth`.  A match arm or closure whose body is a block is counted only once.
+    fn nested(&mut self, body: &syn::Expr, visit: impl FnOnce(&mut Self)) {
+        let counts = !matches!(*body, syn::Expr::Block(_));
+        self.block_depth += counts as usize;
+        visit(self);
+        self.block_depth -= counts as usize;
+    }
+
+    /// Visit an item with attributes `attrs` using `visit`, treating everything inside it as test
+    /// code if it's marked `#[cfg(test)]` or `#[test]`.
+    fn with_test_attrs(&mut self, attrs: &[Attribute], visit: impl FnOnce(&mut Self)) {
+        let is_test = is_cfg_test(attrs) || attrs.iter().any(|attr| attr.path().is_ident("test"));
+        self.test_depth += is_test as usize;
+        visit(self);
+        self.test_depth -= is_test as usize;
+    }
+
+    fn with_scope(&mut self, scope: TraversalScope, visit: impl FnOnce(&mut Self)) {
+        self.scopes.push(scope);
+        visit(self);
+        self.scopes
+            .pop()
+            .expect("scope pushed immediately before traversal");
+    }
+
+    fn current_item(&self) -> Option<&ItemKind> {
+        // TODO: Treat `Method` as a boundary when finding the current item. Searching past it
+        // preserves the old two-field behavior, but can attribute unsafe in an untracked
+        // associated item of a local impl to the enclosing function.
+        self.scopes.iter().rev().find_map(|s| match s {
+            TraversalScope::Item(i) => Some(i),
+            TraversalScope::Method(_) => None,
+        })
+    }
+
+    fn method_scope(&self) -> Option<&MethodScope> {
+        self.scopes.iter().rev().find_map(|s| match s {
+            TraversalScope::Item(_) => None,
+            TraversalScope::Method(m) => Some(m),
+        })
+    }
+}
+
+/// Collects function pointer types with a non-Rust ABI.
+#[derive(Default)]
+struct ForeignFnPtrCollector {
+    types: BTreeSet<String>,
+}
+
+impl<'ast> Visit<'ast> for ForeignFnPtrCollector {
+    fn visit_type_bare_fn(&mut self, x: &'ast syn::TypeBareFn) {
+        if x.abi.as_ref().and_then(foreign_abi).is_some() {
+            self.types.insert(type_to_string(x));
+        }
+        visit::visit_type_bare_fn(self, x);
+    }
+}
+
+/// Collects the names of all `static mut` items, including those declared in `extern` blocks.
+#[derive(Default)]
+struct StaticMutCollector {
+    names: HashSet<String>,
+}
+
+impl<'ast> Visit<'ast> for StaticMutCollector {
+    fn visit_item_static(&mut self, item_static: &'ast ItemStatic) {
+        if matches!(item_static.mutability, StaticMutability::Mut(_)) {
+            self.names.insert(item_static.ident.to_string());
+        }
+        visit::visit_item_static(self, item_static);
+    }
+
+    fn visit_foreign_item_static(&mut self, item_static: &'ast syn::ForeignItemStatic) {
+        if matches!(item_static.mutability, StaticMutability::Mut(_)) {
+            self.names.insert(item_static.ident.to_string());
+        }
+        visit::visit_foreign_item_static(self, item_static);
+    }
+}
+
+impl<'ast> Visit<'ast> for Visitor {
+    fn visit_file(&mut self, file: &'ast syn::File) {
+        let mut c = StaticMutCollector::default();
+        c.visit_file(file);
+        self.static_muts = c.names;
+        let names = self.module_names(&file.items);
+        self.module_names.push(names);
+        visit::visit_file(self, file);
+        self.module_names.pop();
+    }
+
+    fn visit_item_mod(&mut self, item_mod: &'ast ItemMod) {
+        self.with_test_attrs(&item_mod.attrs, |v| {
+            let Some(ref mut mod_path) = v.mod_path else {
+                v.visit_module(item_mod);
+                return;
+            };
+            mod_path.push(item_mod.ident.to_string());
+            v.visit_module(item_mod);
+            v.mod_path
+                .as_mut()
+                .expect("mod_path was set above")
+                .pop();
+        });
+    }
+
+    fn visit_item_fn(&mut self, item_fn: &'ast ItemFn) {
+        self.with_test_attrs(&item_fn.attrs, |v| {
+            let name = v.qualify(item_fn.sig.ident.to_string());
+            v.record_fn_ptr_types(&name, |c| c.visit_signature(&item_fn.sig));
+            if item_fn.sig.unsafety.is_some()
+                && !v.record_unsafe_fn(&name, &item_fn.sig, &item_fn.attrs)
+            {
+                return;
+            }
+
+            v.visit_fn(name, &item_fn.sig, true, |v| visit::visit_item_fn(v, item_fn));
+        });
+    }
+
+    fn visit_item_impl(&mut self, item_impl: &'ast ItemImpl) {
+        let self_ty = type_to_string(&*item_impl.self_ty);
+        let scope = match &item_impl.trait_ {
+            Some((_bang, trait_path, _for)) => MethodScope::TraitImpl {
+                self_ty,
+                trait_path: type_to_string(trait_path),
+            },
+            None => MethodScope::Inherent(self_ty),
+        };
+        self.with_test_attrs(&item_impl.attrs, |v| {
+            v.with_scope(TraversalScope::Method(scope), |v| visit::visit_item_impl(v, item_impl))
+        });
+    }
+
+    fn visit_item_trait(&mut self, item_trait: &'ast ItemTrait) {
+        self.with_scope(
+            TraversalScope::Method(MethodScope::TraitDef(item_trait.ident.to_string())),
+            |v| visit::visit_item_trait(v, item_trait),
+        );
+    }
+
+    fn visit_impl_item_fn(&mut self, item_fn: &'ast ImplItemFn) {
+        let name = self.qualify(match self.method_scope() {
+            Some(scope) => scope.qualify(&item_fn.sig.ident),
+            None => item_fn.sig.ident.to_string(),
+        });
+        self.record_fn_ptr_types(&name, |c| c.visit_signature(&item_fn.sig));
+        if item_fn.sig.unsafety.is_some()
+            && !self.record_unsafe_fn(&name, &item_fn.sig, &item_fn.attrs)
+        {
+            return;
+        }
+
+        self.visit_fn(name, &item_fn.sig, true, |v| visit::visit_impl_item_fn(v, item_fn));
+    }
+
+    fn visit_trait_item_fn(&mut self, item_fn: &'ast TraitItemFn) {
+        // Only default-method bodies can contain `unsafe` blocks; signatures without bodies are
+        // still tracked for `unsafe fn` reporting.
+        let name = self.qualify(match self.method_scope() {
+            Some(scope) => scope.qualify(&item_fn.sig.ident),
+            None => item_fn.sig.ident.to_string(),
+        });
+        self.record_fn_ptr_types(&name, |c| c.visit_signature(&item_fn.sig));
+        if item_fn.sig.unsafety.is_some()
+            && !self.record_unsafe_fn(&name, &item_fn.sig, &item_fn.attrs)
+        {
+            return;
+        }
+
+        let has_body = item_fn.default.is_some();
+        self.visit_fn(name, &item_fn.sig, has_body, |v| visit::visit_trait_item_fn(v, item_fn));
+    }
+
+    fn visit_item_struct(&mut self, item_struct: &'ast syn::ItemStruct) {
+        let name = self.qualify(item_struct.ident.to_string());
+        self.record_fn_ptr_types(&name, |c| c.visit_fields(&item_struct.fields));
+        self.record_repr_c_type(name, "struct", &item_struct.attrs, item_struct.fields.len());
+        visit::visit_item_struct(self, item_struct);
+    }
+
+    fn visit_item_enum(&mut self, item_enum: &'ast syn::ItemEnum) {
+        let name = self.qualify(item_enum.ident.to_string());
+        self.record_repr_c_type(name, "enum", &item_enum.attrs, item_enum.variants.len());
+        visit::visit_item_enum(self, item_enum);
+    }
+
+    fn visit_item_union(&mut self, item_union: &'ast syn::ItemUnion) {
+        let name = self.qualify(item_union.ident.to_string());
+        self.record_repr_c_type(name, "union", &item_union.attrs, item_union.fields.named.len());
+        visit::visit_item_union(self, item_union);
+    }
+
+    fn visit_item_static(&mut self, item_static: &'ast ItemStatic) {
+        let name = self.qualify(item_static.ident.to_string());
+        if matches!(item_static.mutability, StaticMutability::Mut(_)) {
+            self.out.mutable_statics.insert(name.clone());
+        }
+
+        self.with_scope(TraversalScope::Item(ItemKind::Static(name)), |v| {
+            visit::visit_item_static(v, item_static)
+        });
+    }
+
+    fn visit_item_const(&mut self, item_const: &'ast ItemConst) {
+        let name = format!("const {}", self.qualify(item_const.ident.to_string()));
+        self.with_scope(TraversalScope::Item(ItemKind::Const(name)), |v| {
+            visit::visit_item_const(v, item_const)
+        });
+    }
+
+    fn visit_impl_item_const(&mut self, item_const: &'ast ImplItemConst) {
+        let name = format!("const {}", self.qualify(match self.method_scope() {
+            Some(scope) => scope.qualify(&item_const.ident),
+            None => item_const.ident.to_string(),
+        }));
+        self.with_scope(TraversalScope::Item(ItemKind::Const(name)), |v| {
+            visit::visit_impl_item_const(v, item_const)
+        });
+    }
+
+    fn visit_expr_unsafe(&mut self, x: &'ast ExprUnsafe) {
+        match self.current_item() {
+            Some(ItemKind::Fn(name)) => self.out.fns_containing_unsafe.insert(name.clone()),
+            Some(ItemKind::Static(name)) | Some(ItemKind::Const(name)) => {
+                self.out.statics_containing_unsafe.insert(name.clone())
+            },
+            None => <_>::default(),
+        };
+        if self.unsafe_depth == 0 {
+            let span = x.span();
+            let loc = span.end().line - span.start().line + 1;
+            self.out.unsafe_loc += loc;
+            if let Some(ItemKind::Fn(name)) = self.current_item() {
+                *self.out.unsafe_loc_by_fn.entry(name.clone()).or_default() += loc;
+            }
+        }
+        if let Some(ItemKind::Fn(name)) = self.current_item() {
+            let max_depth = self.out.max_unsafe_depth.entry(name.clone()).or_default();
+            *max_depth = (*max_depth).max(self.block_depth);
+        }
+        self.update_details(|d| d.unsafe_blocks += 1);
+        self.unsafe_depth += 1;
+        self.unsafe_block_ops.push(OpCounts::default());
+        visit::visit_expr_unsafe(self, x);
+        let ops = self.unsafe_block_ops.pop().expect("pushed before traversal");
+        self.unsafe_depth -= 1;
+        let item = match self.current_item() {
+            Some(ItemKind::Fn(name) | ItemKind::Static(name) | ItemKind::Const(name)) => {
+                Some(name.clone())
+            },
+            None => None,
+        };
+        if self.unsafe_depth == 0
+            && let Some(ref mut unsafe_blocks) = self.out.unsafe_blocks
+        {
+            let span = x.span();
+            // Spans only have source text when they come from parsing a string in this process,
+            // and not, for example, when they were produced by a proc macro.
+            unsafe_blocks.push(UnsafeBlock {
+                item,
+                start_line: span.start().line,
+                end_line: span.end().line,
+                ops: ops.total(),
+                derefs: ops.derefs,
+                calls: ops.calls,
+                statics: ops.statics,
+                field_accesses: ops.field_accesses,
+                macros: ops.macros,
+                source: span.source_text(),
+            });
+        }
+        if ops.total() == 0
+            && let Some(ItemKind::Fn(name)) = self.current_item()
+        {
+            self.out.redundant_unsafe_blocks.insert(name.clone());
+        }
+    }
+
+    fn visit_block(&mut self, x: &'ast syn::Block) {
+        self.block_depth += 1;
+        visit::visit_block(self, x);
+        self.block_depth -= 1;
+    }
+
+    fn visit_arm(&mut self, x: &'ast syn::Arm) {
+        self.nested(&x.body, |v| visit::visit_arm(v, x));
+    }
+
+    fn visit_expr_closure(&mut self, x: &'ast syn::ExprClosure) {
+        self.nested(&x.body, |v| visit::visit_expr_closure(v, x));
+    }
+
+    // This covers both type positions, as in `Pin<&mut T>`, and constructor calls, as in
+    // `MaybeUninit::uninit()`.
+    fn visit_path(&mut self, path: &'ast Path) {
+        for seg in &path.segments {
+            let Some(&ty) = MANUAL_INVARIANT_TYPES.iter().find(|&&ty| seg.ident == ty) else {
+                continue;
+            };
+            match self.current_item() {
+                Some(ItemKind::Fn(name) | ItemKind::Static(name) | ItemKind::Const(name)) => {
+                    self.out.manual_invariant_types.entry(name.clone()).or_default()
+                        .insert(ty.to_owned());
+                },
+                None => {},
+            }
+        }
+        visit::visit_path(self, path);
+    }
+
+    fn visit_expr_unary(&mut self, x: &'ast ExprUnary) {
+        if matches!(x.op, syn::UnOp::Deref(_)) {
+            self.record_unsafe_op(UnsafeOp::Deref);
+            self.record_unsafe_call(None);
+            if self.in_unsafe_code() {
+                self.update_details(|d| d.deref_count += 1);
+            }
+        }
+        visit::visit_expr_unary(self, x);
+    }
+
+    fn visit_expr_call(&mut self, x: &'ast ExprCall) {
+        let is_transmute = match *x.func {
+            syn::Expr::Path(ref ep) => ep.path.segments.last()
+                .is_some_and(|seg| seg.ident == "transmute" || seg.ident == "transmute_copy"),
+            _ => false,
+        };
+        let is_high_risk = match *x.func {
+            syn::Expr::Path(ref ep) => ep.path.segments.last()
+                .is_some_and(|seg| HIGH_RISK_FNS.iter().any(|&name| seg.ident == name)),
+            _ => false,
+        };
+        if is_high_risk {
+            self.record_high_risk();
+        }
+        self.record_unsafe_op(UnsafeOp::Call);
+        if is_transmute {
+            self.update_details(|d| d.transmutes += 1);
+        } else if self.in_unsafe_code() {
+            self.update_details(|d| d.unsafe_calls += 1);
+        }
+        let target = match *x.func {
+            syn::Expr::Path(ref ep) => {
+                let path = ep.path.segments.iter()
+                    .map(|seg| seg.ident.to_string())
+                    .collect::<Vec<_>>();
+                let path = if ep.path.leading_colon.is_some() || ep.qself.is_some() {
+                    path
+                } else {
+                    self.resolve_path(self.module_names.last(), &path)
+                };
+                Some(path.join("::"))
+            },
+            _ => None,
+        };
+        self.record_unsafe_call(target);
+        visit::visit_expr_call(self, x);
+    }
+
+    fn visit_expr_method_call(&mut self, x: &'ast ExprMethodCall) {
+        self.record_unsafe_op(UnsafeOp::Call);
+        if self.in_unsafe_code() {
+            self.update_details(|d| d.unsafe_calls += 1);
+        }
+        self.record_unsafe_call(Some(format!(".{}", x.method)));
+        visit::visit_expr_method_call(self, x);
+    }
+
+    fn visit_expr_path(&mut self, x: &'ast ExprPath) {
+        let is_static_mut = x.path.get_ident()
+            .is_some_and(|i| self.static_muts.contains(&i.to_string()));
+        if is_static_mut && self.in_unsafe_code() {
+            self.update_details(|d| d.static_mut_accesses += 1);
+        }
+        // Statics defined elsewhere, including foreign statics, can only be recognized by their
+        // names.
+        let looks_like_static = x.path.segments.last().is_some_and(|seg| {
+            let name = seg.ident.to_string();
+            name.chars().any(|c| c.is_ascii_uppercase())
+                && !name.chars().any(|c| c.is_ascii_lowercase())
+        });
+        if is_static_mut || looks_like_static {
+            self.record_unsafe_op(UnsafeOp::Static);
+            self.record_unsafe_call(None);
+        }
+        visit::visit_expr_path(self, x);
+    }
+
+    fn visit_expr_index(&mut self, x: &'ast syn::ExprIndex) {
+        let is_literal = matches!(*x.index,
+            syn::Expr::Lit(ref el) if matches!(el.lit, syn::Lit::Int(_)));
+        if !is_literal
+            && self.out.indexing_sites.is_some()
+            && let Some(ItemKind::Fn(name)) = self.current_item()
+        {
+            let name = name.clone();
+            let indexing_sites = self.out.indexing_sites.as_mut().expect("checked above");
+            *indexing_sites.entry(name).or_default() += 1;
+        }
+        visit::visit_expr_index(self, x);
+    }
+
+    // Reading a union field is unsafe, and unions can't be told apart from structs
+    // syntactically.
+    fn visit_expr_field(&mut self, x: &'ast syn::ExprField) {
+        self.record_unsafe_op(UnsafeOp::FieldAccess);
+        visit::visit_expr_field(self, x);
+    }
+
+    // This matches both `macro_rules! m { }` definitions as well item macro invocations,
+    // (ex. `m!()`). ItemMacro::ident would be `Some(m)` in the first case, and `None`
+    // in the second case.
+    fn visit_item_macro(&mut self, item_mac: &'ast ItemMacro) {
+        let Some(name) = item_mac.ident.as_ref().map(|i| self.qualify(i.to_string())) else {
+            // This is an invocation, pass it along.
+            visit::visit_item_macro(self, item_mac);
+            return;
+        };
+        
+        // This is a macro_rules! definition.
+        if token_stream_contains_unsafe(item_mac.mac.tokens.clone()) {
+            self.out.macro_definitions_containing_unsafe.insert(name);
+        }
+    }
+
+    // This matches all macros generically. The only exception is `macro_rules!` definitions,
+    // which are intercepted by Self::visit_item_macro and not passed down.
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        // The macro's expansion might contain anything.
+        self.record_unsafe_op(UnsafeOp::Macro);
+        self.record_unsafe_call(None);
+        let Path {leading_colon, segments } = &mac.path;
+        let name: String = leading_colon
+            .iter()
+            .map(|_| Default::default())
+            .chain(segments
+                .iter()
+                .map(|seg| seg.ident.to_string()))
+            .collect::<Vec<_>>()
+            .join("::");
+
+        if segments.last().is_some_and(|seg| seg.ident == "asm" || seg.ident == "naked_asm") {
+            self.update_details(|d| d.asm_blocks += 1);
+            self.record_high_risk();
+        }
+
+        if token_stream_contains_unsafe(mac.tokens.clone()) {
+            // Attribute unsafe usage in macro invocation to the function we're in, if we can
+            match self.current_item() {
+                Some(ItemKind::Fn(ident)) | Some(ItemKind::Static(ident)) | Some(ItemKind::Const(ident)) => self.out.fns_containing_unsafe.insert(ident.clone()),
+                None => self.out.global_macro_invocations_containing_unsafe.insert(name),
+            };
+        }
+    }
+}
+
+/// Collects the functions a file exports with `#[no_mangle]` or `#[export_name]`, and the names
+/// it might use to refer to functions, for `possibly_dead_exports`.  References are matched by
+/// name alone, so any function or path with the same name as an export counts as a reference to
+/// it.
+#[derive(Clone, Debug, Default)]
+pub struct ExportCollector {
+    /// Qualified name and plain name of each exported function.
+    pub exports: Vec<(String, String)>,
+    /// The last segment of every path in an expression, such as `f` in `m::f(x)` or in a function
+    /// pointer `cb = f`, and every identifier in a macro invocation.
+    pub references: HashSet<String>,
+    mod_path: Vec<String>,
+}
+
+impl ExportCollector {
+    /// Create a collector that qualifies exported names with their module path, starting from
+    /// `mod_path`.
+    pub fn in_module(mod_path: Vec<String>) -> ExportCollector {
+        ExportCollector { mod_path, ..ExportCollector::default() }
+    }
+
+    fn add_token_references(&mut self, tokens: TokenStream) {
+        for token in tokens {
+            match token {
+                TokenTree::Ident(ident) => {
+                    self.references.insert(ident.to_string());
+                },
+                TokenTree::Group(group) => self.add_token_references(group.stream()),
+                _ => {},
+            }
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for ExportCollector {
+    fn visit_item_mod(&mut self, x: &'ast ItemMod) {
+        self.mod_path.push(x.ident.to_string());
+        visit::visit_item_mod(self, x);
+        self.mod_path.pop();
+    }
+
+    fn visit_item_fn(&mut self, x: &'ast ItemFn) {
+        if exporting_attr(&x.attrs).is_some() {
+            let name = x.sig.ident.to_string();
+            let qualified = self.mod_path.iter().chain(iter::once(&name))
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join("::");
+            self.exports.push((qualified, name));
+        }
+        visit::visit_item_fn(self, x);
+    }
+
+    fn visit_expr_path(&mut self, x: &'ast ExprPath) {
+        if let Some(seg) = x.path.segments.last() {
+            self.references.insert(seg.ident.to_string());
+        }
+        visit::visit_expr_path(self, x);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        self.add_token_references(mac.tokens.clone());
+        visit::visit_macro(self, mac);
+    }
+}
+
+/// Options that affect the analysis of each file.
+#[derive(Clone, Copy, Debug, Default, Hash)]
+pub struct Options {
+    pub report_exported_unsafe: bool,
+    pub detailed: bool,
+    pub report_indexing: bool,
+    pub include_source: bool,
+}
+
+impl Options {
+    pub fn visitor(self, mod_path: Option<Vec<String>>) -> Visitor {
+        let mut v = match mod_path {
+            Some(mod_path) => Visitor::in_module(mod_path),
+            None => Visitor::default(),
+        };
+        v.report_exported_unsafe = self.report_exported_unsafe;
+        if self.detailed {
+            v = v.detailed();
+        }
+        if self.report_indexing {
+            v.out.indexing_sites = Some(BTreeMap::new());
+        }
+        if self.include_source {
+            v.out.unsafe_blocks = Some(Vec::new());
+        }
+        v
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn test_funcs() {
+        let file = include_str!("test_funcs.rs");
+        let ast = syn::parse_str(file).unwrap();
+
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        let Output {
+            internal_unsafe_fns,
+            fns_containing_unsafe,
+            ..
+        } = v.out;
+
+        assert_eq!(internal_unsafe_fns, [
+            "f",
+            "H::i",
+        ].into_iter().map(String::from).collect());
+
+        assert_eq!(fns_containing_unsafe, [
+            "a",
+            "c",
+            "f",
+            "d",
+            "g",
+            "H::h",
+            "H::i",
+            "J::k",
+            "<H as J>::j",
+        ].into_iter().map(String::from).collect());
+    }
+
+    #[test]
+    fn test_statics() {
+        let file = include_str!("test_statics.rs");
+        let ast = syn::parse_str(file).unwrap();
+
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        let Output {
+            statics_containing_unsafe,
+            mutable_statics,
+            ..
+        } = v.out;
+
+        assert_eq!(statics_containing_unsafe, [
+            "A",
+            "C",
+            "D",
+            "F",
+            "_F"
+        ].into_iter().map(String::from).collect());
+
+        assert_eq!(mutable_statics, [
+            "_A",
+            "_C",
+            "_D",
+            "_F",
+        ].into_iter().map(String::from).collect());
+    }
+
+    #[test]
+    fn test_macros() {
+        let file = include_str!("test_macros.rs");
+        let ast = syn::parse_str(file).unwrap();
+
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        let Output {
+            global_macro_invocations_containing_unsafe,
+            macro_definitions_containing_unsafe,
+            fns_containing_unsafe,
+            ..
+        } = v.out;
+
+        assert_eq!(global_macro_invocations_containing_unsafe, [
+            "unsafe_within_invocation2"
+       ].into_iter().map(String::from).collect());
+       
+        assert_eq!(macro_definitions_containing_unsafe, [
+            "unsafe_ExprMacro",
+            "unsafe_ItemMacro",
+            "false_positive",
+            "unsafe_StmtMacro",
+            "unsafe_TypeMacro"
+       ].into_iter().map(String::from).collect());
+
+        assert_eq!(fns_containing_unsafe, [
+            "demo"
+       ].into_iter().map(String::from).collect());       
+    }
+    
+
+    #[test]
+    fn test_mod_paths() {
+        let ast = syn::parse_str(r#"
+            fn a() { unsafe { } }
+            mod m {
+                unsafe fn b() { }
+                struct S;
+                impl S {
+                    fn c(&self) { unsafe { } }
+                }
+            }
+        "#).unwrap();
+
+        let mut v = Visitor::in_module(vec!["top".into()]);
+        v.visit_file(&ast);
+        let Output {
+            internal_unsafe_fns,
+            fns_containing_unsafe,
+            ..
+        } = v.out;
+
+        assert_eq!(internal_unsafe_fns, [
+            "top::m::b",
+        ].into_iter().map(String::from).collect());
+
+        assert_eq!(fns_containing_unsafe, [
+            "top::a",
+            "top::m::S::c",
+        ].into_iter().map(String::from).collect());
+    }
+
+    #[test]
+    fn test_exported_unsafe_fns() {
+        let ast = syn::parse_str(r#"
+            #[no_mangle]
+            unsafe extern "C" fn a() { unsafe { } }
+            #[unsafe(export_name = "b_export")]
+            unsafe extern "C" fn b() { }
+            #[no_mangle]
+            extern "C" fn c() { }
+            pub unsafe extern "C" fn d() { }
+            unsafe extern "system" fn e() { }
+            unsafe extern "Rust" fn f() { }
+        "#).unwrap();
+
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        let Output {
+            internal_unsafe_fns,
+            exported_unsafe_fns,
+            ..
+        } = v.out;
+
+        assert_eq!(internal_unsafe_fns, ["f"].into_iter().map(String::from).collect());
+        assert_eq!(exported_unsafe_fns, BTreeMap::from([
+            ("a".to_owned(), "no_mangle".to_owned()),
+            ("b".to_owned(), "export_name = \"b_export\"".to_owned()),
+            ("d".to_owned(), "extern \"C\"".to_owned()),
+            ("e".to_owned(), "extern \"system\"".to_owned()),
+        ]));
+    }
+
+    #[test]
+    fn test_report_exported_unsafe() {
+        let ast = syn::parse_str(r#"
+            #[no_mangle]
+            unsafe extern "C" fn a() { unsafe { } }
+            unsafe fn b() { }
+        "#).unwrap();
+
+        let mut v = Visitor {
+            report_exported_unsafe: true,
+            ..Visitor::default()
+        };
+        v.visit_file(&ast);
+        let Output {
+            internal_unsafe_fns,
+            exported_unsafe_fns,
+            fns_containing_unsafe,
+            ..
+        } = v.out;
+
+        assert_eq!(internal_unsafe_fns, ["a", "b"].into_iter().map(String::from).collect());
+        assert_eq!(exported_unsafe_fns, BTreeMap::from([
+            ("a".to_owned(), "no_mangle".to_owned()),
+        ]));
+        assert_eq!(fns_containing_unsafe, BTreeSet::from(["a".to_owned()]));
+    }
+
+    #[test]
+    fn test_consts() {
+        let ast = syn::parse_str(r#"
+            static S: i32 = unsafe { 0 };
+            const C: i32 = unsafe { 0 };
+            const D: i32 = 0;
+            mod m {
+                struct T;
+                impl T {
+                    const E: i32 = unsafe { 0 };
+                }
+            }
+        "#).unwrap();
+
+        let mut v = Visitor::in_module(vec![]);
+        v.visit_file(&ast);
+        assert_eq!(v.out.statics_containing_unsafe, [
+            "S",
+            "const C",
+            "const m::T::E",
+        ].into_iter().map(String::from).collect());
+        assert_eq!(v.out.fns_containing_unsafe, BTreeSet::new());
+    }
+
+    #[test]
+    fn test_unsafe_loc() {
+        let ast = syn::parse_str(r#"
+            fn a() {
+                unsafe { f() }
+                unsafe {
+                    g();
+                    unsafe { h() }
+                }
+            }
+            fn b() {}
+            static S: i32 = unsafe {
+                0
+            };
+        "#).unwrap();
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert_eq!(v.out.unsafe_loc, 1 + 4 + 3);
+        assert_eq!(v.out.unsafe_loc_by_fn, BTreeMap::from([("a".to_owned(), 1 + 4)]));
+    }
+
+    #[test]
+    fn test_manual_invariant_types() {
+        let ast = syn::parse_str(r#"
+            fn a() {
+                let x: std::mem::MaybeUninit<i32> = MaybeUninit::uninit();
+            }
+            fn b(p: Pin<&mut T>) {
+                let d = ManuallyDrop::new(p);
+            }
+            fn c() {}
+            struct S { x: MaybeUninit<i32> }
+        "#).unwrap();
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert_eq!(v.out.manual_invariant_types, BTreeMap::from([
+            ("a".to_owned(), BTreeSet::from(["MaybeUninit".to_owned()])),
+            ("b".to_owned(), BTreeSet::from(["ManuallyDrop".to_owned(), "Pin".to_owned()])),
+        ]));
+    }
+
+    #[test]
+    fn test_fn_details() {
+        let ast = syn::parse_str(r#"
+            static mut COUNTER: u32 = 0;
+            unsafe fn a(p: *const u32) -> u32 {
+                COUNTER += 1;
+                *p
+            }
+            fn b(x: &u32) -> u32 {
+                let y = *x;
+                unsafe {
+                    let f: f32 = std::mem::transmute(y);
+                    asm!("nop");
+                    a(&COUNTER)
+                }
+            }
+            fn c() {}
+        "#).unwrap();
+        let mut v = Visitor::default().detailed();
+        v.visit_file(&ast);
+        assert_eq!(v.out.fn_details, Some(BTreeMap::from([
+            ("a".to_owned(), FnDetails {
+                declared_unsafe: true,
+                deref_count: 1,
+                static_mut_accesses: 1,
+                ..FnDetails::default()
+            }),
+            ("b".to_owned(), FnDetails {
+                unsafe_blocks: 1,
+                unsafe_calls: 1,
+                static_mut_accesses: 1,
+                transmutes: 1,
+                asm_blocks: 1,
+                ..FnDetails::default()
+            }),
+        ])));
+
+        // Without `--detailed`, the field is omitted from the output.
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert!(serde_json::to_value(&v.out).unwrap().get("fn_details").is_none());
+    }
+
+    #[test]
+    fn test_foreign_static_mut() {
+        let ast = syn::parse_str(r#"
+            extern "C" {
+                static mut errno_value: i32;
+                static environ_len: usize;
+            }
+            fn a() -> usize {
+                unsafe {
+                    errno_value = 0;
+                    environ_len
+                }
+            }
+        "#).unwrap();
+        let mut v = Visitor::default().detailed();
+        v.visit_file(&ast);
+        assert_eq!(v.out.fn_details.unwrap()["a"].static_mut_accesses, 1);
+    }
+
+    #[test]
+    fn test_severity_scores() {
+        let ast = syn::parse_str(r#"
+            static mut COUNTER: u32 = 0;
+            unsafe fn a(p: *const u32) -> u32 {
+                COUNTER += 1;
+                *p
+            }
+            fn b() {
+                unsafe {
+                    let f: f32 = std::mem::transmute(1u32);
+                    asm!("nop");
+                    a(&COUNTER);
+                }
+            }
+            fn c() {}
+        "#).unwrap();
+        let mut v = Visitor::default().detailed();
+        v.visit_file(&ast);
+        let mut outputs = BTreeMap::from([(PathBuf::from("a.rs"), v.out)]);
+        let weights: Weights =
+            serde_json::from_str(r#"{"asm_blocks": 100, "unsafe_calls": 0}"#).unwrap();
+        assert_eq!(weights.transmutes, Weights::default().transmutes);
+        // `a`: 5 for being unsafe, 2 for the dereference, and 3 for the `static mut`.  `b`: 1
+        // for the block, 3 for the `static mut`, 10 for the transmute, and 100 for the `asm!`.
+        assert_eq!(add_severity_scores(&mut outputs, &weights), 124);
+        assert_eq!(outputs[&PathBuf::from("a.rs")].severity_scores, Some(BTreeMap::from([
+            ("a".to_owned(), 10),
+            ("b".to_owned(), 114),
+        ])));
+        assert!(serde_json::from_str::<Weights>(r#"{"bogus": 1}"#).is_err());
+    }
+
+    #[test]
+    fn test_unsafe_call_targets() {
+        let ast = syn::parse_str(r#"
+            unsafe fn a(p: *const u32) -> u32 { helper::read::<u32>(p) }
+            fn b(v: &mut Vec<u8>) {
+                v.clear();
+                unsafe { v.set_len(1) };
+            }
+            fn c(p: *const u32) -> u32 { unsafe { *p } }
+            fn d(f: extern "C" fn()) {
+                unsafe { a(std::ptr::null()) };
+                unsafe { (f)() };
+            }
+            fn e() -> u32 { 1 }
+        "#).unwrap();
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert_eq!(v.out.unsafe_call_targets, BTreeMap::from([
+            ("a".to_owned(), BTreeSet::from(["helper::read".to_owned()])),
+            ("b".to_owned(), BTreeSet::from([".set_len".to_owned()])),
+            ("d".to_owned(), BTreeSet::from(["a".to_owned(), "std::ptr::null".to_owned()])),
+        ]));
+        assert_eq!(v.out.direct_unsafe_fns, BTreeSet::from(["c".to_owned(), "d".to_owned()]));
+    }
+
+    #[test]
+    fn test_resolve_call_targets() {
+        let ast = syn::parse_str(r#"
+            use crate::ffi::{self, free as ffi_free};
+            use super::alloc;
+            use libc::malloc;
+            fn helper() {}
+            unsafe fn a() {
+                helper();
+                self::helper();
+                ffi::strlen();
+                ffi_free();
+                alloc();
+                malloc();
+                crate::b();
+                super::super::c();
+                ::std::process::abort();
+            }
+            mod inner {
+                unsafe fn b() { super::helper(); helper(); }
+            }
+        "#).unwrap();
+        let mut v = Visitor::in_module(vec!["k".to_owned(), "m".to_owned()]);
+        v.crate_path = vec!["k".to_owned()];
+        v.visit_file(&ast);
+        let targets = |names: &[&str]| names.iter().map(|&s| s.to_owned()).collect::<BTreeSet<_>>();
+        assert_eq!(v.out.unsafe_call_targets, BTreeMap::from([
+            ("k::m::a".to_owned(), targets(&[
+                "k::m::helper",
+                "k::ffi::strlen",
+                "k::ffi::free",
+                "k::alloc",
+                "libc::malloc",
+                "k::b",
+                "super::super::c",
+                "std::process::abort",
+            ])),
+            ("k::m::inner::b".to_owned(), targets(&["k::m::helper", "helper"])),
+        ]));
+    }
+
+    #[test]
+    fn test_export_collector() {
+        let ast = syn::parse_str(r#"
+            #[no_mangle]
+            pub extern "C" fn a() {}
+            mod m {
+                #[unsafe(export_name = "m_b")]
+                pub unsafe extern "C" fn b() {}
+                pub fn c() -> i32 { println!("{:?}", super::d as usize); 0 }
+            }
+            fn e() { m::c(); }
+        "#).unwrap();
+        let mut c = ExportCollector::in_module(vec!["k".to_owned()]);
+        c.visit_file(&ast);
+        assert_eq!(c.exports, [
+            ("k::a".to_owned(), "a".to_owned()),
+            ("k::m::b".to_owned(), "b".to_owned()),
+        ]);
+        for name in ["c", "d"] {
+            assert!(c.references.contains(name), "{name} should be referenced");
+        }
+        assert!(!c.references.contains("a") && !c.references.contains("b"));
+    }
+
+    #[test]
+    fn test_indexing_sites() {
+        let ast = syn::parse_str(r#"
+            fn a(v: &[i32], i: usize) -> i32 { v[i] + v[0] + v[i + 1] }
+            fn b(v: &[[i32; 4]], i: usize) -> i32 { v[i][2] }
+            fn c(v: &[i32]) -> &[i32] { &v[1..] }
+            fn d(v: &[i32]) -> i32 { v[0] }
+            static S: i32 = [1, 2][X];
+        "#).unwrap();
+        let options = Options { report_indexing: true, ..Options::default() };
+        let mut v = options.visitor(None);
+        v.visit_file(&ast);
+        assert_eq!(v.out.indexing_sites, Some(BTreeMap::from([
+            ("a".to_owned(), 2),
+            ("b".to_owned(), 1),
+            ("c".to_owned(), 1),
+        ])));
+
+        // Without `--report-indexing`, the field is omitted from the output.
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert!(serde_json::to_value(&v.out).unwrap().get("indexing_sites").is_none());
+    }
+
+    #[test]
+    fn test_unsafe_blocks() {
+        let src = "fn a(p: *const i32) -> i32 {\n    unsafe {\n        *p + unsafe { *p }\n    \
+            }\n}\nstatic S: i32 = unsafe { 0 };\n";
+        let ast = syn::parse_str(src).unwrap();
+        let options = Options { include_source: true, ..Options::default() };
+        let mut v = options.visitor(None);
+        v.visit_file(&ast);
+        assert_eq!(v.out.unsafe_blocks, Some(vec![
+            UnsafeBlock {
+                item: Some("a".to_owned()),
+                start_line: 2,
+                end_line: 4,
+                ops: 2,
+                derefs: 2,
+                calls: 0,
+                statics: 0,
+                field_accesses: 0,
+                macros: 0,
+                source: Some("unsafe {\n        *p + unsafe { *p }\n    }".to_owned()),
+            },
+            UnsafeBlock {
+                item: Some("S".to_owned()),
+                start_line: 6,
+                end_line: 6,
+                ops: 0,
+                derefs: 0,
+                calls: 0,
+                statics: 0,
+                field_accesses: 0,
+                macros: 0,
+                source: Some("unsafe { 0 }".to_owned()),
+            },
+        ]));
+
+        // Each kind of operation is counted separately.
+        let src = "fn b(u: U) { unsafe { f(u.x, G); println!(); } }";
+        let mut v = options.visitor(None);
+        v.visit_file(&syn::parse_str(src).unwrap());
+        let block = &v.out.unsafe_blocks.unwrap()[0];
+        assert_eq!(
+            (block.ops, block.derefs, block.calls, block.statics, block.field_accesses,
+                block.macros),
+            (4, 0, 1, 1, 1, 1),
+        );
+
+        // Spans without source text, such as those of tokens from a proc macro, leave out the
+        // source.
+        let item: syn::ItemFn = syn::parse_quote! { fn c(p: *const i32) { unsafe { *p; } } };
+        let mut v = options.visitor(None);
+        v.visit_item_fn(&item);
+        let blocks = v.out.unsafe_blocks.unwrap();
+        assert_eq!((blocks[0].ops, blocks[0].source.as_deref()), (1, None));
+    }
+
+    #[test]
+    fn test_high_risk_calls() {
+        let ast = syn::parse_str(r#"
+            unsafe fn a(p: *const u8, n: usize) -> &'static [u8] {
+                let x: u32 = mem::transmute(1.0f32);
+                std::slice::from_raw_parts(p, n)
+            }
+            fn b() {
+                unsafe { asm!("nop") };
+                let s = unsafe { slice::from_raw_parts_mut(ptr::null_mut::<u8>(), 0) };
+            }
+            fn c(x: &[u8]) -> usize {
+                x.len()
+            }
+            static S: u32 = unsafe { core::mem::transmute(1.0f32) };
+        "#).unwrap();
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert_eq!(v.out.high_risk_calls, BTreeMap::from([
+            ("a".to_owned(), 2),
+            ("b".to_owned(), 2),
+            ("S".to_owned(), 1),
+        ]));
+    }
+
+    #[test]
+    fn test_test_only_fns() {
+        let ast = syn::parse_str(r#"
+            unsafe fn a() {}
+            #[test]
+            fn b() { unsafe {} }
+            #[cfg(test)]
+            mod tests {
+                unsafe fn c() {}
+                fn d() { unsafe {} }
+                fn e() {}
+            }
+        "#).unwrap();
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert_eq!(v.out.test_only_fns,
+            ["b", "c", "d"].into_iter().map(String::from).collect());
+        assert!(v.out.internal_unsafe_fns.contains("c"));
+    }
+
+    #[test]
+    fn test_repr_c_types() {
+        let ast = syn::parse_str(r#"
+            #[repr(C)]
+            struct A { x: i32, y: *mut u8 }
+            #[repr(C, packed(2))]
+            struct B(u8, u16);
+            #[derive(Clone)]
+            #[repr(C)]
+            #[repr(align(8))]
+            union U { a: i32, b: f32 }
+            #[repr(u8)]
+            enum E { X, Y }
+            #[repr(C)]
+            enum F { X, Y, Z }
+            struct S { x: i32 }
+        "#).unwrap();
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        let ty = |kind: &str, repr: &[&str], fields| ReprCType {
+            kind: kind.to_owned(),
+            repr: repr.iter().map(|&s| s.to_owned()).collect(),
+            fields,
+        };
+        assert_eq!(v.out.repr_c_types, BTreeMap::from([
+            ("A".to_owned(), ty("struct", &["C"], 2)),
+            ("B".to_owned(), ty("struct", &["C", "packed (2)"], 2)),
+            ("U".to_owned(), ty("union", &["C", "align (8)"], 2)),
+            ("F".to_owned(), ty("enum", &["C"], 3)),
+        ]));
+    }
+
+    #[test]
+    fn test_fn_ptr_types() {
+        let ast = syn::parse_str(r#"
+            fn a(cb: extern "C" fn(i32) -> i32, f: fn()) {}
+            fn b() -> Option<unsafe extern "C" fn()> { None }
+            fn c(f: extern "Rust" fn()) {}
+            struct S {
+                cb: Option<extern fn(*mut u8)>,
+                x: i32,
+            }
+        "#).unwrap();
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        let names = v.out.fn_ptr_types.iter()
+            .map(|(name, types)| (name.as_str(), types.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, [("S", 1), ("a", 1), ("b", 1)]);
+        assert!(v.out.fn_ptr_types["a"].contains("extern \"C\" fn (i32) -> i32"),
+            "{:?}", v.out.fn_ptr_types["a"]);
+    }
+
+    #[test]
+    fn test_sorted_output() {
+        let ast = syn::parse_str(r#"
+            fn c() { unsafe {} }
+            fn a() { unsafe {} }
+            fn b() { unsafe {} }
+        "#).unwrap();
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        let json = serde_json::to_value(&v.out).unwrap();
+        assert_eq!(json["fns_containing_unsafe"], serde_json::json!(["a", "b", "c"]));
+    }
+
+    #[test]
+    fn test_redundant_unsafe_blocks() {
+        let ast = syn::parse_str(r#"
+            static mut X: i32 = 0;
+            fn a(x: i32) -> i32 { unsafe { x + 1 } }
+            fn b(p: *const i32) -> i32 { unsafe { *p } }
+            fn c() -> i32 { unsafe { X } }
+            fn d() { unsafe { f() } }
+            fn e() { unsafe { m!() } }
+            fn g(u: U) -> i32 { unsafe { u.x } }
+            fn h(p: *const i32) -> i32 { unsafe { unsafe { *p } } }
+            fn i(p: *const i32) -> i32 { let y = unsafe { 1 }; y + unsafe { *p } }
+            static S: i32 = unsafe { 1 };
+        "#).unwrap();
+
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert_eq!(v.out.redundant_unsafe_blocks,
+            ["a", "i"].into_iter().map(String::from).collect());
+    }
+
+    #[test]
+    fn test_max_unsafe_depth() {
+        let ast = syn::parse_str(r#"
+            fn a() { unsafe { f() } }
+            fn b() {
+                unsafe { unsafe { f() } }
+            }
+            fn c(x: Option<i32>) {
+                let g = || loop {
+                    match x {
+                        Some(_) => unsafe { f() },
+                        None => { unsafe { f() } },
+                    }
+                };
+                fn inner() { unsafe { f() } }
+            }
+            fn d() {}
+        "#).unwrap();
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert_eq!(v.out.max_unsafe_depth, BTreeMap::from([
+            ("a".to_owned(), 1),
+            ("b".to_owned(), 2),
+            ("c".to_owned(), 4),
+            ("inner".to_owned(), 1),
+        ]));
+    }
+
+    #[test]
+    fn test_ceremonially_unsafe_fns() {
+        let ast = syn::parse_str(r#"
+            unsafe fn a(x: i32) -> i32 { x + 1 }
+            unsafe fn b(p: *const i32) -> i32 { *p }
+            unsafe fn c(p: *const i32) -> i32 { unsafe { *p } }
+            unsafe fn d() { fn inner(p: *const i32) -> i32 { unsafe { *p } } }
+            fn e() { unsafe fn inner() {} }
+            struct S;
+            impl S {
+                unsafe fn f(&self) {}
+                unsafe fn g(&self) { self.h() }
+            }
+            trait T {
+                unsafe fn i(&self);
+                unsafe fn j(&self) {}
+            }
+            #[unsafe(no_mangle)]
+            pub unsafe extern "C" fn k() {}
+        "#).unwrap();
+
+        let mut v = Visitor::default();
+        v.visit_file(&ast);
+        assert_eq!(v.out.ceremonially_unsafe_fns,
+            ["S::f", "T::j", "a", "d", "inner"].into_iter().map(String::from).collect());
+    }
+
+    #[test]
+    fn test_is_link_attr_no_mangle() {
+        let attr: Attribute = parse_quote!(#[no_mangle]);
+        assert_eq!(link_attr(&attr).as_deref(), Some("no_mangle"));
+    }
+
+    #[test]
+    fn test_is_link_attr_export_name() {
+        let attr: Attribute = parse_quote!(#[export_name = "some_name"]);
+        assert_eq!(link_attr(&attr).as_deref(), Some("export_name = \"some_name\""));
+    }
+
+    #[test]
+    fn test_is_link_attr_unsafe() {
+        let attr: Attribute = parse_quote!(#[unsafe(export_name = "some_name")]);
+        assert_eq!(link_attr(&attr).as_deref(), Some("export_name = \"some_name\""));
+    }
+
+    #[test]
+    fn test_is_link_attr_invalid() {
+        let attr: Attribute = parse_quote!(#[some_other_attr]);
+        assert_eq!(link_attr(&attr), None);
+    }
+}
@@ -1,302 +1,430 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::{self, Read};
 use std::path::{self, PathBuf};
+use std::process;
 use clap::Parser;
-use proc_macro2::{TokenStream, TokenTree};
-use quote::ToTokens;
-use serde::Serialize;
-use syn::{
-    self, Attribute, ExprUnsafe, ImplItemFn, ItemFn, ItemImpl, ItemMacro, ItemStatic, ItemTrait,
-    Macro, Meta, Path, StaticMutability, TraitItemFn,
-};
-use syn::visit::{self, Visit};
-
-// Include test files to ensure they compile.
-#[allow(warnings)]
-mod test_funcs;
-#[allow(warnings)]
-mod test_statics;
-#[allow(warnings)]
-mod test_macros;
-
-fn is_link_attr(attr: &Attribute) -> bool {
-    is_link_attr_meta(&attr.meta)
-}
-
-fn is_link_attr_meta(meta: &Meta) -> bool {
-    match *meta {
-        Meta::Path(ref p) => is_link_attr_path(p),
-        Meta::List(ref ml) => {
-            if ml.path.get_ident().map_or(false, |i| i == "unsafe") {
-                let sub_meta = match syn::parse2::<Meta>(ml.tokens.clone()) {
-                    Ok(x) => x,
-                    Err(_) => return false,
-                };
-                is_link_attr_meta(&sub_meta)
-            } else {
-                is_link_attr_path(&ml.path)
-            }
-        },
-        Meta::NameValue(ref mnv) => is_link_attr_path(&mnv.path),
-    }
+use find_unsafe::{ExportCollector, Options, Output, Weights, add_severity_scores};
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+use rust_util::collect::{FileCollector, is_cfg_test};
+use syn::visit::Visit;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[clap(flatten)]
+    input: Input,
+
+    /// With `--crate-root`, qualify reported names with this crate name too, as in
+    /// `mycrate::foo::bar`, so names are unique across the crates of a workspace.
+    #[clap(long, requires = "crate_root")]
+    crate_name: Option<String>,
+
+    /// With `--dir` or `--crate-root`, skip files matching this glob pattern, such as vendored
+    /// shims or generated bindings.  Patterns are matched against each file's path both as given
+    /// and relative to the directory (or the directory of the crate root), and `*` doesn't match
+    /// `/`.  Excluded files of a crate are still parsed to find its modules, but aren't
+    /// analyzed.  This can be given several times.
+    #[clap(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Report exported unsafe functions (FFI entry points) in `internal_unsafe_fns` too, and
+    /// analyze their bodies like those of other functions.  By default, they're only listed in
+    /// `exported_unsafe_fns`.
+    #[clap(long)]
+    report_exported_unsafe: bool,
+
+    /// Also report, in `fn_details`, a breakdown of the unsafe operations in each function.
+    #[clap(long)]
+    detailed: bool,
+
+    /// Also report, in `indexing_sites`, the number of indexing expressions that could panic in
+    /// each function.
+    #[clap(long)]
+    report_indexing: bool,
+
+    /// Also report, in `unsafe_blocks`, each unsafe block with its location, operation counts,
+    /// and source text, so the output can be reviewed without looking up the source.  Blocks
+    /// nested in another unsafe block are included in the outer block's text.
+    #[clap(long)]
+    include_source: bool,
+
+    /// Reuse results from previous runs stored in this file, skipping files whose contents
+    /// haven't changed, and update it with the new results.  Entries for files that weren't
+    /// analyzed in this run are dropped, and a cache from another version is ignored.
+    #[clap(long)]
+    cache: Option<PathBuf>,
+
+    /// Ignore `--cache`, analyzing every file from scratch and leaving the cache file untouched.
+    #[clap(long)]
+    no_cache: bool,
+
+    /// Report file names relative to this directory, such as the root of the crate.  File names
+    /// and the root are canonicalized first, so `..` components and symlinks don't matter.  Files
+    /// outside the root are reported by their canonical path.
+    #[clap(long)]
+    root: Option<PathBuf>,
+
+    /// Compare against the output of a previous run, and report only what changed: functions
+    /// that became unsafe or started containing unsafe code, and ones that stopped.  Files are
+    /// matched by name, so the previous run should have used the same input and `--root`
+    /// options.  Only files with changes are included.
+    #[clap(long)]
+    baseline: Option<PathBuf>,
+
+    /// Check `internal_unsafe_fns` against this allowlist, and exit with an error if any unsafe
+    /// function isn't in it.  The allowlist has one `FILE: NAME` entry per line, as written by
+    /// `--write-allow`; blank lines and lines starting with `#` are ignored.  Entries that no
+    /// longer match an unsafe function are reported, so they can be removed.  Functions whose
+    /// unsafe code only calls allowlisted functions are listed in `delegated_unsafe_fns`.
+    #[clap(long)]
+    allow: Option<PathBuf>,
+
+    /// Write every function in `internal_unsafe_fns` to this file as an allowlist for `--allow`.
+    /// Reviewers can start from the full list and remove entries as functions are audited.  When
+    /// combined with `--allow`, the check uses the old contents of the file.
+    #[clap(long)]
+    write_allow: Option<PathBuf>,
+
+    /// Instead of the per-file output, print a summary for each module that contains unsafe
+    /// code: its unsafe functions, and the number of functions containing unsafe code and their
+    /// lines of unsafe code.  Functions are counted in the module that defines them, so inline
+    /// submodules are reported separately from their parent, and the crate root is reported as
+    /// `crate`, or by its `--crate-name`.
+    #[clap(long, requires = "crate_root", conflicts_with = "baseline")]
+    by_module: bool,
+
+    /// Exit with an error if the files contain more than N high-risk operations in total, as
+    /// counted in `high_risk_calls`.  This allows a hard limit on the most dangerous operations,
+    /// separate from any budget for unsafe code in general.
+    #[clap(long, value_name = "N")]
+    max_high_risk: Option<usize>,
+
+    /// Report a weighted score for each function in `severity_scores`, for ranking functions by
+    /// risk, and print the total score of all functions to stderr.  This implies `--detailed`.
+    /// The file is a JSON object mapping categories of `fn_details`, such as `asm_blocks` or
+    /// `deref_count`, to integer weights; categories it leaves out keep their defaults, which
+    /// weigh `asm!` and `transmute` most heavily, so `{}` uses the default weights.
+    #[clap(long, value_name = "PATH")]
+    weights: Option<PathBuf>,
+
+    /// Output format.  `junit` writes a JUnit XML report for CI test dashboards instead of the
+    /// JSON output, with a failing test case for each function in `internal_unsafe_fns`, grouped
+    /// into one test suite per file.
+    #[clap(long, value_enum, default_value_t = Format::Json,
+        conflicts_with_all = ["baseline", "by_module"])]
+    format: Format,
 }
 
-fn is_link_attr_path(path: &Path) -> bool {
-    match path.get_ident() {
-        Some(i) => {
-            i == "no_mangle" || i == "export_name"
-        },
-        None => false,
-    }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum Format {
+    Json,
+    Junit,
 }
 
-/// Returns `true` if the attributes include a link attribute such as `#[no_mangle]` that exports
-/// the item to other compilation units.
-fn attrs_are_exported(attrs: &[Attribute]) -> bool {
-    attrs.iter().any(is_link_attr)
+/// Changes since the `--baseline` run, for one file.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+struct Delta {
+    /// Functions that are unsafe now, but weren't (or didn't exist) in the baseline.  Exported
+    /// unsafe functions are included.
+    new_unsafe_fns: BTreeSet<String>,
+    /// Functions that were unsafe in the baseline, but now are safe or no longer exist.
+    removed_unsafe_fns: BTreeSet<String>,
+    /// Like `new_unsafe_fns`, but for `fns_containing_unsafe`.
+    new_fns_containing_unsafe: BTreeSet<String>,
+    /// Like `removed_unsafe_fns`, but for `fns_containing_unsafe`.
+    removed_fns_containing_unsafe: BTreeSet<String>,
 }
 
-fn token_stream_contains_unsafe(tokens: TokenStream) -> bool {
-    for token in tokens {
-        match token {
-            TokenTree::Ident(ident) if ident == "unsafe" => return true,
-            TokenTree::Group(group) if token_stream_contains_unsafe(group.stream()) => return true,
-            _ => { continue }
-        }
-    }
-    false
-}
-
-
-#[derive(Clone, Debug, Default)]
-#[derive(Serialize)]
-struct Output {
-    /// Functions that are not accessible from other compilation units and are also unsafe.
-    internal_unsafe_fns: Vec<String>,
-    /// Functions that contain an unsafe block.
-    fns_containing_unsafe: HashSet<String>,
-    /// Static that contain an unsafe block.
-    statics_containing_unsafe: HashSet<String>,
-    /// Static that are mutable, regardless of unsafe.
-    mutable_statics: HashSet<String>,
-    /// Macro invocations that contain an unsafe token, and don't belong to an item (ex global invocations).
-    global_macro_invocations_containing_unsafe: HashSet<String>,
-    /// Macro definitions (macro_rules!) that contain an unsafe token.
-    macro_definitions_containing_unsafe: HashSet<String>,
-}
-
-#[derive(Clone, Debug)]
-enum ItemKind {
-    Fn(String),
-    Static(String),
-}
-
-/// Tracks the enclosing `impl` or `trait` so methods can be reported with a qualified name.
-#[derive(Clone, Debug)]
-enum MethodScope {
-    /// `impl Type { ... }` — methods are reported as `Type::method`.
-    Inherent(String),
-    /// `impl Trait for Type { ... }` — methods are reported as `<Type as Trait>::method`.
-    TraitImpl { self_ty: String, trait_path: String },
-    /// `trait Trait { ... }` — default methods are reported as `Trait::method`.
-    TraitDef(String),
-}
-
-impl MethodScope {
-    fn qualify(&self, method: &syn::Ident) -> String {
-        match self {
-            MethodScope::Inherent(ty) => format!("{}::{}", ty, method),
-            MethodScope::TraitImpl { self_ty, trait_path } => {
-                format!("<{} as {}>::{}", self_ty, trait_path, method)
-            }
-            MethodScope::TraitDef(tr) => format!("{}::{}", tr, method),
-        }
+impl Delta {
+    fn is_empty(&self) -> bool {
+        *self == Delta::default()
     }
 }
 
-fn type_to_string<T: ToTokens>(ty: &T) -> String {
-    ty.to_token_stream().to_string()
+fn unsafe_fns(out: &Output) -> BTreeSet<&String> {
+    out.internal_unsafe_fns.iter().chain(out.exported_unsafe_fns.keys()).collect()
 }
 
-#[derive(Clone, Debug)]
-enum TraversalScope {
-    Item(ItemKind),
-    Method(MethodScope),
+fn diff_output(old: &Output, new: &Output) -> Delta {
+    let added = |old: &BTreeSet<&String>, new: &BTreeSet<&String>| {
+        new.difference(old).map(|&s| s.clone()).collect::<BTreeSet<_>>()
+    };
+    let (old_unsafe, new_unsafe) = (unsafe_fns(old), unsafe_fns(new));
+    let old_containing = old.fns_containing_unsafe.iter().collect();
+    let new_containing = new.fns_containing_unsafe.iter().collect();
+    Delta {
+        new_unsafe_fns: added(&old_unsafe, &new_unsafe),
+        removed_unsafe_fns: added(&new_unsafe, &old_unsafe),
+        new_fns_containing_unsafe: added(&old_containing, &new_containing),
+        removed_fns_containing_unsafe: added(&new_containing, &old_containing),
+    }
 }
 
-#[derive(Clone, Debug, Default)]
-struct Visitor {
-    out: Output,
-    scopes: Vec<TraversalScope>,
+/// Compute the `--baseline` delta for each file in either `baseline` or `outputs`.  A file
+/// missing from one side is treated as having no unsafe code there.
+fn diff_outputs(
+    baseline: &BTreeMap<PathBuf, Output>,
+    outputs: &BTreeMap<PathBuf, Output>,
+) -> BTreeMap<PathBuf, Delta> {
+    let empty = Output::default();
+    baseline.keys().chain(outputs.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|file_name| {
+            let old = baseline.get(file_name).unwrap_or(&empty);
+            let new = outputs.get(file_name).unwrap_or(&empty);
+            (file_name.clone(), diff_output(old, new))
+        })
+        .filter(|(_, delta)| !delta.is_empty())
+        .collect()
 }
 
-impl Visitor {
-    fn with_scope(&mut self, scope: TraversalScope, visit: impl FnOnce(&mut Self)) {
-        self.scopes.push(scope);
-        visit(self);
-        self.scopes
-            .pop()
-            .expect("scope pushed immediately before traversal");
-    }
+/// Totals for one module, reported by `--by-module`.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+struct ModuleSummary {
+    /// The functions in `internal_unsafe_fns` defined in this module.
+    internal_unsafe_fns: BTreeSet<String>,
+    /// Number of functions in `fns_containing_unsafe` defined in this module.
+    fns_containing_unsafe: usize,
+    /// Total `unsafe_loc_by_fn` of those functions.
+    unsafe_loc: usize,
+}
 
-    fn current_item(&self) -> Option<&ItemKind> {
-        // TODO: Treat `Method` as a boundary when finding the current item. Searching past it
-        // preserves the old two-field behavior, but can attribute unsafe in an untracked
-        // associated item of a local impl to the enclosing function.
-        self.scopes.iter().rev().find_map(|s| match s {
-            TraversalScope::Item(i) => Some(i),
-            TraversalScope::Method(_) => None,
-        })
+/// Group the functions in `outputs` by the module that defines them for `--by-module`.  This is
+/// the longest of `mod_paths` that prefixes the function's qualified name, since methods are
+/// qualified by their type as well, as in `m::S::f`.
+fn summarize_by_module(
+    outputs: &BTreeMap<PathBuf, Output>,
+    mod_paths: &[String],
+) -> BTreeMap<String, ModuleSummary> {
+    let module_of = |name: &str| {
+        mod_paths.iter()
+            .filter(|m| !m.is_empty() && name.starts_with(&format!("{m}::")))
+            .max_by_key(|m| m.len())
+            .map_or("crate", |m| m.as_str())
+            .to_owned()
+    };
+    let mut summaries = BTreeMap::<String, ModuleSummary>::new();
+    for out in outputs.values() {
+        for name in &out.internal_unsafe_fns {
+            summaries.entry(module_of(name)).or_default().internal_unsafe_fns.insert(name.clone());
+        }
+        for name in &out.fns_containing_unsafe {
+            let summary = summaries.entry(module_of(name)).or_default();
+            summary.fns_containing_unsafe += 1;
+            summary.unsafe_loc += out.unsafe_loc_by_fn.get(name).copied().unwrap_or(0);
+        }
     }
+    summaries
+}
 
-    fn method_scope(&self) -> Option<&MethodScope> {
-        self.scopes.iter().rev().find_map(|s| match s {
-            TraversalScope::Item(_) => None,
-            TraversalScope::Method(m) => Some(m),
-        })
+/// Format `outputs` as a JUnit XML report for `--format junit`.  Files without unsafe functions are
+/// left out.
+fn format_junit(outputs: &BTreeMap<PathBuf, Output>) -> String {
+    let num_fns = outputs.values().map(|out| out.internal_unsafe_fns.len()).sum::<usize>();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"find_unsafe\" tests=\"{num_fns}\" failures=\"{num_fns}\">\n"));
+    for (file_name, out) in outputs {
+        if out.internal_unsafe_fns.is_empty() {
+            continue;
+        }
+        let file = xml_escape(&file_name.display().to_string());
+        let n = out.internal_unsafe_fns.len();
+        xml.push_str(&format!(
+            "  <testsuite name=\"{file}\" tests=\"{n}\" failures=\"{n}\">\n"));
+        for name in &out.internal_unsafe_fns {
+            let line = out.unsafe_fn_lines.get(name).copied().unwrap_or(0);
+            let message = xml_escape(&format!("unsafe function {name} at {}:{line}",
+                file_name.display()));
+            xml.push_str(&format!("    <testcase classname=\"{file}\" name=\"{}\">\n",
+                xml_escape(name)));
+            xml.push_str(&format!("      <failure type=\"unsafe\" message=\"{message}\"/>\n"));
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n");
     }
+    xml.push_str("</testsuites>\n");
+    xml
 }
 
-impl<'ast> Visit<'ast> for Visitor {
-    fn visit_item_fn(&mut self, item_fn: &'ast ItemFn) {
-        let name = item_fn.sig.ident.to_string();
-        if item_fn.sig.unsafety.is_some() {
-            if attrs_are_exported(&item_fn.attrs) {
-                // Ignore unsafety inside of FFI entry points, as it's often unavoidable.
-                return;
-            } else {
-                self.out.internal_unsafe_fns.push(name.clone());
-            }
+/// Escape `s` for use in XML text or a double-quoted attribute.
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
         }
-
-        self.with_scope(TraversalScope::Item(ItemKind::Fn(name)), |v| {
-            visit::visit_item_fn(v, item_fn)
-        });
     }
+    out
+}
 
-    fn visit_item_impl(&mut self, item_impl: &'ast ItemImpl) {
-        let self_ty = type_to_string(&*item_impl.self_ty);
-        let scope = match &item_impl.trait_ {
-            Some((_bang, trait_path, _for)) => MethodScope::TraitImpl {
-                self_ty,
-                trait_path: type_to_string(trait_path),
-            },
-            None => MethodScope::Inherent(self_ty),
-        };
-        self.with_scope(TraversalScope::Method(scope), |v| {
-            visit::visit_item_impl(v, item_impl)
-        });
-    }
+/// Get the `--allow` entries for all the unsafe functions in `outputs`, as `(file, name)` pairs.
+fn allowlist_entries(outputs: &BTreeMap<PathBuf, Output>) -> BTreeSet<(PathBuf, String)> {
+    outputs.iter()
+        .flat_map(|(file_name, out)| {
+            out.internal_unsafe_fns.iter().map(|name| (file_name.clone(), name.clone()))
+        })
+        .collect()
+}
 
-    fn visit_item_trait(&mut self, item_trait: &'ast ItemTrait) {
-        self.with_scope(
-            TraversalScope::Method(MethodScope::TraitDef(item_trait.ident.to_string())),
-            |v| visit::visit_item_trait(v, item_trait),
-        );
-    }
+fn format_allowlist(entries: &BTreeSet<(PathBuf, String)>) -> String {
+    entries.iter().map(|(file_name, name)| format!("{}: {}\n", file_name.display(), name))
+        .collect()
+}
 
-    fn visit_impl_item_fn(&mut self, item_fn: &'ast ImplItemFn) {
-        let name = match self.method_scope() {
-            Some(scope) => scope.qualify(&item_fn.sig.ident),
-            None => item_fn.sig.ident.to_string(),
-        };
-        if item_fn.sig.unsafety.is_some() {
-            if attrs_are_exported(&item_fn.attrs) {
-                return;
-            } else {
-                self.out.internal_unsafe_fns.push(name.clone());
-            }
-        }
+fn parse_allowlist(s: &str) -> Result<BTreeSet<(PathBuf, String)>, String> {
+    s.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| {
+            let (file_name, name) = line.trim().split_once(": ").ok_or_else(|| {
+                format!("line {}: expected `FILE: NAME`, but got {:?}", i + 1, line)
+            })?;
+            Ok((PathBuf::from(file_name), name.to_owned()))
+        })
+        .collect()
+}
 
-        self.with_scope(TraversalScope::Item(ItemKind::Fn(name)), |v| {
-            visit::visit_impl_item_fn(v, item_fn)
-        });
+/// Fill in `delegated_unsafe_fns` for each of `outputs`: the functions whose unsafe code only
+/// calls functions in `allowed`, and return how many there are.  Function calls are matched by
+/// their full path, so `ptr::read` doesn't match an allowed `m::read`.  Without type information,
+/// a method call matches any allowed function of the same name.
+fn add_delegated_fns(
+    outputs: &mut BTreeMap<PathBuf, Output>,
+    allowed: &BTreeSet<(PathBuf, String)>,
+) -> usize {
+    let allowed_names = allowed.iter().map(|(_, name)| name.as_str()).collect::<HashSet<_>>();
+    let allowed_methods = allowed.iter()
+        .map(|(_, name)| name.rsplit("::").next().unwrap_or(name))
+        .collect::<HashSet<_>>();
+    let is_allowed = |target: &str| match target.strip_prefix('.') {
+        Some(method) => allowed_methods.contains(method),
+        None => allowed_names.contains(target),
+    };
+    let mut total = 0;
+    for out in outputs.values_mut() {
+        let delegated = out.unsafe_call_targets.iter()
+            .filter(|(name, targets)| {
+                !out.direct_unsafe_fns.contains(*name)
+                    && targets.iter().all(|t| is_allowed(t))
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<BTreeSet<_>>();
+        total += delegated.len();
+        out.delegated_unsafe_fns = Some(delegated);
     }
+    total
+}
 
-    fn visit_trait_item_fn(&mut self, item_fn: &'ast TraitItemFn) {
-        // Only default-method bodies can contain `unsafe` blocks; signatures without bodies are
-        // still tracked for `unsafe fn` reporting.
-        let name = match self.method_scope() {
-            Some(scope) => scope.qualify(&item_fn.sig.ident),
-            None => item_fn.sig.ident.to_string(),
-        };
-        if item_fn.sig.unsafety.is_some() {
-            if attrs_are_exported(&item_fn.attrs) {
-                return;
-            } else {
-                self.out.internal_unsafe_fns.push(name.clone());
-            }
-        }
+/// `--cache` entry for a single file.
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Hash of the file contents and the options that affect its `Output`, from `cache_key`.
+    key: u64,
+    output: Output,
+}
 
-        self.with_scope(TraversalScope::Item(ItemKind::Fn(name)), |v| {
-            visit::visit_trait_item_fn(v, item_fn)
-        });
+/// Previously computed `Output`s, indexed by file name.  Each file has at most one entry, so the
+/// entry for a file is replaced, rather than added to, when its contents change.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cache {
+    /// The `CACHE_VERSION` the entries were computed with.  Caches written before this field
+    /// existed read as version 0.
+    #[serde(default)]
+    version: u32,
+    entries: BTreeMap<PathBuf, CacheEntry>,
+    /// Files whose entries were looked up in this run.  Only these are kept by `save`, so entries
+    /// for files that were deleted, renamed, or excluded don't accumulate.
+    #[serde(skip)]
+    used: HashSet<PathBuf>,
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache { version: CACHE_VERSION, entries: BTreeMap::new(), used: HashSet::new() }
     }
+}
 
-    fn visit_item_static(&mut self, item_static: &'ast ItemStatic) {
-        let name = item_static.ident.to_string();
-        if matches!(item_static.mutability, StaticMutability::Mut(_)) {
-            self.out.mutable_statics.insert(name.clone());
+impl Cache {
+    /// Load the cache from `path`.  A missing or unreadable cache, or one from another version of
+    /// the analysis, is treated as empty.
+    fn load(path: &path::Path) -> Cache {
+        let Ok(json) = fs::read_to_string(path) else {
+            return Cache::default();
+        };
+        match serde_json::from_str::<Cache>(&json) {
+            Ok(cache) if cache.version == CACHE_VERSION => cache,
+            Ok(cache) => {
+                eprintln!("note: discarding cache {:?} from analysis version {}", path,
+                    cache.version);
+                Cache::default()
+            },
+            Err(e) => {
+                eprintln!("warning: ignoring invalid cache {:?}: {}", path, e);
+                Cache::default()
+            },
         }
-
-        self.with_scope(TraversalScope::Item(ItemKind::Static(name)), |v| {
-            visit::visit_item_static(v, item_static)
-        });
     }
 
-    fn visit_expr_unsafe(&mut self, x: &'ast ExprUnsafe) {
-        match self.current_item() {
-            Some(ItemKind::Fn(name)) => self.out.fns_containing_unsafe.insert(name.clone()),
-            Some(ItemKind::Static(name)) => self.out.statics_containing_unsafe.insert(name.clone()),
-            None => <_>::default(),
-        };
-        visit::visit_expr_unsafe(self, x);
+    /// Drop the entries for files that weren't looked up in this run.
+    fn prune(&mut self) {
+        self.entries.retain(|file_name, _| self.used.contains(file_name));
     }
 
-    // This matches both `macro_rules! m { }` definitions as well item macro invocations,
-    // (ex. `m!()`). ItemMacro::ident would be `Some(m)` in the first case, and `None`
-    // in the second case.
-    fn visit_item_macro(&mut self, item_mac: &'ast ItemMacro) {
-        let Some(name) = item_mac.ident.as_ref().map(|i| i.to_string()) else {
-            // This is an invocation, pass it along.
-            visit::visit_item_macro(self, item_mac);
-            return;
-        };
-        
-        // This is a macro_rules! definition.
-        if token_stream_contains_unsafe(item_mac.mac.tokens.clone()) {
-            self.out.macro_definitions_containing_unsafe.insert(name);
-        }
+    /// Write the cache to `path`, after pruning it.
+    fn save(mut self, path: &path::Path) -> Result<(), String> {
+        self.prune();
+        let json = serde_json::to_string(&self).map_err(|e| e.to_string())?;
+        fs::write(path, json).map_err(|e| format!("failed to write {:?}: {}", path, e))
     }
 
-    // This matches all macros generically. The only exception is `macro_rules!` definitions,
-    // which are intercepted by Self::visit_item_macro and not passed down.
-    fn visit_macro(&mut self, mac: &'ast Macro) {
-        let Path {leading_colon, segments } = &mac.path;
-        let name: String = leading_colon
-            .iter()
-            .map(|_| Default::default())
-            .chain(segments
-                .iter()
-                .map(|seg| seg.ident.to_string()))
-            .collect::<Vec<_>>()
-            .join("::");
-
-        if token_stream_contains_unsafe(mac.tokens.clone()) {
-            // Attribute unsafe usage in macro invocation to the function we're in, if we can
-            match self.current_item() {
-                Some(ItemKind::Fn(ident)) | Some(ItemKind::Static(ident)) => self.out.fns_containing_unsafe.insert(ident.clone()),
-                None => self.out.global_macro_invocations_containing_unsafe.insert(name),
-            };
+    /// Return the cached output for `file_name` if it was computed with the same `key`, or
+    /// otherwise compute it with `analyze` and store it.
+    fn get_or_analyze(
+        &mut self,
+        file_name: &path::Path,
+        key: u64,
+        analyze: impl FnOnce() -> Result<Output, String>,
+    ) -> Result<Output, String> {
+        self.used.insert(file_name.to_owned());
+        if let Some(entry) = self.entries.get(file_name)
+            && entry.key == key
+        {
+            return Ok(entry.output.clone());
         }
+        let output = analyze()?;
+        self.entries.insert(file_name.to_owned(), CacheEntry { key, output: output.clone() });
+        Ok(output)
     }
 }
 
+/// Version of the analysis, recorded in the `--cache` file.  This should be bumped whenever
+/// `Output` or the way it's computed changes, so results from older versions aren't reused.
+/// (Older entries would still parse, since missing fields default to empty.)
+const CACHE_VERSION: u32 = 13;
+
+/// Compute the `--cache` key for a file with contents `src`.  The module path and options are
+/// included, since they also affect the output.  `DefaultHasher::new` is deterministic, though
+/// its results may change between Rust releases; that only causes spurious cache misses.
+fn cache_key(src: &str, mod_path: &[String], in_test: bool, options: Options) -> u64 {
+    let mut h = DefaultHasher::new();
+    src.hash(&mut h);
+    mod_path.hash(&mut h);
+    in_test.hash(&mut h);
+    options.hash(&mut h);
+    h.finish()
+}
 
-#[derive(Parser, Debug)]
+/// Where to read the code to analyze from.  Exactly one of these must be given.
+#[derive(clap::Args, Debug)]
 #[group(multiple = false, required = true)]
-struct Args {
+struct Input {
     /// Read a single file from stdin and report any unsafe code it contains.
     #[clap(long)]
     stdin: bool,
@@ -316,6 +444,12 @@ struct Args {
     /// Read all files within a directory (recursively) and report on all of them.
     #[clap(long)]
     dir: Option<PathBuf>,
+
+    /// Read the crate rooted at the given file (`lib.rs` or `main.rs`), following `mod`
+    /// declarations to find the rest of its files.  Reported names are qualified with their
+    /// module path.
+    #[clap(long)]
+    crate_root: Option<PathBuf>,
 }
 
 fn read_stdin() -> io::Result<HashMap<PathBuf, String>> {
@@ -347,181 +481,590 @@ fn read_file_into(
     Ok(())
 }
 
+/// Read every `.rs` file under `path`.  A file that can be reached by several paths, such as
+/// through a symlink, is only read once, under the first of its paths in sorted order.
 fn read_dir(path: &path::Path) -> io::Result<HashMap<PathBuf, String>> {
     let mut m = HashMap::new();
-    read_dir_into(path, &mut m)?;
+    read_dir_into(path, &mut m, &mut HashSet::new())?;
     Ok(m)
 }
 
 fn read_dir_into(
     path: &path::Path,
     dest: &mut HashMap<PathBuf, String>,
+    seen: &mut HashSet<PathBuf>,
 ) -> io::Result<()> {
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
+    let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
         if entry.file_type()?.is_dir() {
-            read_dir_into(&entry.path(), dest)?;
+            read_dir_into(&entry.path(), dest, seen)?;
         } else {
-            if let Some(name) = entry.file_name().to_str() {
-                if name.ends_with(".rs") && !name.starts_with('.') {
-                    read_file_into(&entry.path(), dest)?;
-                }
+            if let Some(name) = entry.file_name().to_str()
+                && name.ends_with(".rs")
+                && !name.starts_with('.')
+                && seen.insert(fs::canonicalize(entry.path())?)
+            {
+                read_file_into(&entry.path(), dest)?;
             }
         }
     }
     Ok(())
 }
 
-fn read_files(args: &Args) -> Result<HashMap<PathBuf, String>, String> {
-    if args.stdin {
+fn read_files(input: &Input) -> Result<HashMap<PathBuf, String>, String> {
+    if input.stdin {
         read_stdin().map_err(|e| e.to_string())
-    } else if args.stdin_cbor {
+    } else if input.stdin_cbor {
         read_stdin_cbor()
-    } else if let Some(ref file) = args.file {
+    } else if let Some(ref file) = input.file {
         read_file(file).map_err(|e| e.to_string())
-    } else if let Some(ref dir) = args.dir {
+    } else if let Some(ref dir) = input.dir {
         read_dir(dir).map_err(|e| e.to_string())
     } else {
         panic!("must pass at least one input option")
     }
 }
 
+/// Check whether `file_path` matches any of the `--exclude` `patterns`, either as given or
+/// relative to `base_dir`.
+fn is_excluded(file_path: &path::Path, base_dir: &path::Path, patterns: &[Pattern]) -> bool {
+    let paths = [Some(file_path), file_path.strip_prefix(base_dir).ok()];
+    // As in the shell, `*` doesn't match `/`; use `**` to match across directories.
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+    patterns.iter().any(|pattern| {
+        paths.iter().flatten().any(|path| pattern.matches_path_with(path, options))
+    })
+}
+
+/// Analyze every file of the crate rooted at `root_path`, qualifying names by module path, which
+/// starts with `crate_name` if it's set.  Files matching `exclude` are skipped.  Returns the
+/// output for each file, along with the paths of all the crate's modules, including inline ones.
+///
+/// With a `cache`, files are still parsed to find the crate's modules, but files whose outputs
+/// are cached aren't visited again.
+fn analyze_crate(
+    root_path: &path::Path,
+    crate_name: Option<&str>,
+    exclude: &[Pattern],
+    options: Options,
+    mut cache: Option<&mut Cache>,
+) -> Result<(BTreeMap<PathBuf, Output>, Vec<String>), String> {
+    let mut fc = FileCollector::default();
+    fc.keep_sources = cache.is_some();
+    fc.crate_name = crate_name.map(|s| s.to_owned());
+    fc.parse(root_path, vec![], true).map_err(|e| e.to_string())?;
+
+    // Files of modules declared `#[cfg(test)]`, or inside such a module, are test code throughout.
+    let test_mods = fc.mods.iter()
+        .filter(|m| is_cfg_test(&m.attrs))
+        .map(|m| &m.mod_path)
+        .collect::<Vec<_>>();
+    let root_dir = root_path.parent().unwrap_or(path::Path::new(""));
+    let mut outputs = BTreeMap::new();
+    for (file_name, mod_path, ast) in &fc.files {
+        if is_excluded(file_name, root_dir, exclude) {
+            continue;
+        }
+        let in_test = test_mods.iter().any(|test_mod| mod_path.starts_with(test_mod));
+        let analyze = || {
+            let mut v = options.visitor(Some(mod_path.clone()));
+            v.crate_path = crate_name.map(|s| vec![s.to_owned()]).unwrap_or_default();
+            v.test_depth = in_test as usize;
+            v.visit_file(ast);
+            Ok(v.out)
+        };
+        let output = match cache {
+            Some(ref mut cache) => {
+                let key = cache_key(&fc.sources[file_name], mod_path, in_test, options);
+                cache.get_or_analyze(file_name, key, analyze)?
+            },
+            None => analyze()?,
+        };
+        outputs.insert(file_name.clone(), output);
+    }
+
+    // References from excluded files still keep exports alive.
+    let mut references = HashSet::new();
+    let mut exports = Vec::new();
+    for (file_name, mod_path, ast) in &fc.files {
+        let mut c = ExportCollector::in_module(mod_path.clone());
+        c.visit_file(ast);
+        references.extend(c.references);
+        exports.push((file_name, c.exports));
+    }
+    for (file_name, file_exports) in exports {
+        if let Some(out) = outputs.get_mut(file_name) {
+            out.possibly_dead_exports = Some(file_exports.into_iter()
+                .filter(|(_, name)| !references.contains(name))
+                .map(|(qualified, _)| qualified)
+                .collect());
+        }
+    }
+
+    let mod_paths = fc.mods.iter().map(|m| m.mod_path.join("::")).collect();
+    Ok((outputs, mod_paths))
+}
+
+/// Analyze each of `files`, a map from file names to file contents.
+fn analyze_files(
+    files: HashMap<PathBuf, String>,
+    options: Options,
+    mut cache: Option<&mut Cache>,
+) -> Result<BTreeMap<PathBuf, Output>, String> {
+    let mut outputs = BTreeMap::new();
+    for (file_name, src) in files {
+        let analyze = || {
+            let ast = syn::parse_file(&src)
+                .map_err(|e| format!("failed to parse {:?}: {}", file_name, e))?;
+            let mut v = options.visitor(None);
+            v.visit_file(&ast);
+            Ok(v.out)
+        };
+        let output = match cache {
+            Some(ref mut cache) => {
+                let key = cache_key(&src, &[], false, options);
+                cache.get_or_analyze(&file_name, key, analyze)?
+            },
+            None => analyze()?,
+        };
+        outputs.insert(file_name, output);
+    }
+    Ok(outputs)
+}
+
+/// Rename each file in `outputs` to its path relative to `root`.  See `Args::root`.  Names that
+/// don't refer to an existing file, as with `--stdin`, are left as they are.
+fn relative_to_root(
+    outputs: BTreeMap<PathBuf, Output>,
+    root: &path::Path,
+) -> Result<BTreeMap<PathBuf, Output>, String> {
+    let root = fs::canonicalize(root).map_err(|e| format!("bad --root {:?}: {}", root, e))?;
+    let mut out = BTreeMap::new();
+    for (file_name, output) in outputs {
+        let file_name = fs::canonicalize(&file_name).unwrap_or(file_name);
+        let file_name = match file_name.strip_prefix(&root) {
+            Ok(rel) => rel.to_owned(),
+            Err(_) => file_name,
+        };
+        if out.insert(file_name.clone(), output).is_some() {
+            return Err(format!("file {:?} was analyzed twice", file_name));
+        }
+    }
+    Ok(out)
+}
+
 fn main() {
     let args = Args::parse();
+    let options = Options {
+        report_exported_unsafe: args.report_exported_unsafe,
+        detailed: args.detailed || args.weights.is_some(),
+        report_indexing: args.report_indexing,
+        include_source: args.include_source,
+    };
+    let weights = args.weights.as_ref().map(|weights_path| {
+        let json = fs::read_to_string(weights_path).unwrap_or_else(|e| {
+            panic!("failed to read weights {:?}: {}", weights_path, e);
+        });
+        serde_json::from_str::<Weights>(&json).unwrap_or_else(|e| {
+            panic!("failed to parse weights {:?}: {}", weights_path, e);
+        })
+    });
+    let cache_path = args.cache.as_deref().filter(|_| !args.no_cache);
+    let mut cache = cache_path.map(Cache::load);
+    let exclude = args.exclude.iter().map(|pattern| {
+        Pattern::new(pattern).unwrap_or_else(|e| {
+            panic!("bad --exclude pattern {:?}: {}", pattern, e);
+        })
+    }).collect::<Vec<_>>();
 
-    let files = read_files(&args).unwrap();
+    let (outputs, mod_paths) = if let Some(ref root_path) = args.input.crate_root {
+        let crate_name = args.crate_name.as_deref();
+        analyze_crate(root_path, crate_name, &exclude, options, cache.as_mut()).unwrap()
+    } else {
+        let mut files = read_files(&args.input).unwrap();
+        if let Some(ref dir) = args.input.dir {
+            files.retain(|file_name, _| !is_excluded(file_name, dir, &exclude));
+        }
+        (analyze_files(files, options, cache.as_mut()).unwrap(), Vec::new())
+    };
 
-    let mut outputs = HashMap::new();
-    for (file_name, src) in files {
-        let ast = syn::parse_file(&src).unwrap();
+    if let (Some(cache), Some(cache_path)) = (cache, cache_path) {
+        cache.save(cache_path).unwrap();
+    }
+    let mut outputs = match args.root {
+        Some(ref root) => relative_to_root(outputs, root).unwrap(),
+        None => outputs,
+    };
+    if let Some(ref weights) = weights {
+        let total = add_severity_scores(&mut outputs, weights);
+        eprintln!("total severity score: {}", total);
+    }
 
-        let mut v = Visitor::default();
-        v.visit_file(&ast);
-        outputs.insert(file_name, v.out);
+    // Read the allowlist first, in case `--write-allow` overwrites it.
+    let allowed = args.allow.as_ref().map(|allow_path| {
+        let s = fs::read_to_string(allow_path).unwrap_or_else(|e| {
+            panic!("failed to read allowlist {:?}: {}", allow_path, e);
+        });
+        parse_allowlist(&s).unwrap_or_else(|e| panic!("{}: {}", allow_path.display(), e))
+    });
+    if let Some(ref allowed) = allowed {
+        let total = add_delegated_fns(&mut outputs, allowed);
+        eprintln!("note: {} functions only call allowlisted unsafe functions", total);
+    }
+    let found = allowlist_entries(&outputs);
+    if let Some(ref write_allow_path) = args.write_allow {
+        fs::write(write_allow_path, format_allowlist(&found)).unwrap();
+    }
+
+    if let Some(ref baseline_path) = args.baseline {
+        let json = fs::read_to_string(baseline_path).unwrap_or_else(|e| {
+            panic!("failed to read baseline {:?}: {}", baseline_path, e);
+        });
+        let baseline: BTreeMap<PathBuf, Output> = serde_json::from_str(&json).unwrap_or_else(|e| {
+            panic!("failed to parse baseline {:?}: {}", baseline_path, e);
+        });
+        serde_json::to_writer(io::stdout(), &diff_outputs(&baseline, &outputs)).unwrap();
+    } else if args.by_module {
+        serde_json::to_writer(io::stdout(), &summarize_by_module(&outputs, &mod_paths)).unwrap();
+    } else if args.format == Format::Junit {
+        print!("{}", format_junit(&outputs));
+    } else {
+        serde_json::to_writer(io::stdout(), &outputs).unwrap();
+    }
+    if args.format == Format::Json {
+        println!();
+    }
+
+    let mut failed = false;
+    if let Some(max) = args.max_high_risk {
+        let total = outputs.values().flat_map(|out| out.high_risk_calls.values()).sum::<usize>();
+        if total > max {
+            for (file_name, out) in &outputs {
+                for (name, count) in &out.high_risk_calls {
+                    eprintln!("error: {} in {:?} has {} high-risk operations", name, file_name,
+                        count);
+                }
+            }
+            eprintln!("error: found {} high-risk operations, more than the limit of {}", total,
+                max);
+            failed = true;
+        }
     }
 
-    serde_json::to_writer(io::stdout(), &outputs).unwrap();
-    println!();
+    if let Some(allowed) = allowed {
+        for (file_name, name) in allowed.difference(&found) {
+            eprintln!("note: allowed function {} in {:?} is no longer unsafe", name, file_name);
+        }
+        let new = found.difference(&allowed).collect::<Vec<_>>();
+        for (file_name, name) in &new {
+            eprintln!("error: unsafe function {} in {:?} is not in the allowlist", name, file_name);
+        }
+        if !new.is_empty() {
+            eprintln!("error: found {} unsafe functions not in the allowlist", new.len());
+            failed = true;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use syn::parse_quote;
+    use find_unsafe::Visitor;
 
     #[test]
-    fn test_funcs() {
-        let file = include_str!("test_funcs.rs");
-        let ast = syn::parse_str(file).unwrap();
-
+    fn test_format_junit() {
+        let src = "unsafe fn a() {}\nfn b() {}\nimpl<T> S<T> {\n    unsafe fn c() {}\n}\n";
+        let ast = syn::parse_str(src).unwrap();
         let mut v = Visitor::default();
         v.visit_file(&ast);
-        let Output {
-            internal_unsafe_fns,
-            fns_containing_unsafe,
-            ..
-        } = v.out;
-
-        assert_eq!(internal_unsafe_fns, [
-            "f",
-            "H::i",
+        let outputs = BTreeMap::from([
+            (PathBuf::from("src/a&b.rs"), v.out),
+            (PathBuf::from("src/safe.rs"), Output::default()),
         ]);
+        assert_eq!(format_junit(&outputs), concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<testsuites name=\"find_unsafe\" tests=\"2\" failures=\"2\">\n",
+            "  <testsuite name=\"src/a&amp;b.rs\" tests=\"2\" failures=\"2\">\n",
+            "    <testcase classname=\"src/a&amp;b.rs\" name=\"S &lt; T &gt;::c\">\n",
+            "      <failure type=\"unsafe\" \
+                message=\"unsafe function S &lt; T &gt;::c at src/a&amp;b.rs:4\"/>\n",
+            "    </testcase>\n",
+            "    <testcase classname=\"src/a&amp;b.rs\" name=\"a\">\n",
+            "      <failure type=\"unsafe\" message=\"unsafe function a at src/a&amp;b.rs:1\"/>\n",
+            "    </testcase>\n",
+            "  </testsuite>\n",
+            "</testsuites>\n",
+        ));
+    }
 
-        assert_eq!(fns_containing_unsafe, [
-            "a",
-            "c",
-            "f",
-            "d",
-            "g",
-            "H::h",
-            "H::i",
-            "J::k",
-            "<H as J>::j",
-        ].into_iter().map(String::from).collect());
+    #[test]
+    fn test_test_only_fns_out_of_line() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "#[cfg(test)]\nmod tests;\nunsafe fn f() {}\n")
+            .unwrap();
+        fs::create_dir(dir.path().join("tests")).unwrap();
+        fs::write(dir.path().join("tests.rs"), "mod sub;\nunsafe fn g() {}\n").unwrap();
+        fs::write(dir.path().join("tests/sub.rs"), "fn h() { unsafe {} }\n").unwrap();
+        let options = Options::default();
+        let (outputs, _) =
+            analyze_crate(&dir.path().join("lib.rs"), None, &[], options, None).unwrap();
+        let test_only = |file: &str| outputs[&dir.path().join(file)].test_only_fns.clone();
+        assert!(test_only("lib.rs").is_empty());
+        assert_eq!(test_only("tests.rs"), BTreeSet::from(["tests::g".to_owned()]));
+        assert_eq!(test_only("tests/sub.rs"), BTreeSet::from(["tests::sub::h".to_owned()]));
     }
 
     #[test]
-    fn test_statics() {
-        let file = include_str!("test_statics.rs");
-        let ast = syn::parse_str(file).unwrap();
+    fn test_possibly_dead_exports() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), r#"
+            mod ffi;
+            fn run() { ffi::used(); }
+        "#).unwrap();
+        fs::write(dir.path().join("ffi.rs"), r#"
+            #[no_mangle]
+            pub extern "C" fn used() {}
+            #[no_mangle]
+            pub extern "C" fn callback() {}
+            #[export_name = "ffi_dead"]
+            pub extern "C" fn dead() {}
+            pub fn register() -> extern "C" fn() { callback }
+        "#).unwrap();
+        let (outputs, _) = analyze_crate(&dir.path().join("lib.rs"), None, &[],
+            Options::default(), None).unwrap();
+        let dead = |file: &str| outputs[&dir.path().join(file)].possibly_dead_exports.clone();
+        assert_eq!(dead("lib.rs"), Some(BTreeSet::new()));
+        assert_eq!(dead("ffi.rs"), Some(BTreeSet::from(["ffi::dead".to_owned()])));
+    }
 
-        let mut v = Visitor::default();
-        v.visit_file(&ast);
-        let Output {
-            statics_containing_unsafe,
-            mutable_statics,
-            ..
-        } = v.out;
-
-        assert_eq!(statics_containing_unsafe, [
-            "A",
-            "C",
-            "D",
-            "F",
-            "_F"
-        ].into_iter().map(String::from).collect());
-
-        assert_eq!(mutable_statics, [
-            "_A",
-            "_C",
-            "_D",
-            "_F",
-        ].into_iter().map(String::from).collect());
+    #[test]
+    fn test_summarize_by_module() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "mod a;\nunsafe fn f() {}\n").unwrap();
+        fs::write(dir.path().join("a.rs"), r#"
+            unsafe fn g() {}
+            fn h() { unsafe {} }
+            struct S;
+            impl S {
+                unsafe fn i(&self) {}
+            }
+            mod b {
+                fn j() {
+                    unsafe {
+                    }
+                }
+            }
+        "#).unwrap();
+        let options = Options::default();
+        let (outputs, mod_paths) =
+            analyze_crate(&dir.path().join("lib.rs"), None, &[], options, None).unwrap();
+        let summaries = summarize_by_module(&outputs, &mod_paths);
+        let names = |names: &[&str]| names.iter().map(|&s| s.to_owned()).collect();
+        assert_eq!(summaries, BTreeMap::from([
+            ("crate".to_owned(), ModuleSummary {
+                internal_unsafe_fns: names(&["f"]),
+                ..ModuleSummary::default()
+            }),
+            ("a".to_owned(), ModuleSummary {
+                internal_unsafe_fns: names(&["a::S::i", "a::g"]),
+                fns_containing_unsafe: 1,
+                unsafe_loc: 1,
+            }),
+            ("a::b".to_owned(), ModuleSummary {
+                internal_unsafe_fns: BTreeSet::new(),
+                fns_containing_unsafe: 1,
+                unsafe_loc: 2,
+            }),
+        ]));
     }
 
     #[test]
-    fn test_macros() {
-        let file = include_str!("test_macros.rs");
-        let ast = syn::parse_str(file).unwrap();
+    fn test_exclude() {
+        let patterns = [Pattern::new("vendor/**").unwrap(), Pattern::new("*_bindings.rs").unwrap()];
+        let check = |path: &str| {
+            is_excluded(path::Path::new(path), path::Path::new("src"), &patterns)
+        };
+        assert!(check("src/vendor/shim.rs"));
+        assert!(check("src/vendor/a/b.rs"));
+        assert!(check("src/c_bindings.rs"));
+        assert!(!check("src/ffi/c_bindings.rs"));
+        assert!(!check("src/lib.rs"));
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "mod vendor;\nunsafe fn f() {}\n").unwrap();
+        fs::write(dir.path().join("vendor.rs"), "unsafe fn g() {}\n").unwrap();
+        let options = Options::default();
+        let exclude = [Pattern::new("vendor.rs").unwrap()];
+        let (outputs, mod_paths) =
+            analyze_crate(&dir.path().join("lib.rs"), None, &exclude, options, None).unwrap();
+        assert_eq!(outputs.keys().cloned().collect::<Vec<_>>(), [dir.path().join("lib.rs")]);
+        assert_eq!(mod_paths, ["vendor", ""]);
+    }
+
+    #[test]
+    fn test_read_dir_dedup() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.rs"), "fn a() {}").unwrap();
+        fs::write(dir.path().join("sub/b.rs"), "fn b() { unsafe {} }").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("sub/b.rs"), dir.path().join("c.rs")).unwrap();
+
+        let files = read_dir(dir.path()).unwrap();
+        let mut names = files.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [dir.path().join("a.rs"), dir.path().join("c.rs")]);
+
+        let options = Options::default();
+        let outputs = analyze_files(files, options, None).unwrap();
+        let outputs = relative_to_root(outputs, &dir.path().join("sub/..")).unwrap();
+        // `c.rs` is reported by its canonical path, which is under the root.
+        let names = outputs.keys().cloned().collect::<Vec<_>>();
+        assert_eq!(names, [PathBuf::from("a.rs"), PathBuf::from("sub/b.rs")]);
+    }
 
-        let mut v = Visitor::default();
-        v.visit_file(&ast);
-        let Output {
-            global_macro_invocations_containing_unsafe,
-            macro_definitions_containing_unsafe,
-            fns_containing_unsafe,
-            ..
-        } = v.out;
-
-        assert_eq!(global_macro_invocations_containing_unsafe, [
-            "unsafe_within_invocation2"
-       ].into_iter().map(String::from).collect());
-       
-        assert_eq!(macro_definitions_containing_unsafe, [
-            "unsafe_ExprMacro",
-            "unsafe_ItemMacro",
-            "false_positive",
-            "unsafe_StmtMacro",
-            "unsafe_TypeMacro"
-       ].into_iter().map(String::from).collect());
-
-        assert_eq!(fns_containing_unsafe, [
-            "demo"
-       ].into_iter().map(String::from).collect());       
-    }
-    
     #[test]
-    fn test_is_link_attr_no_mangle() {
-        let attr: Attribute = parse_quote!(#[no_mangle]);
-        assert!(is_link_attr(&attr));
+    fn test_baseline() {
+        let analyze = |src: &str| {
+            let mut v = Visitor::default();
+            v.visit_file(&syn::parse_str(src).unwrap());
+            v.out
+        };
+        let baseline = BTreeMap::from([
+            (PathBuf::from("a.rs"), analyze("unsafe fn a() {} fn b() { unsafe {} } fn c() {}")),
+            (PathBuf::from("gone.rs"), analyze("unsafe fn g() {}")),
+            (PathBuf::from("same.rs"), analyze("unsafe fn s() {}")),
+        ]);
+        let outputs = BTreeMap::from([
+            (PathBuf::from("a.rs"), analyze("fn a() {} fn b() { unsafe {} } unsafe fn c() {}")),
+            (PathBuf::from("same.rs"), analyze("unsafe fn s() {}")),
+            (PathBuf::from("new.rs"), analyze("fn n() { unsafe {} }")),
+        ]);
+        let set = |names: &[&str]| names.iter().map(|&s| s.to_owned()).collect::<BTreeSet<_>>();
+        assert_eq!(diff_outputs(&baseline, &outputs), BTreeMap::from([
+            (PathBuf::from("a.rs"), Delta {
+                new_unsafe_fns: set(&["c"]),
+                removed_unsafe_fns: set(&["a"]),
+                ..Delta::default()
+            }),
+            (PathBuf::from("gone.rs"), Delta {
+                removed_unsafe_fns: set(&["g"]),
+                ..Delta::default()
+            }),
+            (PathBuf::from("new.rs"), Delta {
+                new_fns_containing_unsafe: set(&["n"]),
+                ..Delta::default()
+            }),
+        ]));
+
+        // Output from before a field was added still parses.
+        let old: Output = serde_json::from_str(r#"{"internal_unsafe_fns": ["x"]}"#).unwrap();
+        assert_eq!(old.internal_unsafe_fns, BTreeSet::from(["x".to_owned()]));
     }
 
     #[test]
-    fn test_is_link_attr_export_name() {
-        let attr: Attribute = parse_quote!(#[export_name = "some_name"]);
-        assert!(is_link_attr(&attr));
+    fn test_allowlist() {
+        let out = Output {
+            internal_unsafe_fns: BTreeSet::from(["m::f".to_owned(), "<S as T>::g".to_owned()]),
+            ..Output::default()
+        };
+        let outputs = BTreeMap::from([(PathBuf::from("src/lib.rs"), out)]);
+        let entries = allowlist_entries(&outputs);
+        let s = format_allowlist(&entries);
+        assert_eq!(s, "src/lib.rs: <S as T>::g\nsrc/lib.rs: m::f\n");
+        assert_eq!(parse_allowlist(&s).unwrap(), entries);
+
+        let allowed = parse_allowlist("# reviewed\n\nsrc/lib.rs: m::f\n").unwrap();
+        let new = entries.difference(&allowed).collect::<Vec<_>>();
+        assert_eq!(new, [&(PathBuf::from("src/lib.rs"), "<S as T>::g".to_owned())]);
+        assert!(parse_allowlist("m::f\n").is_err());
     }
 
     #[test]
-    fn test_is_link_attr_unsafe() {
-        let attr: Attribute = parse_quote!(#[unsafe(export_name = "some_name")]);
-        assert!(is_link_attr(&attr));
+    fn test_delegated_fns() {
+        let ast = syn::parse_str(r#"
+            use std::ptr;
+            unsafe fn read(p: *const u32) -> u32 { *p }
+            unsafe fn unreviewed(p: *const u32) -> u32 { *p }
+            fn a(p: *const u32) -> u32 { unsafe { read(p) } }
+            fn b(p: *const u32) -> u32 { unsafe { self::read(p) + unreviewed(p) } }
+            fn c(p: *const u32) -> u32 { unsafe { read(p) + *p } }
+            unsafe fn d(p: *const u32) -> u32 { read(p) }
+            fn e(p: *const u32) -> u32 { unsafe { ptr::read(p) } }
+            mod n {
+                use crate::m::read as m_read;
+                fn f(p: *const u32) -> u32 { unsafe { super::read(p) + m_read(p) } }
+                fn g(p: *const u32) -> u32 { unsafe { ptr::read(p) } }
+            }
+        "#).unwrap();
+        let mut v = Visitor::in_module(vec!["k".to_owned(), "m".to_owned()]);
+        v.crate_path = vec!["k".to_owned()];
+        v.visit_file(&ast);
+        let mut outputs = BTreeMap::from([(PathBuf::from("src/m.rs"), v.out)]);
+        let allowed = parse_allowlist("src/m.rs: k::m::read\n").unwrap();
+        assert_eq!(add_delegated_fns(&mut outputs, &allowed), 3);
+        assert_eq!(outputs[&PathBuf::from("src/m.rs")].delegated_unsafe_fns, Some(BTreeSet::from([
+            "k::m::a".to_owned(),
+            "k::m::d".to_owned(),
+            "k::m::n::f".to_owned(),
+        ])));
     }
 
     #[test]
-    fn test_is_link_attr_invalid() {
-        let attr: Attribute = parse_quote!(#[some_other_attr]);
-        assert!(!is_link_attr(&attr));
+    fn test_cache() {
+        let options = Options::default();
+        let analyze = |src: &str| {
+            let mut v = options.visitor(None);
+            v.visit_file(&syn::parse_str(src).unwrap());
+            Ok(v.out)
+        };
+        let file_name = path::Path::new("a.rs");
+        let src1 = "fn a() { unsafe {} }";
+        let src2 = "fn b() { unsafe {} }";
+
+        let mut cache = Cache::default();
+        let key = cache_key(src1, &[], false, options);
+        let out = cache.get_or_analyze(file_name, key, || analyze(src1));
+        assert_eq!(out.unwrap().fns_containing_unsafe, BTreeSet::from(["a".to_owned()]));
+
+        // A hit doesn't run the analysis.
+        let key = cache_key(src1, &[], false, options);
+        let out = cache.get_or_analyze(file_name, key, || unreachable!());
+        assert_eq!(out.unwrap().fns_containing_unsafe, BTreeSet::from(["a".to_owned()]));
+
+        // Changed contents replace the old entry.
+        let key = cache_key(src2, &[], false, options);
+        let out = cache.get_or_analyze(file_name, key, || analyze(src2));
+        assert_eq!(out.unwrap().fns_containing_unsafe, BTreeSet::from(["b".to_owned()]));
+        assert_eq!(cache.entries.len(), 1);
+
+        // Options are part of the key.
+        let detailed = Options { detailed: true, ..options };
+        assert_ne!(cache_key(src2, &[], false, options), cache_key(src2, &[], false, detailed));
+
+        // The cache survives a round trip through a file.  Entries that weren't used in this run
+        // are dropped when it's saved.
+        let other_name = path::Path::new("b.rs");
+        cache.entries.insert(other_name.to_owned(), CacheEntry {
+            key: 0,
+            output: Output::default(),
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("cache.json");
+        cache.save(&cache_path).unwrap();
+        let mut cache = Cache::load(&cache_path);
+        assert_eq!(cache.version, CACHE_VERSION);
+        assert_eq!(cache.entries.keys().collect::<Vec<_>>(), [file_name]);
+        assert_eq!(cache.entries[file_name].key, cache_key(src2, &[], false, options));
+
+        // A cache from another version of the analysis is discarded.
+        cache.version = CACHE_VERSION - 1;
+        cache.used.insert(file_name.to_owned());
+        cache.save(&cache_path).unwrap();
+        assert!(Cache::load(&cache_path).entries.is_empty());
     }
 }
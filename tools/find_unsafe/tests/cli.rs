@@ -0,0 +1,48 @@
+use std::fs;
+use std::process::Command;
+
+/// `--crate-name` goes with `--crate-root`, and qualifies the reported names.
+#[test]
+fn crate_name() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("lib.rs"), "mod a;\n").unwrap();
+    fs::write(dir.path().join("a.rs"), "pub unsafe fn f() {}\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_find-unsafe"))
+        .arg("--crate-root")
+        .arg(dir.path().join("lib.rs"))
+        .arg("--crate-name")
+        .arg("foo")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "find_unsafe failed: {}",
+        String::from_utf8_lossy(&output.stderr));
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let a_rs = dir.path().join("a.rs");
+    assert_eq!(json[a_rs.to_str().unwrap()]["internal_unsafe_fns"],
+        serde_json::json!(["foo::a::f"]));
+}
+
+/// `--exclude` goes with `--dir` and `--crate-root`, and leaves out the matching files.
+#[test]
+fn exclude() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("lib.rs"), "mod a;\nmod shim;\n").unwrap();
+    fs::write(dir.path().join("a.rs"), "unsafe fn f() {}\n").unwrap();
+    fs::write(dir.path().join("shim.rs"), "unsafe fn g() {}\n").unwrap();
+    for input in ["--dir", "--crate-root"] {
+        let path = if input == "--dir" { dir.path().to_owned() } else { dir.path().join("lib.rs") };
+        let output = Command::new(env!("CARGO_BIN_EXE_find-unsafe"))
+            .arg(input)
+            .arg(path)
+            .arg("--exclude")
+            .arg("shim.rs")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "find_unsafe {input} failed: {}",
+            String::from_utf8_lossy(&output.stderr));
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let files = json.as_object().unwrap().keys().cloned().collect::<Vec<_>>();
+        assert!(files.iter().any(|f| f.ends_with("a.rs")), "{input}: {files:?}");
+        assert!(!files.iter().any(|f| f.ends_with("shim.rs")), "{input}: {files:?}");
+    }
+}
@@ -1,10 +1,14 @@
 #!/bin/bash
 set -euo pipefail
 dir=$(dirname "$0")
+cargo install --locked --path "$dir"/api_surface
+cargo install --locked --path "$dir"/extract_unsafe
 cargo install --locked --path "$dir"/find_unsafe
 cargo install --locked --path "$dir"/find_unsafe2
 cargo install --locked --path "$dir"/find_unsafe2/cargo_subcommands
 cargo install --locked --path "$dir"/merge_rust
+cargo install --locked --path "$dir"/move_item
 cargo install --locked --path "$dir"/related_decls
+cargo install --locked --path "$dir"/snippet_lint
 cargo install --locked --path "$dir"/split_ffi_entry_points
 cargo install --locked --path "$dir"/split_rust
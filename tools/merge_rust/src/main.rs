@@ -1,185 +1,2951 @@
 use clap::Parser;
-use indexmap::IndexMap;
-use rust_util::collect::FileCollector;
-use rust_util::item_span::item_spans;
-use serde_json;
+use glob::{MatchOptions, Pattern};
+use indexmap::{IndexMap, IndexSet};
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use rust_util::collect::{self, FileCollector, ImplHeader, ItemKind, ItemRef};
+use rust_util::edit::{
+    Rewrite, missing_modules, mod_decl, module_file_path, try_apply_rewrites,
+    write_file_atomic,
+};
+use rust_util::item_span::{item_spans, item_spans_with_fn_bodies};
+use rust_util::line_index::LineIndex;
+use rust_util::path::normalize_item_path;
+use rust_util::snippet::{
+    INNER_ATTRS_SEGMENT, VARIANT_MARKER, check_item_path, check_snippet_name, inner_attrs_mod,
+    item_name,
+};
+use regex::Regex;
+use std::cmp::Reverse;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::iter;
-use std::path::{Path, PathBuf};
-use syn;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{self, Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use syn::ext::IdentExt;
+use syn::spanned::Spanned;
 
 /// Merge updated item definitions into a Rust codebase.
 #[derive(Parser)]
 struct Args {
-    /// Root Rust source file to update (`lib.rs` or `main.rs`).
-    src_root_path: PathBuf,
-    /// JSON file containing mapping from Rust item paths to desired new contents.
-    new_snippets_file: PathBuf,
+    /// Root Rust source file to update (`lib.rs` or `main.rs`).  This is omitted when
+    /// `--crate-roots` is used, in which case the only positional argument is the JSON file.
+    #[clap(required_unless_present_any = ["crate_roots", "apply_plan"])]
+    src_root_path: Option<PathBuf>,
+    /// JSON file containing mapping from Rust item paths to desired new contents.  Mapping a
+    /// module to `null` deletes the module along with its files.  With `--jsonl`, the file
+    /// instead contains one `{"path": ..., "content": ...}` object per line.
+    ///
+    /// In place of the new contents, a snippet can also be given as an object
+    /// `{"content": ..., "order": N}`, or with `--jsonl` by adding an `"order"` field.  New items
+    /// with an `order` are added after those without, sorted by `order` and then by path.  The
+    /// `order` of an existing item is ignored.
+    ///
+    /// A method, associated constant, or associated type of an inherent `impl` block can be
+    /// replaced on its own under the key `TYPE::NAME`, as in `foo::Bar::new`, where `TYPE` is the
+    /// name of the type without its generic arguments, so this works for `impl<T> Bar<T>` too.
+    /// Members missing from the JSON file are kept, since they're part of their `impl` block, and
+    /// new ones can't be added this way.  A key matching members of several `impl` blocks, as
+    /// with `impl Bar<u8>` and `impl Bar<u16>`, is an error.
+    ///
+    /// A key of the form `foo::#inner_attrs` (or `#inner_attrs` for the crate root) adds the
+    /// inner attributes in its snippet, such as `#![allow(dead_code)]`, to module `foo`.  The
+    /// key `crate` names the crate root itself: its snippet can hold both inner attributes and
+    /// items, which are handled as if given under `#inner_attrs` and `crate::NAME`.  Items
+    /// without a name, such as `use` and `impl` items, are added to the crate root unless it
+    /// already has an identical one.
+    ///
+    /// As an escape hatch for changes that don't line up with items, a key of the form
+    /// `src/foo.rs:10-20` replaces lines 10 through 20 of that file, relative to the directory
+    /// of the crate root, with its snippet (or removes them, for `null`).  The lines must not
+    /// overlap any item that's updated or removed by other snippets.
+    new_snippets_file: Option<PathBuf>,
+
+    /// JSON file mapping crate names to their root source files, for merging into several crates
+    /// at once.  The first segment of each snippet key selects the crate, as in
+    /// `mycrate::foo::bar`, and the rest of the key is handled as usual.  Relative root paths are
+    /// resolved relative to the directory containing the JSON file.  All snippets are checked
+    /// against their crates before any files are written.
+    #[clap(long)]
+    crate_roots: Option<PathBuf>,
 
     /// Use the JSON contents to overwrite existing definitions, but don't add or remove anything.
     #[clap(long)]
     update_only: bool,
+
+    /// Module path to prepend to every key in the JSON file, e.g. `mymod::foo`.  When this is set,
+    /// only items inside that module are removed if they're missing from the JSON file.
+    #[clap(long)]
+    key_prefix: Option<String>,
+
+    /// Module that `self::` and `super::` keys are relative to.  Defaults to `--key-prefix` if
+    /// that's set, and to the crate root otherwise.
+    #[clap(long)]
+    key_base: Option<String>,
+
+    /// Report snippets whose item name doesn't match their key as warnings instead of errors.
+    #[clap(long)]
+    lenient: bool,
+
+    /// Let keys address items declared inside function bodies, such as a helper `fn` defined
+    /// within another function, so just that definition is replaced.  These are keyed by the path
+    /// of the enclosing function, as in `foo::outer::inner`.  When one function body declares
+    /// several items of the same name, as in separate blocks, the second is keyed `inner#2`, the
+    /// third `inner#3`, and so on, in source order.  Nested items missing from the JSON file are
+    /// kept, since they're part of the enclosing function, and new ones can't be added this way.
+    #[clap(long)]
+    nested_items: bool,
+
+    /// Treat a snippet whose item is of a different kind than the existing item at the same
+    /// path, such as a `fn` replacing a `struct`, as an error rather than a warning.
+    #[clap(long)]
+    strict_kinds: bool,
+
+    /// Print a warning for each snippet that changes the visibility of the item it replaces, as
+    /// when `pub fn` becomes `fn`, which can break callers in other modules.
+    #[clap(long)]
+    warn_visibility_change: bool,
+
+    /// After writing all files, run `cargo check` in the crate directory, and exit with an error
+    /// if it fails.
+    #[clap(long)]
+    check_build: bool,
+
+    /// Print statistics about the changes that would be made, without modifying any files.
+    #[clap(long)]
+    stats: bool,
+
+    /// Instead of merging, list the existing items that have no snippet, and print the fraction
+    /// of items that do, without modifying any files.  With `--key-prefix`, only items inside
+    /// that module are counted.  Items deleted by a `null` snippet count as covered.
+    #[clap(long)]
+    coverage: bool,
+
+    /// Keep running, and re-run the merge each time the JSON file changes, until interrupted.
+    #[clap(long)]
+    watch: bool,
+
+    /// Only check that the crate root and all the modules it references can be parsed, then exit
+    /// without reading the JSON file or modifying anything.
+    #[clap(long)]
+    parse_only: bool,
+
+    /// Read snippets as JSON lines, one object per line, as is convenient for tools that produce
+    /// snippets one at a time.  New items are appended to their module in the order they appear
+    /// in the input, as in the default mode.
+    #[clap(long)]
+    jsonl: bool,
+
+    /// Read the JSON file in the rich format, where each value is an object
+    /// `{"content": ..., "mode": ..., "preserve_attrs": ..., "order": ...}` giving options for
+    /// that item alone.  `mode` is `replace` (the default, which updates or adds the item as
+    /// usual), `delete` (which deletes the item, and takes no `content`), or `update-only` (which
+    /// updates the item only if it already exists).  With `preserve_attrs`, an existing item
+    /// keeps its own attributes and doc comments, and those in `content` are dropped.  With
+    /// `keep_body`, an existing function keeps its body exactly as written, comments included,
+    /// and only its signature is replaced; `content` can then be just the signature.  Only
+    /// `content` is required, except with `delete`.  In place of `content`, `"file": PATH` reads
+    /// the snippet from a file, relative to the directory containing the JSON file (or to
+    /// `--bodies-dir`), so large items can be kept in ordinary `.rs` files.  Snippets with the
+    /// same `"transaction": ID` are applied together or not at all: each transaction is first
+    /// tried along with the snippets outside any transaction and the transactions accepted before
+    /// it, and if any of its snippets fails, for example because it doesn't parse, updates an
+    /// item another transaction already updates, or would cause overlapping rewrites, the whole
+    /// transaction is skipped with a warning and the other snippets are applied as usual.
+    #[clap(long, conflicts_with = "jsonl")]
+    rich: bool,
+
+    /// Directory to resolve the `"file"` paths of `--rich` snippets against, instead of the
+    /// directory containing the JSON file, so the body files can live in a separate tree.
+    #[clap(long, value_name = "DIR", requires = "rich")]
+    bodies_dir: Option<PathBuf>,
+
+    /// Format each snippet with `rustfmt` before splicing it in, leaving the rest of the file
+    /// untouched.  Snippets that can't be formatted on their own are inserted as they are.
+    #[clap(long)]
+    format_changed_only: bool,
+
+    /// Write a JSON report of side effects other than item rewrites to this file: the modules
+    /// created for new items, and the extra items defined by snippets with several items.
+    #[clap(long)]
+    report: Option<PathBuf>,
+
+    /// Replace every existing item whose path matches the regex PATTERN with TEMPLATE, in which
+    /// `$1`, `${name}`, etc. are replaced with the pattern's captures.  Paths are written as in
+    /// JSON keys, relative to `--key-prefix` if it's set, and the pattern must match the whole
+    /// path.  This can be given several times, but each item can be matched by only one pattern,
+    /// and not by a key in the JSON file.  Items not matched by either are handled as usual, so
+    /// combine this with `--update-only` and an empty JSON file to change only the matched items.
+    #[clap(long, num_args = 2, value_names = ["PATTERN", "TEMPLATE"])]
+    by_regex: Vec<String>,
+
+    /// After writing all files, stage the modified, created, and deleted files in git, so the
+    /// changes can be reviewed with `git diff --cached`.  With `--stats`, the files that would be
+    /// staged are listed instead.
+    #[clap(long)]
+    git_stage: bool,
+
+    /// After writing all files, run this shell command with the paths of the modified and
+    /// created files appended as arguments, as in `--post-hook 'rustfmt --edition 2021'`.  If the
+    /// command fails, merge_rust exits with the same status.  The hook runs before `--git-stage`
+    /// and `--check-build`, so their results include its changes.
+    #[clap(long)]
+    post_hook: Option<String>,
+
+    /// Never modify, create, or delete files matching this glob pattern, such as hand-maintained
+    /// modules in an otherwise generated crate.  Patterns are matched against each file's path
+    /// both as given and relative to the directory of its crate root, and `*` doesn't match `/`.
+    /// If the snippets would change a matching file, merge_rust reports the file and exits
+    /// without writing anything.  This can be given several times.
+    #[clap(long, value_name = "GLOB")]
+    no_modify: Vec<String>,
+
+    /// Write a JSON file of snippets that undoes this run to this path.  Merging it back into
+    /// the crate, with the same `--key-prefix` or `--crate-roots`, restores the previous contents
+    /// of each item the run updated or removed, and deletes the items and modules it added.
+    /// Every other existing item is listed too, so nothing else is removed.  Removed items are
+    /// restored at the end of their module, and added enum variants and inner attributes aren't
+    /// undone.
+    #[clap(long, value_name = "PATH")]
+    emit_undo: Option<PathBuf>,
+
+    /// Exit with an error, listing the modules, if any snippet would need a new module file to be
+    /// created.  This is checked before anything is written.  New items in existing modules are
+    /// still added, unless `--update-only` is also given.
+    #[clap(long)]
+    fail_on_new_file: bool,
+
+    /// Instead of modifying any files, write a JSON plan of the changes to this path, for review
+    /// before running `--apply-plan`.  The plan lists the module files to create, each rewrite
+    /// (its file, byte range, old and new text, and the reason for it, such as `update foo::f`),
+    /// and the files to delete.  Paths are written as merge_rust sees them, so the plan should be
+    /// applied from the same directory.
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["stats", "coverage"])]
+    plan: Option<PathBuf>,
+
+    /// Apply a plan written by `--plan`, which may have been edited in between, instead of
+    /// merging snippets.  This only splices bytes: nothing is parsed.  Every rewrite's old text
+    /// must still match the file, and new module files must not exist yet; otherwise nothing is
+    /// written.  No other arguments are needed, and the other options don't apply.
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["src_root_path", "crate_roots"])]
+    apply_plan: Option<PathBuf>,
+
+    /// After writing all files, print the number of functions that `find-unsafe` reports in
+    /// `internal_unsafe_fns` across the modified, created, and deleted files, before and after
+    /// the merge, along with the net change, to show whether the snippets made the code safer.
+    /// Files that don't parse before or after are left out of both counts.
+    #[clap(long)]
+    unsafe_delta: bool,
+
+    /// After writing all files, parse each modified or created file again, and exit with an
+    /// error listing those that no longer parse.  Snippets are checked one at a time, so this
+    /// catches output that's broken only once they're spliced together, as with unbalanced
+    /// braces in line range snippets.  The files are left as written, for inspection.  This runs
+    /// before `--post-hook`.
+    #[clap(long)]
+    verify_after: bool,
+
+    /// Before writing each file, print the changes to be made to it, and ask on stdin whether to
+    /// apply them: `y` applies them, `n` skips the file, `all` applies them and the rest without
+    /// asking again, and `quit` stops without touching the remaining files, leaving the ones
+    /// already written as they are.  Deletions of module files are confirmed the same way.
+    #[clap(long, conflicts_with_all = ["stats", "plan", "watch"])]
+    interactive: bool,
+}
+
+/// A `--by-regex` pattern and its template.
+struct RegexSnippet {
+    pattern: String,
+    /// `pattern`, anchored to match whole paths.
+    regex: Regex,
+    template: String,
+}
+
+/// Summary of the changes made by a run, printed by `--stats`.
+#[derive(Clone, Debug, Default)]
+struct Stats {
+    items_updated: usize,
+    items_added: usize,
+    items_removed: usize,
+    files_touched: usize,
+    files_deleted: usize,
+    bytes_added: usize,
+    bytes_removed: usize,
 }
 
 type ModPath = String;
 
-fn main() {
-    let args = Args::parse();
-    let src_root_path = args.src_root_path;
-    let src_root_dir = Path::new(&src_root_path).parent().unwrap();
-    let new_snippet_json_path = args.new_snippets_file;
-
-    let new_snippets_file = File::open(&new_snippet_json_path).unwrap();
-    let new_snippets: IndexMap<String, String> =
-        serde_json::from_reader(new_snippets_file).unwrap();
-
-    let mut fc = FileCollector::default();
-    fc.parse(&src_root_path, vec![], true).unwrap();
-
-    let mut files: Vec<(PathBuf, ModPath, syn::File)> = fc.files.into_iter()
-        .map(|(file_path, mod_path_parts, ast)| (file_path, mod_path_parts.join("::"), ast))
-        .collect();
-    // Gives the file path and end position for each module.
-    let mut mod_locations = fc.mods.iter().map(|m| {
-        (m.mod_path.join("::"), (m.file_path.clone(), m.inner_end_pos))
-    }).collect::<HashMap<ModPath, (PathBuf, usize)>>();
-
-
-    // For every module mentioned in `new_snippets`, if the module doesn't exist in `fc.mods`,
-    // create it.
-    let snippet_modules = new_snippets.keys()
-        .map(|k| k.rsplit_once("::").map_or("", |(parent_path, _child_name)| parent_path))
-        .map(|x| x.to_owned())
-        .collect::<HashSet<_>>();
-    let mut new_snippets = new_snippets;
-    for mod_path in &snippet_modules {
-        // Iterate over all ancestors of `mod_path`.
-        let idxs = iter::once(mod_path.len())
-            .chain(mod_path.rmatch_indices("::").map(|(idx, _)| idx));
-        for idx in idxs {
-            let mod_path = &mod_path[..idx];
-            // Skip modules that already exist.
-            if mod_locations.contains_key(mod_path) {
-                continue;
+/// Find the definition of the enum at `enum_path` among the collected `files`.  Returns the path
+/// of the file containing the enum along with its AST.
+fn find_enum<'a>(
+    files: &'a [(PathBuf, ModPath, syn::File)],
+    enum_path: &str,
+) -> Option<(&'a Path, &'a syn::ItemEnum)> {
+    fn go<'a>(items: &'a [syn::Item], mod_path: &str, enum_path: &str) -> Option<&'a syn::ItemEnum> {
+        for item in items {
+            match *item {
+                syn::Item::Enum(ref ie) => {
+                    if join_path(mod_path, &ie.ident.unraw().to_string()) == enum_path {
+                        return Some(ie);
+                    }
+                },
+                syn::Item::Mod(ref im) => {
+                    if let Some((_, ref inline_items)) = im.content {
+                        let child_path = join_path(mod_path, &im.ident.unraw().to_string());
+                        if let Some(ie) = go(inline_items, &child_path, enum_path) {
+                            return Some(ie);
+                        }
+                    }
+                },
+                _ => {},
             }
+        }
+        None
+    }
 
-            // Create an empty file on disk and add it to `files`.
-            debug_assert!(mod_path.len() != 0);
-            let file_path_rel = mod_path.replace("::", "/") + ".rs";
-            let file_path = src_root_dir.join(&file_path_rel);
-            assert!(!fs::exists(&file_path).unwrap(),
-                "file {:?} is missing from mod_spans, but exists on disk?", file_path);
-            fs::write(&file_path, "").unwrap();
-            let ast = syn::File {
-                shebang: None,
-                attrs: Vec::new(),
-                items: Vec::new(),
-            };
-            files.push((file_path.clone(), mod_path.to_owned(), ast));
-            // The file is empty, so new items should be inserted at byte position 0.
-            mod_locations.insert(mod_path.to_owned(), (file_path, 0));
+    files.iter().find_map(|(file_path, mod_path, ast)| {
+        go(&ast.items, mod_path, enum_path).map(|ie| (file_path as &Path, ie))
+    })
+}
+
+/// Convert `key` into a path relative to the crate root, as used internally.  A leading `crate::`
+/// makes the key absolute, `self::` and `super::` are resolved relative to `key_base`, and any
+/// other key is relative to `key_prefix` (if given) or the crate root.  See `normalize_item_path`.
+/// Returns an error if `super` would go above the crate root.
+fn normalize_key(key: &str, key_base: &str, key_prefix: Option<&str>) -> Result<String, String> {
+    let has_keyword = ["crate", "self", "super"].iter().any(|&kw| {
+        key == kw || key.starts_with(&format!("{kw}::"))
+    });
+    if !has_keyword && let Some(prefix) = key_prefix {
+        return normalize_item_path(&join_path(prefix, key), &[]).map(|path| path.join("::"));
+    }
+    let base = key_base.split("::").filter(|s| !s.is_empty()).map(|s| s.to_owned())
+        .collect::<Vec<_>>();
+    normalize_item_path(key, &base).map(|path| path.join("::"))
+}
+
+/// Applies `normalize_key` to each snippet key in turn, detecting keys that refer to the same item,
+/// as with `foo` and `crate::foo`.
+#[derive(Clone)]
+struct KeyNormalizer<'a> {
+    key_base: &'a str,
+    key_prefix: Option<&'a str>,
+    /// Original key and snippet for each normalized key seen so far, used to tell an identical
+    /// duplicate from a conflicting one.
+    seen: HashMap<String, (String, (Option<String>, SnippetOptions))>,
+}
+
+impl<'a> KeyNormalizer<'a> {
+    fn new(key_base: &'a str, key_prefix: Option<&'a str>) -> KeyNormalizer<'a> {
+        KeyNormalizer {
+            key_base,
+            key_prefix,
+            seen: HashMap::new(),
+        }
+    }
 
-            // Add a `mod foo;` snippet to the parent module.
-            let (_parent_mod_path, mod_name) = mod_path.rsplit_once("::")
-                .unwrap_or(("", mod_path));
-            let old = new_snippets.insert(mod_path.to_owned(), format!("mod {mod_name};"));
-            assert!(old.is_none(), "item {:?} exists but is not a module", mod_path);
+    /// Normalize `key`.  Returns `None` if an earlier key referred to the same item with an
+    /// identical snippet, in which case this one can be ignored, and an error if the earlier
+    /// snippet was different.
+    fn normalize(
+        &mut self,
+        key: String,
+        value: &(Option<String>, SnippetOptions),
+    ) -> Result<Option<String>, String> {
+        let norm_key = normalize_key(&key, self.key_base, self.key_prefix)?;
+        match self.seen.get(&norm_key) {
+            Some((old_key, old_value)) if old_value == value => {
+                eprintln!("note: keys {:?} and {:?} refer to the same item; ignoring the duplicate",
+                    old_key, key);
+                Ok(None)
+            },
+            Some((old_key, _)) => {
+                Err(format!("keys {:?} and {:?} both refer to {:?}, with different contents",
+                    old_key, key, norm_key))
+            },
+            None => {
+                self.seen.insert(norm_key.clone(), (key, value.clone()));
+                Ok(Some(norm_key))
+            },
         }
     }
-    let new_snippets = new_snippets;
+}
 
+/// Remove the `--key-prefix` from `key`, for use in messages that refer to keys as the user wrote
+/// them.  Keys that don't start with `prefix` are returned unchanged.
+fn strip_key_prefix<'a>(key: &'a str, prefix: Option<&str>) -> &'a str {
+    prefix
+        .and_then(|prefix| key.strip_prefix(prefix))
+        .and_then(|rest| rest.strip_prefix("::"))
+        .unwrap_or(key)
+}
 
-    let mut file_rewrites = IndexMap::<PathBuf, Vec<(usize, usize, &str)>>::new();
+fn join_path(parent: &str, child: &str) -> String {
+    if parent.is_empty() {
+        child.to_owned()
+    } else {
+        format!("{parent}::{child}")
+    }
+}
 
-    // Collect rewrites for updated or removed items.  We record each item in `snippets_applied` as
-    // we apply it.
-    let mut snippets_applied = HashSet::<String>::new();
-    for &(ref file_path, ref mod_path, ref ast) in &files {
-        eprintln!("visit {file_path:?}");
-        let old_src = fs::read_to_string(file_path).unwrap();
+/// Return the whitespace at the start of the line containing byte position `pos`.
+fn line_indent(src: &str, pos: usize) -> &str {
+    let bol = src[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line = &src[bol..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
 
-        let rewrites = file_rewrites.entry(file_path.to_owned()).or_insert(Vec::new());
+/// Unit of indentation used in a file or snippet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IndentUnit {
+    Tab,
+    Spaces(usize),
+}
 
-        // Update or remove existing items.
-        let mod_path_parts = if mod_path == "" {
-            Vec::new()
-        } else {
-            mod_path.split("::").map(|s| s.to_owned()).collect::<Vec<String>>()
+impl IndentUnit {
+    fn text(self) -> String {
+        match self {
+            IndentUnit::Tab => "\t".to_owned(),
+            IndentUnit::Spaces(n) => " ".repeat(n),
+        }
+    }
+}
+
+/// Find the lines of `src`, numbered from 0, that begin inside a multi-line literal such as a
+/// string.  Their leading whitespace belongs to the literal, so it isn't indentation.  If `src`
+/// can't be tokenized, no lines are returned.
+fn literal_lines(src: &str) -> HashSet<usize> {
+    fn go(tokens: TokenStream, out: &mut HashSet<usize>) {
+        for tt in tokens {
+            match tt {
+                TokenTree::Group(g) => go(g.stream(), out),
+                // Doc comments are literals too, as the values of `#[doc]` attributes.
+                TokenTree::Literal(lit) => {
+                    // Span lines are numbered from 1, so this is the range of lines after the
+                    // first, numbered from 0.
+                    out.extend(lit.span().start().line..lit.span().end().line);
+                },
+                TokenTree::Ident(_) | TokenTree::Punct(_) => {},
+            }
+        }
+    }
+    let mut out = HashSet::new();
+    if let Ok(tokens) = src.parse() {
+        go(tokens, &mut out);
+    }
+    out
+}
+
+/// Guess the indentation unit of `src` from the leading whitespace of its lines: tabs if more
+/// lines are indented with tabs than with spaces, and otherwise the greatest common divisor of
+/// the numbers of spaces lines are indented by.  Continuation lines, which are indented further
+/// than the line before even though it doesn't end with an opening bracket, are left out if there
+/// are any other indented lines, since they're often aligned with something on the line above.
+/// Lines inside multi-line literals are ignored.  Returns `None` if no line is indented.
+fn detect_indent_unit(src: &str) -> Option<IndentUnit> {
+    fn gcd(a: usize, b: usize) -> usize {
+        if b == 0 { a } else { gcd(b, a % b) }
+    }
+    let literal_lines = literal_lines(src);
+    let mut tab_lines = 0;
+    let mut space_lines = 0;
+    // GCD of the indentation of ordinary lines and of continuation lines.
+    let mut block_gcd = 0;
+    let mut continuation_gcd = 0;
+    let mut prev_spaces = 0;
+    let mut prev_opens = false;
+    for (i, line) in src.lines().enumerate() {
+        let rest = line.trim_start_matches([' ', '\t']);
+        // Skip blank lines and the ` * ` lines of block comments, which are offset by one space.
+        if rest.is_empty() || rest.starts_with('*') || literal_lines.contains(&i) {
+            continue;
+        }
+        if line.starts_with('\t') {
+            tab_lines += 1;
+            continue;
+        }
+        let spaces = line.len() - line.trim_start_matches(' ').len();
+        if spaces > 0 {
+            space_lines += 1;
+            if spaces > prev_spaces && !prev_opens {
+                continuation_gcd = gcd(continuation_gcd, spaces);
+            } else {
+                block_gcd = gcd(block_gcd, spaces);
+            }
+        }
+        prev_spaces = spaces;
+        prev_opens = rest.trim_end().ends_with(['{', '(', '[']);
+    }
+    if tab_lines > space_lines {
+        Some(IndentUnit::Tab)
+    } else if space_lines > 0 {
+        Some(IndentUnit::Spaces(if block_gcd > 0 { block_gcd } else { continuation_gcd }))
+    } else {
+        None
+    }
+}
+
+/// Convert the indentation of each line of `snippet` to `unit`, keeping the same number of
+/// levels.  Leftover spaces that don't make up a whole level, as used for alignment, are kept,
+/// and so are lines inside multi-line literals.  The snippet is returned unchanged if it already
+/// uses `unit`.
+fn convert_indent(snippet: &str, unit: IndentUnit) -> String {
+    let from = match detect_indent_unit(snippet) {
+        Some(from) if from != unit => from,
+        _ => return snippet.to_owned(),
+    };
+    let literal_lines = literal_lines(snippet);
+    let unit_text = unit.text();
+    snippet.split('\n').enumerate().map(|(i, line)| {
+        if literal_lines.contains(&i) {
+            return line.to_owned();
+        }
+        let rest = line.trim_start_matches([' ', '\t']);
+        let ws = &line[..line.len() - rest.len()];
+        let tabs = ws.matches('\t').count();
+        let spaces = ws.len() - tabs;
+        let (levels, extra) = match from {
+            IndentUnit::Tab => (tabs, spaces),
+            IndentUnit::Spaces(n) => (tabs + spaces / n, spaces % n),
         };
-        for (item_path, lo, hi) in item_spans(mod_path_parts, ast) {
-            let old_snippet = &old_src[lo..hi];
-            let item_path_str = item_path.join("::");
-            let new_snippet = match new_snippets.get(&item_path_str) {
-                Some(x) => {
-                    snippets_applied.insert(item_path_str);
-                    x
+        format!("{}{}{}", unit_text.repeat(levels), " ".repeat(extra), rest)
+    }).collect::<Vec<_>>().join("\n")
+}
+
+/// Re-indent a multi-line `snippet` so that it can be placed at indentation level `indent`.  The
+/// first line is left as is, since it's expected to follow existing indentation in the output.
+/// The common leading whitespace of the remaining lines is replaced with `indent`.
+fn reindent(snippet: &str, indent: &str) -> String {
+    let mut lines = snippet.lines();
+    let mut out = lines.next().unwrap_or("").to_owned();
+    let rest = lines.collect::<Vec<_>>();
+    let common = rest.iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    for line in rest {
+        out.push('\n');
+        if !line.trim().is_empty() {
+            out.push_str(indent);
+            out.push_str(&line[common..]);
+        }
+    }
+    out
+}
+
+/// Compute the insertion that adds `new_snippet` at the end of the inline module whose closing
+/// brace is at `close_pos`.  The snippet is placed after the module's last item (or after the
+/// opening brace, if it's empty) and indented one level deeper than the module itself, using the
+/// file's indentation `unit`.  `first` indicates whether this is the first item being inserted
+/// into this module.
+fn inline_mod_insertion(
+    src: &str,
+    close_pos: usize,
+    new_snippet: &str,
+    first: bool,
+    unit: IndentUnit,
+) -> (usize, String) {
+    let pos = src[..close_pos].trim_end().len();
+    let item_indent = format!("{}{}", line_indent(src, close_pos), unit.text());
+    let sep = if first && src[..pos].ends_with('{') { "\n" } else { "\n\n" };
+    let new_snippet = convert_indent(new_snippet.trim(), unit);
+    (pos, format!("{sep}{item_indent}{}", reindent(&new_snippet, &item_indent)))
+}
+
+/// Format `snippet` with `rustfmt`, as a standalone sequence of items.  Returns `None` if that
+/// fails, for example because the snippet doesn't parse on its own or `rustfmt` isn't installed.
+fn rustfmt_snippet(snippet: &str) -> Option<String> {
+    let mut child = Command::new("rustfmt")
+        .args(["--edition", "2021", "--emit", "stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    // Write from another thread, so a large snippet can't fill the pipe and deadlock with
+    // `rustfmt` writing its output.
+    let mut stdin = child.stdin.take().unwrap();
+    let input = snippet.to_owned();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().ok()?;
+    writer.join().unwrap().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let formatted = String::from_utf8(output.stdout).ok()?;
+    Some(formatted.trim_end().to_owned())
+}
+
+fn item_attrs(item: &syn::Item) -> &[syn::Attribute] {
+    match *item {
+        syn::Item::Const(ref x) => &x.attrs,
+        syn::Item::Enum(ref x) => &x.attrs,
+        syn::Item::ExternCrate(ref x) => &x.attrs,
+        syn::Item::Fn(ref x) => &x.attrs,
+        syn::Item::ForeignMod(ref x) => &x.attrs,
+        syn::Item::Impl(ref x) => &x.attrs,
+        syn::Item::Macro(ref x) => &x.attrs,
+        syn::Item::Mod(ref x) => &x.attrs,
+        syn::Item::Static(ref x) => &x.attrs,
+        syn::Item::Struct(ref x) => &x.attrs,
+        syn::Item::Trait(ref x) => &x.attrs,
+        syn::Item::TraitAlias(ref x) => &x.attrs,
+        syn::Item::Type(ref x) => &x.attrs,
+        syn::Item::Union(ref x) => &x.attrs,
+        syn::Item::Use(ref x) => &x.attrs,
+        _ => &[],
+    }
+}
+
+fn item_vis(item: &syn::Item) -> Option<&syn::Visibility> {
+    match *item {
+        syn::Item::Const(ref x) => Some(&x.vis),
+        syn::Item::Enum(ref x) => Some(&x.vis),
+        syn::Item::ExternCrate(ref x) => Some(&x.vis),
+        syn::Item::Fn(ref x) => Some(&x.vis),
+        syn::Item::Mod(ref x) => Some(&x.vis),
+        syn::Item::Static(ref x) => Some(&x.vis),
+        syn::Item::Struct(ref x) => Some(&x.vis),
+        syn::Item::Trait(ref x) => Some(&x.vis),
+        syn::Item::TraitAlias(ref x) => Some(&x.vis),
+        syn::Item::Type(ref x) => Some(&x.vis),
+        syn::Item::Union(ref x) => Some(&x.vis),
+        syn::Item::Use(ref x) => Some(&x.vis),
+        _ => None,
+    }
+}
+
+/// Describe the visibility of the item in `snippet`, as in `pub(crate)`, or `private` if it has
+/// none.  Returns `None` if `snippet` isn't a single item with a visibility.
+fn snippet_visibility(snippet: &str) -> Option<String> {
+    let item = syn::parse_str::<syn::Item>(snippet).ok()?;
+    Some(match *item_vis(&item)? {
+        syn::Visibility::Inherited => "private".to_owned(),
+        ref vis => vis.to_token_stream().to_string().replace(" (", "(").replace(" :: ", "::"),
+    })
+}
+
+/// Get the length of the outer attributes and doc comments at the start of `snippet`, including
+/// the whitespace after them.  Returns 0 if `snippet` isn't a single item.
+fn outer_attrs_len(snippet: &str) -> usize {
+    let Ok(item) = syn::parse_str::<syn::Item>(snippet) else {
+        return 0;
+    };
+    let Some(last) = item_attrs(&item).iter()
+        .rfind(|attr| matches!(attr.style, syn::AttrStyle::Outer))
+    else {
+        return 0;
+    };
+    let end = last.span().byte_range().end;
+    end + (snippet[end..].len() - snippet[end..].trim_start().len())
+}
+
+/// Replace the outer attributes of `snippet` with those of `old`, the item it replaces, for the
+/// `preserve_attrs` option.
+fn replace_outer_attrs(snippet: &str, old: &str) -> String {
+    let old_attrs = &old[..outer_attrs_len(old)];
+    format!("{}{}", old_attrs, &snippet[outer_attrs_len(snippet)..])
+}
+
+/// Replace the signature of the function `old` with the one in `snippet`, keeping the body of
+/// `old` verbatim.  `snippet` can be a whole function, whose body is ignored, or just a signature.
+/// Returns `None` if either one isn't a function.
+fn replace_signature(snippet: &str, old: &str) -> Option<String> {
+    let block_start = |s: &str| {
+        let item_fn = syn::parse_str::<syn::ItemFn>(s).ok()?;
+        Some(item_fn.block.span().byte_range().start)
+    };
+    let old_block_start = block_start(old)?;
+    let sig = match block_start(snippet) {
+        Some(pos) => &snippet[..pos],
+        None => {
+            // Check that it's a signature by parsing it with an empty body.
+            block_start(&format!("{snippet} {{}}"))?;
+            snippet
+        },
+    };
+    // Keep the whitespace between the old signature and body.
+    let old_sig = old[..old_block_start].trim_end();
+    Some(format!("{}{}", sig.trim_end(), &old[old_sig.len()..]))
+}
+
+/// Return the kind of the first item in `snippet`, or `None` if it doesn't parse or is empty.
+fn snippet_kind(snippet: &str) -> Option<ItemKind> {
+    let file = syn::parse_str::<syn::File>(snippet).ok()?;
+    file.items.first().map(ItemKind::of)
+}
+
+/// Namespace of the name an item defines.  Items of the same name in different namespaces don't
+/// clash, as with a struct and a function.  Macros have a namespace of their own, so they aren't
+/// listed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Namespace {
+    Type,
+    Value,
+}
+
+/// Return the namespaces in which `item` defines its name.  Tuple and unit structs define a
+/// constructor in the value namespace too, and a `use` item may import names in either.
+fn item_namespaces(item: &syn::Item) -> &'static [Namespace] {
+    match *item {
+        syn::Item::Struct(ref is) if !matches!(is.fields, syn::Fields::Named(_)) => {
+            &[Namespace::Type, Namespace::Value]
+        },
+        syn::Item::Struct(_) | syn::Item::Enum(_) | syn::Item::Union(_) | syn::Item::Trait(_)
+            | syn::Item::TraitAlias(_) | syn::Item::Type(_) | syn::Item::Mod(_)
+            | syn::Item::ExternCrate(_) => &[Namespace::Type],
+        syn::Item::Fn(_) | syn::Item::Const(_) | syn::Item::Static(_) => &[Namespace::Value],
+        syn::Item::Use(_) => &[Namespace::Type, Namespace::Value],
+        _ => &[],
+    }
+}
+
+/// Check whether `snippet` consists only of `use` and `extern crate` items.
+fn is_import_snippet(snippet: &str) -> bool {
+    syn::parse_str::<syn::File>(snippet).is_ok_and(|file| {
+        !file.items.is_empty() && file.items.iter()
+            .all(|item| matches!(*item, syn::Item::Use(_) | syn::Item::ExternCrate(_)))
+    })
+}
+
+/// Parse a line range key of the form `src/foo.rs:FIRST-LAST`, returning the file path and the
+/// first and last lines, counting from 1.  Returns `None` for any other key.
+fn parse_line_range_key(key: &str) -> Option<(PathBuf, usize, usize)> {
+    let (file, range) = key.rsplit_once(':')?;
+    let (first, last) = range.split_once('-')?;
+    if !file.ends_with(".rs") {
+        return None;
+    }
+    Some((PathBuf::from(file), first.parse().ok()?, last.parse().ok()?))
+}
+
+/// Convert the range of lines `first..=last` of `src`, counting from 1, into a byte range.  The
+/// line break at the end of the last line isn't included.  Returns `None` if the range is empty
+/// or goes past the end of `src`.  Lines are counted like `str::lines` does, so a final line
+/// break doesn't start another line.
+fn line_range_bytes(src: &str, first: usize, last: usize) -> Option<(usize, usize)> {
+    if first == 0 || first > last || last > src.lines().count() {
+        return None;
+    }
+    let li = LineIndex::new(src);
+    let lo = li.line_start(first);
+    let hi = if last < li.line_count() { li.line_start(last + 1) - 1 } else { src.len() };
+    let hi = if src[..hi].ends_with('\r') { hi - 1 } else { hi };
+    Some((lo, hi))
+}
+
+/// Compute the rewrite that adds a new variant to an existing enum.  The variant is spliced into
+/// the existing list of variants, leaving the rest of the enum untouched.  `unit` is the
+/// indentation unit of the file.
+fn enum_variant_rewrite(
+    src: &str,
+    ie: &syn::ItemEnum,
+    variant_name: &str,
+    new_snippet: &str,
+    unit: IndentUnit,
+) -> (usize, String) {
+    let enum_name = &ie.ident;
+    let variant = syn::parse_str::<syn::Variant>(new_snippet).unwrap_or_else(|e| {
+        panic!("failed to parse new variant {enum_name}::{variant_name}: {e}");
+    });
+    assert!(
+        variant.ident == variant_name,
+        "snippet for variant {enum_name}::{variant_name} defines variant {} instead",
+        variant.ident,
+    );
+    assert!(
+        !ie.variants.iter().any(|v| v.ident == variant_name),
+        "enum {enum_name} already has a variant named {variant_name}",
+    );
+
+    let new_snippet = convert_indent(new_snippet.trim(), unit);
+    let close_pos = ie.brace_token.span.close().byte_range().start;
+    match ie.variants.pairs().next_back() {
+        None => {
+            let indent = line_indent(src, ie.brace_token.span.open().byte_range().start);
+            let variant_indent = format!("{indent}{}", unit.text());
+            let new_snippet = reindent(&new_snippet, &variant_indent);
+            let open_end = ie.brace_token.span.open().byte_range().end;
+            if src[open_end..close_pos].contains('\n') {
+                // The closing brace is already on a line of its own, so the variant goes on a new
+                // line just before it.
+                let bol = src[..close_pos].rfind('\n').unwrap() + 1;
+                (bol, format!("{variant_indent}{new_snippet},\n"))
+            } else {
+                (close_pos, format!("\n{variant_indent}{new_snippet},\n{indent}"))
+            }
+        },
+        Some(pair) => {
+            let last = pair.value();
+            let last_start = last.span().byte_range().start;
+            let indent = line_indent(src, last_start);
+            let new_snippet = reindent(&new_snippet, indent);
+            match pair.punct() {
+                Some(comma) => {
+                    let pos = comma.span.byte_range().end;
+                    (pos, format!("\n{indent}{new_snippet},"))
                 },
                 None => {
-                    if args.update_only {
-                        // We would normally delete this item, but we're currently in
-                        // update-only mode.
+                    let pos = last.span().byte_range().end;
+                    (pos, format!(",\n{indent}{new_snippet}"))
+                },
+            }
+        },
+    }
+}
+
+/// How often `--watch` mode checks the JSON file for changes.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long the JSON file must go without changes before `--watch` mode re-runs the merge.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Set when `--watch` mode is interrupted, so the watcher stops after the current merge.
+static WATCH_STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Make `SIGINT` and `SIGTERM` set `WATCH_STOPPED` instead of killing the process, so the watcher
+/// can clean up before it exits.
+#[cfg(unix)]
+fn stop_watch_on_signal() {
+    extern "C" fn handle(_: libc::c_int) {
+        WATCH_STOPPED.store(true, Ordering::SeqCst);
+    }
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: `handle` only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn stop_watch_on_signal() {}
+
+/// The `--watch` lock file, which is removed when this is dropped.
+struct WatchLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl Drop for WatchLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Run the merge once on startup, and again each time `new_snippets_file` changes, until
+/// interrupted.  Each merge runs in a subprocess with the same arguments (minus `--watch`), so a
+/// failing run doesn't stop the watcher.  Only one watcher can run on a given crate at a time;
+/// this is enforced by locking `.merge_rust.lock` next to `src_root_path`, which is the
+/// `--crate-roots` file when merging into several crates.  The lock file is removed on exit.
+fn watch(src_root_path: &Path, new_snippets_file: &Path) {
+    let lock_path = src_root_path.with_file_name(".merge_rust.lock");
+    let lock_file = File::create(&lock_path).unwrap();
+    if lock_file.try_lock().is_err() {
+        eprintln!("another instance of merge_rust --watch is already running on {:?}",
+            src_root_path);
+        process::exit(1);
+    }
+    let _lock = WatchLock { path: lock_path, _file: lock_file };
+    stop_watch_on_signal();
+
+    let child_args = env::args_os().skip(1).filter(|a| a != "--watch").collect::<Vec<_>>();
+    let mtime = || fs::metadata(new_snippets_file).and_then(|m| m.modified()).ok();
+    let mut last_mtime = None;
+    let (mut num_runs, mut num_failed) = (0, 0);
+    while !WATCH_STOPPED.load(Ordering::SeqCst) {
+        let mut cur_mtime = mtime();
+        if cur_mtime.is_some() && cur_mtime != last_mtime {
+            // Wait for writes to settle before running the merge.
+            loop {
+                thread::sleep(WATCH_DEBOUNCE);
+                let new_mtime = mtime();
+                if new_mtime == cur_mtime {
+                    break;
+                }
+                cur_mtime = new_mtime;
+            }
+            last_mtime = cur_mtime;
+
+            num_runs += 1;
+            eprintln!("[watch] run {num_runs}: merging {:?}", new_snippets_file);
+            let start = Instant::now();
+            let status = Command::new(env::current_exe().unwrap())
+                .args(&child_args)
+                .status()
+                .unwrap();
+            let outcome = if status.success() {
+                "succeeded".to_owned()
+            } else {
+                num_failed += 1;
+                format!("failed ({status})")
+            };
+            eprintln!("[watch] run {num_runs}: merge {outcome} in {:.2}s; {} of {num_runs} runs \
+                failed so far; waiting for changes", start.elapsed().as_secs_f64(), num_failed);
+        }
+        thread::sleep(WATCH_POLL_INTERVAL);
+    }
+    eprintln!("[watch] stopping after {num_runs} runs, {num_failed} of which failed");
+}
+
+/// One line of `--jsonl` input.
+#[derive(Deserialize)]
+struct JsonlSnippet {
+    path: String,
+    content: Option<String>,
+    order: Option<i64>,
+}
+
+/// A non-`null` value in the snippets map: either the new contents of the item, or an object
+/// giving the contents along with an `order` for placing new items.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SnippetValue {
+    Content(String),
+    Ordered { content: String, order: Option<i64> },
+}
+
+impl SnippetValue {
+    fn into_parts(self) -> (String, SnippetOptions) {
+        match self {
+            SnippetValue::Content(content) => (content, SnippetOptions::default()),
+            SnippetValue::Ordered { content, order } => {
+                (content, SnippetOptions { order, ..SnippetOptions::default() })
+            },
+        }
+    }
+}
+
+/// What a `--rich` snippet does to its item.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SnippetMode {
+    #[default]
+    Replace,
+    Delete,
+    UpdateOnly,
+}
+
+/// A value in the `--rich` snippets map.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RichSnippet {
+    content: Option<String>,
+    /// File to read the content from instead, relative to the directory of the JSON file or to
+    /// `--bodies-dir`.
+    file: Option<PathBuf>,
+    #[serde(default)]
+    mode: SnippetMode,
+    #[serde(default)]
+    preserve_attrs: bool,
+    #[serde(default)]
+    keep_body: bool,
+    order: Option<i64>,
+    /// Id of the transaction this snippet belongs to, if any.
+    transaction: Option<String>,
+}
+
+impl RichSnippet {
+    /// Convert to the arguments of `Merge::add_snippet`, reading the content from `file` if it's
+    /// given.  `key` is only used in error messages, and `base_dir` is the directory `file` is
+    /// relative to.
+    fn into_parts(self, key: &str, base_dir: &Path) -> (Option<String>, SnippetOptions) {
+        let RichSnippet { content, file, mode, preserve_attrs, keep_body, order, transaction: _ } =
+            self;
+        let content = match (content, file) {
+            (Some(_), Some(_)) => panic!("snippet for {key:?} has both content and a file"),
+            (None, Some(file)) => {
+                let path = base_dir.join(file);
+                if !path.exists() {
+                    panic!("snippet for {key:?} refers to {}, which doesn't exist", path.display());
+                }
+                let src = collect::read_source(&path).unwrap_or_else(|e| {
+                    panic!("failed to read snippet for {key:?}: {e}");
+                });
+                // Files normally end with a newline, which isn't part of the item.
+                Some(src.trim_end().to_owned())
+            },
+            (content, None) => content,
+        };
+        match (mode, &content) {
+            (SnippetMode::Delete, Some(_)) => {
+                panic!("snippet for {key:?} has mode \"delete\", but also has content")
+            },
+            (SnippetMode::Replace | SnippetMode::UpdateOnly, None) => {
+                panic!("snippet for {key:?} has no content")
+            },
+            _ => {},
+        }
+        let update_only = mode == SnippetMode::UpdateOnly;
+        (content, SnippetOptions { order, update_only, preserve_attrs, keep_body })
+    }
+}
+
+/// Per-item options for a snippet.  Only `order` can be given outside of `--rich` mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct SnippetOptions {
+    /// Where to put the item if it's new; see `Merge::ordered_items`.
+    order: Option<i64>,
+    /// Like `--update-only`, but for this item alone.
+    update_only: bool,
+    /// Keep the attributes of the existing item instead of those in the snippet.
+    preserve_attrs: bool,
+    /// Keep the body of the existing function, replacing only its signature.
+    keep_body: bool,
+}
+
+/// A module created by the merge to hold new items.
+#[derive(Clone, Debug, Serialize)]
+struct CreatedModule {
+    module: String,
+    file: PathBuf,
+    /// The file the `mod` declaration was added to.
+    declared_in: PathBuf,
+    mod_decl: String,
+}
+
+/// Contents of the `--report` file.
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    created_modules: Vec<CreatedModule>,
+    extra_items: Vec<String>,
+    /// Outcome of each `--rich` transaction: `null` if it was applied, or else the error that
+    /// caused it to be skipped.
+    transactions: IndexMap<String, Option<String>>,
+}
+
+/// Contents of the `--plan` file.  Applying it creates `created_files` (empty), applies
+/// `rewrites`, and then deletes `deleted_files`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Plan {
+    created_files: Vec<PathBuf>,
+    /// Rewrites, grouped by file and sorted by position within each file.
+    rewrites: Vec<PlannedRewrite>,
+    deleted_files: Vec<PathBuf>,
+}
+
+/// A single rewrite in a `--plan` file: bytes `lo .. hi` of `file`, currently `old`, are replaced
+/// with `new`.
+#[derive(Debug, Serialize, Deserialize)]
+struct PlannedRewrite {
+    file: PathBuf,
+    lo: usize,
+    hi: usize,
+    old: String,
+    new: String,
+    reason: String,
+}
+
+/// State of a merge in progress.  Snippets are passed to `add_snippet` one at a time, and each is
+/// turned into rewrites right away.  Afterward, `finish` handles the existing items that had no
+/// snippet.
+#[derive(Clone)]
+struct Merge<'a> {
+    args: &'a Args,
+    key_prefix: Option<&'a str>,
+    src_root_dir: &'a Path,
+    /// Module path and AST of each file, used to find enums for new variants.
+    files: Vec<(PathBuf, ModPath, syn::File)>,
+    /// Source text of each file, including newly created module files.
+    sources: HashMap<PathBuf, String>,
+    /// Indentation unit of each existing file in which it could be detected.  Other files,
+    /// including new ones, use `default_indent_unit`.
+    indent_units: HashMap<PathBuf, IndentUnit>,
+    /// Indentation unit of the crate root, or four spaces if it couldn't be detected.
+    default_indent_unit: IndentUnit,
+    /// File and byte range of each existing item, keyed by item path.
+    items: IndexMap<String, Vec<(PathBuf, usize, usize)>>,
+    /// Paths in `items` of items declared inside function bodies, with `--nested-items`.
+    nested_items: HashSet<String>,
+    /// Paths in `items` of the members of inherent `impl` blocks, as in `foo::Bar::new`.
+    impl_members: HashSet<String>,
+    /// Paths of members that match members of several `impl` blocks, which aren't in `items`.
+    ambiguous_impl_members: HashSet<String>,
+    /// Paths of the types with inherent `impl` blocks, such as `foo::Bar`.
+    impl_types: HashSet<String>,
+    /// Kinds of the existing named items at each path, including kinds not tracked in `items`,
+    /// such as structs.  A path can have several, as with a struct and a function of the same
+    /// name.
+    item_kinds: HashMap<String, Vec<ItemKind>>,
+    /// File and line of the first existing named item at each path in each namespace.  New items
+    /// are checked against this, since `items` doesn't cover every kind of item.
+    item_lines: HashMap<(String, Namespace), (PathBuf, usize)>,
+    /// File and module path of every module that existed before the merge, including inline ones.
+    all_mods: Vec<(ModPath, PathBuf, bool)>,
+    /// Gives the file path and end position for each module.
+    mod_locations: HashMap<ModPath, (PathBuf, usize)>,
+    /// Start of the contents of each module, and end of its inner attributes.
+    mod_inner_attrs: HashMap<ModPath, (usize, usize)>,
+    /// End of the last `use` or `extern crate` item in each module that has any.  New imports are
+    /// inserted there, to keep them together.
+    last_import_ends: HashMap<ModPath, usize>,
+    /// Inline modules, for which `mod_locations` gives the position of the closing brace.
+    inline_mods: HashSet<ModPath>,
+    /// Inline modules that new items have been inserted into.
+    extended_inline_mods: IndexSet<ModPath>,
+    /// Modules created by this merge.
+    created_mods: IndexMap<ModPath, CreatedModule>,
+    /// Keys of all non-`null` snippets seen so far.
+    snippet_keys: HashSet<String>,
+    /// Keys of all `null` snippets seen so far.
+    deleted_keys: IndexSet<String>,
+    /// Keys of the snippets in failed `--rich` transactions.  Their existing items are kept as
+    /// they are.
+    kept_keys: HashSet<String>,
+    /// Keys of the new items added by snippets, not counting the modules created for them.
+    added_keys: IndexSet<String>,
+    /// New items with an explicit order, as `(order, key, snippet)`.  These are added in
+    /// `finish`, after all the unordered ones.
+    ordered_items: Vec<(i64, String, String)>,
+    /// Line range snippets, as `(key, file, lo, hi, new_text)`.  These are added to
+    /// `file_rewrites` in `finish`, once it's known that they don't overlap any other rewrite.
+    line_ranges: Vec<(String, PathBuf, usize, usize, String)>,
+    /// Files of deleted modules, to be removed once all rewrites have been applied.
+    deleted_files: IndexSet<PathBuf>,
+    /// The rewrites of each file, each along with why it's made, such as `update foo::f`, for
+    /// `--plan` and `--interactive`.
+    file_rewrites: IndexMap<PathBuf, Vec<(Rewrite, String)>>,
+    /// Paths of items defined by snippets in addition to the item named by the snippet's key.
+    /// These aren't tracked by key, so they're reported at the end of the run.
+    extra_items: Vec<String>,
+    stats: Stats,
+}
+
+impl<'a> Merge<'a> {
+    fn new(args: &'a Args, src_root_path: &'a Path, key_prefix: Option<&'a str>) -> Merge<'a> {
+        let src_root_dir = src_root_path.parent().unwrap();
+        let mut fc = FileCollector::default();
+        fc.keep_sources = true;
+        fc.parse(src_root_path, vec![], true).unwrap();
+
+        let mut items = IndexMap::<String, Vec<_>>::new();
+        let mut nested_items = HashSet::new();
+        for (file_path, mod_path, ast) in &fc.files {
+            let spans = if args.nested_items {
+                let top_level = item_spans(mod_path.clone(), ast).into_iter()
+                    .map(|(item_path, _, _)| item_path)
+                    .collect::<HashSet<_>>();
+                let spans = item_spans_with_fn_bodies(mod_path.clone(), ast);
+                for (item_path, _, _) in &spans {
+                    if !top_level.contains(item_path) {
+                        nested_items.insert(item_path.join("::"));
+                    }
+                }
+                spans
+            } else {
+                item_spans(mod_path.clone(), ast)
+            };
+            for (item_path, lo, hi) in spans {
+                items.entry(item_path.join("::")).or_default().push((file_path.clone(), lo, hi));
+            }
+        }
+        let sources = mem::take(&mut fc.sources);
+        let mod_files = fc.mods.iter()
+            .map(|m| (m.mod_path.clone(), &m.file_path))
+            .collect::<HashMap<_, _>>();
+        let mut item_kinds = HashMap::<String, Vec<_>>::new();
+        let mut item_lines = HashMap::new();
+        let mut member_spans = IndexMap::<String, Vec<_>>::new();
+        let mut impl_types = HashSet::new();
+        for (mut mod_path, kind, item, span) in fc.items() {
+            let file_path = mod_files[&mod_path].clone();
+            match item {
+                ItemRef::Item(item) => {
+                    let Some(name) = item_name(item) else {
                         continue;
-                    } else {
-                        ""
+                    };
+                    mod_path.push(name.unraw().to_string());
+                    let item_path = mod_path.join("::");
+                    for &ns in item_namespaces(item) {
+                        item_lines.entry((item_path.clone(), ns))
+                            .or_insert((file_path.clone(), span.start().line));
                     }
+                    item_kinds.entry(item_path).or_default().push(kind);
                 },
-            };
-            if new_snippet != old_snippet {
-                rewrites.push((lo, hi, new_snippet));
+                ItemRef::ImplMember(imp, _) => {
+                    // Members of trait impls would need the trait in their keys as well.
+                    let header = ImplHeader::of(imp);
+                    let (None, Some(ty), Some(name)) =
+                        (header.trait_path, header.self_ty_name, item.ident())
+                    else {
+                        continue;
+                    };
+                    mod_path.push(ty);
+                    impl_types.insert(mod_path.join("::"));
+                    mod_path.push(name.unraw().to_string());
+                    let range = span.byte_range();
+                    member_spans.entry(mod_path.join("::")).or_default()
+                        .push((file_path, range.start, range.end));
+                },
+                ItemRef::TraitMember(..) => {},
+            }
+        }
+        let mut impl_members = HashSet::new();
+        let mut ambiguous_impl_members = HashSet::new();
+        for (member_path, locations) in member_spans {
+            if items.contains_key(&member_path) {
+                continue;
+            }
+            if locations.len() > 1 {
+                ambiguous_impl_members.insert(member_path);
+            } else {
+                impl_members.insert(member_path.clone());
+                items.insert(member_path, locations);
             }
         }
+        let indent_units = sources.iter()
+            .filter_map(|(file_path, src)| Some((file_path.clone(), detect_indent_unit(src)?)))
+            .collect::<HashMap<_, _>>();
+        let default_indent_unit = indent_units.get(src_root_path).copied()
+            .unwrap_or(IndentUnit::Spaces(4));
+
+        let mod_locations = fc.mods.iter().map(|m| {
+            (m.mod_path.join("::"), (m.file_path.clone(), m.inner_end_pos))
+        }).collect();
+        let mod_inner_attrs = fc.mods.iter().map(|m| {
+            (m.mod_path.join("::"), (m.inner_start_pos, m.inner_attrs_end_pos))
+        }).collect();
+        let last_import_ends = fc.mods.iter()
+            .filter_map(|m| Some((m.mod_path.join("::"), m.import_spans.last()?.1)))
+            .collect();
+        let inline_mods = fc.mods.iter()
+            .filter(|m| m.is_inline)
+            .map(|m| m.mod_path.join("::"))
+            .collect();
+        let all_mods = fc.mods.iter()
+            .map(|m| (m.mod_path.join("::"), m.file_path.clone(), m.is_inline))
+            .collect();
+        let files = fc.files.into_iter()
+            .map(|(file_path, mod_path_parts, ast)| (file_path, mod_path_parts.join("::"), ast))
+            .collect();
+
+        Merge {
+            args,
+            key_prefix,
+            src_root_dir,
+            files,
+            sources,
+            indent_units,
+            default_indent_unit,
+            items,
+            nested_items,
+            impl_members,
+            ambiguous_impl_members,
+            impl_types,
+            item_kinds,
+            item_lines,
+            all_mods,
+            mod_locations,
+            mod_inner_attrs,
+            last_import_ends,
+            inline_mods,
+            extended_inline_mods: IndexSet::new(),
+            created_mods: IndexMap::new(),
+            snippet_keys: HashSet::new(),
+            deleted_keys: IndexSet::new(),
+            kept_keys: HashSet::new(),
+            added_keys: IndexSet::new(),
+            ordered_items: Vec::new(),
+            line_ranges: Vec::new(),
+            deleted_files: IndexSet::new(),
+            file_rewrites: IndexMap::new(),
+            extra_items: Vec::new(),
+            stats: Stats::default(),
+        }
     }
 
-    // Collect rewrites for newly added items.  Any entry in `new_snippets` that wasn't added to
-    // `snippets_applied` above must be a newly added item.
-    if !args.update_only {
-        for (item_path, new_snippet) in &new_snippets {
-            if snippets_applied.contains(item_path) {
-                continue;
+    fn indent_unit(&self, file_path: &Path) -> IndentUnit {
+        self.indent_units.get(file_path).copied().unwrap_or(self.default_indent_unit)
+    }
+
+    /// Convert `key` back to the form the user wrote, for use in messages.
+    fn user_key<'k>(&self, key: &'k str) -> &'k str {
+        strip_key_prefix(key, self.key_prefix)
+    }
+
+    /// Handle the snippet for the item at (normalized) path `key`.  A value of `None` deletes
+    /// the item.
+    fn add_snippet(&mut self, key: String, new_snippet: Option<String>, opts: SnippetOptions) {
+        if key.is_empty()
+            && let Some(ref snippet) = new_snippet
+        {
+            self.add_root_snippet(snippet, opts);
+            return;
+        }
+        let new_snippet = match new_snippet {
+            Some(x) => {
+                self.snippet_keys.insert(key.clone());
+                x
+            },
+            None => {
+                self.delete(key);
+                return;
+            },
+        };
+
+        if self.args.emit_undo.is_some()
+            && (inner_attrs_mod(&key).is_some() || key.contains(VARIANT_MARKER))
+        {
+            eprintln!("warning: --emit-undo can't undo the snippet for {:?}", self.user_key(&key));
+        }
+
+        if let Some(mod_path) = inner_attrs_mod(&key) {
+            self.add_inner_attrs(mod_path, &new_snippet);
+            return;
+        }
+
+        if key.contains(VARIANT_MARKER) {
+            // New enum variants are handled separately from ordinary items, since they don't
+            // correspond to any entry in `item_spans`.
+            self.add_variant(&key, &new_snippet);
+            return;
+        }
+
+        // The `#2` in a nested item key like `outer::inner#2` isn't part of the item's name.
+        let name_key = match key.rsplit_once('#') {
+            Some((name_key, n)) if n.parse::<u32>().is_ok() && self.nested_items.contains(&key) => {
+                name_key
+            },
+            _ => &key,
+        };
+        match check_snippet_name(name_key, &new_snippet) {
+            Ok(extra_names) => {
+                let parent = key.rsplit_once("::").map_or("", |(parent, _child)| parent);
+                for name in extra_names {
+                    self.extra_items.push(join_path(parent, &name));
+                }
+            },
+            Err(name) => {
+                let msg = format!("snippet for {:?} defines an item named {:?} instead",
+                    self.user_key(&key), name);
+                if self.args.lenient {
+                    eprintln!("warning: {msg}");
+                } else {
+                    panic!("{msg}");
+                }
+            },
+        }
+
+        if let Some(old_kinds) = self.item_kinds.get(&key)
+            && let Some(new_kind) = snippet_kind(&new_snippet)
+            && !old_kinds.contains(&new_kind)
+        {
+            let msg = format!("snippet for {:?} is a {}, but the existing item is a {}",
+                self.user_key(&key), new_kind.description(), old_kinds[0].description());
+            if self.args.strict_kinds {
+                panic!("{msg}");
+            } else {
+                eprintln!("warning: {msg}");
             }
-            let mod_path = item_path.rsplit_once("::").map_or("", |(parent, _child)| parent);
-            let &(ref file_path, end_pos) = mod_locations.get(mod_path).unwrap_or_else(|| {
-                unreachable!("parent mod for {:?} should be added above", item_path);
-            });
-            let rewrites = file_rewrites.entry(file_path.clone()).or_insert(Vec::new());
-            rewrites.push((end_pos, end_pos, "\n\n"));
-            rewrites.push((end_pos, end_pos, new_snippet));
+        }
+
+        match self.items.get(&key) {
+            Some(locations) => {
+                if self.args.warn_visibility_change
+                    && let Some(&(ref file_path, lo, hi)) = locations.first()
+                    && let Some(old_vis) = snippet_visibility(&self.sources[file_path][lo..hi])
+                    && let Some(new_vis) = snippet_visibility(&new_snippet)
+                    && old_vis != new_vis
+                {
+                    eprintln!("warning: snippet for {:?} changes its visibility from {} to {}",
+                        self.user_key(&key), old_vis, new_vis);
+                }
+                let mut formatted = None;
+                for &(ref file_path, lo, hi) in locations {
+                    let src = &self.sources[file_path];
+                    if src[lo..hi] == new_snippet {
+                        // Unchanged, so there's nothing to format either.
+                        continue;
+                    }
+                    let new_text = if self.args.format_changed_only {
+                        // `rustfmt` formats the snippet as if it were at the top level, so its
+                        // continuation lines need to be indented to match the item it replaces.
+                        let formatted = formatted
+                            .get_or_insert_with(|| self.format_snippet(&key, &new_snippet));
+                        match *formatted {
+                            Some(ref x) => {
+                                let x = convert_indent(x, self.indent_unit(file_path));
+                                reindent(&x, line_indent(src, lo))
+                            },
+                            None => new_snippet.clone(),
+                        }
+                    } else {
+                        new_snippet.clone()
+                    };
+                    let new_text = if opts.keep_body {
+                        replace_signature(&new_text, &src[lo..hi]).unwrap_or_else(|| {
+                            panic!("can't keep the body of {:?}: the snippet and the existing \
+                                item must both be functions", self.user_key(&key));
+                        })
+                    } else {
+                        new_text
+                    };
+                    let new_text = if opts.preserve_attrs {
+                        replace_outer_attrs(&new_text, &src[lo..hi])
+                    } else {
+                        new_text
+                    };
+                    if src[lo..hi] != new_text {
+                        self.stats.items_updated += 1;
+                        let reason = format!("update {}", self.user_key(&key));
+                        self.file_rewrites.entry(file_path.clone()).or_default()
+                            .push(((lo, hi, new_text), reason));
+                    }
+                }
+            },
+            None => {
+                assert!(!self.created_mods.contains_key(&key), "item {:?} exists but is not a module",
+                    self.user_key(&key));
+                assert!(!self.ambiguous_impl_members.contains(&key),
+                    "key {:?} matches members of several impl blocks", self.user_key(&key));
+                let parent = key.rsplit_once("::").map_or("", |(parent, _child)| parent);
+                let in_impl = self.impl_types.contains(parent)
+                    && !self.mod_locations.contains_key(parent);
+                assert!(!in_impl, "snippet for {:?} would add a new member to an impl block, \
+                    which isn't supported", self.user_key(&key));
+                if !self.args.update_only && !opts.update_only {
+                    self.check_not_duplicate(&key, &new_snippet);
+                    let new_snippet = if self.args.format_changed_only {
+                        self.format_snippet(&key, &new_snippet).unwrap_or(new_snippet)
+                    } else {
+                        new_snippet
+                    };
+                    self.added_keys.insert(key.clone());
+                    match opts.order {
+                        Some(order) => self.ordered_items.push((order, key, new_snippet)),
+                        None => self.add_item(&key, new_snippet),
+                    }
+                }
+            },
         }
     }
 
-    // Apply the collected rewrites to each file.
-    for (file_path, mut rewrites) in file_rewrites {
-        if rewrites.len() == 0 {
-            continue;
+    /// Check that the new item `key`, which isn't in `items`, doesn't already exist as some other
+    /// kind of item or under another spelling, such as a raw identifier, which would make adding
+    /// it define the name twice.  Only items in the same namespace clash, so adding `fn stat()`
+    /// next to `struct stat` is fine.  A snippet that doesn't parse is checked against every
+    /// namespace.
+    fn check_not_duplicate(&self, key: &str, new_snippet: &str) {
+        let namespaces = match syn::parse_str::<syn::File>(new_snippet) {
+            Ok(file) => file.items.first().map_or(&[][..], item_namespaces),
+            Err(_) => &[Namespace::Type, Namespace::Value],
+        };
+        let Some(&(ref old_file, old_line)) = namespaces.iter()
+            .find_map(|&ns| self.item_lines.get(&(key.to_owned(), ns)))
+        else {
+            return;
+        };
+        let parent = key.rsplit_once("::").map_or("", |(parent, _)| parent);
+        let &(ref new_file, pos) = &self.mod_locations[parent];
+        let new_line = self.sources[new_file][..pos].lines().count().max(1);
+        panic!("snippet for {:?} would add a second item with that name at {}:{}, but one \
+            already exists at {}:{}", self.user_key(key), new_file.display(), new_line,
+            old_file.display(), old_line);
+    }
+
+    /// Handle a snippet for the crate root itself, whose key is `crate` (or empty).  Its inner
+    /// attributes are added as with `#inner_attrs`, and each of its items is updated or added as
+    /// if it had its own snippet, under the key `crate::NAME`.  Items without a name, such as
+    /// `use` and `impl` items, are added to the crate root unless it already has an identical one.
+    fn add_root_snippet(&mut self, snippet: &str, opts: SnippetOptions) {
+        let file = syn::parse_file(snippet).unwrap_or_else(|e| {
+            panic!("snippet for the crate root doesn't parse: {e}");
+        });
+        let mut keys = Vec::new();
+        if !file.attrs.is_empty() {
+            let attrs = file.attrs.iter()
+                .map(|attr| &snippet[attr.span().byte_range()])
+                .collect::<Vec<_>>();
+            keys.push((Some(INNER_ATTRS_SEGMENT.to_owned()), attrs.join("\n")));
+        }
+        for item in &file.items {
+            let name = item_name(item).map(|name| name.unraw().to_string());
+            keys.push((name, snippet[item.span().byte_range()].to_owned()));
         }
+        for (key, item_snippet) in keys {
+            match key {
+                Some(key) => {
+                    assert!(!self.snippet_keys.contains(&key),
+                        "{:?} is given both in the crate root snippet and on its own", key);
+                    self.add_snippet(key, Some(item_snippet), opts);
+                },
+                None => self.add_unnamed_root_item(item_snippet, opts),
+            }
+        }
+    }
 
-        let old_src = fs::read_to_string(&file_path).unwrap();
-
-        // Apply rewrites
-        //
-        // Sort by `lo`, then by `hi`.  This means `0..10 < 10..10 < 10..20`.
-        rewrites.sort_by_key(|&(lo, hi, _)| (lo, hi));
-        let mut new_src = String::with_capacity(old_src.len());
-        let mut pos = 0;
-        for &(lo, hi, ref new_snippet) in &rewrites {
-            assert!(
-                lo >= pos,
-                "overlapping rewrites: previous rewrite ended at {}, \
-                but current rewrite covers {} .. {}",
-                pos,
+    /// Add `item_snippet`, an item without a name, to the crate root, unless the crate root
+    /// already has the same item or `--update-only` is set.
+    fn add_unnamed_root_item(&mut self, item_snippet: String, opts: SnippetOptions) {
+        if self.args.update_only || opts.update_only {
+            return;
+        }
+        let tokens = |s: &str| s.parse::<TokenStream>().map(|ts| ts.to_string()).ok();
+        let new_tokens = tokens(&item_snippet);
+        let src = &self.sources[&self.mod_locations[""].0];
+        let exists = syn::parse_file(src).is_ok_and(|file| {
+            file.items.iter().any(|item| {
+                item_name(item).is_none() && tokens(&src[item.span().byte_range()]) == new_tokens
+            })
+        });
+        if exists {
+            return;
+        }
+        if self.args.emit_undo.is_some() {
+            eprintln!("warning: --emit-undo can't undo the crate root item {:?}", item_snippet);
+        }
+        self.add_item("", item_snippet);
+    }
+
+    /// Handle the snippet for the line range key `key`, which replaces lines `first..=last` of
+    /// `file`, a path relative to the directory of the crate root.  A value of `None` removes the
+    /// lines.
+    fn add_line_range(
+        &mut self,
+        key: &str,
+        (file, first, last): (PathBuf, usize, usize),
+        new_snippet: Option<String>,
+    ) {
+        let file_path = self.src_root_dir.join(&file);
+        let Some(src) = self.sources.get(&file_path) else {
+            panic!("file {:?} in key {:?} is not part of the crate", file, key);
+        };
+        let (lo, hi) = line_range_bytes(src, first, last).unwrap_or_else(|| {
+            panic!("key {:?} has an empty line range or goes past the end of {:?}", key, file);
+        });
+        if self.args.emit_undo.is_some() {
+            eprintln!("warning: --emit-undo can't undo the snippet for {:?}", key);
+        }
+        self.stats.items_updated += 1;
+        self.line_ranges.push((key.to_owned(), file_path, lo, hi, new_snippet.unwrap_or_default()));
+    }
+
+    /// Add a snippet for each existing item matched by one of the `--by-regex` patterns.  This
+    /// must be called after all the JSON snippets have been added, so conflicts with them can be
+    /// detected.  With `--crate-roots`, paths are qualified with `crate_name`, as in keys.
+    fn add_regex_snippets(&mut self, crate_name: &str, regex_snippets: &[RegexSnippet]) {
+        let mut matched = Vec::new();
+        for key in self.items.keys() {
+            let path = join_path(crate_name, self.user_key(key));
+            let mut found: Option<(&RegexSnippet, String)> = None;
+            for rs in regex_snippets {
+                let Some(caps) = rs.regex.captures(&path) else {
+                    continue;
+                };
+                if let Some((old, _)) = found {
+                    panic!("item {:?} is matched by both --by-regex patterns {:?} and {:?}",
+                        path, old.pattern, rs.pattern);
+                }
+                let mut snippet = String::new();
+                caps.expand(&rs.template, &mut snippet);
+                found = Some((rs, snippet));
+            }
+            if let Some((rs, snippet)) = found {
+                assert!(!self.snippet_keys.contains(key) && !self.deleted_keys.contains(key),
+                    "item {:?} is matched by --by-regex pattern {:?} but also has a key in the \
+                    JSON file", path, rs.pattern);
+                matched.push((key.clone(), snippet));
+            }
+        }
+        for (key, snippet) in matched {
+            self.add_snippet(key, Some(snippet), SnippetOptions::default());
+        }
+    }
+
+    /// Format the snippet for `key` for `--format-changed-only`.  On failure, prints a warning and
+    /// returns `None`, in which case the snippet should be inserted as is.
+    fn format_snippet(&self, key: &str, snippet: &str) -> Option<String> {
+        let formatted = rustfmt_snippet(snippet);
+        if formatted.is_none() {
+            eprintln!("warning: failed to format snippet for {:?}; inserting it as is",
+                self.user_key(key));
+        }
+        formatted
+    }
+
+    /// Record that the item at `key` should be deleted.  If it's a module, its files are deleted
+    /// too.  The item itself is removed in `finish`, once it's known which other items are
+    /// deleted along with it.
+    fn delete(&mut self, key: String) {
+        assert!(!key.is_empty(), "can't delete the crate root");
+        assert!(!key.contains(VARIANT_MARKER), "deleting enum variants is not supported: {:?}",
+            self.user_key(&key));
+        assert!(inner_attrs_mod(&key).is_none(),
+            "deleting inner attributes is not supported: {:?}", self.user_key(&key));
+        if !self.items.contains_key(&key) {
+            eprintln!("warning: can't delete {:?}, since it doesn't exist", self.user_key(&key));
+        }
+        if self.mod_locations.contains_key(&key) {
+            let child_prefix = format!("{key}::");
+            for &(ref mod_path, ref file_path, is_inline) in &self.all_mods {
+                if !is_inline && (*mod_path == key || mod_path.starts_with(&child_prefix)) {
+                    self.deleted_files.insert(file_path.clone());
+                }
+            }
+        }
+        self.deleted_keys.insert(key);
+    }
+
+    /// Add a new item at the end of its parent module, creating the module if needed.
+    fn add_item(&mut self, key: &str, new_snippet: String) {
+        let mod_path = key.rsplit_once("::").map_or("", |(parent, _child)| parent);
+        for new_mod_path in missing_modules(mod_path, |m| self.mod_locations.contains_key(m)) {
+            assert!(!self.snippet_keys.contains(new_mod_path)
+                && !self.items.contains_key(new_mod_path),
+                "item {:?} exists but is not a module", self.user_key(new_mod_path));
+            // The file is only recorded here.  It's created on disk along with the other writes,
+            // once nothing can stop the merge from being applied.
+            let file_path = module_file_path(self.src_root_dir, new_mod_path);
+            self.sources.insert(file_path.clone(), String::new());
+            // Add a `mod foo;` declaration to the parent module.
+            let parent_mod_path = new_mod_path.rsplit_once("::").map_or("", |(parent, _)| parent);
+            let declared_in = self.mod_locations[parent_mod_path].0.clone();
+            // Keep `mod` declarations together, after the last existing one if there is one.
+            match self.last_mod_decl_end(parent_mod_path, &declared_in) {
+                Some(pos) => {
+                    let text = format!("\n{}{}",
+                        line_indent(&self.sources[&declared_in], pos), mod_decl(new_mod_path));
+                    let reason = format!("declare new module {}", self.user_key(new_mod_path));
+                    self.file_rewrites.entry(declared_in.clone()).or_default()
+                        .push(((pos, pos, text), reason));
+                    self.stats.items_added += 1;
+                },
+                None => self.add_item(new_mod_path, mod_decl(new_mod_path)),
+            }
+            self.created_mods.insert(new_mod_path.to_owned(), CreatedModule {
+                module: new_mod_path.to_owned(),
+                file: file_path.clone(),
+                declared_in,
+                mod_decl: mod_decl(new_mod_path),
+            });
+            // The file is empty, so new items should be inserted at byte position 0.
+            self.mod_locations.insert(new_mod_path.to_owned(), (file_path, 0));
+        }
+
+        if is_import_snippet(&new_snippet)
+            && let Some(&pos) = self.last_import_ends.get(mod_path)
+        {
+            // Keep imports together, after the last existing one.
+            let file_path = self.mod_locations[mod_path].0.clone();
+            let indent = line_indent(&self.sources[&file_path], pos);
+            let new_snippet = convert_indent(new_snippet.trim(), self.indent_unit(&file_path));
+            let text = format!("\n{indent}{}", reindent(&new_snippet, indent));
+            let reason = self.add_reason(key);
+            self.file_rewrites.entry(file_path).or_default().push(((pos, pos, text), reason));
+            self.stats.items_added += 1;
+            return;
+        }
+
+        let &(ref file_path, end_pos) = &self.mod_locations[mod_path];
+        let unit = self.indent_unit(file_path);
+        let reason = self.add_reason(key);
+        let rewrites = self.file_rewrites.entry(file_path.clone()).or_default();
+        self.stats.items_added += 1;
+        if self.inline_mods.contains(mod_path) {
+            let first = self.extended_inline_mods.insert(mod_path.to_owned());
+            let (pos, text) =
+                inline_mod_insertion(&self.sources[file_path], end_pos, &new_snippet, first, unit);
+            rewrites.push(((pos, pos, text), reason));
+        } else {
+            rewrites.push(((end_pos, end_pos, "\n\n".to_owned()), reason.clone()));
+            rewrites.push(((end_pos, end_pos, convert_indent(&new_snippet, unit)), reason));
+        }
+    }
+
+    /// Describe the addition of `key` in the report.  The empty key is used for items without a
+    /// name added to the crate root.
+    fn add_reason(&self, key: &str) -> String {
+        if key.is_empty() {
+            "add to the crate root".to_owned()
+        } else {
+            format!("add {}", self.user_key(key))
+        }
+    }
+
+    /// Add the inner attributes in `new_snippet` to the existing module `mod_path`, after any it
+    /// already has.  Attributes the module already has are skipped, so this can be repeated.
+    fn add_inner_attrs(&mut self, mod_path: &str, new_snippet: &str) {
+        let Some(&(start, attrs_end)) = self.mod_inner_attrs.get(mod_path) else {
+            panic!("module {:?} not found (referenced by inner attributes snippet)",
+                self.user_key(mod_path));
+        };
+        let file_path = &self.mod_locations[mod_path].0;
+        let src = &self.sources[file_path];
+        // Attributes are compared by their tokens, so formatting differences don't matter.
+        let old_attrs = syn::parse_file(&src[start..attrs_end]).unwrap().attrs.iter()
+            .map(|attr| attr.to_token_stream().to_string())
+            .collect::<HashSet<_>>();
+        let new_file = syn::parse_file(new_snippet).ok().filter(|file| file.items.is_empty())
+            .unwrap_or_else(|| {
+                panic!("snippet for {:?} should contain only inner attributes",
+                    self.user_key(&join_path(mod_path, INNER_ATTRS_SEGMENT)));
+            });
+        let new_texts = new_file.attrs.iter()
+            .filter(|attr| !old_attrs.contains(&attr.to_token_stream().to_string()))
+            .map(|attr| &new_snippet[attr.span().byte_range()])
+            .collect::<Vec<_>>();
+        if new_texts.is_empty() {
+            return;
+        }
+
+        let mut text = String::new();
+        if self.inline_mods.contains(mod_path) || attrs_end > start {
+            let indent = if self.inline_mods.contains(mod_path) {
+                let close_pos = self.mod_locations[mod_path].1;
+                format!("{}{}", line_indent(src, close_pos), self.indent_unit(file_path).text())
+            } else {
+                String::new()
+            };
+            for attr in &new_texts {
+                text.push('\n');
+                text.push_str(&indent);
+                text.push_str(attr);
+            }
+        } else {
+            // There are no inner attributes at the start of the file yet.
+            for attr in &new_texts {
+                text.push_str(attr);
+                text.push('\n');
+            }
+            if !src.trim().is_empty() {
+                text.push('\n');
+            }
+        }
+        let reason = format!("add {}", self.user_key(&join_path(mod_path, INNER_ATTRS_SEGMENT)));
+        self.file_rewrites.entry(file_path.clone()).or_default()
+            .push(((attrs_end, attrs_end, text), reason));
+    }
+
+    /// Find the end of the last declaration of an out-of-line submodule, as in `mod foo;`, in the
+    /// existing module `mod_path`, whose declarations are in `file_path`.
+    fn last_mod_decl_end(&self, mod_path: &str, file_path: &Path) -> Option<usize> {
+        self.all_mods.iter()
+            .filter(|&&(ref child, _, is_inline)| {
+                !is_inline
+                    && !child.is_empty()
+                    && child.rsplit_once("::").map_or("", |(parent, _)| parent) == mod_path
+            })
+            .flat_map(|(child, _, _)| self.items.get(child).into_iter().flatten())
+            .filter(|&(decl_file, _, _)| decl_file == file_path)
+            .map(|&(_, _, hi)| hi)
+            .max()
+    }
+
+    fn add_variant(&mut self, key: &str, new_snippet: &str) {
+        let (enum_path, variant_name) = key.split_once(VARIANT_MARKER).unwrap();
+        let (file_path, ie) = find_enum(&self.files, enum_path).unwrap_or_else(|| {
+            panic!("enum {:?} not found (referenced by snippet {:?})",
+                self.user_key(enum_path), self.user_key(key));
+        });
+        let (pos, text) = enum_variant_rewrite(&self.sources[file_path], ie, variant_name,
+            new_snippet, self.indent_unit(file_path));
+        let reason = format!("add {}", self.user_key(key));
+        self.file_rewrites.entry(file_path.to_owned()).or_default()
+            .push(((pos, pos, text), reason));
+        self.stats.items_added += 1;
+    }
+
+    /// Check whether `item_path` is outside the module covered by the JSON file, as given by
+    /// `--key-prefix`.
+    fn outside_key_prefix(&self, item_path: &str) -> bool {
+        self.key_prefix.is_some_and(|prefix| {
+            !item_path.starts_with(prefix) || !item_path[prefix.len()..].starts_with("::")
+        })
+    }
+
+    /// For `--coverage`, count the existing items within `--key-prefix`, and list (as written in
+    /// keys) those with no snippet.  This must be called after all snippets have been added.
+    fn uncovered_items(&self) -> (usize, Vec<String>) {
+        let mut num_items = 0;
+        let mut uncovered = Vec::new();
+        for item_path in self.items.keys() {
+            if self.outside_key_prefix(item_path)
+                || self.nested_items.contains(item_path)
+                || self.impl_members.contains(item_path)
+            {
+                continue;
+            }
+            num_items += 1;
+            let covered = self.snippet_keys.contains(item_path)
+                || self.deleted_keys.contains(item_path)
+                || self.deleted_keys.iter().any(|k| item_path.starts_with(&format!("{k}::")));
+            if !covered {
+                uncovered.push(self.user_key(item_path).to_owned());
+            }
+        }
+        (num_items, uncovered)
+    }
+
+    /// Get the text of the existing item at `lo..hi` in `file_path` after the rewrites inside it
+    /// have been applied.  Insertions at either end of the item are outside it.
+    fn rewritten_text(&self, file_path: &Path, lo: usize, hi: usize) -> String {
+        let mut rewrites = self.file_rewrites.get(file_path).into_iter().flatten()
+            .filter(|&&((r_lo, r_hi, _), _)| {
+                lo <= r_lo && r_hi <= hi && !(r_lo == r_hi && (r_lo == lo || r_hi == hi))
+            })
+            .map(|&((r_lo, r_hi, ref text), _)| (r_lo - lo, r_hi - lo, text.clone()))
+            .collect::<Vec<_>>();
+        try_apply_rewrites(&self.sources[file_path][lo..hi], &mut rewrites)
+            .unwrap_or_else(|conflict_src| conflict_src)
+    }
+
+    /// Build the `--emit-undo` snippets, keyed as written by the user.  This must be called after
+    /// `finish`.  Items that were updated or removed get their old text, and other existing items
+    /// their new text, so the undo run leaves them as they are.  Items nested in one that gets
+    /// its old text are covered by it, and the declaration of a module whose file is deleted is
+    /// left out, since restoring the items in the file recreates it.  Files in `skipped` were left
+    /// as they were, so their items keep their old text and new items in them are left out.
+    fn undo_snippets(&self, skipped: &HashSet<PathBuf>) -> IndexMap<String, Option<String>> {
+        let mut undo = IndexMap::new();
+        let mut restored = HashSet::new();
+        for (item_path, locations) in &self.items {
+            if self.outside_key_prefix(item_path) {
+                continue;
+            }
+            let &(ref file_path, lo, hi) = &locations[0];
+            let parent_path = item_path.rsplit_once("::").map_or("", |(parent, _)| parent);
+            if restored.contains(parent_path)
+                && self.items[parent_path].iter().any(|(p, _, _)| p == file_path)
+            {
+                restored.insert(item_path.as_str());
+                continue;
+            }
+            if !self.inline_mods.contains(item_path)
+                && self.mod_locations.get(item_path)
+                    .is_some_and(|(mod_file, _)| self.deleted_files.contains(mod_file))
+            {
+                continue;
+            }
+            let old_text = &self.sources[file_path][lo..hi];
+            let replaced = self.file_rewrites.get(file_path).into_iter().flatten()
+                .any(|&((r_lo, r_hi, _), _)| (r_lo, r_hi) == (lo, hi));
+            let text = if self.deleted_files.contains(file_path) || replaced
+                || skipped.contains(file_path)
+            {
+                restored.insert(item_path.as_str());
+                old_text.to_owned()
+            } else {
+                self.rewritten_text(file_path, lo, hi)
+            };
+            undo.insert(self.user_key(item_path).to_owned(), Some(text));
+        }
+        for key in self.added_keys.iter().chain(self.created_mods.keys()) {
+            let parent_path = key.rsplit_once("::").map_or("", |(parent, _)| parent);
+            if !self.created_mods.contains_key(parent_path)
+                && !skipped.contains(&self.mod_locations[parent_path].0)
+            {
+                undo.insert(self.user_key(key).to_owned(), None);
+            }
+        }
+        undo
+    }
+
+    /// Finish the merge after all snippets have been passed to `add_snippet`.  This adds the new
+    /// items that have an explicit order, and removes existing items that had no snippet (unless
+    /// `--update-only` is set).
+    fn finish(&mut self) {
+        for key in &self.deleted_keys {
+            let child_prefix = format!("{key}::");
+            if let Some(child) = self.snippet_keys.iter().find(|k| k.starts_with(&child_prefix)) {
+                panic!("can't delete module {:?}, since snippet {:?} refers to an item inside it",
+                    self.user_key(key), self.user_key(child));
+            }
+        }
+
+        // Ties are broken by key, which is unique.
+        let mut ordered_items = mem::take(&mut self.ordered_items);
+        ordered_items.sort();
+        for (_, key, new_snippet) in ordered_items {
+            self.add_item(&key, new_snippet);
+        }
+
+        for (item_path, locations) in &self.items {
+            if self.snippet_keys.contains(item_path) {
+                // Already updated in `add_snippet`.
+                continue;
+            }
+            if self.kept_keys.contains(item_path) {
+                continue;
+            }
+            if self.deleted_keys.iter().any(|k| item_path.starts_with(&format!("{k}::"))) {
+                // This item is inside a deleted module, which is removed as a whole.
+                continue;
+            }
+            if !self.deleted_keys.contains(item_path) {
+                if self.nested_items.contains(item_path) || self.impl_members.contains(item_path) {
+                    // Nested items and impl members are part of the enclosing function or impl
+                    // block, so they're kept unless they're deleted explicitly.
+                    continue;
+                }
+                if self.args.update_only {
+                    // We would normally delete this item, but we're currently in update-only
+                    // mode.
+                    continue;
+                }
+                if self.outside_key_prefix(item_path) {
+                    // This item is outside the module covered by the JSON file, so its absence
+                    // from the JSON doesn't mean it should be removed.
+                    continue;
+                }
+            }
+            for &(ref file_path, lo, hi) in locations {
+                if self.deleted_files.contains(file_path) {
+                    continue;
+                }
+                self.stats.items_removed += 1;
+                let reason = format!("remove {}", self.user_key(item_path));
+                self.file_rewrites.entry(file_path.clone()).or_default()
+                    .push(((lo, hi, String::new()), reason));
+            }
+        }
+
+        // Put the closing brace of each extended inline module on its own line, replacing any
+        // whitespace that preceded it.  This comes after all the insertions, so it sorts after
+        // them in `try_apply_rewrites`, even for an empty module like `mod foo {}`.
+        for mod_path in &self.extended_inline_mods {
+            let &(ref file_path, close_pos) = &self.mod_locations[mod_path];
+            let src = &self.sources[file_path];
+            let pos = src[..close_pos].trim_end().len();
+            let indent = line_indent(src, close_pos);
+            let reason = format!("put the closing brace of module {} on its own line",
+                self.user_key(mod_path));
+            self.file_rewrites.entry(file_path.clone()).or_default()
+                .push(((pos, close_pos, format!("\n{indent}")), reason));
+        }
+
+        // Line ranges may not overlap anything else, since they don't line up with items.
+        // Insertions right at the start or end of a range are allowed.
+        for (key, file_path, lo, hi, new_text) in mem::take(&mut self.line_ranges) {
+            assert!(!self.deleted_files.contains(&file_path),
+                "snippet {:?} changes a file that's deleted with its module", key);
+            let rewrites = self.file_rewrites.entry(file_path.clone()).or_default();
+            let overlaps = rewrites.iter().any(|&((r_lo, r_hi, _), _)| {
+                if r_lo == r_hi { lo < r_lo && r_lo < hi } else { r_lo < hi && lo < r_hi }
+            });
+            assert!(!overlaps,
+                "lines replaced by snippet {:?} overlap another snippet's change", key);
+            rewrites.push(((lo, hi, new_text), format!("replace {key}")));
+        }
+
+        // Files of deleted modules are removed entirely, so there's no need to rewrite them.
+        for file_path in &self.deleted_files {
+            self.file_rewrites.shift_remove(file_path);
+        }
+    }
+}
+
+/// Build the `--plan` for applying `file_rewrites` to the files in `sources`.  Returns the files
+/// whose rewrites overlap as an error.
+fn make_plan(
+    file_rewrites: IndexMap<PathBuf, Vec<(Rewrite, String)>>,
+    sources: &HashMap<PathBuf, String>,
+    created_files: Vec<PathBuf>,
+    deleted_files: Vec<PathBuf>,
+) -> Result<Plan, Vec<PathBuf>> {
+    let mut plan = Plan { created_files, rewrites: Vec::new(), deleted_files };
+    let mut conflicts = Vec::new();
+    for (file_path, mut rewrites) in file_rewrites {
+        let src = &sources[&file_path];
+        // This is the same stable sort as in `try_apply_rewrites`, so `--apply-plan` applies
+        // insertions at the same position in the same order.
+        rewrites.sort_by_key(|&((lo, hi, _), _)| (lo, hi));
+        if rewrites.windows(2).any(|w| w[1].0.0 < w[0].0.1) {
+            conflicts.push(file_path);
+            continue;
+        }
+        for ((lo, hi, new), reason) in rewrites {
+            plan.rewrites.push(PlannedRewrite {
+                file: file_path.clone(),
                 lo,
-                hi
-            );
-            new_src.push_str(&old_src[pos..lo]);
-            new_src.push_str(&new_snippet);
-            pos = hi;
-        }
-        new_src.push_str(&old_src[pos..]);
-
-        let tmp_path = file_path.with_extension(".new");
-        fs::write(&tmp_path, &new_src).unwrap();
-        fs::rename(&tmp_path, &file_path).unwrap();
-        eprintln!("applied {} rewrites to {:?}", rewrites.len(), file_path);
+                hi,
+                old: src[lo..hi].to_owned(),
+                new,
+                reason,
+            });
+        }
+    }
+    if conflicts.is_empty() { Ok(plan) } else { Err(conflicts) }
+}
+
+/// Count the functions that `find-unsafe` reports in `internal_unsafe_fns` for `src`, the
+/// contents of `file_path`, for `--unsafe-delta`.  Returns `None` if `src` doesn't parse.
+fn count_unsafe_fns(file_path: &Path, src: &str) -> Option<usize> {
+    match syn::parse_file(src) {
+        Ok(ast) => Some(find_unsafe::analyze_file(&ast, find_unsafe::Options::default())
+            .internal_unsafe_fns.len()),
+        Err(e) => {
+            eprintln!("warning: not counting unsafe functions in {file_path:?}: {e}");
+            None
+        },
+    }
+}
+
+/// Apply the `--apply-plan` file at `plan_path`.  Everything is checked against the current
+/// files before anything is written, so a plan for a tree that has changed since is rejected as
+/// a whole.
+fn apply_plan(plan_path: &Path) {
+    let plan_json = fs::read_to_string(plan_path).unwrap();
+    let plan: Plan = serde_json::from_str(&plan_json).unwrap_or_else(|e| {
+        eprintln!("error: bad plan file {plan_path:?}: {e}");
+        process::exit(2);
+    });
+
+    let mut num_stale = 0;
+    let mut sources = IndexMap::new();
+    for file_path in &plan.created_files {
+        if fs::exists(file_path).unwrap() {
+            eprintln!("error: new module file {file_path:?} already exists");
+            num_stale += 1;
+        }
+        sources.insert(file_path.clone(), String::new());
+    }
+    for file_path in &plan.deleted_files {
+        if !fs::exists(file_path).unwrap() {
+            eprintln!("error: file {file_path:?} to be deleted doesn't exist");
+            num_stale += 1;
+        }
+    }
+    let mut file_rewrites = IndexMap::<_, Vec<Rewrite>>::new();
+    for r in plan.rewrites {
+        let src = sources.entry(r.file.clone())
+            .or_insert_with(|| fs::read_to_string(&r.file).unwrap());
+        if src.get(r.lo..r.hi) != Some(&r.old) {
+            eprintln!("error: bytes {}..{} of {:?} no longer match the plan (for {:?})",
+                r.lo, r.hi, r.file, r.reason);
+            num_stale += 1;
+            continue;
+        }
+        file_rewrites.entry(r.file).or_default().push((r.lo, r.hi, r.new));
+    }
+    if num_stale > 0 {
+        eprintln!("error: the plan doesn't match {num_stale} files or ranges; nothing was written");
+        process::exit(1);
+    }
+
+    let mut new_sources = Vec::new();
+    for (file_path, src) in sources {
+        let mut rewrites = file_rewrites.shift_remove(&file_path).unwrap_or_default();
+        match try_apply_rewrites(&src, &mut rewrites) {
+            Ok(new_src) => new_sources.push((file_path, new_src, rewrites.len())),
+            Err(_) => {
+                eprintln!("error: the plan has overlapping rewrites in {file_path:?}; nothing \
+                    was written");
+                process::exit(1);
+            },
+        }
+    }
+    for (file_path, new_src, num_rewrites) in new_sources {
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        write_file_atomic(&file_path, &new_src).unwrap();
+        eprintln!("applied {} rewrites to {:?}", num_rewrites, file_path);
+    }
+    delete_module_files(&plan.deleted_files);
+}
+
+/// Delete the files of removed modules, along with any module directories left empty.
+fn delete_module_files(deleted_files: &[PathBuf]) {
+    let mut deleted_dirs = Vec::new();
+    for file_path in deleted_files {
+        fs::remove_file(file_path).unwrap();
+        eprintln!("deleted {:?}", file_path);
+        // The directory holding the module's submodules, if any.
+        let dir = if file_path.file_name().is_some_and(|n| n == "mod.rs") {
+            file_path.parent().unwrap().to_owned()
+        } else {
+            file_path.with_extension("")
+        };
+        deleted_dirs.push(dir);
+    }
+    // Remove the innermost directories first, so their parents can become empty.
+    deleted_dirs.sort_by_key(|dir| Reverse(dir.components().count()));
+    for dir in deleted_dirs {
+        if dir.is_dir() && fs::read_dir(&dir).unwrap().next().is_none() {
+            fs::remove_dir(&dir).unwrap();
+            eprintln!("deleted empty directory {:?}", dir);
+        }
+    }
+}
+
+/// Run a `git` command for `--git-stage` in `dir`, exiting with an error if it fails or `git`
+/// can't be run at all.  Returns the command's standard output.
+fn run_git(dir: &Path, args: &[&OsStr]) -> String {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap_or_else(|e| {
+            eprintln!("error: --git-stage requires git, but it couldn't be run: {e}");
+            process::exit(1);
+        });
+    if !output.status.success() {
+        eprintln!("error: `git {}` failed ({})",
+            args.iter().map(|a| a.to_string_lossy()).collect::<Vec<_>>().join(" "), output.status);
+        process::exit(1);
+    }
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Find the `--no-modify` pattern, if any, that protects `file_path`.  `crate_dirs` are the
+/// directories containing the crate roots, which patterns may also be relative to.
+fn protecting_pattern<'a>(
+    file_path: &Path,
+    crate_dirs: &[&Path],
+    patterns: &'a [Pattern],
+) -> Option<&'a Pattern> {
+    let relative_paths = crate_dirs.iter().filter_map(|dir| file_path.strip_prefix(dir).ok());
+    let paths = [file_path].into_iter().chain(relative_paths).collect::<Vec<_>>();
+    // As in the shell, `*` doesn't match `/`; use `**` to match across directories.
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+    patterns.iter().find(|pattern| {
+        paths.iter().any(|path| pattern.matches_path_with(path, options))
+    })
+}
+
+/// Read the `--crate-roots` file at `path`.  Relative root paths are resolved relative to the
+/// directory containing the file.
+fn read_crate_roots(path: &Path) -> IndexMap<String, PathBuf> {
+    let crate_roots: IndexMap<String, PathBuf> =
+        serde_json::from_reader(File::open(path).unwrap()).unwrap();
+    let base_dir = path.parent().unwrap();
+    crate_roots.into_iter()
+        .map(|(crate_name, root)| (crate_name, base_dir.join(root)))
+        .collect()
+}
+
+/// Answer to an `--interactive` prompt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Confirm {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+/// Ask whether to go ahead with `action`, reading answers from stdin until one is valid.  The end
+/// of input counts as `quit`.
+fn confirm(action: &str) -> Confirm {
+    loop {
+        eprint!("{action}? [y/n/all/quit] ");
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line).unwrap() == 0 {
+            eprintln!();
+            return Confirm::Quit;
+        }
+        match line.trim() {
+            "y" | "yes" => return Confirm::Yes,
+            "n" | "no" => return Confirm::No,
+            "a" | "all" => return Confirm::All,
+            "q" | "quit" => return Confirm::Quit,
+            _ => eprintln!("please answer y, n, all, or quit"),
+        }
+    }
+}
+
+/// Split the crate name off of `key` with `--crate-roots`, returning the crate name and the rest
+/// of the key.  Otherwise, the crate name is empty.
+fn split_crate_key(args: &Args, key: String) -> (String, String) {
+    if args.crate_roots.is_some() {
+        let (crate_name, rest) = key.split_once("::").unwrap_or_else(|| {
+            panic!("key {key:?} doesn't start with a crate name");
+        });
+        (crate_name.to_owned(), rest.to_owned())
+    } else {
+        (String::new(), key)
+    }
+}
+
+/// Add a snippet to the `Merge` for the crate its key belongs to.
+fn route_snippet(
+    args: &Args,
+    merges: &mut IndexMap<String, (KeyNormalizer<'_>, Merge<'_>)>,
+    key: String,
+    value: Option<String>,
+    opts: SnippetOptions,
+) {
+    let (crate_name, key) = split_crate_key(args, key);
+    let (key_normalizer, merge) = merges.get_mut(&crate_name).unwrap_or_else(|| {
+        panic!("unknown crate {crate_name:?} in key {key:?}");
+    });
+    if let Some(line_range) = parse_line_range_key(&key) {
+        merge.add_line_range(&key, line_range, value);
+        return;
+    }
+    let item_path = key.split_once(VARIANT_MARKER).map_or(&*key, |(enum_path, _)| enum_path);
+    let item_path = inner_attrs_mod(item_path).unwrap_or(item_path);
+    if !item_path.is_empty()
+        && let Err(e) = check_item_path(item_path)
+    {
+        panic!("bad snippet key {key:?}: {e}");
+    }
+    let value = (value, opts);
+    let key = key_normalizer.normalize(key, &value).unwrap_or_else(|e| panic!("{e}"));
+    let (value, opts) = value;
+    if let Some(key) = key {
+        merge.add_snippet(key, value, opts);
+    }
+}
+
+/// Keep the existing item at `key`, which belongs to a failed transaction, as it is, rather than
+/// removing it for lack of a snippet.
+fn keep_item(
+    args: &Args,
+    merges: &mut IndexMap<String, (KeyNormalizer<'_>, Merge<'_>)>,
+    key: String,
+) {
+    let (crate_name, key) = split_crate_key(args, key);
+    let (key_normalizer, merge) = merges.get_mut(&crate_name).unwrap_or_else(|| {
+        panic!("unknown crate {crate_name:?} in key {key:?}");
+    });
+    // A key that doesn't normalize refers to no existing item, so there's nothing to keep.
+    if parse_line_range_key(&key).is_none()
+        && let KeyNormalizer { key_base, key_prefix, .. } = *key_normalizer
+        && let Ok(key) = normalize_key(&key, key_base, key_prefix)
+    {
+        merge.kept_keys.insert(key);
+    }
+}
+
+/// Run `f`, turning a panic into an error carrying its message.  The panic itself isn't printed,
+/// since the caller reports the error.
+fn catch_failure<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    panic::set_hook(hook);
+    result.map_err(|payload| {
+        payload.downcast_ref::<String>().cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|&s| s.to_owned()))
+            .unwrap_or_else(|| "unknown error".to_owned())
+    })
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Some(ref plan_path) = args.apply_plan {
+        apply_plan(plan_path);
+        return;
+    }
+    // Root of each crate to merge into, keyed by crate name.  Without `--crate-roots`, there's a
+    // single crate, whose name is empty.
+    let (crate_roots, new_snippet_json_path) = match args.crate_roots {
+        Some(ref crate_roots_path) => {
+            // The crate root is omitted, so the JSON file is the first positional argument.
+            assert!(args.new_snippets_file.is_none(),
+                "only the JSON file should be given when using --crate-roots");
+            (read_crate_roots(crate_roots_path), args.src_root_path.as_deref())
+        },
+        None => {
+            let src_root_path = args.src_root_path.clone().unwrap();
+            (IndexMap::from([(String::new(), src_root_path)]), args.new_snippets_file.as_deref())
+        },
+    };
+    if args.parse_only {
+        let mut num_files = 0;
+        for src_root_path in crate_roots.values() {
+            let mut fc = FileCollector::default();
+            if let Err(e) = fc.parse(src_root_path, vec![], true) {
+                eprintln!("error: {e}");
+                process::exit(1);
+            }
+            num_files += fc.files.len();
+        }
+        eprintln!("parsed {num_files} files");
+        return;
+    }
+    let Some(new_snippet_json_path) = new_snippet_json_path else {
+        eprintln!("error: no JSON file given");
+        process::exit(2);
+    };
+    if args.watch {
+        let lock_base = args.crate_roots.as_deref().or(args.src_root_path.as_deref()).unwrap();
+        watch(lock_base, new_snippet_json_path);
+        return;
+    }
+
+    let regex_snippets = args.by_regex.chunks(2).map(|pair| {
+        let [ref pattern, ref template] = *pair else { unreachable!() };
+        let regex = Regex::new(&format!("^(?:{pattern})$")).unwrap_or_else(|e| {
+            eprintln!("error: bad --by-regex pattern {pattern:?}: {e}");
+            process::exit(2);
+        });
+        RegexSnippet { pattern: pattern.clone(), regex, template: template.clone() }
+    }).collect::<Vec<_>>();
+    let no_modify_patterns = args.no_modify.iter().map(|pattern| {
+        Pattern::new(pattern).unwrap_or_else(|e| {
+            eprintln!("error: bad --no-modify pattern {pattern:?}: {e}");
+            process::exit(2);
+        })
+    }).collect::<Vec<_>>();
+
+    let key_prefix = args.key_prefix.as_deref().map(|p| p.trim_end_matches("::"));
+    let key_base = args.key_base.as_deref().map(|p| p.trim_end_matches("::"))
+        .or(key_prefix)
+        .unwrap_or("");
+    let new_merges = || crate_roots.iter().map(|(crate_name, src_root_path)| {
+        let merge = Merge::new(&args, src_root_path, key_prefix);
+        (crate_name.clone(), (KeyNormalizer::new(key_base, key_prefix), merge))
+    }).collect::<IndexMap<_, _>>();
+    let mut merges = new_merges();
+
+    let new_snippets_file = File::open(new_snippet_json_path).unwrap();
+    let mut add_snippet = |key: String, value: Option<String>, opts: SnippetOptions| {
+        route_snippet(&args, &mut merges, key, value, opts);
+    };
+    // Keys of the `--rich` snippets skipped because their transaction failed.
+    let mut skipped_keys = Vec::new();
+    let mut transactions = IndexMap::new();
+    if args.jsonl {
+        for (i, line) in BufReader::new(new_snippets_file).lines().enumerate() {
+            let line = line.unwrap();
+            if line.trim().is_empty() {
+                continue;
+            }
+            let snippet: JsonlSnippet = serde_json::from_str(&line).unwrap_or_else(|e| {
+                panic!("{}:{}: {}", new_snippet_json_path.display(), i + 1, e);
+            });
+            let opts = SnippetOptions { order: snippet.order, ..SnippetOptions::default() };
+            add_snippet(snippet.path, snippet.content, opts);
+        }
+    } else if args.rich {
+        let new_snippets: IndexMap<String, RichSnippet> =
+            serde_json::from_reader(new_snippets_file).unwrap();
+        let base_dir = args.bodies_dir.as_deref()
+            .unwrap_or_else(|| new_snippet_json_path.parent().unwrap());
+        // Transactions are tried against the merge of all the snippets outside any transaction,
+        // so conflicts with those are caught too.
+        let mut transaction_snippets = IndexMap::<String, Vec<_>>::new();
+        let mut base_merges = new_merges();
+        for (key, value) in &new_snippets {
+            match value.transaction {
+                Some(ref id) => transaction_snippets.entry(id.clone()).or_default()
+                    .push((key.clone(), value.clone())),
+                None => {
+                    let (content, opts) = value.clone().into_parts(key, base_dir);
+                    route_snippet(&args, &mut base_merges, key.clone(), content, opts);
+                },
+            }
+        }
+        // Each transaction is tried on a copy of that merge, which also includes the transactions
+        // accepted so far, so a failure in one doesn't affect the others, and transactions that
+        // conflict with each other are caught as well.  Nothing is written during the trials.
+        for (id, snippets) in transaction_snippets {
+            let num_snippets = snippets.len();
+            let result = catch_failure(|| {
+                let mut merges = base_merges.clone();
+                for (key, value) in snippets {
+                    let (content, opts) = value.into_parts(&key, base_dir);
+                    route_snippet(&args, &mut merges, key, content, opts);
+                }
+                for (_, (_, mut merge)) in merges.clone() {
+                    merge.finish();
+                    for (file_path, rewrites) in merge.file_rewrites {
+                        let mut rewrites = rewrites.into_iter().map(|(r, _)| r)
+                            .collect::<Vec<_>>();
+                        if try_apply_rewrites(&merge.sources[&file_path], &mut rewrites).is_err() {
+                            panic!("overlapping rewrites in {file_path:?}");
+                        }
+                    }
+                }
+                merges
+            });
+            let error = match result {
+                Ok(merges) => {
+                    eprintln!("transaction {id:?}: applying {num_snippets} snippets");
+                    base_merges = merges;
+                    None
+                },
+                Err(e) => {
+                    eprintln!("warning: transaction {id:?} failed, so none of its \
+                        {num_snippets} snippets were applied: {e}");
+                    Some(e)
+                },
+            };
+            transactions.insert(id, error);
+        }
+        for (key, value) in new_snippets {
+            if let Some(ref id) = value.transaction
+                && transactions[id].is_some()
+            {
+                skipped_keys.push(key);
+                continue;
+            }
+            let (content, opts) = value.into_parts(&key, base_dir);
+            add_snippet(key, content, opts);
+        }
+    } else {
+        let new_snippets: IndexMap<String, Option<SnippetValue>> =
+            serde_json::from_reader(new_snippets_file).unwrap();
+        for (key, value) in new_snippets {
+            let (content, opts) = value.map(SnippetValue::into_parts).unzip();
+            add_snippet(key, content, opts.unwrap_or_default());
+        }
+    }
+    for key in skipped_keys {
+        keep_item(&args, &mut merges, key);
+    }
+
+    // Finish every crate before applying anything, so an error in one crate leaves the whole
+    // workspace untouched.
+    let mut file_rewrites = IndexMap::new();
+    let mut deleted_files = IndexSet::new();
+    let mut stats = Stats::default();
+    let mut report = Report { transactions, ..Report::default() };
+    let mut sources = HashMap::new();
+    let mut num_items = 0;
+    let mut uncovered = Vec::new();
+    let mut undo_merges = Vec::new();
+    for (crate_name, (_, mut merge)) in merges {
+        merge.add_regex_snippets(&crate_name, &regex_snippets);
+        if args.coverage {
+            let (n, items) = merge.uncovered_items();
+            num_items += n;
+            uncovered.extend(items.iter().map(|item_path| join_path(&crate_name, item_path)));
+            continue;
+        }
+        merge.finish();
+        if args.emit_undo.is_some() {
+            // The undo snippets depend on which files get written, so they're built at the end.
+            undo_merges.push((crate_name.clone(), merge.clone()));
+        }
+        // Report items that were spliced in as part of another item's snippet, so tools that
+        // track items by key know they exist.
+        for item_path in &merge.extra_items {
+            let item_path = join_path(&crate_name, strip_key_prefix(item_path, key_prefix));
+            println!("extra item: {item_path}");
+            report.extra_items.push(item_path);
+        }
+        for (_, mut created) in merge.created_mods {
+            created.module = join_path(&crate_name, &created.module);
+            eprintln!("created module {:?} in {:?}, declared in {:?}",
+                created.module, created.file, created.declared_in);
+            report.created_modules.push(created);
+        }
+        file_rewrites.extend(merge.file_rewrites);
+        sources.extend(merge.sources);
+        deleted_files.extend(merge.deleted_files);
+        stats.items_updated += merge.stats.items_updated;
+        stats.items_added += merge.stats.items_added;
+        stats.items_removed += merge.stats.items_removed;
+    }
+
+    if args.coverage {
+        for item_path in &uncovered {
+            println!("no snippet: {item_path}");
+        }
+        let num_covered = num_items - uncovered.len();
+        let percent = if num_items == 0 {
+            100.0
+        } else {
+            100.0 * num_covered as f64 / num_items as f64
+        };
+        println!("coverage: {num_covered}/{num_items} items ({percent:.1}%)");
+        return;
+    }
+
+    if args.fail_on_new_file && !report.created_modules.is_empty() {
+        for created in &report.created_modules {
+            eprintln!("error: snippets would create module {:?} in new file {:?}",
+                created.module, created.file);
+        }
+        eprintln!("error: {} new module files would be created; nothing was written",
+            report.created_modules.len());
+        process::exit(1);
+    }
+
+    // Refuse to touch protected files.  This is checked before anything is written, so a blocked
+    // change leaves the tree as it was.
+    let crate_dirs = crate_roots.values().map(|p| p.parent().unwrap()).collect::<Vec<_>>();
+    let mut num_blocked = 0;
+    for file_path in file_rewrites.iter()
+        .filter(|(_, rewrites)| !rewrites.is_empty())
+        .map(|(file_path, _)| file_path)
+        .chain(&deleted_files)
+    {
+        if let Some(pattern) = protecting_pattern(file_path, &crate_dirs, &no_modify_patterns) {
+            eprintln!("error: snippets would modify {file_path:?}, which is protected by \
+                --no-modify {:?}", pattern.as_str());
+            num_blocked += 1;
+        }
+    }
+    if num_blocked > 0 {
+        eprintln!("error: {num_blocked} protected files would be modified; nothing was written");
+        process::exit(1);
+    }
+
+    // Write the `--report` and `--emit-undo` files, leaving out the changes to `skipped` files,
+    // which were left as they were.
+    let write_report = |report: &mut Report, skipped: &HashSet<PathBuf>| {
+        if let Some(ref report_path) = args.report {
+            report.created_modules.retain(|created| !skipped.contains(&created.file));
+            fs::write(report_path, serde_json::to_string_pretty(&report).unwrap()).unwrap();
+        }
+        if let Some(ref undo_path) = args.emit_undo {
+            let mut undo = IndexMap::new();
+            for (crate_name, merge) in &undo_merges {
+                for (key, value) in merge.undo_snippets(skipped) {
+                    undo.insert(join_path(crate_name, &key), value);
+                }
+            }
+            fs::write(undo_path, serde_json::to_string_pretty(&undo).unwrap()).unwrap();
+        }
+    };
+
+    if let Some(ref plan_path) = args.plan {
+        write_report(&mut report, &HashSet::new());
+        let created_files = report.created_modules.iter().map(|c| c.file.clone()).collect();
+        let plan = make_plan(file_rewrites, &sources, created_files,
+            deleted_files.into_iter().collect()).unwrap_or_else(|conflicts| {
+                for file_path in &conflicts {
+                    eprintln!("error: overlapping rewrites in {file_path:?}");
+                }
+                eprintln!("error: {} files have overlapping rewrites; no plan was written",
+                    conflicts.len());
+                process::exit(1);
+            });
+        fs::write(plan_path, serde_json::to_string_pretty(&plan).unwrap()).unwrap();
+        eprintln!("wrote plan with {} rewrites to {:?}", plan.rewrites.len(), plan_path);
+        return;
+    }
+
+    // For `--git-stage`, check that the crate is in a git work tree before changing anything.
+    let git_dir = crate_roots.values().next().unwrap().parent().unwrap();
+    let git_dir = if git_dir.as_os_str().is_empty() { Path::new(".") } else { git_dir };
+    if args.git_stage {
+        run_git(git_dir, &["rev-parse".as_ref(), "--show-toplevel".as_ref()]);
+    }
+
+    if args.stats {
+        write_report(&mut report, &HashSet::new());
+        if args.git_stage {
+            for file_path in file_rewrites.keys().chain(&deleted_files) {
+                println!("would stage {}", file_path.display());
+            }
+        }
+        for rewrites in file_rewrites.values() {
+            if rewrites.is_empty() {
+                continue;
+            }
+            stats.files_touched += 1;
+            for &((lo, hi, ref new_snippet), _) in rewrites {
+                stats.bytes_added += new_snippet.len();
+                stats.bytes_removed += hi - lo;
+            }
+        }
+        stats.files_deleted = deleted_files.len();
+        let Stats {
+            items_updated, items_added, items_removed, files_touched, files_deleted,
+            bytes_added, bytes_removed,
+        } = stats;
+        println!("items updated: {items_updated}");
+        println!("items added: {items_added}");
+        println!("items removed: {items_removed}");
+        println!("files touched: {files_touched}");
+        println!("files deleted: {files_deleted}");
+        println!("bytes added: {bytes_added}");
+        println!("bytes removed: {bytes_removed}");
+        return;
+    }
+
+    // New module files must not exist yet.  This is checked before anything is written, like the
+    // protected files above.
+    let existing = report.created_modules.iter().filter(|c| fs::exists(&c.file).unwrap())
+        .collect::<Vec<_>>();
+    if !existing.is_empty() {
+        for created in &existing {
+            eprintln!("error: file {:?} for new module {:?} already exists on disk",
+                created.file, created.module);
+        }
+        eprintln!("error: {} new module files already exist; nothing was written",
+            existing.len());
+        process::exit(1);
+    }
+
+    let created_files = report.created_modules.iter().map(|c| c.file.clone())
+        .collect::<HashSet<_>>();
+
+    // Files are written in groups.  With `--interactive`, a new module's file is in the same
+    // group as the file declaring it, so each new module is confirmed along with its `mod`
+    // declaration, and neither is written without the other.
+    let mut groups = file_rewrites.into_iter()
+        .filter(|(_, rewrites)| !rewrites.is_empty())
+        .map(|entry| vec![entry])
+        .collect::<Vec<_>>();
+    if args.interactive {
+        for created in &report.created_modules {
+            let group_of = |file_path: &Path| {
+                groups.iter().position(|group| group.iter().any(|(p, _)| p == file_path))
+            };
+            if let (Some(i), Some(j)) = (group_of(&created.declared_in), group_of(&created.file))
+                && i != j
+            {
+                let group = groups.remove(i.max(j));
+                groups[i.min(j)].extend(group);
+            }
+        }
+    }
+
+    // Apply the collected rewrites to each file.  If some rewrites to a file overlap, the file is
+    // left unchanged, and the candidate contents are written to a `.conflict` file next to it.
+    let mut modified_files = Vec::new();
+    let mut conflict_files = Vec::new();
+    // Files left unchanged because they were declined with `--interactive` or had conflicts.
+    let mut skipped_files = HashSet::new();
+    let mut unsafe_counts = (0, 0);
+    let mut ask = args.interactive;
+    let mut quit = false;
+    for group in groups {
+        let mut new_srcs = Vec::new();
+        for (file_path, rewrites) in group {
+            let (mut rewrites, reasons): (Vec<_>, Vec<_>) = rewrites.into_iter().unzip();
+            match try_apply_rewrites(&sources[&file_path], &mut rewrites) {
+                Ok(new_src) => new_srcs.push((file_path, reasons, new_src)),
+                Err(conflict_src) => {
+                    skipped_files.insert(file_path.clone());
+                    let mut conflict_path = file_path.into_os_string();
+                    conflict_path.push(".conflict");
+                    let conflict_path = PathBuf::from(conflict_path);
+                    fs::write(&conflict_path, conflict_src).unwrap();
+                    eprintln!("overlapping rewrites; wrote conflicts to {:?}", conflict_path);
+                    conflict_files.push(conflict_path);
+                },
+            }
+        }
+        if ask && !quit && !new_srcs.is_empty() {
+            for (file_path, reasons, _) in &new_srcs {
+                eprintln!("{} rewrites to {:?}:", reasons.len(), file_path);
+                for reason in reasons.iter().collect::<IndexSet<_>>() {
+                    eprintln!("  {reason}");
+                }
+            }
+            match confirm("apply them") {
+                Confirm::Yes => {},
+                Confirm::No => {
+                    for (file_path, _, _) in new_srcs {
+                        eprintln!("skipped {:?}", file_path);
+                        skipped_files.insert(file_path);
+                    }
+                    continue;
+                },
+                Confirm::All => ask = false,
+                Confirm::Quit => quit = true,
+            }
+        }
+        if quit {
+            skipped_files.extend(new_srcs.into_iter().map(|(file_path, _, _)| file_path));
+            continue;
+        }
+        for (file_path, reasons, new_src) in new_srcs {
+            if args.unsafe_delta
+                && let Some(old) = count_unsafe_fns(&file_path, &sources[&file_path])
+                && let Some(new) = count_unsafe_fns(&file_path, &new_src)
+            {
+                unsafe_counts.0 += old;
+                unsafe_counts.1 += new;
+            }
+            if created_files.contains(&file_path)
+                && let Some(parent) = file_path.parent()
+            {
+                fs::create_dir_all(parent).unwrap();
+            }
+            write_file_atomic(&file_path, &new_src).unwrap();
+            eprintln!("applied {} rewrites to {:?}", reasons.len(), file_path);
+            modified_files.push(file_path);
+        }
+    }
+
+    if !conflict_files.is_empty() {
+        write_report(&mut report, &skipped_files);
+        eprintln!("some files were not updated due to conflicts:");
+        for conflict_path in &conflict_files {
+            eprintln!("  {}", conflict_path.display());
+        }
+        process::exit(1);
+    }
+
+    let mut deleted_files = deleted_files.into_iter().collect::<Vec<_>>();
+    if quit {
+        deleted_files.clear();
+    } else if ask {
+        let mut remaining = deleted_files.into_iter();
+        let mut confirmed = Vec::new();
+        while let Some(file_path) = remaining.next() {
+            match confirm(&format!("delete {file_path:?}")) {
+                Confirm::Yes => confirmed.push(file_path),
+                Confirm::No => eprintln!("kept {:?}", file_path),
+                Confirm::All => {
+                    confirmed.push(file_path);
+                    confirmed.extend(remaining.by_ref());
+                },
+                Confirm::Quit => {
+                    quit = true;
+                    break;
+                },
+            }
+        }
+        deleted_files = confirmed;
+    }
+    if args.unsafe_delta {
+        for file_path in &deleted_files {
+            let src = fs::read_to_string(file_path).unwrap();
+            unsafe_counts.0 += count_unsafe_fns(file_path, &src).unwrap_or(0);
+        }
+        let (old, new) = unsafe_counts;
+        eprintln!("internal unsafe functions: {old} -> {new} ({:+})", new as i64 - old as i64);
+    }
+    delete_module_files(&deleted_files);
+    write_report(&mut report, &skipped_files);
+    if quit {
+        // The files written so far still get the checks and hooks below.
+        eprintln!("quitting; modified {} files and deleted {}", modified_files.len(),
+            deleted_files.len());
+    }
+
+    if args.verify_after {
+        let mut num_broken = 0;
+        for file_path in &modified_files {
+            let src = fs::read_to_string(file_path).unwrap();
+            if let Err(e) = syn::parse_file(&src) {
+                let start = e.span().start();
+                eprintln!("error: {}:{}:{}: modified file doesn't parse: {e}",
+                    file_path.display(), start.line, start.column + 1);
+                num_broken += 1;
+            }
+        }
+        if num_broken > 0 {
+            eprintln!("error: {num_broken} modified files don't parse");
+            process::exit(1);
+        }
+    }
+    modified_files.extend(deleted_files);
+
+    if let Some(ref post_hook) = args.post_hook {
+        // `"$@"` expands to the file arguments, which follow `$0`.
+        let written = modified_files.iter().filter(|p| p.exists()).collect::<Vec<_>>();
+        eprintln!("running post-hook {post_hook:?} on {} files", written.len());
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{post_hook} \"$@\""))
+            .arg("merge_rust")
+            .args(&written)
+            .status()
+            .unwrap_or_else(|e| {
+                eprintln!("error: failed to run post-hook {post_hook:?}: {e}");
+                process::exit(1);
+            });
+        if !status.success() {
+            eprintln!("post-hook {post_hook:?} failed ({status})");
+            process::exit(status.code().unwrap_or(1));
+        }
+    }
+
+    if args.git_stage && !modified_files.is_empty() {
+        // `git` runs in the crate's directory, so paths relative to ours need to be made absolute.
+        let (deleted, written): (Vec<_>, Vec<_>) = modified_files.iter()
+            .map(|file_path| path::absolute(file_path).unwrap())
+            .partition(|file_path| !file_path.exists());
+        if !written.is_empty() {
+            let mut git_args = vec![OsStr::new("add"), OsStr::new("--")];
+            git_args.extend(written.iter().map(|p| p.as_os_str()));
+            run_git(git_dir, &git_args);
+        }
+        if !deleted.is_empty() {
+            // `--ignore-unmatch` allows for deleted files that were never tracked.
+            let mut git_args = ["rm", "--cached", "--quiet", "--ignore-unmatch", "--"]
+                .map(OsStr::new).to_vec();
+            git_args.extend(deleted.iter().map(|p| p.as_os_str()));
+            run_git(git_dir, &git_args);
+        }
+        eprintln!("staged {} files in git", modified_files.len());
+    }
+
+    if args.check_build {
+        let mut crate_dirs = IndexSet::new();
+        for src_root_path in crate_roots.values() {
+            let src_root_dir = src_root_path.parent().unwrap();
+            let crate_dir = src_root_dir.ancestors()
+                .find(|dir| dir.join("Cargo.toml").is_file())
+                .unwrap_or_else(|| panic!("no Cargo.toml found above {:?}", src_root_dir));
+            // `parent()` of a bare file name like `lib.rs` is the empty path.
+            let crate_dir = if crate_dir.as_os_str().is_empty() { Path::new(".") } else { crate_dir };
+            crate_dirs.insert(crate_dir);
+        }
+        for crate_dir in crate_dirs {
+            eprintln!("running cargo check in {crate_dir:?}");
+            let status = Command::new("cargo")
+                .arg("check")
+                .current_dir(crate_dir)
+                .status()
+                .unwrap();
+            if !status.success() {
+                eprintln!("cargo check failed ({status}); files modified by this run:");
+                for file_path in &modified_files {
+                    eprintln!("  {}", file_path.display());
+                }
+                process::exit(status.code().unwrap_or(1));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_key() {
+        // Every form refers to the same item, `a::b::f`.
+        for key in ["crate::a::b::f", "a::b::f", "self::f", "super::b::f", "self::super::b::f"] {
+            assert_eq!(normalize_key(key, "a::b", None).as_deref(), Ok("a::b::f"),
+                "key = {key:?}");
+        }
+        for key in ["crate::a::b::f", "f", "self::f", "super::b::f"] {
+            assert_eq!(normalize_key(key, "a::b", Some("a::b")).as_deref(), Ok("a::b::f"),
+                "key = {key:?}");
+        }
+        assert_eq!(normalize_key("super::f", "a", None).as_deref(), Ok("f"));
+        assert_eq!(normalize_key("r#type::f", "", Some("m")).as_deref(), Ok("m::type::f"));
+    }
+
+    #[test]
+    fn test_normalize_keys() {
+        let snippet = |s: &str| (Some(s.to_owned()), SnippetOptions::default());
+        let mut kn = KeyNormalizer::new("", None);
+        assert_eq!(kn.normalize("crate::a::f".to_owned(), &snippet("fn f() {}")),
+            Ok(Some("a::f".to_owned())));
+        assert_eq!(kn.normalize("a::f".to_owned(), &snippet("fn f() {}")), Ok(None));
+        assert_eq!(kn.normalize("a::g".to_owned(), &snippet("fn g() {}")),
+            Ok(Some("a::g".to_owned())));
+
+        let mut kn = KeyNormalizer::new("a", None);
+        kn.normalize("crate::a::f".to_owned(), &snippet("fn f() {}")).unwrap();
+        let err = kn.normalize("self::f".to_owned(), &snippet("fn f() { 1; }")).unwrap_err();
+        assert!(err.contains("\"crate::a::f\" and \"self::f\""), "{err}");
+        let with_order = (Some("fn f() {}".to_owned()), SnippetOptions {
+            order: Some(1),
+            ..SnippetOptions::default()
+        });
+        assert!(kn.normalize("self::f".to_owned(), &with_order).is_err());
+    }
+
+    #[test]
+    fn test_reindent() {
+        let snippet = "fn f() {\n        if x {\n            y\n\n        }\n    }";
+        assert_eq!(reindent(snippet, "  "), "fn f() {\n      if x {\n          y\n\n      }\n  }");
+        assert_eq!(reindent("fn f() {}", "    "), "fn f() {}");
+    }
+
+    #[test]
+    fn test_detect_indent_unit() {
+        assert_eq!(detect_indent_unit("fn f() {\n\tx\n}"), Some(IndentUnit::Tab));
+        assert_eq!(detect_indent_unit("fn f() {\n  x\n    /*\n     * y\n     */\n}"),
+            Some(IndentUnit::Spaces(2)));
+        assert_eq!(detect_indent_unit("fn f() {}\n"), None);
+        // Continuation lines and the contents of multi-line strings don't count.
+        let src = "fn f() {\n    g(a,\n      b);\n    let s = \"x\n  y\n  z\";\n}";
+        assert_eq!(detect_indent_unit(src), Some(IndentUnit::Spaces(4)));
+    }
+
+    #[test]
+    fn test_convert_indent() {
+        let snippet = "fn f() {\n    if x {\n        y(a,\n          b)\n    }\n}";
+        assert_eq!(convert_indent(snippet, IndentUnit::Tab),
+            "fn f() {\n\tif x {\n\t\ty(a,\n\t\t  b)\n\t}\n}");
+        assert_eq!(convert_indent(snippet, IndentUnit::Spaces(4)), snippet);
+        assert_eq!(convert_indent("fn f() {\n\tx\n}", IndentUnit::Spaces(2)), "fn f() {\n  x\n}");
+        let snippet = "fn f() {\n    let s = \"a\n    b\";\n}";
+        assert_eq!(convert_indent(snippet, IndentUnit::Tab),
+            "fn f() {\n\tlet s = \"a\n    b\";\n}");
+    }
+
+    #[test]
+    fn test_protecting_pattern() {
+        let patterns = [Pattern::new("ffi/*.rs").unwrap(), Pattern::new("**/keep.rs").unwrap()];
+        let crate_dirs = [Path::new("crate/src")];
+        let check = |path: &str| {
+            protecting_pattern(Path::new(path), &crate_dirs, &patterns).map(|p| p.as_str())
+        };
+        assert_eq!(check("crate/src/ffi/a.rs"), Some("ffi/*.rs"));
+        assert_eq!(check("crate/src/ffi/a/b.rs"), None);
+        assert_eq!(check("crate/src/m/keep.rs"), Some("**/keep.rs"));
+        assert_eq!(check("other/ffi/a.rs"), None);
+        assert_eq!(check("crate/src/lib.rs"), None);
+    }
+
+    #[test]
+    fn test_line_range_key() {
+        assert_eq!(parse_line_range_key("src/a.rs:3-5"), Some((PathBuf::from("src/a.rs"), 3, 5)));
+        assert_eq!(parse_line_range_key("a::b"), None);
+        assert_eq!(parse_line_range_key("a.rs:3"), None);
+
+        let src = "a\nbc\r\nd\n";
+        assert_eq!(line_range_bytes(src, 1, 1), Some((0, 1)));
+        assert_eq!(line_range_bytes(src, 2, 3), Some((2, 7)));
+        assert_eq!(line_range_bytes(src, 2, 2), Some((2, 4)));
+        assert_eq!(line_range_bytes(src, 3, 3), Some((6, 7)));
+        assert_eq!(line_range_bytes(src, 4, 4), None);
+        assert_eq!(line_range_bytes(src, 3, 5), None);
+        assert_eq!(line_range_bytes("a\nb", 2, 2), Some((2, 3)));
+        assert_eq!(line_range_bytes(src, 2, 1), None);
+    }
+
+    #[test]
+    fn test_replace_outer_attrs() {
+        let old = "/// Docs.\n#[inline]\nfn f() -> i32 { 1 }";
+        assert_eq!(replace_outer_attrs("fn f() -> i32 { 2 }", old),
+            "/// Docs.\n#[inline]\nfn f() -> i32 { 2 }");
+        assert_eq!(replace_outer_attrs("#[cold] fn f() -> i32 { 2 }", old),
+            "/// Docs.\n#[inline]\nfn f() -> i32 { 2 }");
+        assert_eq!(replace_outer_attrs("/// New.\nfn f() {}", "fn f() { #![allow(x)] }"),
+            "fn f() {}");
+    }
+
+    #[test]
+    fn test_snippet_visibility() {
+        assert_eq!(snippet_visibility("fn f() {}").as_deref(), Some("private"));
+        assert_eq!(snippet_visibility("pub fn f() {}").as_deref(), Some("pub"));
+        assert_eq!(snippet_visibility("/// Docs.\npub(crate) struct S;").as_deref(),
+            Some("pub(crate)"));
+        assert_eq!(snippet_visibility("pub(in crate::a) mod m;").as_deref(),
+            Some("pub(in crate::a)"));
+        assert_eq!(snippet_visibility("impl S {}"), None);
+    }
+
+    #[test]
+    fn test_replace_signature() {
+        let old = "fn f(x: i32) -> i32 {\n    // Comment.\n    x  +  1\n}";
+        assert_eq!(replace_signature("pub fn f(x: i32) -> i32 { 0 }", old).unwrap(),
+            format!("pub {old}"));
+        assert_eq!(replace_signature("pub fn f(x: i32) -> i32", old).unwrap(),
+            format!("pub {old}"));
+        assert_eq!(replace_signature("fn f<T>(x: T)\nwhere\n    T: Copy,", "fn f() {}").unwrap(),
+            "fn f<T>(x: T)\nwhere\n    T: Copy, {}");
+        assert_eq!(replace_signature("struct S;", old), None);
+        assert_eq!(replace_signature("fn f() {}", "mod m {}"), None);
+    }
+
+    #[test]
+    fn test_snippet_kind() {
+        assert_eq!(snippet_kind("/// Docs\npub struct Foo;"), Some(ItemKind::Struct));
+        assert_eq!(snippet_kind("fn foo() {}\nimpl S {}"), Some(ItemKind::Fn));
+        assert_eq!(snippet_kind("fn foo( {"), None);
+        assert_eq!(snippet_kind(""), None);
+    }
+
+    #[test]
+    fn test_normalize_key_above_root() {
+        assert!(normalize_key("super::super::f", "a", None).is_err());
+        assert!(normalize_key("super::f", "", Some("m")).is_err());
     }
 }
@@ -1,9 +1,13 @@
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::ffi::OsStr;
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Output, Stdio};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
 
 
 fn read_tree(path: impl AsRef<Path>) -> io::Result<HashMap<PathBuf, String>> {
@@ -20,7 +24,7 @@ fn read_tree(path: impl AsRef<Path>) -> io::Result<HashMap<PathBuf, String>> {
                 if name_str.starts_with(".") {
                     continue;
                 }
-                let s = fs::read_to_string(&path.join(&child_sub_path))?;
+                let s = fs::read_to_string(path.join(&child_sub_path))?;
                 out.insert(child_sub_path, s);
             }
         }
@@ -34,7 +38,7 @@ fn read_tree(path: impl AsRef<Path>) -> io::Result<HashMap<PathBuf, String>> {
 fn write_tree(path: impl AsRef<Path>, m: &HashMap<PathBuf, String>) -> io::Result<()> {
     let path = path.as_ref();
     for (sub_path, s) in m {
-        let full_path = path.join(&sub_path);
+        let full_path = path.join(sub_path);
         let parent = full_path.parent().unwrap();
         fs::create_dir_all(parent)?;
         fs::write(full_path, s)?;
@@ -63,9 +67,26 @@ fn test(file_name: &str) -> io::Result<()> {
     write_tree(&output_dir, &input)?;
     drop(input);
 
-    let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
-        .arg(output_dir.join("lib.rs"))
+    // Extra command-line arguments can be given in `args.txt`, one per line.
+    let args_path = test_dir.join("args.txt");
+    let extra_args = if fs::exists(&args_path)? {
+        fs::read_to_string(&args_path)?.lines().map(|s| s.to_owned()).collect()
+    } else {
+        Vec::new()
+    };
+
+    // Tests covering several crates provide a `crate_roots.json` alongside the crate directories
+    // in `input/`, instead of a root `lib.rs`.
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_merge_rust"));
+    let crate_roots_path = output_dir.join("crate_roots.json");
+    if fs::exists(&crate_roots_path)? {
+        cmd.arg("--crate-roots").arg(crate_roots_path);
+    } else {
+        cmd.arg(output_dir.join("lib.rs"));
+    }
+    let status = cmd
         .arg(test_dir.join("snippets.json"))
+        .args(&extra_args)
         .status()?;
     assert!(status.success(), "subcommand failed");
 
@@ -108,6 +129,644 @@ fn test(file_name: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Return the path of the `split_rust` binary.  Cargo only provides `CARGO_BIN_EXE_*` paths for
+/// the binaries of the package under test, so it's built here, next to `merge_rust`.
+fn split_rust_exe() -> &'static Path {
+    static EXE: OnceLock<PathBuf> = OnceLock::new();
+    EXE.get_or_init(|| {
+        let status = Command::new(env!("CARGO"))
+            .args(["build", "--quiet", "--package", "split_rust"])
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status()
+            .unwrap();
+        assert!(status.success(), "failed to build split_rust");
+        let merge_rust = Path::new(env!("CARGO_BIN_EXE_merge_rust"));
+        merge_rust.with_file_name(format!("split_rust{}", env::consts::EXE_SUFFIX))
+    })
+}
+
+/// Split the crate rooted at `src_root_path` into a map from item paths to source text, by
+/// running `split_rust`.
+fn split(src_root_path: &Path) -> io::Result<HashMap<String, String>> {
+    let output = Command::new(split_rust_exe())
+        .arg(src_root_path)
+        .stderr(Stdio::null())
+        .output()?;
+    assert!(output.status.success(), "split_rust failed on {src_root_path:?}");
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Check that splitting the input of test `file_name` with `split_rust` and merging the snippets
+/// back in leaves every file byte-identical, and that splitting the result gives the same
+/// snippets.  This catches span bugs in `item_spans` and in the merge.
+fn test_round_trip(file_name: &str) -> io::Result<()> {
+    let input = read_tree(golden_dir().join(file_name).join("input"))?;
+    let dir = tempfile::tempdir()?;
+    let crate_dir = dir.path().join("crate");
+    write_tree(&crate_dir, &input)?;
+
+    let crate_roots_path = crate_dir.join("crate_roots.json");
+    let src_root_paths = if fs::exists(&crate_roots_path)? {
+        let crate_roots: HashMap<String, PathBuf> =
+            serde_json::from_str(&fs::read_to_string(&crate_roots_path)?)?;
+        crate_roots.into_values().map(|p| crate_dir.join(p)).collect()
+    } else {
+        vec![crate_dir.join("lib.rs")]
+    };
+    for (i, src_root_path) in src_root_paths.iter().enumerate() {
+        let snippets = split(src_root_path)?;
+        assert!(!snippets.is_empty(), "split_rust found no items in {src_root_path:?}");
+        let snippets_path = dir.path().join(format!("snippets{i}.json"));
+        fs::write(&snippets_path, serde_json::to_string(&snippets)?)?;
+        let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+            .arg(src_root_path)
+            .arg(&snippets_path)
+            .status()?;
+        assert!(status.success(), "subcommand failed");
+        assert_eq!(split(src_root_path)?, snippets, "round trip changed the items");
+    }
+
+    let output = read_tree(&crate_dir)?;
+    let mut keys = input.keys().chain(output.keys()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        assert_eq!(output.get(key), input.get(key), "round trip changed {key:?}");
+    }
+    Ok(())
+}
+
+/// Check that merging the snippets of test `file_name` with `--emit-undo`, then merging the undo
+/// file back in, restores every item of the input.  Removed items come back at the end of their
+/// module, so the items are compared rather than the files.  Inline modules are compared through
+/// the items inside them, since their own text also depends on where those items are.
+fn test_undo(file_name: &str) -> io::Result<()> {
+    let test_dir = golden_dir().join(file_name);
+    let dir = tempfile::tempdir()?;
+    let undone_dir = dir.path();
+    write_tree(undone_dir, &read_tree(test_dir.join("input"))?)?;
+
+    let src_root_path = undone_dir.join("lib.rs");
+    let before = split(&src_root_path)?;
+    let undo_path = undone_dir.join(".undo.json");
+    let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(&src_root_path)
+        .arg(test_dir.join("snippets.json"))
+        .arg("--emit-undo")
+        .arg(&undo_path)
+        .status()?;
+    assert!(status.success(), "subcommand failed");
+    let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(&src_root_path)
+        .arg(&undo_path)
+        .status()?;
+    assert!(status.success(), "undo subcommand failed");
+
+    let after = split(&src_root_path)?;
+    let is_container = |k: &str| {
+        before.keys().chain(after.keys()).any(|other| other.starts_with(&format!("{k}::")))
+    };
+    let mut changed = before.keys().chain(after.keys())
+        .filter(|k| before.get(*k) != after.get(*k) && !is_container(k))
+        .collect::<Vec<_>>();
+    changed.sort();
+    changed.dedup();
+    assert!(changed.is_empty(), "undo changed {:?}", changed);
+    Ok(())
+}
+
+/// Check that `--fail-on-new-file` rejects the snippets of the `add_module` test, which add a
+/// module, without changing any files.
+#[test]
+fn fail_on_new_file() -> io::Result<()> {
+    let test_dir = golden_dir().join("add_module");
+    let dir = tempfile::tempdir()?;
+    let output_dir = dir.path();
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(output_dir, &input)?;
+
+    let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(output_dir.join("lib.rs"))
+        .arg(test_dir.join("snippets.json"))
+        .arg("--fail-on-new-file")
+        .status()?;
+    assert!(!status.success(), "subcommand should have failed");
+    assert_eq!(read_tree(output_dir)?, input);
+    Ok(())
+}
+
+/// Check that `--no-modify` blocks the new module file of the `add_module` test before it's
+/// created, leaving the input as it was.
+#[test]
+fn no_modify_new_module() -> io::Result<()> {
+    let test_dir = golden_dir().join("add_module");
+    let dir = tempfile::tempdir()?;
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(dir.path(), &input)?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(dir.path().join("lib.rs"))
+        .arg(test_dir.join("snippets.json"))
+        .arg("--no-modify")
+        .arg("submod.rs")
+        .output()?;
+    assert!(!output.status.success(), "subcommand should have failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("which is protected by --no-modify \"submod.rs\""),
+        "unexpected output: {stderr}");
+    assert_eq!(read_tree(dir.path())?, input);
+    Ok(())
+}
+
+/// Check the items `--coverage` lists for the `coverage` test, and its summary line.
+#[test]
+fn coverage_output() -> io::Result<()> {
+    let test_dir = golden_dir().join("coverage");
+    let dir = tempfile::tempdir()?;
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(dir.path(), &input)?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(dir.path().join("lib.rs"))
+        .arg(test_dir.join("snippets.json"))
+        .arg("--coverage")
+        .output()?;
+    assert!(output.status.success(), "subcommand failed");
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+        "no snippet: m\nno snippet: m::b\nno snippet: d\ncoverage: 2/5 items (40.0%)\n");
+    assert_eq!(read_tree(dir.path())?, input);
+    Ok(())
+}
+
+/// Run `git` with `args` in `dir`, returning its standard output.
+fn git(dir: &Path, args: &[&str]) -> io::Result<String> {
+    let output = Command::new("git").args(args).current_dir(dir).output()?;
+    assert!(output.status.success(), "git {args:?} failed: {output:?}");
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Check that `--git-stage` stages exactly the created, modified, and deleted files of the
+/// `add_module` and `remove_module` tests, in a fresh git repository.
+#[test]
+fn git_stage() -> io::Result<()> {
+    let cases = [
+        ("add_module", "lib.rs\nsubmod.rs\n"),
+        ("remove_module", "a.rs\na/inner.rs\nlib.rs\n"),
+    ];
+    for (name, staged) in cases {
+        let test_dir = golden_dir().join(name);
+        let dir = tempfile::tempdir()?;
+        write_tree(dir.path(), &read_tree(test_dir.join("input"))?)?;
+        git(dir.path(), &["init", "--quiet"])?;
+        git(dir.path(), &["add", "."])?;
+        git(dir.path(), &["-c", "user.name=test", "-c", "user.email=test@example.com",
+            "commit", "--quiet", "-m", "input"])?;
+
+        let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+            .arg(dir.path().join("lib.rs"))
+            .arg(test_dir.join("snippets.json"))
+            .arg("--git-stage")
+            .status()?;
+        assert!(status.success(), "subcommand failed");
+        assert_eq!(git(dir.path(), &["diff", "--cached", "--name-only"])?, staged, "{name}");
+        // Everything that changed was staged.
+        assert_eq!(git(dir.path(), &["diff", "--name-only"])?, "", "{name}");
+    }
+    Ok(())
+}
+
+/// Check that merging the `crate_root` snippets again doesn't add their unnamed items twice.
+#[test]
+fn crate_root_again() -> io::Result<()> {
+    let test_dir = golden_dir().join("crate_root");
+    let dir = tempfile::tempdir()?;
+    let good = read_tree(test_dir.join("good"))?;
+    write_tree(dir.path(), &good)?;
+    // Module `b` was added by the first merge, so it needs a snippet to be kept this time.
+    let snippets = fs::read_to_string(test_dir.join("snippets.json"))?;
+    let snippets = snippets.trim_end().strip_suffix('}').unwrap().trim_end().to_owned()
+        + ",\n  \"b\": \"mod b;\"\n}\n";
+    let snippets_path = dir.path().join(".snippets.json");
+    fs::write(&snippets_path, snippets)?;
+
+    let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(dir.path().join("lib.rs"))
+        .arg(&snippets_path)
+        .status()?;
+    assert!(status.success(), "subcommand failed");
+    assert_eq!(read_tree(dir.path())?, good);
+    Ok(())
+}
+
+/// Check that updating a function along with an item nested in its body is reported as a
+/// conflict, rather than one update silently winning.
+#[test]
+fn nested_item_overlap() -> io::Result<()> {
+    let test_dir = golden_dir().join("nested_items");
+    let dir = tempfile::tempdir()?;
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(dir.path(), &input)?;
+    let snippets_path = dir.path().join(".snippets.json");
+    // The golden snippets leave `outer` unchanged, so change it alongside `outer::inner#2`.
+    let snippets = fs::read_to_string(test_dir.join("snippets.json"))?;
+    assert!(snippets.contains("a + inner()"));
+    fs::write(&snippets_path, snippets.replace("a + inner()", "a * inner()"))?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(dir.path().join("lib.rs"))
+        .arg(&snippets_path)
+        .arg("--nested-items")
+        .output()?;
+    assert!(!output.status.success(), "subcommand should have failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("overlapping rewrites"), "unexpected output: {stderr}");
+    assert!(fs::exists(dir.path().join("lib.rs.conflict"))?);
+    assert_eq!(fs::read_to_string(dir.path().join("lib.rs"))?, input[Path::new("lib.rs")]);
+    Ok(())
+}
+
+/// Check that a key matching methods in several impl blocks of the same type is an error.
+#[test]
+fn ambiguous_impl_member() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let src = "struct Foo<T>(T);\n\nimpl Foo<u8> {\n    fn get(&self) {}\n}\n\n\
+        impl Foo<u16> {\n    fn get(&self) {}\n}\n";
+    fs::write(dir.path().join("lib.rs"), src)?;
+    let snippets_path = dir.path().join(".snippets.json");
+    fs::write(&snippets_path, r#"{"Foo::get": "fn get(&self) { }"}"#)?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(dir.path().join("lib.rs"))
+        .arg(&snippets_path)
+        .output()?;
+    assert!(!output.status.success(), "subcommand should have failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("key \"Foo::get\" matches members of several impl blocks"),
+        "unexpected output: {stderr}");
+    assert_eq!(fs::read_to_string(dir.path().join("lib.rs"))?, src);
+    Ok(())
+}
+
+/// Check that writing a `--plan` for the snippets of test `file_name` leaves the input
+/// untouched, and that applying the plan gives the same output as merging directly.
+fn test_plan(file_name: &str) -> io::Result<()> {
+    let test_dir = golden_dir().join(file_name);
+    let dir = tempfile::tempdir()?;
+    let planned_dir = dir.path();
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(planned_dir, &input)?;
+
+    let plan_path = planned_dir.join(".plan.json");
+    let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(planned_dir.join("lib.rs"))
+        .arg(test_dir.join("snippets.json"))
+        .arg("--plan")
+        .arg(&plan_path)
+        .status()?;
+    assert!(status.success(), "subcommand failed");
+    assert_eq!(read_tree(planned_dir)?, input);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg("--apply-plan")
+        .arg(&plan_path)
+        .status()?;
+    assert!(status.success(), "apply subcommand failed");
+    assert_eq!(read_tree(planned_dir)?, read_tree(test_dir.join("good"))?);
+
+    // The files now differ from the plan's old text, so applying it again changes nothing.
+    let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg("--apply-plan")
+        .arg(&plan_path)
+        .status()?;
+    assert!(!status.success(), "stale plan should have been rejected");
+    assert_eq!(read_tree(planned_dir)?, read_tree(test_dir.join("good"))?);
+    Ok(())
+}
+
+/// Check that `--verify-after` rejects a merge whose output doesn't parse, using a line range
+/// snippet, which isn't parsed on its own.
+#[test]
+fn verify_after() -> io::Result<()> {
+    let test_dir = golden_dir().join("line_range");
+    let dir = tempfile::tempdir()?;
+    let output_dir = dir.path();
+    write_tree(output_dir, &read_tree(test_dir.join("input"))?)?;
+
+    let run = |snippets: &str| -> io::Result<bool> {
+        let snippets_path = output_dir.join(".snippets.json");
+        fs::write(&snippets_path, snippets)?;
+        let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+            .arg(output_dir.join("lib.rs"))
+            .arg(&snippets_path)
+            .arg("--update-only")
+            .arg("--verify-after")
+            .status()?;
+        Ok(status.success())
+    };
+    assert!(run(r#"{"lib.rs:5-5": "const A: i32 = 3;"}"#)?, "valid output was rejected");
+    assert!(!run(r#"{"lib.rs:5-5": "const A: i32 = {"}"#)?, "broken output was accepted");
+    Ok(())
+}
+
+/// Check that a snippet for an existing item that `merge_rust` doesn't track by key, such as a
+/// `const`, is refused with both locations instead of being added a second time.
+#[test]
+fn duplicate_item() -> io::Result<()> {
+    let test_dir = golden_dir().join("line_range");
+    let dir = tempfile::tempdir()?;
+    let output_dir = dir.path();
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(output_dir, &input)?;
+
+    let snippets_path = output_dir.join(".snippets.json");
+    fs::write(&snippets_path, r#"{"f": "fn f() -> i32 {\n    A + B\n}", "g": "fn g() {}",
+        "A": "const A: i32 = 10;"}"#)?;
+    let output = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(output_dir.join("lib.rs"))
+        .arg(&snippets_path)
+        .output()?;
+    assert!(!output.status.success(), "subcommand should have failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lib_rs = output_dir.join("lib.rs");
+    let expected = format!("at {}:8, but one already exists at {}:5", lib_rs.display(),
+        lib_rs.display());
+    assert!(stderr.contains(&expected), "unexpected error: {stderr}");
+    fs::remove_file(&snippets_path)?;
+    assert_eq!(read_tree(output_dir)?, input);
+    Ok(())
+}
+
+/// Check that `--unsafe-delta` reports the change in the number of unsafe functions.
+#[test]
+fn unsafe_delta_summary() -> io::Result<()> {
+    let test_dir = golden_dir().join("unsafe_delta");
+    let dir = tempfile::tempdir()?;
+    let output_dir = dir.path();
+    write_tree(output_dir, &read_tree(test_dir.join("input"))?)?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(output_dir.join("lib.rs"))
+        .arg(test_dir.join("snippets.json"))
+        .arg("--unsafe-delta")
+        .output()?;
+    assert!(output.status.success(), "subcommand failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("internal unsafe functions: 2 -> 1 (-1)"),
+        "unexpected output: {stderr}");
+    Ok(())
+}
+
+/// Check that a `--rich` snippet whose body file is missing is reported by key and path, and
+/// leaves the input untouched.
+#[test]
+fn missing_body_file() -> io::Result<()> {
+    let test_dir = golden_dir().join("bodies_dir");
+    let dir = tempfile::tempdir()?;
+    let output_dir = dir.path();
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(output_dir, &input)?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(output_dir.join("lib.rs"))
+        .arg(test_dir.join("snippets.json"))
+        .arg("--rich")
+        .arg("--bodies-dir")
+        .arg(output_dir)
+        .output()?;
+    assert!(!output.status.success(), "subcommand should have failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let path = output_dir.join("fns/a.rs");
+    assert!(stderr.contains(&format!("snippet for \"a\" refers to {}", path.display())),
+        "unexpected error: {stderr}");
+    assert_eq!(read_tree(output_dir)?, input);
+    Ok(())
+}
+
+/// Check that a failed `--rich` transaction is reported, both on stderr and in the `--report`
+/// file, while the other snippets are still applied.
+#[test]
+fn failed_transaction() -> io::Result<()> {
+    let test_dir = golden_dir().join("transactions");
+    let dir = tempfile::tempdir()?;
+    let output_dir = dir.path();
+    write_tree(output_dir, &read_tree(test_dir.join("input"))?)?;
+    let report_path = output_dir.join("report.json");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(output_dir.join("lib.rs"))
+        .arg(test_dir.join("snippets.json"))
+        .arg("--rich")
+        .arg("--report")
+        .arg(&report_path)
+        .output()?;
+    assert!(output.status.success(), "subcommand failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("transaction \"constructor\": applying 2 snippets"),
+        "unexpected output: {stderr}");
+    assert!(stderr.contains("warning: transaction \"rename\" failed, so none of its 2 snippets \
+        were applied: snippet for \"double\" defines an item named \"twice\" instead"),
+        "unexpected output: {stderr}");
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report_path)?)?;
+    assert_eq!(report["transactions"]["constructor"], serde_json::Value::Null);
+    assert!(report["transactions"]["rename"].is_string(), "unexpected report: {report}");
+    Ok(())
+}
+
+/// Check that a transaction updating an item that an earlier transaction already updates is
+/// skipped, while the earlier one is still applied.
+#[test]
+fn conflicting_transactions() -> io::Result<()> {
+    let test_dir = golden_dir().join("transactions");
+    let dir = tempfile::tempdir()?;
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(dir.path(), &input)?;
+    let snippets_path = dir.path().join(".snippets.json");
+    fs::write(&snippets_path, serde_json::json!({
+        "norm": {
+            "content": "pub fn norm(p: &Point) -> i32 {\n    p.x * p.x\n}",
+            "transaction": "first",
+        },
+        "crate::norm": {
+            "content": "pub fn norm(p: &Point) -> i32 {\n    p.y * p.y\n}",
+            "transaction": "second",
+        },
+    }).to_string())?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(dir.path().join("lib.rs"))
+        .arg(&snippets_path)
+        .arg("--rich")
+        .output()?;
+    assert!(output.status.success(), "subcommand failed: {output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("transaction \"first\": applying 1 snippets"),
+        "unexpected output: {stderr}");
+    assert!(stderr.contains("warning: transaction \"second\" failed"),
+        "unexpected output: {stderr}");
+    let lib_rs = fs::read_to_string(dir.path().join("lib.rs"))?;
+    assert!(lib_rs.contains("p.x * p.x") && !lib_rs.contains("p.y * p.y"),
+        "unexpected output: {lib_rs}");
+    Ok(())
+}
+
+/// Run merge_rust with `--interactive` on the crate at `src_root_path`, giving it `answers` on
+/// stdin.
+fn run_interactive(
+    src_root_path: &Path,
+    snippets_path: &Path,
+    answers: &str,
+    extra_args: &[&OsStr],
+) -> io::Result<Output> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(src_root_path)
+        .arg(snippets_path)
+        .arg("--interactive")
+        .args(extra_args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(answers.as_bytes())?;
+    child.wait_with_output()
+}
+
+/// Check that `--interactive` skips a file when the answer is `n`, and applies the rest without
+/// asking again after `all`.
+#[test]
+fn interactive() -> io::Result<()> {
+    let test_dir = golden_dir().join("inner_attrs");
+    let dir = tempfile::tempdir()?;
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(dir.path(), &input)?;
+    let output = run_interactive(&dir.path().join("lib.rs"), &test_dir.join("snippets.json"),
+        "n\nall\n", &[])?;
+    assert!(output.status.success(), "subcommand failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("[y/n/all/quit]").count(), 2, "unexpected output: {stderr}");
+
+    let good = read_tree(test_dir.join("good"))?;
+    let (skipped, applied): (Vec<_>, Vec<_>) = read_tree(dir.path())?.into_iter()
+        .partition(|(path, src)| input[path] == *src);
+    assert_eq!(skipped.len(), 1, "expected exactly one file to be skipped");
+    assert!(stderr.contains(&format!("skipped {:?}", dir.path().join(&skipped[0].0))),
+        "unexpected output: {stderr}");
+    for (path, src) in applied {
+        assert_eq!(src, good[&path], "{path:?} differs from the golden output");
+    }
+    Ok(())
+}
+
+/// Check that quitting `--interactive` after confirming one file still runs `--post-hook` on
+/// that file, and that the `--emit-undo` file only undoes the changes that were made.
+#[test]
+fn interactive_quit() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = HashMap::from([
+        (PathBuf::from("lib.rs"), "mod a;\n\npub fn f() {}\n".to_owned()),
+        (PathBuf::from("a.rs"), "pub fn g() {}\n".to_owned()),
+    ]);
+    write_tree(dir.path(), &input)?;
+    let snippets_path = dir.path().join(".snippets.json");
+    fs::write(&snippets_path, r#"{
+        "a": "mod a;",
+        "a::g": "pub fn g() -> i32 { 1 }",
+        "f": "pub fn f() -> i32 { 2 }"
+    }"#)?;
+    let undo_path = dir.path().join(".undo.json");
+    let src_root_path = dir.path().join("lib.rs");
+    let output = run_interactive(&src_root_path, &snippets_path, "y\nquit\n", &[
+        "--post-hook".as_ref(), "echo hooked".as_ref(),
+        "--emit-undo".as_ref(), undo_path.as_os_str(),
+    ])?;
+    assert!(output.status.success(), "subcommand failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("quitting; modified 1 files and deleted 0"),
+        "unexpected output: {stderr}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let a_path = dir.path().join("a.rs");
+    assert_eq!(stdout.trim(), format!("hooked {}", a_path.display()));
+    assert_eq!(fs::read_to_string(&a_path)?, "pub fn g() -> i32 { 1 }\n");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(&src_root_path)
+        .arg(&undo_path)
+        .status()?;
+    assert!(status.success(), "undo subcommand failed");
+    assert_eq!(read_tree(dir.path())?, input);
+    Ok(())
+}
+
+/// Check that `--interactive` asks about a new module's file together with the file declaring
+/// it, and that quitting creates neither.
+#[test]
+fn interactive_new_module() -> io::Result<()> {
+    let test_dir = golden_dir().join("add_module");
+    let dir = tempfile::tempdir()?;
+    let input = read_tree(test_dir.join("input"))?;
+    write_tree(dir.path(), &input)?;
+    let report_path = dir.path().join(".report.json");
+    let output = run_interactive(&dir.path().join("lib.rs"), &test_dir.join("snippets.json"),
+        "quit\n", &["--report".as_ref(), report_path.as_os_str()])?;
+    assert!(output.status.success(), "subcommand failed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("[y/n/all/quit]").count(), 1, "unexpected output: {stderr}");
+    assert!(stderr.contains(&format!("rewrites to {:?}", dir.path().join("submod.rs"))),
+        "unexpected output: {stderr}");
+
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report_path)?)?;
+    assert_eq!(report["created_modules"], serde_json::json!([]));
+    assert_eq!(read_tree(dir.path())?, input);
+    Ok(())
+}
+
+/// Poll `cond` until it holds, panicking if it takes more than a generous timeout.
+fn wait_for(what: &str, mut cond: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !cond() {
+        assert!(start.elapsed() < Duration::from_secs(30), "timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Check that `--watch` merges on startup and after each change to the JSON file, summarizing
+/// each run, and that it removes its lock file when interrupted.
+#[cfg(unix)]
+#[test]
+fn watch() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let lib_path = dir.path().join("lib.rs");
+    let lock_path = dir.path().join(".merge_rust.lock");
+    let snippets_path = dir.path().join(".snippets.json");
+    fs::write(&lib_path, "pub fn f() -> i32 { 1 }\n")?;
+    fs::write(&snippets_path, r#"{"f": "pub fn f() -> i32 { 2 }"}"#)?;
+
+    let child = Command::new(env!("CARGO_BIN_EXE_merge_rust"))
+        .arg(&lib_path)
+        .arg(&snippets_path)
+        .arg("--watch")
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let lib_has = |body: &str| fs::read_to_string(&lib_path).unwrap().contains(body);
+    wait_for("the first merge", || lib_has("{ 2 }"));
+    assert!(fs::exists(&lock_path)?, "lock file is missing");
+    fs::write(&snippets_path, r#"{"f": "pub fn f() -> i32 { 3 }"}"#)?;
+    wait_for("the second merge", || lib_has("{ 3 }"));
+
+    let status = Command::new("kill").arg("-INT").arg(child.id().to_string()).status()?;
+    assert!(status.success(), "kill failed");
+    let output = child.wait_with_output()?;
+    assert!(output.status.success(), "watcher failed: {output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for run in 1..=2 {
+        assert!(stderr.contains(&format!("[watch] run {run}: merge succeeded in ")),
+            "unexpected output: {stderr}");
+    }
+    assert!(stderr.contains("[watch] stopping after 2 runs, 0 of which failed"),
+        "unexpected output: {stderr}");
+    assert!(!fs::exists(&lock_path)?, "lock file was left behind");
+    Ok(())
+}
+
 fn check_for_missing_tests_helper(known_tests: HashSet<&'static str>) -> io::Result<()> {
     let mut missing_tests = Vec::new();
     let golden_dir = golden_dir();
@@ -125,7 +784,7 @@ fn check_for_missing_tests_helper(known_tests: HashSet<&'static str>) -> io::Res
         }
         missing_tests.push(name);
     }
-    if missing_tests.len() > 0 {
+    if !missing_tests.is_empty() {
         panic!("tests exist on disk, but aren't listed in tests/golden.rs: {:?}", missing_tests);
     }
     Ok(())
@@ -141,6 +800,16 @@ macro_rules! define_tests {
             }
         )*
 
+        /// Split/merge round trips on the input of each test.
+        mod round_trip {
+            $(
+                #[test]
+                fn $name() -> std::io::Result<()> {
+                    super::test_round_trip(stringify!($name))
+                }
+            )*
+        }
+
         #[test]
         fn check_for_missing_tests() -> io::Result<()> {
             let known_tests = HashSet::from([ $( stringify!($name), )* ]);
@@ -154,4 +823,85 @@ define_tests! {
     add_remove,
     add_module,
     inline_module,
+    add_enum_variant,
+    key_prefix,
+    key_prefixes,
+    add_item_to_empty_inline_mod,
+    stats,
+    remove_module,
+    jsonl,
+    multi_item,
+    crate_roots,
+    format_changed_only,
+    ordered_items,
+    tab_indent,
+    by_regex,
+    post_hook,
+    bom,
+    rich,
+    bodies_dir,
+    transactions,
+    transaction_module,
+    inner_attrs,
+    coverage,
+    rich_file,
+    group_mod_decls,
+    keep_body,
+    undo,
+    line_range,
+    nested_inline_modules,
+    nested_same_name,
+    nested_items,
+    impl_members,
+    unsafe_delta,
+    crate_root,
+    new_import,
+    namespaces,
+}
+
+/// `--emit-undo` round trips on tests that cover adding, updating, and removing items.
+mod undo {
+    macro_rules! define_undo_tests {
+        ($($name:ident,)*) => {
+            $(
+                #[test]
+                fn $name() -> std::io::Result<()> {
+                    super::test_undo(stringify!($name))
+                }
+            )*
+        }
+    }
+
+    define_undo_tests! {
+        update,
+        add_remove,
+        add_module,
+        inline_module,
+        remove_module,
+        undo,
+    }
+}
+
+/// `--plan` and `--apply-plan` on tests that cover adding, updating, and removing items and
+/// modules.
+mod plan {
+    macro_rules! define_plan_tests {
+        ($($name:ident,)*) => {
+            $(
+                #[test]
+                fn $name() -> std::io::Result<()> {
+                    super::test_plan(stringify!($name))
+                }
+            )*
+        }
+    }
+
+    define_plan_tests! {
+        update,
+        add_remove,
+        add_module,
+        inline_module,
+        remove_module,
+        line_range,
+    }
 }
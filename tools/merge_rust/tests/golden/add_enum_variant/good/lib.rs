@@ -0,0 +1,22 @@
+pub enum Shape {
+    Point,
+    Circle(f64),
+    Rect { w: f64, h: f64 },
+    Line(f64, f64),
+}
+
+pub enum Empty {
+    Unit,
+}
+
+mod inner {
+    pub enum Color {
+        Red,
+        Green,
+        Blue
+    }
+}
+
+pub fn f() -> i32 {
+    1
+}
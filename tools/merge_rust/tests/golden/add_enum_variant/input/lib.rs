@@ -0,0 +1,17 @@
+pub enum Shape {
+    Point,
+    Circle(f64),
+}
+
+pub enum Empty {}
+
+mod inner {
+    pub enum Color {
+        Red,
+        Green
+    }
+}
+
+pub fn f() -> i32 {
+    1
+}
@@ -0,0 +1,23 @@
+pub fn f() -> i32 {
+    1
+}
+
+mod foo {
+    pub fn bar() -> i32 {
+        2
+    }
+
+    pub fn baz() -> i32 {
+        3
+    }
+}
+
+mod outer {
+    pub mod inner {
+        fn g(x: bool) {
+            if x {
+                f();
+            }
+        }
+    }
+}
@@ -0,0 +1,9 @@
+pub fn f() -> i32 {
+    1
+}
+
+mod foo {}
+
+mod outer {
+    pub mod inner { }
+}
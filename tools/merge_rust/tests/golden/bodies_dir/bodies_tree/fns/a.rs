@@ -0,0 +1,4 @@
+/// Loaded from the bodies directory.
+pub fn a() -> i32 {
+    3
+}
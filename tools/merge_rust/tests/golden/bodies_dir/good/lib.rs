@@ -0,0 +1,6 @@
+/// Loaded from the bodies directory.
+pub fn a() -> i32 {
+    3
+}
+
+pub fn b() {}
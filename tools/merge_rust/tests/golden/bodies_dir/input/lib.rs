@@ -0,0 +1,5 @@
+pub fn a() -> i32 {
+    1
+}
+
+pub fn b() {}
@@ -0,0 +1,3 @@
+fn g() {}
+
+fn h() {}
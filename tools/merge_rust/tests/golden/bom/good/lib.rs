@@ -0,0 +1,5 @@
+mod a;
+
+fn f() -> i32 {
+    2
+}
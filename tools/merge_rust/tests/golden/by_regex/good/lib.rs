@@ -0,0 +1,11 @@
+fn check(name: &str) {
+    println!("{name}");
+}
+
+fn test_a() { check("a"); }
+
+fn test_b() { check("b"); }
+
+fn helper() {
+    // updated
+}
@@ -0,0 +1,13 @@
+fn check(name: &str) {
+    println!("{name}");
+}
+
+fn test_a() {
+    check("old");
+}
+
+fn test_b() {
+    check("old");
+}
+
+fn helper() {}
@@ -0,0 +1,8 @@
+mod m {
+    fn a() {}
+    fn b() {}
+}
+
+fn c() {}
+
+fn d() {}
@@ -0,0 +1,24 @@
+//! Crate docs.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fmt;
+
+fn f() -> i32 {
+    1
+}
+
+mod a {
+    fn g() {}
+}
+
+/// A new function.
+fn new_fn() {}
+
+impl fmt::Debug for a::S {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("S")
+    }
+}
+
+mod b;
@@ -0,0 +1,9 @@
+//! Crate docs.
+
+use std::collections::HashMap;
+
+fn f() {}
+
+mod a {
+    fn g() {}
+}
@@ -0,0 +1,5 @@
+pub fn f() -> i32 {
+    10
+}
+
+mod m;
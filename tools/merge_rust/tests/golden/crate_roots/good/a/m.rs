@@ -0,0 +1,5 @@
+
+
+pub fn k() -> i32 {
+    4
+}
\ No newline at end of file
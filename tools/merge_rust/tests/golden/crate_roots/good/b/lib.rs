@@ -0,0 +1,5 @@
+pub fn g() -> i32 {
+    2
+}
+
+
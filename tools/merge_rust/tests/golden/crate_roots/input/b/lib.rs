@@ -0,0 +1,7 @@
+pub fn g() -> i32 {
+    2
+}
+
+pub fn h() -> i32 {
+    3
+}
@@ -0,0 +1,23 @@
+fn  untouched( ) -> i32 { 0 }
+
+pub fn f() -> i32 {
+    if true {
+        10
+    } else {
+        11
+    }
+}
+
+mod inner {
+    fn  also_untouched( ) {}
+
+    pub fn g() -> i32 {
+        20
+    }
+
+    fn h() {
+        g();
+    }
+}
+
+fn broken( {
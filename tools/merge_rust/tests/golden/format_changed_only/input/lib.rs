@@ -0,0 +1,13 @@
+fn  untouched( ) -> i32 { 0 }
+
+pub fn f() -> i32 {
+    1
+}
+
+mod inner {
+    fn  also_untouched( ) {}
+
+    pub fn g() -> i32 {
+        2
+    }
+}
@@ -0,0 +1,3 @@
+
+
+fn g() {}
\ No newline at end of file
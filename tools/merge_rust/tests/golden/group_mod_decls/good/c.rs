@@ -0,0 +1,3 @@
+
+
+fn h() {}
\ No newline at end of file
@@ -0,0 +1,4 @@
+mod y;
+mod v;
+
+fn z() {}
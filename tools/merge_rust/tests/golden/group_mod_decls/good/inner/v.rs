@@ -0,0 +1,3 @@
+
+
+fn u() {}
\ No newline at end of file
@@ -0,0 +1,8 @@
+//! Docs.
+
+mod a;
+pub mod inner;
+mod b;
+mod c;
+
+fn f() {}
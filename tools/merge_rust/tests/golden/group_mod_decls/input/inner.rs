@@ -0,0 +1,3 @@
+mod y;
+
+fn z() {}
@@ -0,0 +1,6 @@
+//! Docs.
+
+mod a;
+pub mod inner;
+
+fn f() {}
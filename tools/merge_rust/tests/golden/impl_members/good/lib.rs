@@ -0,0 +1,24 @@
+struct Foo<T>(T);
+
+impl<T: Clone> Foo<T>
+where
+    T: Default,
+{
+    fn get(&self) -> T {
+        T::default()
+    }
+
+    const K: u8 = 1;
+
+    
+}
+
+impl Iterator for Foo<u8> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+fn f() {}
@@ -0,0 +1,26 @@
+struct Foo<T>(T);
+
+impl<T: Clone> Foo<T>
+where
+    T: Default,
+{
+    fn get(&self) -> T {
+        self.0.clone()
+    }
+
+    const K: u8 = 1;
+
+    fn reset(&mut self) {
+        self.0 = T::default();
+    }
+}
+
+impl Iterator for Foo<u8> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+fn f() {}
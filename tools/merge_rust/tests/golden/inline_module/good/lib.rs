@@ -9,10 +9,10 @@ mod foo {
 
     mod bar;
 
-
-pub fn g2() -> i32 {
+    pub fn g2() -> i32 {
         20
-    }}
+    }
+}
 
 pub fn f2() -> i32 {
     10
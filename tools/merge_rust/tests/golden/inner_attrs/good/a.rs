@@ -0,0 +1,4 @@
+#![allow(unused)]
+#![allow(dead_code)]
+
+fn g() {}
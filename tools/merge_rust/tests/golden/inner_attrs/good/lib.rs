@@ -0,0 +1,9 @@
+//! Crate docs.
+#![allow(dead_code)]
+
+mod a;
+mod b {
+    #![allow(non_snake_case)]
+    fn f() {}
+}
+mod c;
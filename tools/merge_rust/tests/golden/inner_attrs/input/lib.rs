@@ -0,0 +1,7 @@
+//! Crate docs.
+
+mod a;
+mod b {
+    fn f() {}
+}
+mod c;
@@ -0,0 +1,11 @@
+pub fn f() -> i32 {
+    10
+}
+
+pub fn g() -> i32 {
+    2
+}
+
+
+
+mod b;
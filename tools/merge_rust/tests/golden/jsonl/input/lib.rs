@@ -0,0 +1,11 @@
+pub fn f() -> i32 {
+    1
+}
+
+pub fn g() -> i32 {
+    2
+}
+
+pub fn h() -> i32 {
+    3
+}
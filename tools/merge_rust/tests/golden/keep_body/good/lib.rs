@@ -0,0 +1,7 @@
+/// Adds one.
+pub fn add(x: i32) -> i32 {
+    // Hand-written comment.
+    x   +   1 // Odd spacing is kept.
+}
+
+pub(crate) fn other() {}
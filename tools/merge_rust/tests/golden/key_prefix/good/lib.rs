@@ -0,0 +1,5 @@
+pub fn f() -> i32 {
+    1
+}
+
+pub mod outer;
@@ -0,0 +1,15 @@
+pub mod inner {
+    pub fn g() -> i32 {
+        20
+    }
+
+    
+
+    pub fn new_fn() -> i32 {
+        5
+    }
+}
+
+pub fn k() -> i32 {
+    4
+}
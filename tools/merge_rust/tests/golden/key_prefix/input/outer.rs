@@ -0,0 +1,13 @@
+pub mod inner {
+    pub fn g() -> i32 {
+        2
+    }
+
+    pub fn h() -> i32 {
+        3
+    }
+}
+
+pub fn k() -> i32 {
+    4
+}
@@ -0,0 +1,15 @@
+pub fn f() -> i32 {
+    10
+}
+
+pub mod a {
+    pub fn g() -> i32 {
+        20
+    }
+
+    pub mod b {
+        pub fn h() -> i32 {
+            30
+        }
+    }
+}
@@ -0,0 +1,15 @@
+pub fn f() -> i32 {
+    1
+}
+
+pub mod a {
+    pub fn g() -> i32 {
+        2
+    }
+
+    pub mod b {
+        pub fn h() -> i32 {
+            3
+        }
+    }
+}
@@ -0,0 +1,8 @@
+fn f() -> i32 {
+    A + B
+}
+
+const A: i32 = 10;
+const B: i32 = 20;
+
+fn g() {}
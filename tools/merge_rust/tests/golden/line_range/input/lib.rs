@@ -0,0 +1,8 @@
+fn f() -> i32 {
+    A + B
+}
+
+const A: i32 = 1;
+const B: i32 = 2;
+
+fn g() {}
@@ -0,0 +1,7 @@
+pub fn f() -> i32 {
+    helper()
+}
+
+fn helper() -> i32 {
+    1
+}
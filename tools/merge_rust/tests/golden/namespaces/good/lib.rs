@@ -0,0 +1,12 @@
+#[repr(C)]
+pub struct stat {
+    pub st_size: i64,
+}
+
+pub fn size(s: &stat) -> i64 {
+    s.st_size
+}
+
+pub fn stat(size: i64) -> stat {
+    stat { st_size: size }
+}
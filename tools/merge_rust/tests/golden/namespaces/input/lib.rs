@@ -0,0 +1,8 @@
+#[repr(C)]
+pub struct stat {
+    pub st_size: i64,
+}
+
+pub fn size(s: &stat) -> i64 {
+    s.st_size
+}
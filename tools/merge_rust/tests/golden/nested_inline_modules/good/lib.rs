@@ -0,0 +1,23 @@
+mod a {
+    pub fn f() -> i32 {
+        1
+    }
+
+    mod b {
+        pub fn g() -> i32 {
+            20
+        }
+
+        
+
+        mod c {
+            pub fn h() -> i32 {
+                3
+            }
+
+            pub fn h2() -> i32 {
+                30
+            }
+        }
+    }
+}
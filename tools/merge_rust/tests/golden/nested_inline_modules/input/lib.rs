@@ -0,0 +1,19 @@
+mod a {
+    pub fn f() -> i32 {
+        1
+    }
+
+    mod b {
+        pub fn g() -> i32 {
+            2
+        }
+
+        pub fn gone() {}
+
+        mod c {
+            pub fn h() -> i32 {
+                3
+            }
+        }
+    }
+}
@@ -0,0 +1,14 @@
+fn outer() -> i32 {
+    fn inner() -> i32 {
+        1
+    }
+    let a = inner();
+    {
+        fn inner() -> i32 {
+            2
+        }
+        a + inner()
+    }
+}
+
+fn other() {}
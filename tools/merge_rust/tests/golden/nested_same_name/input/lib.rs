@@ -0,0 +1,11 @@
+mod foo {
+    pub fn bar() -> i32 {
+        1
+    }
+
+    mod foo {
+        pub fn bar() -> i32 {
+            2
+        }
+    }
+}
@@ -0,0 +1,19 @@
+//! Crate docs.
+
+use std::fmt;
+use std::io;
+use std::collections::HashMap;
+
+pub fn a() -> fmt::Result {
+    let _ = HashMap::<i32, i32>::new();
+    Ok(())
+}
+
+pub mod m {
+    use super::io;
+    use std::io::Write;
+
+    pub fn b() -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
@@ -0,0 +1,16 @@
+//! Crate docs.
+
+use std::fmt;
+use std::io;
+
+pub fn a() -> fmt::Result {
+    Ok(())
+}
+
+pub mod m {
+    use super::io;
+
+    pub fn b() -> io::Result<()> {
+        Ok(())
+    }
+}
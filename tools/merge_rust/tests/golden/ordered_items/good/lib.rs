@@ -0,0 +1,13 @@
+pub fn f() -> i32 {
+    1
+}
+
+pub fn z() {}
+
+mod m;
+
+pub fn a() {}
+
+pub fn b() {}
+
+pub fn c() {}
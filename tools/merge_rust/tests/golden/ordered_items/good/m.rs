@@ -0,0 +1,5 @@
+
+
+pub fn y() {}
+
+pub fn x() {}
\ No newline at end of file
@@ -0,0 +1,5 @@
+pub fn f() -> i32 {
+    2
+}
+
+pub fn g() {}
@@ -0,0 +1,7 @@
+
+
+mod c;
+
+pub fn f() -> i32 {
+    1
+}
@@ -0,0 +1,3 @@
+mod inner;
+
+pub fn h() {}
@@ -0,0 +1,9 @@
+mod a;
+mod b {
+    pub fn g() {}
+}
+mod c;
+
+pub fn f() -> i32 {
+    1
+}
@@ -0,0 +1,15 @@
+/// Adds one.
+#[inline]
+pub fn a(x: i32) -> i32 {
+    x + 2
+}
+
+
+
+pub fn c() -> i32 {
+    2
+}
+
+pub fn keep() {}
+
+pub fn e() {}
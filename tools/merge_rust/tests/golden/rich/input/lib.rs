@@ -0,0 +1,13 @@
+/// Adds one.
+#[inline]
+pub fn a(x: i32) -> i32 {
+    x + 1
+}
+
+pub fn b() {}
+
+pub fn c() -> i32 {
+    1
+}
+
+pub fn keep() {}
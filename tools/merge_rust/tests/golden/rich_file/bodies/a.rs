@@ -0,0 +1,4 @@
+/// Loaded from a file.
+pub fn a() -> i32 {
+    2
+}
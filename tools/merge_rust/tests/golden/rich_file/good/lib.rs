@@ -0,0 +1,6 @@
+/// Loaded from a file.
+pub fn a() -> i32 {
+    2
+}
+
+pub fn b() {}
@@ -0,0 +1,7 @@
+pub fn f() -> i32 {
+    1
+}
+
+pub fn g() -> i32 {
+    2
+}
@@ -0,0 +1,27 @@
+pub fn f() -> i32 {
+	1
+}
+
+mod a {
+	pub fn g() -> i32 {
+		2
+	}
+
+	pub fn h(x: i32) -> i32 {
+		if x > 0 {
+			x
+		} else {
+			0
+		}
+	}
+}
+
+pub enum E {
+	A {
+		x: i32,
+	},
+}
+
+pub fn k() {
+	let _ = 1;
+}
@@ -0,0 +1,12 @@
+pub fn f() -> i32 {
+	1
+}
+
+mod a {
+	pub fn g() -> i32 {
+		2
+	}
+}
+
+pub enum E {
+}
@@ -0,0 +1,9 @@
+pub fn area(r: f64) -> f64 {
+    std::f64::consts::PI * r * r
+}
+
+pub fn perimeter(r: f64) -> f64 {
+    std::f64::consts::TAU * r
+}
+
+mod shapes;
@@ -0,0 +1,9 @@
+
+
+pub struct Circle {
+    pub r: f64,
+}
+
+pub fn unit() -> Circle {
+    Circle { r: 1.0 }
+}
\ No newline at end of file
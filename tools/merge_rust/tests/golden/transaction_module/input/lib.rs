@@ -0,0 +1,7 @@
+pub fn area(r: f64) -> f64 {
+    3.14 * r * r
+}
+
+pub fn perimeter(r: f64) -> f64 {
+    2.0 * 3.14 * r
+}
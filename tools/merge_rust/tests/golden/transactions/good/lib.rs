@@ -0,0 +1,24 @@
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+pub fn origin() -> Point {
+    new_point(0, 0)
+}
+
+pub fn norm(p: &Point) -> i32 {
+    p.x.abs() + p.y.abs()
+}
+
+pub fn double(x: i32) -> i32 {
+    x * 2
+}
+
+pub fn new_point(x: i32, y: i32) -> Point {
+    Point { x, y }
+}
+
+pub fn triple(x: i32) -> i32 {
+    x * 3
+}
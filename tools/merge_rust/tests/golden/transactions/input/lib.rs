@@ -0,0 +1,16 @@
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+pub fn origin() -> Point {
+    Point { x: 0, y: 0 }
+}
+
+pub fn norm(p: &Point) -> i32 {
+    p.x.abs() + p.y.abs()
+}
+
+pub fn double(x: i32) -> i32 {
+    x * 2
+}
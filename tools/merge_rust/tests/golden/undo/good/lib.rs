@@ -0,0 +1,22 @@
+pub fn f() -> i32 {
+    10
+}
+
+
+
+mod inner {
+    pub fn h() -> i32 {
+        30
+    }
+
+    
+
+    pub fn i2() -> i32 {
+        40
+    }
+}
+
+
+mod new_mod;
+
+pub fn f2() {}
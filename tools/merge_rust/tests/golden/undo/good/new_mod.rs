@@ -0,0 +1,3 @@
+
+
+pub fn l() {}
\ No newline at end of file
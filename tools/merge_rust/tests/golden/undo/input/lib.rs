@@ -0,0 +1,19 @@
+pub fn f() -> i32 {
+    1
+}
+
+pub fn g() -> i32 {
+    2
+}
+
+mod inner {
+    pub fn h() -> i32 {
+        3
+    }
+
+    pub fn i() -> i32 {
+        4
+    }
+}
+
+mod m;
@@ -0,0 +1,7 @@
+pub fn j() -> i32 {
+    5
+}
+
+mod n {
+    pub fn k() {}
+}
@@ -0,0 +1,7 @@
+fn a(p: &i32) -> i32 {
+    *p
+}
+
+unsafe fn b() {}
+
+fn c() {}
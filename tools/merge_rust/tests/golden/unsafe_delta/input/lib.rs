@@ -0,0 +1,7 @@
+unsafe fn a(p: *const i32) -> i32 {
+    *p
+}
+
+unsafe fn b() {}
+
+fn c() {}
@@ -0,0 +1,282 @@
+use clap::Parser;
+use indexmap::IndexMap;
+use rust_util::collect::{self, FileCollector};
+use rust_util::edit::{
+    Rewrite, apply_rewrites, create_module_file, missing_modules, mod_decl, write_file_atomic,
+};
+use rust_util::item_span::item_spans;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+
+/// Move an item from one module of a Rust codebase to another.
+///
+/// References to the moved item are reported on stdout, but are not rewritten.
+#[derive(Parser)]
+struct Args {
+    /// Root Rust source file of the codebase (`lib.rs` or `main.rs`).
+    src_root_path: PathBuf,
+    /// Path of the item to move, such as `a::foo`.  Items declared inside function bodies can't
+    /// be moved, since they're part of the enclosing function.
+    item_path: String,
+    /// Path of the module to move the item into, such as `b`.  Use `crate` for the crate root.
+    /// The module is created if it doesn't exist yet.
+    target_mod_path: String,
+}
+
+type ModPath = String;
+
+/// Strip a leading `crate` segment from a user-provided path.
+fn normalize_path(path: &str) -> &str {
+    if path == "crate" {
+        ""
+    } else {
+        path.strip_prefix("crate::").unwrap_or(path)
+    }
+}
+
+/// Build a rewrite inserting `text` as a new item at `pos`.  New items are separated from
+/// preceding ones by a blank line, except at the start of a file.
+fn insert_item(pos: usize, text: &str) -> Rewrite {
+    if pos == 0 {
+        (pos, pos, text.to_owned())
+    } else {
+        (pos, pos, format!("\n\n{text}"))
+    }
+}
+
+/// Return the leading whitespace of the line containing `pos`, if the line has only whitespace
+/// before `pos`.
+fn indent_before(src: &str, pos: usize) -> Option<&str> {
+    let line_start = src[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let indent = &src[line_start..pos];
+    indent.trim().is_empty().then_some(indent)
+}
+
+/// Extend the item span `lo .. hi` to cover whole lines, if the item is alone on them, so that
+/// removing it doesn't leave a whitespace-only line behind.
+fn removal_span(src: &str, lo: usize, hi: usize) -> (usize, usize) {
+    let rest = &src[hi..];
+    let line_end = rest.find('\n').map_or(src.len(), |i| hi + i + 1);
+    match indent_before(src, lo) {
+        Some(indent) if src[hi..line_end].trim().is_empty() => (lo - indent.len(), line_end),
+        _ => (lo, hi),
+    }
+}
+
+/// Re-indent the lines of `text` after the first, replacing the leading `from` (the indentation
+/// of the line `text` started on) with `to`.  Blank lines are left empty.
+fn reindent(text: &str, from: &str, to: &str) -> String {
+    let mut lines = text.split('\n');
+    let mut out = lines.next().unwrap_or("").to_owned();
+    for line in lines {
+        out.push('\n');
+        if !line.trim().is_empty() {
+            out.push_str(to);
+            out.push_str(line.strip_prefix(from).unwrap_or(line));
+        }
+    }
+    out
+}
+
+/// Build a rewrite inserting `text` as a new item at the end of the inline module whose closing
+/// brace is at `close_pos`, indented one level deeper than the brace.
+fn insert_inline_item(src: &str, close_pos: usize, text: &str) -> Rewrite {
+    let close_indent = indent_before(src, close_pos);
+    let item_indent = format!("{}    ", close_indent.unwrap_or(""));
+    let pos = src[..close_pos].trim_end().len();
+    let sep = if src[..pos].ends_with('{') {
+        "\n"
+    } else {
+        "\n\n"
+    };
+    let text = reindent(text, "", &item_indent);
+    let mut new_text = format!("{sep}{item_indent}{text}");
+    if close_indent.is_none() {
+        // Put the closing brace on its own line, after the new item.
+        new_text.push('\n');
+    }
+    (pos, pos, new_text)
+}
+
+fn join_path(parent: &str, child: &str) -> String {
+    if parent.is_empty() {
+        child.to_owned()
+    } else {
+        format!("{parent}::{child}")
+    }
+}
+
+/// Collects the locations of paths and `use` items whose final segment is `name`.
+struct ReferenceVisitor<'a> {
+    name: &'a str,
+    /// Byte range to ignore, covering the definition of the item being moved.
+    skip: (usize, usize),
+    refs: Vec<(usize, String)>,
+}
+
+impl ReferenceVisitor<'_> {
+    fn record(&mut self, pos: usize, desc: String) {
+        let (skip_lo, skip_hi) = self.skip;
+        if pos < skip_lo || pos >= skip_hi {
+            self.refs.push((pos, desc));
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for ReferenceVisitor<'_> {
+    fn visit_path(&mut self, path: &'ast syn::Path) {
+        if path
+            .segments
+            .last()
+            .is_some_and(|seg| seg.ident == self.name)
+        {
+            let desc = quote::ToTokens::to_token_stream(path)
+                .to_string()
+                .replace(' ', "");
+            self.record(path.span().byte_range().start, desc);
+        }
+        visit::visit_path(self, path);
+    }
+
+    fn visit_use_name(&mut self, un: &'ast syn::UseName) {
+        if un.ident == self.name {
+            self.record(un.span().byte_range().start, format!("use {}", un.ident));
+        }
+    }
+
+    fn visit_use_rename(&mut self, ur: &'ast syn::UseRename) {
+        if ur.ident == self.name {
+            self.record(ur.span().byte_range().start, format!("use {}", ur.ident));
+        }
+    }
+}
+
+fn main() -> Result<(), String> {
+    let args = Args::parse();
+    let src_root_dir = args
+        .src_root_path
+        .parent()
+        .ok_or_else(|| format!("root path {:?} has no parent", args.src_root_path))?;
+    let item_path = normalize_path(&args.item_path);
+    let target_mod_path = normalize_path(&args.target_mod_path);
+    let (_, item_name) = item_path.rsplit_once("::").unwrap_or(("", item_path));
+    let new_item_path = join_path(target_mod_path, item_name);
+
+    if target_mod_path == item_path || target_mod_path.starts_with(&format!("{item_path}::")) {
+        return Err(format!("can't move {item_path:?} into itself"));
+    }
+
+    let mut fc = FileCollector::default();
+    fc.parse(&args.src_root_path, vec![], true)
+        .map_err(|e| e.to_string())?;
+
+    if fc
+        .mods
+        .iter()
+        .any(|m| m.mod_path.join("::") == item_path && !m.is_inline)
+    {
+        return Err(format!(
+            "{item_path:?} is an out-of-line module; moving it would change its file location"
+        ));
+    }
+
+    // Find the item to move, and check that the target module doesn't already have an item of the
+    // same name.
+    let mut sources = HashMap::new();
+    let mut found = None;
+    for (file_path, mod_path, ast) in &fc.files {
+        let src = collect::read_source(file_path).map_err(|e| e.to_string())?;
+        for (path, lo, hi) in item_spans(mod_path.clone(), ast) {
+            let path = path.join("::");
+            if path == item_path {
+                found = Some((file_path.clone(), lo, hi));
+            } else if path == new_item_path {
+                return Err(format!("{new_item_path:?} already exists in {file_path:?}"));
+            }
+        }
+        sources.insert(file_path.clone(), src);
+    }
+    let (item_file, lo, hi) = found.ok_or_else(|| format!("item {item_path:?} not found"))?;
+    // The moved item is written at the top level of its new module, so its lines are dedented by
+    // the indentation it had in the old one.
+    let item_indent = indent_before(&sources[&item_file], lo).unwrap_or("");
+    let item_src = reindent(&sources[&item_file][lo..hi], item_indent, "");
+
+    // Report references before making any changes, so the reported positions are consistent with
+    // the parsed ASTs.
+    for (file_path, _mod_path, ast) in &fc.files {
+        let mut v = ReferenceVisitor {
+            name: item_name,
+            skip: if *file_path == item_file {
+                (lo, hi)
+            } else {
+                (0, 0)
+            },
+            refs: Vec::new(),
+        };
+        v.visit_file(ast);
+        for (pos, desc) in v.refs {
+            let (line, col) = fc.byte_to_linecol(file_path, pos).unwrap_or((0, 0));
+            println!(
+                "{}:{}:{}: reference to `{}`",
+                file_path.display(),
+                line,
+                col + 1,
+                desc
+            );
+        }
+    }
+
+    let mut mod_locations: HashMap<ModPath, (PathBuf, usize, bool)> = fc
+        .mods
+        .iter()
+        .map(|m| {
+            (
+                m.mod_path.join("::"),
+                (m.file_path.clone(), m.inner_end_pos, m.is_inline),
+            )
+        })
+        .collect();
+    let mut file_rewrites = IndexMap::<PathBuf, Vec<Rewrite>>::new();
+    let (remove_lo, remove_hi) = removal_span(&sources[&item_file], lo, hi);
+    file_rewrites
+        .entry(item_file.clone())
+        .or_default()
+        .push((remove_lo, remove_hi, String::new()));
+
+    // Create the target module and any missing ancestors, declaring each in its parent.
+    for mod_path in missing_modules(target_mod_path, |m| mod_locations.contains_key(m)) {
+        let file_path = create_module_file(src_root_dir, mod_path).map_err(|e| e.to_string())?;
+        eprintln!("created module {mod_path:?} in {file_path:?}");
+        let parent_mod_path = mod_path.rsplit_once("::").map_or("", |(parent, _)| parent);
+        let (parent_file, parent_end, parent_inline) = mod_locations[parent_mod_path].clone();
+        let rewrite = if parent_inline {
+            insert_inline_item(&sources[&parent_file], parent_end, &mod_decl(mod_path))
+        } else {
+            insert_item(parent_end, &mod_decl(mod_path))
+        };
+        file_rewrites.entry(parent_file).or_default().push(rewrite);
+        sources.insert(file_path.clone(), String::new());
+        mod_locations.insert(mod_path.to_owned(), (file_path, 0, false));
+    }
+
+    let (target_file, target_end, target_inline) = mod_locations[target_mod_path].clone();
+    let rewrite = if target_inline {
+        insert_inline_item(&sources[&target_file], target_end, &item_src)
+    } else {
+        insert_item(target_end, &item_src)
+    };
+    file_rewrites.entry(target_file).or_default().push(rewrite);
+
+    for (file_path, mut rewrites) in file_rewrites {
+        let new_src = apply_rewrites(&sources[&file_path], &mut rewrites);
+        write_file_atomic(Path::new(&file_path), &new_src)
+            .map_err(|e| format!("writing {file_path:?}: {e}"))?;
+        eprintln!("applied {} rewrites to {:?}", rewrites.len(), file_path);
+    }
+    eprintln!("moved {item_path:?} to {new_item_path:?}");
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn move_to_new_module() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib_path = dir.path().join("lib.rs");
+    fs::write(
+        &lib_path,
+        "mod a {\n    pub fn foo() -> i32 {\n        1\n    }\n}\n\npub fn bar() -> i32 {\n    a::foo()\n}\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_move_item"))
+        .arg(&lib_path)
+        .arg("crate::a::foo")
+        .arg("b::c")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "move_item failed: {output:?}");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        format!("{}:8:5: reference to `a::foo`\n", lib_path.display())
+    );
+
+    assert_eq!(
+        fs::read_to_string(&lib_path).unwrap(),
+        "mod a {\n}\n\npub fn bar() -> i32 {\n    a::foo()\n}\n\nmod b;\n",
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("b.rs")).unwrap(),
+        "mod c;",
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("b/c.rs")).unwrap(),
+        "pub fn foo() -> i32 {\n    1\n}",
+    );
+}
+
+#[test]
+fn move_to_inline_module() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib_path = dir.path().join("lib.rs");
+    fs::write(
+        &lib_path,
+        "mod a {\n    pub fn foo() -> i32 {\n        1\n    }\n}\n\nmod b {\n    pub fn bar() {}\n}\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_move_item"))
+        .arg(&lib_path)
+        .arg("a::foo")
+        .arg("b")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "move_item failed: {output:?}");
+    assert_eq!(
+        fs::read_to_string(&lib_path).unwrap(),
+        "mod a {\n}\n\nmod b {\n    pub fn bar() {}\n\n    pub fn foo() -> i32 {\n        1\n    }\n}\n",
+    );
+}
+
+#[test]
+fn nested_item_not_found() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib_path = dir.path().join("lib.rs");
+    let src =
+        "pub fn outer() -> i32 {\n    fn inner() -> i32 {\n        1\n    }\n    inner()\n}\n";
+    fs::write(&lib_path, src).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_move_item"))
+        .arg(&lib_path)
+        .arg("outer::inner")
+        .arg("b")
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "move_item should have failed");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("outer::inner") && stderr.contains("not found"),
+        "unexpected output: {stderr}"
+    );
+    assert_eq!(fs::read_to_string(&lib_path).unwrap(), src);
+}
@@ -62,7 +62,7 @@ fn module_def_source(
         ModuleDef::Static(x) => source_text_range(sema, x),
         ModuleDef::Trait(x) => source_text_range(sema, x),
         ModuleDef::TypeAlias(x) => source_text_range(sema, x),
-        ModuleDef::BuiltinType(_) => return None, // Builtins are not defined in source code
+        ModuleDef::BuiltinType(_) => None, // Builtins are not defined in source code
         ModuleDef::Macro(x) => source_text_range(sema, x),
     }
 }
@@ -98,7 +98,7 @@ fn item_uses(
     let usages = def.usages(sema).include_self_refs().all();
 
     // Find the innermost item containing the text range of each usage occurrence
-    let references = usages
+    usages
         .into_iter()
         .flat_map(|(file_id, refs)| {
             refs.into_iter().filter_map(move |file_ref| {
@@ -113,11 +113,10 @@ fn item_uses(
                     .find(|(item_range, _item)| {
                         item_range.file_id == file_id && item_range.value.contains_range(range)
                     })
-                    .map(|(_item_range, item)| item.clone())
+                    .map(|(_item_range, item)| *item)
             })
         })
-        .collect();
-    references
+        .collect()
 }
 
 /// Return the function's signature as written in the source
@@ -152,7 +151,7 @@ fn pp_function_signature(db: &RootDatabase, sig: &FunctionSignature) -> String {
 
     let _ = write!(&mut out, "fn {}", sig.name.display(db, Edition::DEFAULT));
 
-    if sig.generic_params.len() > 0 {
+    if !sig.generic_params.is_empty() {
         out.push('<');
         for (_lt_id, lt_info) in sig.generic_params.iter_lt() {
             let _ = write!(&mut out, "{}, ", lt_info.name.display(db, Edition::DEFAULT));
@@ -376,7 +375,7 @@ fn items_used_by(sema: &Semantics<RootDatabase>, module_def: ModuleDef) -> HashS
             match e {
                 NodeOrToken::Node(_n) => {}
                 NodeOrToken::Token(token) => {
-                    if let Some(defn) = ra_ap_ide_db::helpers::get_definition(&sema, token) {
+                    if let Some(defn) = ra_ap_ide_db::helpers::get_definition(sema, token) {
                         // Convert jump-to-defn `Definition` into item `ModuleDef`.
                         // Skips non-item definitions like locals, labels, builtins, etc.
                         if let Some(module_def) = definition_source(defn) {
@@ -394,8 +393,10 @@ fn items_used_by(sema: &Semantics<RootDatabase>, module_def: ModuleDef) -> HashS
 fn find_related_decls(args: Args) -> Result<serde_json::Map<String, serde_json::Value>, String> {
     let cargo_dir_path = Path::new(&args.cargo_dir_path);
 
-    let mut cargo_config = CargoConfig::default();
-    cargo_config.sysroot = Some(ra_ap_project_model::RustLibSource::Discover);
+    let cargo_config = CargoConfig {
+        sysroot: Some(ra_ap_project_model::RustLibSource::Discover),
+        ..CargoConfig::default()
+    };
 
     let load_cargo_config: LoadCargoConfig = LoadCargoConfig {
         load_out_dirs_from_check: true,
@@ -428,15 +429,15 @@ fn find_related_decls_impl(
 ) -> Result<serde_json::Map<String, serde_json::Value>, String> {
     let sema = Semantics::new(db);
 
-    let krate = match crates::find_in_dir(&sema, &vfs, cargo_dir_path).as_slice()
+    let krate = match *crates::find_in_dir(&sema, &vfs, cargo_dir_path).as_slice()
     {
-        &[] => {
+        [] => {
             return Err(format!(
                 "no crates found in directory {:?}",
                 cargo_dir_path.display()
             ));
         }
-        &[krate] => krate,
+        [krate] => krate,
         _ => {
             // At present, this tool only supports a single crate because it resolves paths without
             // a crate name relative to that crate.
@@ -466,7 +467,6 @@ fn find_related_decls_impl(
 
     let mut items_by_range = Vec::new();
     for file in &files {
-        let db = db;
         let mut observe_def = |module_def: ModuleDef| {
             log::trace!(
                 "traversal saw item {:?}",
@@ -558,7 +558,7 @@ fn find_related_decls_impl(
             let using_items = item_uses(&sema, &items_by_range, module_def);
             let mut paths = using_items
                 .into_iter()
-                .map(|module_def| mod_def_path(module_def))
+                .map(&mod_def_path)
                 .collect::<Vec<_>>();
             paths.sort();
             path_info.insert("uses".to_owned(), paths.into());
@@ -568,7 +568,7 @@ fn find_related_decls_impl(
             let used_items = items_used_by(&sema, module_def);
             let mut paths = used_items
                 .into_iter()
-                .map(|module_def| mod_def_path(module_def))
+                .map(mod_def_path)
                 .collect::<Vec<_>>();
             paths.sort();
             path_info.insert("used_items".to_owned(), paths.into());
@@ -616,7 +616,7 @@ fn find_related_decls_impl(
                 let sig = FunctionSignature::of(db, func_id);
                 path_info.insert(
                     "signature".to_owned(),
-                    pp_function_signature(db, &sig).into(),
+                    pp_function_signature(db, sig).into(),
                 );
                 path_info.insert(
                     "written_signature".to_owned(),
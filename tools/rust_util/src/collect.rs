@@ -1,6 +1,8 @@
 use crate::error::Error;
+use crate::line_index::LineIndex;
 use proc_macro2::Span;
-use std::collections::HashSet;
+use quote::ToTokens;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::iter;
 use std::mem;
@@ -9,12 +11,254 @@ use syn;
 use syn::ext::IdentExt;
 use syn::spanned::Spanned;
 
+/// Read the Rust source file at `path`, stripping a leading UTF-8 byte-order mark if there is one.
+///
+/// `syn::parse_file` skips the BOM itself, so span byte offsets are relative to the text after it.
+/// Reading files through this function keeps offsets and source text consistent.  Files rewritten
+/// from the returned text lose their BOM, which rustc doesn't need.
+pub fn read_source(path: impl AsRef<Path>) -> Result<String, Error> {
+    let path = path.as_ref();
+    let bytes = fs::read(path).map_err(|e| Error::from(e).at(format_args!("reading {path:?}")))?;
+    let mut src = String::from_utf8(bytes).map_err(|e| {
+        let pos = e.utf8_error().valid_up_to();
+        Error::from(format!(
+            "{path:?} is not valid UTF-8 (invalid byte at offset {pos})"
+        ))
+    })?;
+    if src.starts_with('\u{feff}') {
+        src.drain(..'\u{feff}'.len_utf8());
+    }
+    Ok(src)
+}
+
+/// A module path, as a list of identifiers relative to the crate root.
+pub type ModPath = Vec<String>;
+
+/// The kind of a `syn::Item`, without its contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ItemKind {
+    Const,
+    Enum,
+    ExternCrate,
+    Fn,
+    ForeignMod,
+    Impl,
+    Macro,
+    Mod,
+    Static,
+    Struct,
+    Trait,
+    TraitAlias,
+    Type,
+    Union,
+    Use,
+    Other,
+}
+
+impl ItemKind {
+    pub fn of(item: &syn::Item) -> ItemKind {
+        match *item {
+            syn::Item::Const(_) => ItemKind::Const,
+            syn::Item::Enum(_) => ItemKind::Enum,
+            syn::Item::ExternCrate(_) => ItemKind::ExternCrate,
+            syn::Item::Fn(_) => ItemKind::Fn,
+            syn::Item::ForeignMod(_) => ItemKind::ForeignMod,
+            syn::Item::Impl(_) => ItemKind::Impl,
+            syn::Item::Macro(_) => ItemKind::Macro,
+            syn::Item::Mod(_) => ItemKind::Mod,
+            syn::Item::Static(_) => ItemKind::Static,
+            syn::Item::Struct(_) => ItemKind::Struct,
+            syn::Item::Trait(_) => ItemKind::Trait,
+            syn::Item::TraitAlias(_) => ItemKind::TraitAlias,
+            syn::Item::Type(_) => ItemKind::Type,
+            syn::Item::Union(_) => ItemKind::Union,
+            syn::Item::Use(_) => ItemKind::Use,
+            _ => ItemKind::Other,
+        }
+    }
+    /// The kind of a member of an `impl` block.  Methods are `Fn`, and associated constants and
+    /// types are `Const` and `Type`.
+    pub fn of_impl_item(item: &syn::ImplItem) -> ItemKind {
+        match *item {
+            syn::ImplItem::Const(_) => ItemKind::Const,
+            syn::ImplItem::Fn(_) => ItemKind::Fn,
+            syn::ImplItem::Macro(_) => ItemKind::Macro,
+            syn::ImplItem::Type(_) => ItemKind::Type,
+            _ => ItemKind::Other,
+        }
+    }
+
+    /// The kind of a member of a `trait`, as with `of_impl_item`.
+    pub fn of_trait_item(item: &syn::TraitItem) -> ItemKind {
+        match *item {
+            syn::TraitItem::Const(_) => ItemKind::Const,
+            syn::TraitItem::Fn(_) => ItemKind::Fn,
+            syn::TraitItem::Macro(_) => ItemKind::Macro,
+            syn::TraitItem::Type(_) => ItemKind::Type,
+            _ => ItemKind::Other,
+        }
+    }
+
+    /// A short description of this kind of item for use in messages, such as `fn` or
+    /// `extern crate`.
+    pub fn description(self) -> &'static str {
+        match self {
+            ItemKind::Const => "const",
+            ItemKind::Enum => "enum",
+            ItemKind::ExternCrate => "extern crate",
+            ItemKind::Fn => "fn",
+            ItemKind::ForeignMod => "extern block",
+            ItemKind::Impl => "impl",
+            ItemKind::Macro => "macro",
+            ItemKind::Mod => "mod",
+            ItemKind::Static => "static",
+            ItemKind::Struct => "struct",
+            ItemKind::Trait => "trait",
+            ItemKind::TraitAlias => "trait alias",
+            ItemKind::Type => "type alias",
+            ItemKind::Union => "union",
+            ItemKind::Use => "use",
+            ItemKind::Other => "item",
+        }
+    }
+}
+
+/// An item yielded by `FileCollector::items`: either an item of a module, or a member of an
+/// `impl` or `trait` block, along with the block containing it.
+#[derive(Clone, Copy, Debug)]
+pub enum ItemRef<'a> {
+    Item(&'a syn::Item),
+    ImplMember(&'a syn::ItemImpl, &'a syn::ImplItem),
+    TraitMember(&'a syn::ItemTrait, &'a syn::TraitItem),
+}
+
+impl<'a> ItemRef<'a> {
+    /// The item itself, if this is an item of a module rather than a member.
+    pub fn item(self) -> Option<&'a syn::Item> {
+        match self {
+            ItemRef::Item(item) => Some(item),
+            _ => None,
+        }
+    }
+
+    /// The name of the item or member, if it has one.
+    pub fn ident(self) -> Option<&'a syn::Ident> {
+        match self {
+            ItemRef::Item(item) => crate::snippet::item_name(item),
+            ItemRef::ImplMember(_, member) => match *member {
+                syn::ImplItem::Const(ref x) => Some(&x.ident),
+                syn::ImplItem::Fn(ref x) => Some(&x.sig.ident),
+                syn::ImplItem::Type(ref x) => Some(&x.ident),
+                _ => None,
+            },
+            ItemRef::TraitMember(_, member) => match *member {
+                syn::TraitItem::Const(ref x) => Some(&x.ident),
+                syn::TraitItem::Fn(ref x) => Some(&x.sig.ident),
+                syn::TraitItem::Type(ref x) => Some(&x.ident),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The header of an `impl` block, as needed to tell apart the impls of a generic type.  Token
+/// strings, as in `self_ty`, are normalized so they don't depend on how the source was formatted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImplHeader {
+    /// The `Self` type, as a token string such as `Foo < T >`.
+    pub self_ty: String,
+    /// Name of the `Self` type without its generic arguments, as in `Foo`, if it's a path.  Keys
+    /// for methods of a generic type can use this, since the arguments vary between impls.
+    pub self_ty_name: Option<String>,
+    /// The impl's generic parameters and their bounds, as a token string such as
+    /// `< T : Clone >`, or empty if it has none.
+    pub generics: String,
+    /// The impl's `where` clause, as a token string, or empty if it has none.
+    pub where_clause: String,
+    /// The implemented trait, as a token string, or `None` for an inherent impl.
+    pub trait_path: Option<String>,
+}
+
+impl ImplHeader {
+    pub fn of(imp: &syn::ItemImpl) -> ImplHeader {
+        let self_ty_name = match *imp.self_ty {
+            syn::Type::Path(ref tp) => tp.path.segments.last().map(|s| s.ident.unraw().to_string()),
+            _ => None,
+        };
+        ImplHeader {
+            self_ty: imp.self_ty.to_token_stream().to_string(),
+            self_ty_name,
+            generics: imp.generics.to_token_stream().to_string(),
+            where_clause: imp.generics.where_clause.to_token_stream().to_string(),
+            trait_path: imp
+                .trait_
+                .as_ref()
+                .map(|(bang, path, _)| quote::quote!(#bang #path).to_string()),
+        }
+    }
+}
+
+/// Rust edition, which determines how `mod foo;` declarations are resolved to files.
+///
+/// The only difference handled is that the original 2015 module model doesn't allow out-of-line
+/// modules to be declared in non-`mod.rs` files: `mod foo;` in `a.rs` is an error, while in the
+/// 2018 model it refers to `a/foo.rs`.  (Current compilers accept the 2018 form in every edition,
+/// so this only makes a difference for code that must build with old toolchains.)  Modules with an
+/// explicit `#[path]` attribute are resolved the same way in both models.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Edition {
+    Edition2015,
+    #[default]
+    Edition2018,
+}
+
 #[derive(Clone, Debug)]
 pub struct ModInfo {
     pub mod_path: Vec<String>,
     pub file_path: PathBuf,
+    /// Start of the module's contents: 0 for a file, or just after the `{` of an inline module.
+    pub inner_start_pos: usize,
+    /// End of the module's inner attributes, such as `#![allow(dead_code)]`, or `inner_start_pos`
+    /// if there are none.  New inner attributes should be inserted here, ahead of the items.
+    pub inner_attrs_end_pos: usize,
     pub inner_end_pos: usize,
+    /// Byte ranges of the module's own `use` and `extern crate` items, including their
+    /// attributes, in source order.  A new import should be inserted after the last of these, to
+    /// keep it with the others.
+    pub import_spans: Vec<(usize, usize)>,
     pub is_inline: bool,
+    /// The module is in a file marked as generated; see `FileCollector::generated_marker`.
+    pub is_generated: bool,
+    /// Outer attributes on the module's declaration, such as `#[cfg(unix)]` or `#[path = "x.rs"]`
+    /// on `mod foo;`, so tools that move or rename modules can keep them.  This is empty for the
+    /// crate root and for any module whose file was parsed directly by a call to `parse`.
+    pub attrs: Vec<syn::Attribute>,
+    /// Visibility of the module's declaration.  The crate root, and any module whose file was
+    /// parsed directly by a call to `parse`, have inherited (private) visibility.
+    pub vis: syn::Visibility,
+}
+
+/// A `macro_rules!` definition found by `FileCollector`.
+#[derive(Clone, Debug)]
+pub struct MacroInfo {
+    pub name: String,
+    /// Path of the module containing the definition.
+    pub mod_path: Vec<String>,
+    /// File containing the definition.  `span` is relative to this file.
+    pub file_path: PathBuf,
+    /// Span of the whole definition, including its attributes.
+    pub span: Span,
+    /// Whether the macro is marked `#[macro_export]`, making it available at the crate root.
+    pub is_exported: bool,
+}
+
+/// Size of a file's source text, as recorded in `FileCollector::file_sizes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileSize {
+    /// Length in bytes, not counting a byte order mark.
+    pub bytes: usize,
+    /// Number of lines.  A final line without a trailing newline is counted too.
+    pub lines: usize,
 }
 
 #[derive(Clone, Default)]
@@ -22,30 +266,166 @@ pub struct FileCollector {
     /// File path, module path, and AST for each file visited so far.
     pub files: Vec<(PathBuf, Vec<String>, syn::File)>,
     pub mods: Vec<ModInfo>,
-    /// Inline modules collected by `walk_items`.  These are converted into `ModInfo`s in the
-    /// enclosing call to `parse`.
-    inline_mods: Vec<(Vec<String>, Span)>,
-    seen: HashSet<PathBuf>,
+    /// `macro_rules!` definitions in the files visited so far, including those in inline modules.
+    pub macros: Vec<MacroInfo>,
+    /// Edition whose module model is used to find the files of out-of-line modules.  This should
+    /// be set before calling `parse`.
+    pub edition: Edition,
+    /// If set, modules marked `#[cfg(test)]` are skipped, along with everything inside them: their
+    /// files aren't parsed, and they don't appear in `mods` or `items()`.  Inline test modules are
+    /// still present in the ASTs in `files`.  This should be set before calling `parse`.
+    pub skip_test_mods: bool,
+    /// If set, the source text of each file is kept in `sources`, so callers don't need to read
+    /// the files again.  This is off by default, since it means holding every file in memory.
+    /// This should be set before calling `parse`.
+    pub keep_sources: bool,
+    /// Source text of each file visited so far, if `keep_sources` is set.
+    pub sources: HashMap<PathBuf, String>,
+    /// Size of each file in `files`, for estimating how much text would be processed without
+    /// keeping the sources.
+    pub file_sizes: HashMap<PathBuf, FileSize>,
+    /// If set, `mod foo;` declarations whose file doesn't exist are recorded in `unresolved`
+    /// instead of causing `parse` to fail.  This is useful for partial trees, where some module
+    /// files haven't been written yet.  This should be set before calling `parse`.
+    pub allow_unresolved: bool,
+    /// Module path and expected file path of each out-of-line module whose file was missing, if
+    /// `allow_unresolved` is set.  For a module with no `#[path]` attribute, the expected path is
+    /// `foo.rs` rather than `foo/mod.rs`.
+    pub unresolved: Vec<(Vec<String>, PathBuf)>,
+    /// If set, files whose header comments contain this marker, such as `@generated`, are treated
+    /// as generated code: they're listed in `generated_files`, and their modules have
+    /// `is_generated` set.  Only the comments before the first line of code are checked.  This
+    /// should be set before calling `parse`.
+    pub generated_marker: Option<String>,
+    /// If set along with `generated_marker`, generated files are skipped entirely: they aren't
+    /// parsed, and neither they nor their submodules appear in `files`, `mods`, or `items()`.
+    /// This should be set before calling `parse`.
+    pub skip_generated: bool,
+    /// Files found to be generated, if `generated_marker` is set, including skipped ones.
+    pub generated_files: Vec<PathBuf>,
+    /// If set, the crate root's module path is this one segment instead of being empty, so every
+    /// module path recorded by `parse` starts with the crate name, as in `mycrate::foo::bar`.
+    /// This keeps paths unique across the crates of a workspace.  It only applies when `parse` is
+    /// called with `is_root` set and an empty `mod_path`.  This should be set before calling
+    /// `parse`.
+    pub crate_name: Option<String>,
+    /// If set, only these files are fully parsed, as for incremental runs where only a few files
+    /// have changed.  Other files of the crate, including the root, are still read and scanned
+    /// for `mod` declarations, so the listed files get their correct module paths, but they
+    /// aren't parsed with `syn`, and neither they nor their inline modules appear in `files`,
+    /// `mods`, `macros`, or `items()`.  Paths are compared after canonicalization.  This should
+    /// be set before calling `parse`.
+    pub only_files: Option<Vec<PathBuf>>,
+    /// Canonicalized `only_files`, computed by `parse`.
+    only_files_canonical: Option<HashSet<PathBuf>>,
+    /// Inline modules collected by `walk_items`.  These are added to `mods` in the enclosing call
+    /// to `parse`, which sets their `is_generated`.
+    inline_mods: Vec<ModInfo>,
+    /// Module path of each file visited so far, keyed by canonicalized file path.
+    seen: HashMap<PathBuf, Vec<String>>,
+    /// Line index for each file visited so far, used by `byte_to_linecol`.
+    line_indexes: HashMap<PathBuf, LineIndex>,
 }
 
 impl FileCollector {
+    /// Parse the file at `file_path`, which contains the module `mod_path`, and recursively parse
+    /// the files of its out-of-line submodules.
+    ///
+    /// Each file can belong to only one module.  Parsing a file a second time for the same module
+    /// does nothing, but if two different modules refer to the same file, as with two `mod`
+    /// declarations that have the same `#[path]`, this returns an error.  (`rustc` accepts this,
+    /// compiling the file once for each module, but then the file's items have several paths, and
+    /// tools that edit the file would need to keep the copies in sync.)
+    ///
+    /// A module with a `#[path]` attribute is read from exactly that path, whatever its extension,
+    /// as with generated `foo.rs.in` files.  For other out-of-line modules, only `foo/mod.rs` and
+    /// `foo.rs` are tried, as in `rustc`.
     pub fn parse(
         &mut self,
         file_path: impl AsRef<Path>,
         mod_path: Vec<String>,
         is_root: bool,
     ) -> Result<(), Error> {
-        let file_path = file_path.as_ref();
-        if self.seen.contains(file_path) {
-            return Ok(());
+        self.only_files_canonical = self.only_files.as_ref().map(|files| {
+            files
+                .iter()
+                .map(|f| fs::canonicalize(f).unwrap_or_else(|_| f.clone()))
+                .collect()
+        });
+        self.parse_module(
+            file_path.as_ref(),
+            mod_path,
+            is_root,
+            Vec::new(),
+            syn::Visibility::Inherited,
+        )
+    }
+
+    /// Implementation of `parse`, for a module whose declaration has the outer attributes `attrs`
+    /// and visibility `vis`.
+    fn parse_module(
+        &mut self,
+        file_path: &Path,
+        mod_path: Vec<String>,
+        is_root: bool,
+        attrs: Vec<syn::Attribute>,
+        vis: syn::Visibility,
+    ) -> Result<(), Error> {
+        let mod_path = match self.crate_name {
+            Some(ref name) if is_root && mod_path.is_empty() => vec![name.clone()],
+            _ => mod_path,
+        };
+        // Canonicalize so that different spellings of the same path, such as `a/../b.rs` and
+        // `b.rs`, are detected.  If this fails, reading the file below will report the error.
+        let seen_key = fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_owned());
+        if let Some(old_mod_path) = self.seen.get(&seen_key) {
+            if *old_mod_path == mod_path {
+                return Ok(());
+            }
+            return Err(format!(
+                "file {file_path:?} is used by both module `{}` and module `{}`",
+                display_mod_path(old_mod_path),
+                display_mod_path(&mod_path),
+            )
+            .into());
+        }
+        let src = read_source(file_path)?;
+        let is_generated = self
+            .generated_marker
+            .as_ref()
+            .is_some_and(|marker| has_generated_marker(&src, marker));
+        if is_generated {
+            self.generated_files.push(file_path.to_owned());
+            if self.skip_generated {
+                self.seen.insert(seen_key, mod_path);
+                return Ok(());
+            }
         }
-        let src = fs::read_to_string(file_path)
-            .map_err(|e| Error::from(e).at(format_args!("reading {file_path:?}")))?;
-        let ast: syn::File = syn::parse_file(&src)
-            .map_err(|e| Error::from(e).at(format_args!("parsing {file_path:?}")))?;
+        let is_listed = self
+            .only_files_canonical
+            .as_ref()
+            .is_none_or(|files| files.contains(&seen_key));
+        let ast: syn::File = if is_listed {
+            syn::parse_file(&src)
+        } else {
+            syn::parse::Parser::parse_str(parse_mod_skeleton, &src)
+        }
+        .map_err(|e| Error::from(e).at(format_args!("parsing {file_path:?}")))?;
         // Set `seen` immediately, but don't add to `files` (and give up ownership) until we're
         // done walking `ast`.
-        self.seen.insert(file_path.to_owned());
+        self.seen.insert(seen_key, mod_path.clone());
+        if is_listed {
+            self.line_indexes
+                .insert(file_path.to_owned(), LineIndex::new(&src));
+            let size = FileSize {
+                bytes: src.len(),
+                lines: src.lines().count(),
+            };
+            self.file_sizes.insert(file_path.to_owned(), size);
+        }
+        if self.keep_sources && is_listed {
+            self.sources.insert(file_path.to_owned(), src);
+        }
         let is_mod_rs = is_root || file_path.file_name().is_some_and(|n| n == "mod.rs");
         let base_path_storage;
         let base_path = if is_mod_rs {
@@ -58,40 +438,197 @@ impl FileCollector {
         };
 
         let old_inline_mods = mem::take(&mut self.inline_mods);
-        self.walk_items(&ast.items, base_path, mod_path.clone(), &[])?;
+        self.walk_items(
+            &ast.items,
+            file_path,
+            base_path,
+            is_mod_rs,
+            mod_path.clone(),
+            &[],
+        )?;
         let new_inline_mods = mem::replace(&mut self.inline_mods, old_inline_mods);
+        if !is_listed {
+            return Ok(());
+        }
 
         self.mods.push(ModInfo {
             mod_path: mod_path.clone(),
             file_path: file_path.to_owned(),
-            inner_end_pos: ast.span().byte_range().end as usize,
+            inner_start_pos: 0,
+            inner_attrs_end_pos: inner_attrs_end(&ast.attrs, 0),
+            inner_end_pos: ast.span().byte_range().end,
+            import_spans: import_spans(&ast.items),
             is_inline: false,
+            is_generated,
+            attrs,
+            vis,
         });
-        for (mod_path, span) in new_inline_mods {
-            self.mods.push(ModInfo {
-                mod_path: mod_path,
-                file_path: file_path.to_owned(),
-                inner_end_pos: span.byte_range().end as usize - 1,
-                is_inline: true,
-            });
+        for mut m in new_inline_mods {
+            m.is_generated = is_generated;
+            self.mods.push(m);
         }
         self.files.push((file_path.to_owned(), mod_path, ast));
 
         Ok(())
     }
 
+    /// Describe the tree of modules visited so far as JSON, for tools that need the crate's
+    /// module layout.  Each module is an object like
+    /// `{"path": "a::b", "file": "src/a/b.rs", "inline": false, "visibility": "pub(crate)",
+    /// "generated": false, "children": [...]}`, where `visibility` is empty for private modules
+    /// and `children` are sorted by name.  The root's path is empty, unless `crate_name` is set.
+    /// Returns `null` if nothing has been parsed yet.
+    pub fn module_tree_json(&self) -> serde_json::Value {
+        fn go(mods: &[&ModInfo], i: usize) -> serde_json::Value {
+            let m = mods[i];
+            let children = mods
+                .iter()
+                .enumerate()
+                .filter(|&(_, c)| {
+                    c.mod_path.len() == m.mod_path.len() + 1 && c.mod_path.starts_with(&m.mod_path)
+                })
+                .map(|(j, _)| go(mods, j))
+                .collect::<Vec<_>>();
+            serde_json::json!({
+                "path": m.mod_path.join("::"),
+                "file": m.file_path,
+                "inline": m.is_inline,
+                "visibility": vis_string(&m.vis),
+                "generated": m.is_generated,
+                "children": children,
+            })
+        }
+        let mut mods = self.mods.iter().collect::<Vec<_>>();
+        mods.sort_by(|a, b| a.mod_path.cmp(&b.mod_path));
+        if mods.is_empty() {
+            return serde_json::Value::Null;
+        }
+        // The root is the first module after sorting, since every other module path starts with
+        // it.
+        go(&mods, 0)
+    }
+
+    pub fn line_index(&self, file_path: impl AsRef<Path>) -> Option<&LineIndex> {
+        self.line_indexes.get(file_path.as_ref())
+    }
+
+    /// Convert a byte offset in one of the collected files into a `(line, column)` pair.  See
+    /// `LineIndex::byte_to_linecol` for details.  Returns `None` if `file_path` hasn't been
+    /// visited by this collector.
+    pub fn byte_to_linecol(
+        &self,
+        file_path: impl AsRef<Path>,
+        byte: usize,
+    ) -> Option<(usize, usize)> {
+        self.line_index(file_path)
+            .map(|li| li.byte_to_linecol(byte))
+    }
+
+    /// Iterate over every item in the collected files, along with the path of the module
+    /// containing it.  Items are yielded in order of `self.files`, and in source order within each
+    /// file.  The contents of an inline module are yielded immediately after the module itself,
+    /// and the members of an `impl` or `trait` block immediately after the block, with the kind
+    /// of the member and the module path of the block.  Items inside function bodies are not
+    /// yielded.
+    pub fn items(&self) -> impl Iterator<Item = (ModPath, ItemKind, ItemRef<'_>, Span)> {
+        fn go<'a>(
+            items: &'a [syn::Item],
+            mod_path: &mut ModPath,
+            skip_test_mods: bool,
+            out: &mut Vec<(ModPath, ItemKind, ItemRef<'a>, Span)>,
+        ) {
+            for item in items {
+                if skip_test_mods
+                    && let syn::Item::Mod(ref im) = *item
+                    && is_cfg_test(&im.attrs)
+                {
+                    continue;
+                }
+                out.push((
+                    mod_path.clone(),
+                    ItemKind::of(item),
+                    ItemRef::Item(item),
+                    item.span(),
+                ));
+                match *item {
+                    syn::Item::Mod(ref im) => {
+                        if let Some((_, ref inline_items)) = im.content {
+                            mod_path.push(im.ident.unraw().to_string());
+                            go(inline_items, mod_path, skip_test_mods, out);
+                            mod_path.pop();
+                        }
+                    }
+                    syn::Item::Impl(ref imp) => {
+                        out.extend(imp.items.iter().map(|member| {
+                            (
+                                mod_path.clone(),
+                                ItemKind::of_impl_item(member),
+                                ItemRef::ImplMember(imp, member),
+                                member.span(),
+                            )
+                        }));
+                    }
+                    syn::Item::Trait(ref tr) => {
+                        out.extend(tr.items.iter().map(|member| {
+                            (
+                                mod_path.clone(),
+                                ItemKind::of_trait_item(member),
+                                ItemRef::TraitMember(tr, member),
+                                member.span(),
+                            )
+                        }));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        for (_, mod_path, ast) in &self.files {
+            go(
+                &ast.items,
+                &mut mod_path.clone(),
+                self.skip_test_mods,
+                &mut out,
+            );
+        }
+        out.into_iter()
+    }
+
     fn walk_items(
         &mut self,
         items: &[syn::Item],
+        file_path: &Path,
         base_path: &Path,
+        is_mod_rs: bool,
         mut mod_path: Vec<String>,
         parent_module: &[&str],
     ) -> Result<(), Error> {
         for item in items {
             let im = match *item {
                 syn::Item::Mod(ref im) => im,
+                syn::Item::Macro(ref im) => {
+                    if let Some(ref ident) = im.ident
+                        && im.mac.path.is_ident("macro_rules")
+                    {
+                        self.macros.push(MacroInfo {
+                            name: ident.unraw().to_string(),
+                            mod_path: mod_path.clone(),
+                            file_path: file_path.to_owned(),
+                            span: im.span(),
+                            is_exported: im
+                                .attrs
+                                .iter()
+                                .any(|attr| attr.path().is_ident("macro_export")),
+                        });
+                    }
+                    continue;
+                }
                 _ => continue,
             };
+            if self.skip_test_mods && is_cfg_test(&im.attrs) {
+                continue;
+            }
             mod_path.push(im.ident.unraw().to_string());
             if let Some((brace, ref inline_items)) = im.content {
                 let name =
@@ -101,16 +638,43 @@ impl FileCollector {
                     .copied()
                     .chain(iter::once(&name as &_))
                     .collect::<Vec<_>>();
-                self.walk_items(inline_items, base_path, mod_path.clone(), &module)?;
-                self.inline_mods.push((mod_path.clone(), brace.span.join()));
+                self.walk_items(
+                    inline_items,
+                    file_path,
+                    base_path,
+                    is_mod_rs,
+                    mod_path.clone(),
+                    &module,
+                )?;
+                let attrs_end = inner_attrs_end(&im.attrs, brace.span.open().byte_range().end);
+                let span = brace.span.join();
+                self.inline_mods.push(ModInfo {
+                    mod_path: mod_path.clone(),
+                    file_path: file_path.to_owned(),
+                    inner_start_pos: span.byte_range().start + 1,
+                    inner_attrs_end_pos: attrs_end,
+                    inner_end_pos: span.byte_range().end - 1,
+                    import_spans: import_spans(inline_items),
+                    is_inline: true,
+                    is_generated: false,
+                    attrs: outer_attrs(&im.attrs),
+                    vis: im.vis.clone(),
+                });
             } else {
                 let mut path = base_path.to_owned();
                 for &m in parent_module {
-                    path.push(m);
+                    push_attr_path(&mut path, m);
                 }
                 if let Some(attr_path) = path_attr_value(&im.attrs)? {
-                    path.push(attr_path);
-                    self.parse(path, mod_path.clone(), false)?;
+                    push_attr_path(&mut path, &attr_path);
+                } else if self.edition == Edition::Edition2015 && !is_mod_rs {
+                    return Err(format!(
+                        "out-of-line module `{}` is declared in a non-mod.rs file, which the \
+                        2015 edition doesn't allow; add a `#[path]` attribute or move the \
+                        parent module to a mod.rs file",
+                        mod_path.join("::")
+                    )
+                    .into());
                 } else {
                     let name = im.ident.unraw().to_string();
                     // Try `foo/mod.rs` first; if it doesn't exist, try `foo.rs` instead.
@@ -120,7 +684,17 @@ impl FileCollector {
                         path.pop();
                         path.set_extension("rs");
                     }
-                    self.parse(path, mod_path.clone(), false)?;
+                }
+                if self.allow_unresolved && !fs::exists(&path)? {
+                    self.unresolved.push((mod_path.clone(), path));
+                } else {
+                    self.parse_module(
+                        &path,
+                        mod_path.clone(),
+                        false,
+                        outer_attrs(&im.attrs),
+                        im.vis.clone(),
+                    )?;
                 }
                 // No need to update `self.mods` - that's handled by the recursive call to `parse`.
             }
@@ -130,6 +704,141 @@ impl FileCollector {
     }
 }
 
+/// Parse a file or inline module body as a `syn::File` containing only its `mod` items, which in
+/// turn contain only their own `mod` items, skipping everything else token by token.  This is
+/// enough for `walk_items` to find the files of out-of-line modules, without the cost of parsing
+/// every item.  `mod` is a keyword, so it can't start anything but a module at the top level.
+fn parse_mod_skeleton(input: syn::parse::ParseStream) -> syn::Result<syn::File> {
+    fn mod_items(input: syn::parse::ParseStream) -> syn::Result<Vec<syn::Item>> {
+        let mut items = Vec::new();
+        while !input.is_empty() {
+            let mut attrs = input.call(syn::Attribute::parse_outer)?;
+            let fork = input.fork();
+            if fork.parse::<syn::Visibility>().is_err() || !fork.peek(syn::Token![mod]) {
+                if !input.is_empty() {
+                    input.step(|cursor| match cursor.token_tree() {
+                        Some((_, rest)) => Ok(((), rest)),
+                        None => Err(cursor.error("expected a token")),
+                    })?;
+                }
+                continue;
+            }
+            let vis = input.parse()?;
+            let mod_token = input.parse()?;
+            let ident = input.call(syn::Ident::parse_any)?;
+            let (content, semi) = if input.peek(syn::Token![;]) {
+                (None, Some(input.parse()?))
+            } else {
+                let body;
+                let brace = syn::braced!(body in input);
+                // As in `syn`, inner attributes are kept along with the outer ones.
+                attrs.extend(body.call(syn::Attribute::parse_inner)?);
+                (Some((brace, mod_items(&body)?)), None)
+            };
+            items.push(syn::Item::Mod(syn::ItemMod {
+                attrs,
+                vis,
+                unsafety: None,
+                mod_token,
+                ident,
+                content,
+                semi,
+            }));
+        }
+        Ok(items)
+    }
+    Ok(syn::File {
+        shebang: None,
+        attrs: input.call(syn::Attribute::parse_inner)?,
+        items: mod_items(input)?,
+    })
+}
+
+/// Format `vis` as written in source, such as `pub(crate)` or `pub(in crate::a)`, or as an empty
+/// string for private items.
+fn vis_string(vis: &syn::Visibility) -> String {
+    match *vis {
+        syn::Visibility::Public(_) => "pub".to_owned(),
+        syn::Visibility::Restricted(ref r) => {
+            let path = r
+                .path
+                .segments
+                .iter()
+                .map(|seg| seg.ident.to_string())
+                .collect::<Vec<_>>()
+                .join("::");
+            let in_ = if r.in_token.is_some() { "in " } else { "" };
+            format!("pub({in_}{path})")
+        }
+        syn::Visibility::Inherited => String::new(),
+    }
+}
+
+/// Get the outer attributes in `attrs`, leaving out the inner attributes of an inline module.
+fn outer_attrs(attrs: &[syn::Attribute]) -> Vec<syn::Attribute> {
+    attrs
+        .iter()
+        .filter(|attr| matches!(attr.style, syn::AttrStyle::Outer))
+        .cloned()
+        .collect()
+}
+
+/// Format `mod_path` for error messages, using `crate` for the crate root.
+fn display_mod_path(mod_path: &[String]) -> String {
+    if mod_path.is_empty() {
+        "crate".to_owned()
+    } else {
+        mod_path.join("::")
+    }
+}
+
+/// Check whether the comments at the top of `src`, before the first line of code, contain
+/// `marker`.
+fn has_generated_marker(src: &str, marker: &str) -> bool {
+    src.lines()
+        .map(str::trim)
+        .take_while(|line| {
+            line.is_empty()
+                || line.starts_with("//")
+                || line.starts_with("/*")
+                || line.starts_with('*')
+        })
+        .any(|line| line.contains(marker))
+}
+
+/// Find the end of the last inner attribute in `attrs`, or return `start` if there are none.
+fn inner_attrs_end(attrs: &[syn::Attribute], start: usize) -> usize {
+    attrs
+        .iter()
+        .filter(|attr| matches!(attr.style, syn::AttrStyle::Inner(_)))
+        .map(|attr| attr.span().byte_range().end)
+        .max()
+        .unwrap_or(start)
+}
+
+/// Get the byte ranges of the `use` and `extern crate` items among `items`.
+fn import_spans(items: &[syn::Item]) -> Vec<(usize, usize)> {
+    items
+        .iter()
+        .filter(|item| matches!(item, syn::Item::Use(_) | syn::Item::ExternCrate(_)))
+        .map(|item| {
+            let range = item.span().byte_range();
+            (range.start, range.end)
+        })
+        .collect()
+}
+
+/// Check whether `attrs` contains `#[cfg(test)]`.
+pub fn is_cfg_test(attrs: &[syn::Attribute]) -> bool {
+    attrs.iter().any(|attr| match attr.meta {
+        syn::Meta::List(ref ml) => {
+            ml.path.is_ident("cfg")
+                && syn::parse2::<syn::Ident>(ml.tokens.clone()).is_ok_and(|i| i == "test")
+        }
+        _ => false,
+    })
+}
+
 fn path_attr_value(attrs: &[syn::Attribute]) -> Result<Option<String>, Error> {
     for attr in attrs {
         if !attr.meta.path().is_ident("path") {
@@ -151,3 +860,683 @@ fn path_attr_value(attrs: &[syn::Attribute]) -> Result<Option<String>, Error> {
     }
     Ok(None)
 }
+
+/// Append the `#[path]` attribute value `attr_path` to `path`.  The value uses `/` as its
+/// separator on every platform, so it's split into components rather than pushed as a single
+/// one, which wouldn't resolve on Windows.
+fn push_attr_path(path: &mut PathBuf, attr_path: &str) {
+    if Path::new(attr_path).is_absolute() {
+        path.push(attr_path);
+        return;
+    }
+    for component in attr_path.split('/').filter(|c| !c.is_empty()) {
+        path.push(component);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::ToTokens;
+
+    #[test]
+    fn test_edition() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "mod a;").unwrap();
+        fs::write(dir.path().join("a.rs"), "mod b;").unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a/b.rs"), "").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert_eq!(fc.files.len(), 3);
+
+        let mut fc = FileCollector {
+            edition: Edition::Edition2015,
+            ..FileCollector::default()
+        };
+        let err = fc
+            .parse(dir.path().join("lib.rs"), vec![], true)
+            .unwrap_err();
+        assert!(err.to_string().contains("`a::b`"), "{err}");
+    }
+
+    #[test]
+    fn test_skip_test_mods() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("lib.rs"),
+            "fn f() {}\n#[cfg(test)]\nmod tests;\n#[cfg(test)]\nmod inline { fn t() {} }\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("tests.rs"), "fn t() {}").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert_eq!(fc.files.len(), 2);
+        assert_eq!(fc.mods.len(), 3);
+
+        let mut fc = FileCollector {
+            skip_test_mods: true,
+            ..FileCollector::default()
+        };
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert_eq!(fc.files.len(), 1);
+        assert_eq!(fc.mods.len(), 1);
+        let kinds = fc.items().map(|(_, kind, _, _)| kind).collect::<Vec<_>>();
+        assert_eq!(kinds, [ItemKind::Fn]);
+    }
+
+    #[test]
+    fn test_keep_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "mod a;").unwrap();
+        fs::write(dir.path().join("a.rs"), "fn f() {}").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert!(fc.sources.is_empty());
+
+        let mut fc = FileCollector {
+            keep_sources: true,
+            ..FileCollector::default()
+        };
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert_eq!(fc.sources.len(), 2);
+        assert_eq!(fc.sources[&dir.path().join("a.rs")], "fn f() {}");
+    }
+
+    #[test]
+    fn test_crate_name() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "mod a;\nmod b {}\n").unwrap();
+        fs::write(dir.path().join("a.rs"), "").unwrap();
+
+        let mut fc = FileCollector {
+            crate_name: Some("mycrate".to_owned()),
+            ..FileCollector::default()
+        };
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let mut mod_paths = fc
+            .mods
+            .iter()
+            .map(|m| m.mod_path.join("::"))
+            .collect::<Vec<_>>();
+        mod_paths.sort();
+        assert_eq!(mod_paths, ["mycrate", "mycrate::a", "mycrate::b"]);
+    }
+
+    #[test]
+    fn test_bom() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "\u{feff}mod a;\nfn f() {}\n").unwrap();
+        fs::write(dir.path().join("a.rs"), b"fn g() {}\n// \xff\n").unwrap();
+
+        let src = read_source(dir.path().join("lib.rs")).unwrap();
+        assert_eq!(src, "mod a;\nfn f() {}\n");
+        let ast = syn::parse_file(&src).unwrap();
+        let spans = crate::item_span::item_spans(vec![], &ast);
+        let (_, lo, hi) = spans.iter().find(|(path, _, _)| path == &["f"]).unwrap();
+        assert_eq!(&src[*lo..*hi], "fn f() {}");
+
+        let mut fc = FileCollector::default();
+        let err = fc
+            .parse(dir.path().join("lib.rs"), vec![], true)
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains("a.rs") && msg.contains("not valid UTF-8"),
+            "{msg}"
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib_src = "#[macro_export]\nmacro_rules! a { () => {} }\nmod m;\nmod n {\n    \
+            macro_rules! b { () => {} }\n    a!();\n}\n";
+        fs::write(dir.path().join("lib.rs"), lib_src).unwrap();
+        fs::write(dir.path().join("m.rs"), "macro_rules! c { () => {} }").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let macros = fc
+            .macros
+            .iter()
+            .map(|m| (m.name.as_str(), m.mod_path.join("::"), m.is_exported))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            macros,
+            [
+                ("a", "".to_owned(), true),
+                ("c", "m".to_owned(), false),
+                ("b", "n".to_owned(), false),
+            ]
+        );
+        let a = &fc.macros[0];
+        assert_eq!(a.file_path, dir.path().join("lib.rs"));
+        assert!(lib_src[a.span.byte_range()].starts_with("#[macro_export]"));
+        assert!(lib_src[a.span.byte_range()].ends_with("}"));
+    }
+
+    #[test]
+    fn test_unresolved() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("lib.rs"),
+            "mod a;\nmod b;\n#[path = \"x/c.rs\"]\nmod c;\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("a.rs"), "mod d;").unwrap();
+
+        let mut fc = FileCollector::default();
+        assert!(fc.parse(dir.path().join("lib.rs"), vec![], true).is_err());
+
+        let mut fc = FileCollector {
+            allow_unresolved: true,
+            ..FileCollector::default()
+        };
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert_eq!(fc.files.len(), 2);
+        let mod_path = |s: &str| s.split("::").map(|s| s.to_owned()).collect::<Vec<_>>();
+        assert_eq!(
+            fc.unresolved,
+            [
+                (mod_path("a::d"), dir.path().join("a/d.rs")),
+                (mod_path("b"), dir.path().join("b.rs")),
+                (mod_path("c"), dir.path().join("x/c.rs")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_path_alias() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("lib.rs"),
+            "#[path = \"shared.rs\"]\nmod a;\n#[path = \"shared.rs\"]\nmod b;\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("shared.rs"), "fn f() {}").unwrap();
+
+        let mut fc = FileCollector::default();
+        let err = fc
+            .parse(dir.path().join("lib.rs"), vec![], true)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("used by both module `a` and module `b`"),
+            "{err}"
+        );
+
+        // Parsing the same file again for the same module is fine.
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("shared.rs"), vec!["a".to_owned()], false)
+            .unwrap();
+        fc.parse(dir.path().join("shared.rs"), vec!["a".to_owned()], false)
+            .unwrap();
+        assert_eq!(fc.files.len(), 1);
+    }
+
+    #[test]
+    fn test_path_attr_components() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("lib.rs"),
+            "#[path = \"sys/unix/imp.rs\"]\nmod imp;\n\
+            #[path = \"plat/x\"]\nmod p { mod q; }\n",
+        )
+        .unwrap();
+        fs::create_dir_all(dir.path().join("sys/unix")).unwrap();
+        fs::write(dir.path().join("sys/unix/imp.rs"), "fn f() {}").unwrap();
+        fs::create_dir_all(dir.path().join("plat/x")).unwrap();
+        fs::write(dir.path().join("plat/x/q.rs"), "fn g() {}").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let imp_path = ["sys", "unix", "imp.rs"]
+            .iter()
+            .fold(dir.path().to_owned(), |p, c| p.join(c));
+        let q_path = ["plat", "x", "q.rs"]
+            .iter()
+            .fold(dir.path().to_owned(), |p, c| p.join(c));
+        let files = fc
+            .files
+            .iter()
+            .map(|(file_path, mod_path, _)| (file_path.clone(), mod_path.join("::")))
+            .collect::<Vec<_>>();
+        assert!(files.contains(&(imp_path, "imp".to_owned())), "{files:?}");
+        assert!(files.contains(&(q_path, "p::q".to_owned())), "{files:?}");
+    }
+
+    #[test]
+    fn test_path_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("lib.rs"),
+            "#[path = \"gen/foo.rs.in\"]\nmod foo;\n",
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("gen")).unwrap();
+        fs::write(dir.path().join("gen/foo.rs.in"), "fn f() {}").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert_eq!(fc.files.len(), 2);
+        assert_eq!(fc.files[0].0, dir.path().join("gen/foo.rs.in"));
+        assert_eq!(fc.files[0].1, ["foo"]);
+
+        // Without `#[path]`, other extensions aren't tried.
+        fs::write(dir.path().join("lib.rs"), "mod bar;\n").unwrap();
+        fs::write(dir.path().join("bar.rs.in"), "fn g() {}").unwrap();
+        let mut fc = FileCollector::default();
+        let err = fc
+            .parse(dir.path().join("lib.rs"), vec![], true)
+            .unwrap_err();
+        assert!(err.to_string().contains("bar.rs"), "{err}");
+    }
+
+    #[test]
+    fn test_inner_attrs_end_pos() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = "//! Docs.\n#![allow(dead_code)]\n\nmod a {\n    #![allow(x)]\n    fn f() {}\n}\n\
+            mod b { fn g() {} }\nmod c;\n";
+        fs::write(dir.path().join("lib.rs"), src).unwrap();
+        fs::write(dir.path().join("c.rs"), "fn h() {}\n").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let attrs = |name: &str| {
+            let m = fc
+                .mods
+                .iter()
+                .find(|m| m.mod_path.join("::") == name)
+                .unwrap();
+            (m.inner_start_pos, m.inner_attrs_end_pos)
+        };
+        let lib = fs::read_to_string(dir.path().join("lib.rs")).unwrap();
+        let (start, end) = attrs("");
+        assert_eq!(&lib[start..end], "//! Docs.\n#![allow(dead_code)]");
+        let (start, end) = attrs("a");
+        assert_eq!(&lib[start..end], "\n    #![allow(x)]");
+        let (start, end) = attrs("b");
+        assert_eq!((&lib[start - 1..start], end), ("{", start));
+        assert_eq!(attrs("c"), (0, 0));
+    }
+
+    #[test]
+    fn test_inline_only_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = "mod a {\n    fn f() {}\n    mod b {\n        mod c { fn g() {} }\n    }\n}\n\
+            mod d {}\n";
+        fs::write(dir.path().join("lib.rs"), src).unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert_eq!(fc.files.len(), 1);
+        let mut mods = fc
+            .mods
+            .iter()
+            .map(|m| {
+                let body = &src[m.inner_start_pos..m.inner_end_pos];
+                (m.mod_path.join("::"), m.is_inline, body)
+            })
+            .collect::<Vec<_>>();
+        mods.sort();
+        assert_eq!(
+            mods,
+            [
+                ("".to_owned(), false, src.trim_end()),
+                (
+                    "a".to_owned(),
+                    true,
+                    "\n    fn f() {}\n    mod b {\n        mod c { fn g() {} }\n    }\n"
+                ),
+                (
+                    "a::b".to_owned(),
+                    true,
+                    "\n        mod c { fn g() {} }\n    "
+                ),
+                ("a::b::c".to_owned(), true, " fn g() {} "),
+                ("d".to_owned(), true, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_same_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let foo_src = "mod foo {\n    mod foo { fn bar() {} }\n    fn bar() {}\n}\nfn bar() {}\n";
+        fs::write(dir.path().join("lib.rs"), "mod foo;\n").unwrap();
+        fs::write(dir.path().join("foo.rs"), foo_src).unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert_eq!(fc.files.len(), 2);
+        let mut mods = fc
+            .mods
+            .iter()
+            .filter(|m| !m.mod_path.is_empty())
+            .map(|m| {
+                assert_eq!(m.file_path, dir.path().join("foo.rs"));
+                let body = &foo_src[m.inner_start_pos..m.inner_end_pos];
+                (m.mod_path.join("::"), m.is_inline, body)
+            })
+            .collect::<Vec<_>>();
+        mods.sort();
+        assert_eq!(
+            mods,
+            [
+                ("foo".to_owned(), false, foo_src.trim_end()),
+                (
+                    "foo::foo".to_owned(),
+                    true,
+                    "\n    mod foo { fn bar() {} }\n    fn bar() {}\n"
+                ),
+                ("foo::foo::foo".to_owned(), true, " fn bar() {} "),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_only_files() {
+        let dir = tempfile::tempdir().unwrap();
+        // The root isn't listed, so it doesn't even need to parse, only to lex.
+        let src = "#![allow(x)]\nfn broken() -> ;\nstruct S { x: [u8; 2] }\n\
+            #[path = \"other/b_file.rs\"]\npub mod b;\nmod a {\n    mod c;\n    mod e;\n}\n\
+            macro_rules! m { (mod $x:ident;) => {} }\nconst X: u8 = { 1 };\n";
+        fs::write(dir.path().join("lib.rs"), src).unwrap();
+        fs::create_dir_all(dir.path().join("a")).unwrap();
+        fs::create_dir_all(dir.path().join("other")).unwrap();
+        fs::write(dir.path().join("a/c.rs"), "fn g() {}\n").unwrap();
+        fs::write(dir.path().join("a/e.rs"), "fn unlisted() {}\n").unwrap();
+        fs::write(dir.path().join("other/b_file.rs"), "pub fn h() {}\n").unwrap();
+
+        let mut fc = FileCollector {
+            only_files: Some(vec![
+                dir.path().join("a/c.rs"),
+                dir.path().join("other/../other/b_file.rs"),
+            ]),
+            ..FileCollector::default()
+        };
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let mut files = fc
+            .files
+            .iter()
+            .map(|(file_path, mod_path, ast)| {
+                (file_path.clone(), mod_path.join("::"), ast.items.len())
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                (dir.path().join("a/c.rs"), "a::c".to_owned(), 1),
+                (dir.path().join("other/b_file.rs"), "b".to_owned(), 1),
+            ]
+        );
+        let mut mods = fc
+            .mods
+            .iter()
+            .map(|m| m.mod_path.join("::"))
+            .collect::<Vec<_>>();
+        mods.sort();
+        assert_eq!(mods, ["a::c", "b"]);
+        assert!(fc.macros.is_empty());
+        assert_eq!(fc.file_sizes.len(), 2);
+    }
+
+    #[test]
+    fn test_file_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "\u{feff}mod a;\nfn f() {}\n").unwrap();
+        fs::write(dir.path().join("a.rs"), "fn g() {\n}").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        assert_eq!(
+            fc.file_sizes[&dir.path().join("lib.rs")],
+            FileSize {
+                bytes: 17,
+                lines: 2
+            }
+        );
+        assert_eq!(
+            fc.file_sizes[&dir.path().join("a.rs")],
+            FileSize {
+                bytes: 10,
+                lines: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_mod_attrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let src =
+            "#[cfg(unix)]\n#[path = \"x.rs\"]\nmod a;\n/// Docs.\nmod b {\n    #![allow(x)]\n}\n";
+        fs::write(dir.path().join("lib.rs"), src).unwrap();
+        fs::write(dir.path().join("x.rs"), "").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let attrs = |name: &str| {
+            let m = fc
+                .mods
+                .iter()
+                .find(|m| m.mod_path.join("::") == name)
+                .unwrap();
+            m.attrs
+                .iter()
+                .map(|attr| attr.to_token_stream().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert!(attrs("").is_empty());
+        assert_eq!(attrs("a"), ["# [cfg (unix)]", "# [path = \"x.rs\"]"]);
+        assert_eq!(attrs("b"), ["# [doc = \" Docs.\"]"]);
+    }
+
+    #[test]
+    fn test_import_spans() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = concat!(
+            "extern crate libc;\n#[cfg(unix)]\nuse std::fs;\nfn f() {}\n",
+            "mod m {\n    use super::f;\n    fn g() { use std::io; }\n}\n",
+        );
+        fs::write(dir.path().join("lib.rs"), src).unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let imports = |name: &str| {
+            let m = fc
+                .mods
+                .iter()
+                .find(|m| m.mod_path.join("::") == name)
+                .unwrap();
+            m.import_spans
+                .iter()
+                .map(|&(lo, hi)| &src[lo..hi])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            imports(""),
+            ["extern crate libc;", "#[cfg(unix)]\nuse std::fs;"]
+        );
+        // Imports inside function bodies aren't included.
+        assert_eq!(imports("m"), ["use super::f;"]);
+    }
+
+    #[test]
+    fn test_module_tree_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = "pub mod b;\npub(crate) mod a {\n    pub(in crate::a) mod c {}\n}\n";
+        fs::write(dir.path().join("lib.rs"), src).unwrap();
+        fs::write(dir.path().join("b.rs"), "").unwrap();
+
+        let mut fc = FileCollector::default();
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let lib = dir.path().join("lib.rs");
+        assert_eq!(
+            fc.module_tree_json(),
+            serde_json::json!({
+                "path": "",
+                "file": lib,
+                "inline": false,
+                "visibility": "",
+                "generated": false,
+                "children": [
+                    {
+                        "path": "a",
+                        "file": lib,
+                        "inline": true,
+                        "visibility": "pub(crate)",
+                        "generated": false,
+                        "children": [{
+                            "path": "a::c",
+                            "file": lib,
+                            "inline": true,
+                            "visibility": "pub(in crate::a)",
+                            "generated": false,
+                            "children": [],
+                        }],
+                    },
+                    {
+                        "path": "b",
+                        "file": dir.path().join("b.rs"),
+                        "inline": false,
+                        "visibility": "pub",
+                        "generated": false,
+                        "children": [],
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_generated() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "mod a;\nmod b;\nmod c;").unwrap();
+        fs::write(
+            dir.path().join("a.rs"),
+            "// @generated by bindgen\nmod d;\nfn f() {}",
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a/d.rs"), "fn g() {}").unwrap();
+        fs::write(dir.path().join("b.rs"), "/*\n * @generated\n */\nfn h() {}").unwrap();
+        fs::write(dir.path().join("c.rs"), "fn i() {}\n// @generated").unwrap();
+
+        let mut fc = FileCollector {
+            generated_marker: Some("@generated".to_owned()),
+            ..FileCollector::default()
+        };
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let generated = |fc: &FileCollector| {
+            let mut mods = fc
+                .mods
+                .iter()
+                .filter(|m| m.is_generated)
+                .map(|m| m.mod_path.join("::"))
+                .collect::<Vec<_>>();
+            mods.sort();
+            mods
+        };
+        assert_eq!(generated(&fc), ["a", "b"]);
+        assert_eq!(fc.generated_files.len(), 2);
+        assert_eq!(fc.files.len(), 5);
+
+        let mut fc = FileCollector {
+            generated_marker: Some("@generated".to_owned()),
+            skip_generated: true,
+            ..FileCollector::default()
+        };
+        fc.parse(dir.path().join("lib.rs"), vec![], true).unwrap();
+        let mods = fc
+            .mods
+            .iter()
+            .map(|m| m.mod_path.join("::"))
+            .collect::<Vec<_>>();
+        assert_eq!(mods, ["c", ""]);
+        assert_eq!(fc.generated_files.len(), 2);
+    }
+
+    #[test]
+    fn test_items() {
+        let fc = FileCollector {
+            files: vec![
+                (
+                    PathBuf::from("lib.rs"),
+                    vec![],
+                    syn::parse_quote! {
+                        fn f() {}
+                        mod a {
+                            struct S;
+                            impl S { fn g(&self) {} const K: u8 = 1; }
+                            trait T { fn t(&self); }
+                        }
+                        mod b;
+                    },
+                ),
+                (
+                    PathBuf::from("b.rs"),
+                    vec!["b".to_owned()],
+                    syn::parse_quote! {
+                        const C: u8 = 0;
+                    },
+                ),
+            ],
+            ..FileCollector::default()
+        };
+        let items = fc
+            .items()
+            .map(|(mod_path, kind, item, _)| {
+                let name = item.ident().map(|i| i.to_string());
+                (mod_path.join("::"), kind, name, item.item().is_none())
+            })
+            .collect::<Vec<_>>();
+        let item = |mod_path: &str, kind, name: Option<&str>, is_member| {
+            (
+                mod_path.to_owned(),
+                kind,
+                name.map(|s| s.to_owned()),
+                is_member,
+            )
+        };
+        assert_eq!(
+            items,
+            [
+                item("", ItemKind::Fn, Some("f"), false),
+                item("", ItemKind::Mod, Some("a"), false),
+                item("a", ItemKind::Struct, Some("S"), false),
+                item("a", ItemKind::Impl, None, false),
+                item("a", ItemKind::Fn, Some("g"), true),
+                item("a", ItemKind::Const, Some("K"), true),
+                item("a", ItemKind::Trait, Some("T"), false),
+                item("a", ItemKind::Fn, Some("t"), true),
+                item("", ItemKind::Mod, Some("b"), false),
+                item("b", ItemKind::Const, Some("C"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_impl_header() {
+        let imp: syn::ItemImpl = syn::parse_quote! {
+            impl<T: Clone> Foo<T> where T: Default {
+                fn get(&self) -> T { self.0.clone() }
+            }
+        };
+        let header = ImplHeader::of(&imp);
+        assert_eq!(header.self_ty, "Foo < T >");
+        assert_eq!(header.self_ty_name.as_deref(), Some("Foo"));
+        assert_eq!(header.generics, "< T : Clone >");
+        assert_eq!(header.where_clause, "where T : Default");
+        assert_eq!(header.trait_path, None);
+
+        let imp: syn::ItemImpl = syn::parse_quote! { impl Iterator for Bar { type Item = u8; } };
+        let header = ImplHeader::of(&imp);
+        assert_eq!(header.generics, "");
+        assert_eq!(header.trait_path.as_deref(), Some("Iterator"));
+    }
+}
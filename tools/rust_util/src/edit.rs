@@ -0,0 +1,189 @@
+use crate::error::Error;
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+/// A replacement of the byte range `lo .. hi` of a file with new text.  Insertions are
+/// represented as empty ranges.
+pub type Rewrite = (usize, usize, String);
+
+/// Apply `rewrites` to `src`, returning the new source text.  Panics if any two rewrites overlap.
+pub fn apply_rewrites(src: &str, rewrites: &mut [Rewrite]) -> String {
+    // Sort by `lo`, then by `hi`.  This means `0..10 < 10..10 < 10..20`.  The sort is stable, so
+    // multiple insertions at the same position are applied in the order they were added.
+    rewrites.sort_by_key(|&(lo, hi, _)| (lo, hi));
+    let mut new_src = String::with_capacity(src.len());
+    let mut pos = 0;
+    for &(lo, hi, ref new_snippet) in rewrites.iter() {
+        assert!(
+            lo >= pos,
+            "overlapping rewrites: previous rewrite ended at {}, \
+            but current rewrite covers {} .. {}",
+            pos,
+            lo,
+            hi
+        );
+        new_src.push_str(&src[pos..lo]);
+        new_src.push_str(new_snippet);
+        pos = hi;
+    }
+    new_src.push_str(&src[pos..]);
+    new_src
+}
+
+/// Apply `rewrites` to `src` like `apply_rewrites`, but without panicking if some rewrites overlap.
+/// If there are no overlaps, returns the new source text.  Otherwise, returns `Err` containing the
+/// source text with the non-overlapping rewrites applied, and with each group of overlapping
+/// rewrites replaced by conflict markers listing the result of applying each rewrite in the group
+/// on its own.
+pub fn try_apply_rewrites(src: &str, rewrites: &mut [Rewrite]) -> Result<String, String> {
+    rewrites.sort_by_key(|&(lo, hi, _)| (lo, hi));
+    let mut new_src = String::with_capacity(src.len());
+    let mut pos = 0;
+    let mut conflict = false;
+    let mut i = 0;
+    while i < rewrites.len() {
+        // Find the group of rewrites that overlap, directly or transitively, with `rewrites[i]`.
+        let (lo, mut hi, _) = rewrites[i];
+        let mut j = i + 1;
+        while j < rewrites.len() && rewrites[j].0 < hi {
+            hi = hi.max(rewrites[j].1);
+            j += 1;
+        }
+        new_src.push_str(&src[pos..lo]);
+        if j == i + 1 {
+            new_src.push_str(&rewrites[i].2);
+        } else {
+            conflict = true;
+            for (k, &(r_lo, r_hi, ref new_snippet)) in rewrites[i..j].iter().enumerate() {
+                push_marker(&mut new_src, if k == 0 { "<<<<<<<" } else { "=======" });
+                new_src.push_str(&src[lo..r_lo]);
+                new_src.push_str(new_snippet);
+                new_src.push_str(&src[r_hi..hi]);
+            }
+            push_marker(&mut new_src, ">>>>>>>");
+        }
+        pos = hi;
+        i = j;
+    }
+    new_src.push_str(&src[pos..]);
+    if conflict { Err(new_src) } else { Ok(new_src) }
+}
+
+/// Append a conflict `marker` to `out` on a line of its own.
+fn push_marker(out: &mut String, marker: &str) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(marker);
+    out.push('\n');
+}
+
+/// Replace the contents of `path` with `contents`.  The new contents are written to a temporary
+/// file, which is then renamed over the original, so `path` is never left partially written.  The
+/// original file's permissions are preserved.
+pub fn write_file_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    // Capture the original permissions before writing, since `rename` replaces the original
+    // file's metadata with that of the temporary file.
+    let perms = match fs::metadata(path) {
+        Ok(meta) => Some(meta.permissions()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let tmp_path = path.with_extension(".new");
+    fs::write(&tmp_path, contents)?;
+    if let Some(perms) = perms {
+        fs::set_permissions(&tmp_path, perms)?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Return `mod_path` and each of its ancestors (excluding the crate root) for which `exists`
+/// returns false, ordered from outermost to innermost.  Module paths are `::`-separated.
+pub fn missing_modules(mod_path: &str, exists: impl Fn(&str) -> bool) -> Vec<&str> {
+    if mod_path.is_empty() {
+        return Vec::new();
+    }
+    let mut missing: Vec<&str> = iter::once(mod_path.len())
+        .chain(mod_path.rmatch_indices("::").map(|(idx, _)| idx))
+        .map(|idx| &mod_path[..idx])
+        .filter(|m| !exists(m))
+        .collect();
+    missing.reverse();
+    missing
+}
+
+/// Return the path of the source file for a new module `mod_path`, placed under `src_root_dir`
+/// according to the default module layout (`foo::bar` becomes `foo/bar.rs`).
+pub fn module_file_path(src_root_dir: &Path, mod_path: &str) -> PathBuf {
+    debug_assert!(!mod_path.is_empty());
+    src_root_dir.join(mod_path.replace("::", "/") + ".rs")
+}
+
+/// Create an empty source file for the new module `mod_path` at `module_file_path`.  Returns the
+/// path of the new file.  Fails if the file already exists.
+pub fn create_module_file(src_root_dir: &Path, mod_path: &str) -> Result<PathBuf, Error> {
+    let file_path = module_file_path(src_root_dir, mod_path);
+    if fs::exists(&file_path)? {
+        return Err(format!(
+            "file {file_path:?} for new module {mod_path:?} already exists on disk"
+        )
+        .into());
+    }
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&file_path, "")?;
+    Ok(file_path)
+}
+
+/// Return the text of a `mod` declaration for the module `mod_path`.
+pub fn mod_decl(mod_path: &str) -> String {
+    let (_parent_mod_path, mod_name) = mod_path.rsplit_once("::").unwrap_or(("", mod_path));
+    format!("mod {mod_name};")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_rewrites() {
+        let mut rewrites = vec![
+            (4, 4, "!".to_owned()),
+            (0, 1, "A".to_owned()),
+            (4, 4, "?".to_owned()),
+            (2, 3, String::new()),
+        ];
+        assert_eq!(apply_rewrites("abcde", &mut rewrites), "Abd!?e");
+    }
+
+    #[test]
+    fn test_try_apply_rewrites() {
+        let mut rewrites = vec![(0, 1, "A".to_owned()), (4, 4, "!".to_owned())];
+        assert_eq!(
+            try_apply_rewrites("abcde", &mut rewrites),
+            Ok("Abcd!e".to_owned())
+        );
+
+        let src = "x\nabc\ny\n";
+        let mut rewrites = vec![
+            (0, 1, "X".to_owned()),
+            (2, 5, "ABC".to_owned()),
+            (3, 4, "B".to_owned()),
+        ];
+        assert_eq!(
+            try_apply_rewrites(src, &mut rewrites),
+            Err("X\n<<<<<<<\nABC\n=======\naBc\n>>>>>>>\n\ny\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_missing_modules() {
+        let missing = missing_modules("a::b::c", |m| m == "a");
+        assert_eq!(missing, ["a::b", "a::b::c"]);
+        assert_eq!(missing_modules("", |_| false), Vec::<&str>::new());
+    }
+}
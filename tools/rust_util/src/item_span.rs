@@ -1,31 +1,87 @@
 use proc_macro2::Span;
+use std::collections::HashMap;
 use syn;
+use syn::ext::IdentExt;
 use syn::spanned::Spanned;
 use syn::visit::{self, Visit};
 
+/// Selects which items to report, based on their declared visibility.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VisibilityFilter {
+    /// Only `pub` items.
+    Public,
+    /// `pub` items, and items with a restricted visibility such as `pub(crate)` or
+    /// `pub(in path)`.  `pub(self)` is equivalent to private, so it's excluded.
+    Restricted,
+    /// All items, including private ones.
+    #[default]
+    All,
+}
+
+impl VisibilityFilter {
+    pub fn matches(self, vis: &syn::Visibility) -> bool {
+        match (self, vis) {
+            (VisibilityFilter::All, _) => true,
+            (_, syn::Visibility::Public(_)) => true,
+            (VisibilityFilter::Restricted, syn::Visibility::Restricted(r)) => {
+                !r.path.is_ident("self")
+            }
+            _ => false,
+        }
+    }
+}
+
 struct ItemSpanVisitor {
     cur_path: Vec<String>,
     item_spans: Vec<(Vec<String>, usize, usize)>,
+    filter: VisibilityFilter,
+    /// Also report items declared in function bodies; see `item_spans_with_fn_bodies`.
+    fn_bodies: bool,
+    /// Number of function bodies enclosing the current item.
+    fn_depth: usize,
+    /// Number of items seen so far at each path inside a function body, for numbering items of
+    /// the same name.
+    nested_counts: HashMap<Vec<String>, usize>,
 }
 
 impl ItemSpanVisitor {
-    pub fn new(mod_path: Vec<String>) -> ItemSpanVisitor {
+    pub fn new(mod_path: Vec<String>, filter: VisibilityFilter) -> ItemSpanVisitor {
         ItemSpanVisitor {
             cur_path: mod_path,
             item_spans: Vec::new(),
+            filter,
+            fn_bodies: false,
+            fn_depth: 0,
+            nested_counts: HashMap::new(),
         }
     }
 
-    fn _emit(&mut self, name: String, sp: Span) {
-        self.enter(name, sp, |_| {});
+    fn _emit(&mut self, name: String, vis: &syn::Visibility, sp: Span) {
+        self.enter(name, vis, sp, |_| {});
     }
 
-    fn enter<R>(&mut self, name: String, sp: Span, f: impl FnOnce(&mut Self) -> R) -> R {
+    fn enter<R>(
+        &mut self,
+        name: String,
+        vis: &syn::Visibility,
+        sp: Span,
+        f: impl FnOnce(&mut Self) -> R,
+    ) -> R {
         self.cur_path.push(name);
+        if self.fn_depth > 0 {
+            let count = self.nested_counts.entry(self.cur_path.clone()).or_default();
+            *count += 1;
+            if *count > 1 {
+                let name = self.cur_path.last_mut().unwrap();
+                *name = format!("{name}#{count}");
+            }
+        }
 
-        let range = sp.byte_range();
-        self.item_spans
-            .push((self.cur_path.clone(), range.start, range.end));
+        if self.filter.matches(vis) {
+            let range = sp.byte_range();
+            self.item_spans
+                .push((self.cur_path.clone(), range.start, range.end));
+        }
         let r = f(self);
 
         self.cur_path.pop();
@@ -37,12 +93,18 @@ impl Visit<'_> for ItemSpanVisitor {
     fn visit_item(&mut self, item: &syn::Item) {
         match *item {
             syn::Item::Fn(ref ifn) => {
-                let name = ifn.sig.ident.to_string();
-                self.enter(name, ifn.span(), |v| v.visit_item_fn(ifn));
+                let name = ifn.sig.ident.unraw().to_string();
+                self.enter(name, &ifn.vis, ifn.span(), |v| {
+                    if v.fn_bodies {
+                        v.fn_depth += 1;
+                        v.visit_item_fn(ifn);
+                        v.fn_depth -= 1;
+                    }
+                });
             }
             syn::Item::Mod(ref im) => {
-                let name = im.ident.to_string();
-                self.enter(name, im.span(), |v| v.visit_item_mod(im));
+                let name = im.ident.unraw().to_string();
+                self.enter(name, &im.vis, im.span(), |v| v.visit_item_mod(im));
             }
             // TODO: handle other items that can contain nested items.  Note that any expr or type
             // can contain items, e.g. `type T = [u8; { fn f(){} 10 }];`
@@ -53,8 +115,104 @@ impl Visit<'_> for ItemSpanVisitor {
     }
 }
 
+/// Find the path and byte range of each function and module in `ast`, a file whose module path
+/// is `mod_path`.  Items declared inside function bodies aren't reported, since they're part of
+/// the enclosing function's text, so tools built on this, such as `split_rust` and `move_item`,
+/// handle them only as part of that function.
 pub fn item_spans(mod_path: Vec<String>, ast: &syn::File) -> Vec<(Vec<String>, usize, usize)> {
-    let mut v = ItemSpanVisitor::new(mod_path);
+    item_spans_with_visibility(mod_path, ast, VisibilityFilter::All)
+}
+
+/// Like `item_spans`, but also reports items declared inside function bodies, under the path of
+/// the enclosing function, as in `foo::outer::inner`.  If a function body declares several items
+/// of the same name, as in separate blocks, the second is reported as `inner#2`, the third as
+/// `inner#3`, and so on, in source order.
+pub fn item_spans_with_fn_bodies(
+    mod_path: Vec<String>,
+    ast: &syn::File,
+) -> Vec<(Vec<String>, usize, usize)> {
+    let mut v = ItemSpanVisitor::new(mod_path, VisibilityFilter::All);
+    v.fn_bodies = true;
     v.visit_file(ast);
     v.item_spans
 }
+
+/// Like `item_spans`, but only reports items whose declared visibility passes `filter`.  Each item
+/// is checked on its own, so a `pub fn` inside a private module is still reported, and the
+/// contents of a filtered-out module are still visited.
+pub fn item_spans_with_visibility(
+    mod_path: Vec<String>,
+    ast: &syn::File,
+    filter: VisibilityFilter,
+) -> Vec<(Vec<String>, usize, usize)> {
+    let mut v = ItemSpanVisitor::new(mod_path, filter);
+    v.visit_file(ast);
+    v.item_spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_item_spans_with_visibility() {
+        let ast: syn::File = syn::parse_quote! {
+            pub fn a() {}
+            pub(crate) fn b() {}
+            pub(self) fn c() {}
+            fn d() {}
+            mod m {
+                pub fn e() {}
+            }
+        };
+        let names = |filter| {
+            item_spans_with_visibility(vec![], &ast, filter)
+                .into_iter()
+                .map(|(path, _, _)| path.join("::"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(VisibilityFilter::Public), ["a", "m::e"]);
+        assert_eq!(names(VisibilityFilter::Restricted), ["a", "b", "m::e"]);
+        assert_eq!(
+            names(VisibilityFilter::All),
+            ["a", "b", "c", "d", "m", "m::e"]
+        );
+    }
+
+    #[test]
+    fn test_item_spans_with_fn_bodies() {
+        let src = "fn outer() {\n    fn inner() {}\n    {\n        \
+            fn inner() { fn leaf() {} }\n    }\n}\n\
+            mod m {\n    fn f() { struct S; fn g() {} }\n}\n";
+        let ast = syn::parse_file(src).unwrap();
+        let spans = |spans: Vec<(Vec<String>, usize, usize)>| {
+            spans
+                .into_iter()
+                .map(|(path, lo, hi)| (path.join("::"), &src[lo..hi]))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            spans(item_spans(vec![], &ast))
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>(),
+            ["outer", "m", "m::f"]
+        );
+        assert_eq!(
+            spans(item_spans_with_fn_bodies(vec![], &ast))[..4],
+            [
+                ("outer".to_owned(), &src[..src.find("\nmod").unwrap()]),
+                ("outer::inner".to_owned(), "fn inner() {}"),
+                ("outer::inner#2".to_owned(), "fn inner() { fn leaf() {} }"),
+                ("outer::inner#2::leaf".to_owned(), "fn leaf() {}"),
+            ]
+        );
+        assert_eq!(
+            spans(item_spans_with_fn_bodies(vec![], &ast))[4..]
+                .iter()
+                .map(|(path, _)| path.as_str())
+                .collect::<Vec<_>>(),
+            ["m", "m::f", "m::f::g"]
+        );
+    }
+}
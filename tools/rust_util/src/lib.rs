@@ -1,4 +1,8 @@
 pub mod collect;
+pub mod edit;
 pub mod error;
 pub mod item_span;
+pub mod line_index;
+pub mod path;
 pub mod rewrite;
+pub mod snippet;
@@ -0,0 +1,52 @@
+/// Index of line start positions in a source file, for converting byte offsets into line/column
+/// positions.
+#[derive(Clone, Debug, Default)]
+pub struct LineIndex {
+    /// Byte offset of the start of each line.  The first entry is always 0.
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(src: &str) -> LineIndex {
+        let line_starts = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { line_starts }
+    }
+
+    /// Convert a byte offset into a `(line, column)` pair.  As in `proc_macro2::LineColumn`, the
+    /// line is 1-based and the column is 0-based; unlike `LineColumn`, the column is counted in
+    /// bytes rather than characters.
+    pub fn byte_to_linecol(&self, byte: usize) -> (usize, usize) {
+        // `line_starts[0] == 0`, so `partition_point` always returns at least 1.
+        let line = self.line_starts.partition_point(|&start| start <= byte);
+        (line, byte - self.line_starts[line - 1])
+    }
+
+    /// Get the byte offset of the start of `line`, counting from 1.  Panics if `line` is out of
+    /// range.
+    pub fn line_start(&self, line: usize) -> usize {
+        self.line_starts[line - 1]
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_to_linecol() {
+        let li = LineIndex::new("ab\ncd\n\nef");
+        assert_eq!(li.byte_to_linecol(0), (1, 0));
+        assert_eq!(li.byte_to_linecol(2), (1, 2));
+        assert_eq!(li.byte_to_linecol(3), (2, 0));
+        assert_eq!(li.byte_to_linecol(6), (3, 0));
+        assert_eq!(li.byte_to_linecol(8), (4, 1));
+        assert_eq!(li.line_count(), 4);
+        assert_eq!(li.line_start(3), 6);
+    }
+}
@@ -0,0 +1,89 @@
+/// Convert a textual item path such as `crate::a::f` or `super::r#type::f` into a canonical
+/// module path relative to the crate root, with one segment per element.
+///
+/// A leading `crate` makes the path absolute.  Leading `self` and `super` segments are resolved
+/// relative to the module `base`.  Any other path is treated as already relative to the crate
+/// root; callers that want such paths to be relative to some other module should prepend it
+/// themselves.  Raw identifiers are unraw'd, so `r#type` becomes `type`.
+///
+/// Returns an error if `super` would go above the crate root.
+pub fn normalize_item_path(key: &str, base: &[String]) -> Result<Vec<String>, String> {
+    let mut segments = key.split("::").filter(|s| !s.is_empty()).peekable();
+    let mut out = Vec::new();
+    match segments.peek().copied() {
+        Some("crate") => {
+            segments.next();
+        }
+        Some("self" | "super") => {
+            out.extend_from_slice(base);
+            while let Some(&seg) = segments.peek() {
+                match seg {
+                    "self" => {}
+                    "super" => {
+                        if out.pop().is_none() {
+                            let mut msg =
+                                format!("key {key:?} refers to the parent of the crate root");
+                            if !base.is_empty() {
+                                msg += &format!(" (relative to {:?})", base.join("::"));
+                            }
+                            return Err(msg);
+                        }
+                    }
+                    _ => break,
+                }
+                segments.next();
+            }
+        }
+        _ => {}
+    }
+    out.extend(segments.map(|s| s.strip_prefix("r#").unwrap_or(s).to_owned()));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(s: &str) -> Vec<String> {
+        s.split("::")
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_normalize_item_path() {
+        let base = path("a::b");
+        assert_eq!(normalize_item_path("crate::a::f", &base), Ok(path("a::f")));
+        assert_eq!(normalize_item_path("crate", &base), Ok(path("")));
+        assert_eq!(normalize_item_path("x::f", &base), Ok(path("x::f")));
+        assert_eq!(normalize_item_path("self::f", &base), Ok(path("a::b::f")));
+        assert_eq!(normalize_item_path("self", &base), Ok(path("a::b")));
+        assert_eq!(normalize_item_path("super::f", &base), Ok(path("a::f")));
+        assert_eq!(normalize_item_path("super::super::f", &base), Ok(path("f")));
+        assert_eq!(
+            normalize_item_path("self::super::f", &base),
+            Ok(path("a::f"))
+        );
+    }
+
+    #[test]
+    fn test_normalize_item_path_raw() {
+        let base = path("m");
+        assert_eq!(
+            normalize_item_path("r#type::r#fn", &[]),
+            Ok(path("type::fn"))
+        );
+        assert_eq!(
+            normalize_item_path("crate::r#match", &[]),
+            Ok(path("match"))
+        );
+        assert_eq!(normalize_item_path("self::r#f", &base), Ok(path("m::f")));
+    }
+
+    #[test]
+    fn test_normalize_item_path_above_root() {
+        assert!(normalize_item_path("super::super::f", &path("a")).is_err());
+        assert!(normalize_item_path("self::super", &[]).is_err());
+    }
+}
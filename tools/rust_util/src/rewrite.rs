@@ -105,7 +105,7 @@ impl<'a> TokenIndex<'a> {
         TokenIndex { tokens, index }
     }
 
-    pub fn find<'b>(&'b self, t: &Token) -> Option<usize> {
+    pub fn find(&self, t: &Token) -> Option<usize> {
         let (start, end) = t.span;
         let lo = ((start, end), 0);
         let hi = ((start, end), usize::MAX);
@@ -175,6 +175,12 @@ impl OutputBuffer {
     }
 }
 
+impl Default for OutputBuffer {
+    fn default() -> OutputBuffer {
+        OutputBuffer::new()
+    }
+}
+
 pub fn render_output(
     orig: &str,
     orig_tokens: &[Token],
@@ -182,7 +188,7 @@ pub fn render_output(
     ts: TokenStream,
     buf: &mut OutputBuffer,
 ) {
-    if let Some(t) = orig_tokens.get(0) {
+    if let Some(t) = orig_tokens.first() {
         let (start_pos, _) = t.span;
         buf.emit(&orig[0..start_pos], Spacing::Joint);
     }
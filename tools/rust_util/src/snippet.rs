@@ -0,0 +1,150 @@
+use syn;
+use syn::ext::IdentExt;
+use syn::parse::Parser;
+
+/// Marker separating an enum's item path from a variant name in snippet keys, as in
+/// `MyEnum::#variant::NewVariant`.
+pub const VARIANT_MARKER: &str = "::#variant::";
+
+/// Last segment of the snippet key for a module's inner attributes, as in `foo::#inner_attrs`, or
+/// just `#inner_attrs` for the crate root.  The snippet contains attributes such as
+/// `#![allow(dead_code)]`.
+pub const INNER_ATTRS_SEGMENT: &str = "#inner_attrs";
+
+/// If `key` names a module's inner attributes, return the module path, which is empty for the
+/// crate root.
+pub fn inner_attrs_mod(key: &str) -> Option<&str> {
+    if key == INNER_ATTRS_SEGMENT {
+        return Some("");
+    }
+    key.strip_suffix(INNER_ATTRS_SEGMENT)?.strip_suffix("::")
+}
+
+/// Check that `path` is an item path as used in snippet keys, such as `crate::a::f`,
+/// `super::r#type`, or `f::inner#2` for the second item named `inner` in the body of `f`.
+/// `crate` and `self` can only be the first segment, and `super` can only follow `self` or
+/// another `super`.
+pub fn check_item_path(path: &str) -> Result<(), String> {
+    let mut leading = true;
+    for (i, seg) in path.split("::").enumerate() {
+        // A `#N` suffix numbers items of the same name in a function body.
+        let name = match seg.rsplit_once('#') {
+            Some((name, index))
+                if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                name
+            }
+            _ => seg,
+        };
+        let ident = syn::Ident::parse_any
+            .parse_str(name)
+            .map_err(|_| format!("{seg:?} is not an identifier"))?;
+        match &*ident.unraw().to_string() {
+            "crate" | "self" if i > 0 => {
+                return Err(format!("`{seg}` can only be the first segment"));
+            }
+            "super" if !leading => {
+                return Err("`super` can only follow `self` or `super`".to_owned());
+            }
+            "crate" | "self" | "super" => {}
+            _ => leading = false,
+        }
+    }
+    Ok(())
+}
+
+/// Return the name of the item defined by `item`, if it has one.  Items such as `impl` blocks and
+/// `use` declarations have no name.
+pub fn item_name(item: &syn::Item) -> Option<&syn::Ident> {
+    match *item {
+        syn::Item::Const(ref x) => Some(&x.ident),
+        syn::Item::Enum(ref x) => Some(&x.ident),
+        syn::Item::ExternCrate(ref x) => Some(&x.ident),
+        syn::Item::Fn(ref x) => Some(&x.sig.ident),
+        syn::Item::Macro(ref x) => x.ident.as_ref(),
+        syn::Item::Mod(ref x) => Some(&x.ident),
+        syn::Item::Static(ref x) => Some(&x.ident),
+        syn::Item::Struct(ref x) => Some(&x.ident),
+        syn::Item::Trait(ref x) => Some(&x.ident),
+        syn::Item::TraitAlias(ref x) => Some(&x.ident),
+        syn::Item::Type(ref x) => Some(&x.ident),
+        syn::Item::Union(ref x) => Some(&x.ident),
+        _ => None,
+    }
+}
+
+/// Check that the item defined by `snippet` has the name given by the last segment of `key`.  On
+/// mismatch, returns the name the snippet actually defines.  If the snippet defines several items,
+/// as with a function followed by a private helper, the first one is checked, and the names of the
+/// others are returned.  Snippets that don't parse are not checked.
+pub fn check_snippet_name(key: &str, snippet: &str) -> Result<Vec<String>, String> {
+    let expected = key.rsplit_once("::").map_or(key, |(_parent, name)| name);
+    let file = match syn::parse_str::<syn::File>(snippet) {
+        Ok(x) => x,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let Some((first, rest)) = file.items.split_first() else {
+        return Ok(Vec::new());
+    };
+    match item_name(first) {
+        Some(name) if name.unraw() != expected => return Err(name.to_string()),
+        _ => {}
+    }
+    Ok(rest
+        .iter()
+        .filter_map(item_name)
+        .map(|name| name.unraw().to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_item_path() {
+        for path in [
+            "a::f",
+            "crate::a::f",
+            "self::super::r#type",
+            "f::inner#2",
+            "crate",
+        ] {
+            assert_eq!(check_item_path(path), Ok(()), "{path}");
+        }
+        for path in ["a b", "a::crate::f", "a::super::f", "f::inner#x", "", "a::"] {
+            assert!(check_item_path(path).is_err(), "{path}");
+        }
+        assert_eq!(inner_attrs_mod("a::#inner_attrs"), Some("a"));
+        assert_eq!(inner_attrs_mod("#inner_attrs"), Some(""));
+        assert_eq!(inner_attrs_mod("a::b"), None);
+    }
+
+    #[test]
+    fn test_check_snippet_name() {
+        assert_eq!(
+            check_snippet_name("a::foo", "/// Docs\nfn foo() {}"),
+            Ok(vec![])
+        );
+        assert_eq!(
+            check_snippet_name("a::foo", "fn bar() {}"),
+            Err("bar".to_owned())
+        );
+        assert_eq!(
+            check_snippet_name("S", "pub struct T;"),
+            Err("T".to_owned())
+        );
+        // Deletions and unnamed items aren't checked.
+        assert_eq!(check_snippet_name("a::foo", ""), Ok(vec![]));
+        assert_eq!(check_snippet_name("a::foo", "impl S {}"), Ok(vec![]));
+        // With multiple items, the first is checked, and the names of the rest are returned.
+        assert_eq!(
+            check_snippet_name("a::foo", "fn foo() {}\nimpl S {}\nfn helper() {}"),
+            Ok(vec!["helper".to_owned()])
+        );
+        assert_eq!(
+            check_snippet_name("a::foo", "fn bar() {}\nfn foo() {}"),
+            Err("bar".to_owned())
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use clap::Parser;
+use rust_util::path::normalize_item_path;
+use rust_util::snippet::{VARIANT_MARKER, check_item_path, check_snippet_name, inner_attrs_mod};
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use syn::ext::IdentExt;
+
+/// Check a snippet JSON file for `merge_rust` for common mistakes, without touching any source
+/// tree: keys that aren't item paths, snippets that don't parse as Rust items, snippets whose item
+/// name doesn't match their key, and keys that refer to the same item.  All problems are reported
+/// at once, and the exit status is nonzero if there are any.
+#[derive(Parser)]
+struct Args {
+    /// JSON file mapping item paths to their new contents, in any of the formats `merge_rust`
+    /// accepts.  Keys are checked as if `--key-prefix` and `--key-base` weren't given.
+    new_snippets_file: PathBuf,
+
+    /// Read the file as JSON lines, with one `{"path": ..., "content": ...}` object per line.
+    #[clap(long)]
+    jsonl: bool,
+}
+
+/// The kinds of keys `merge_rust` accepts, which differ in what their snippets should contain.
+enum KeyKind {
+    Item,
+    Variant,
+    InnerAttrs,
+}
+
+/// Get the snippet from a JSON `value`, which is either the snippet itself, `null` for a
+/// deletion, or an object with a `content` field.  An object without `content`, as with the rich
+/// format's `delete` mode or `file` field, is treated like `null`.
+fn snippet_content(value: &Value) -> Result<Option<String>, String> {
+    match *value {
+        Value::String(ref s) => Ok(Some(s.clone())),
+        Value::Null => Ok(None),
+        Value::Object(ref obj) => match obj.get("content") {
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            None | Some(&Value::Null) => Ok(None),
+            Some(_) => Err("`content` should be a string".to_owned()),
+        },
+        _ => Err("value should be a string, `null`, or an object".to_owned()),
+    }
+}
+
+/// Check that `path` is a path to an item, as with `check_item_path`, that doesn't refer outside
+/// the crate.  Without `--key-base`, keys are relative to the crate root, so a leading `super`
+/// would refer to its parent.
+fn check_key_path(path: &str) -> Result<(), String> {
+    check_item_path(path)?;
+    normalize_item_path(path, &[])?;
+    Ok(())
+}
+
+/// Check that `key` is a valid key, returning its kind and the item path it refers to, without
+/// any variant or inner attributes marker.
+fn check_key(key: &str) -> Result<(KeyKind, &str), String> {
+    if let Some((enum_path, variant_name)) = key.split_once(VARIANT_MARKER) {
+        check_key_path(enum_path)?;
+        syn::parse_str::<syn::Ident>(variant_name)
+            .map_err(|_| format!("variant name {variant_name:?} is not an identifier"))?;
+        return Ok((KeyKind::Variant, enum_path));
+    }
+    if let Some(mod_path) = inner_attrs_mod(key) {
+        if !mod_path.is_empty() {
+            check_key_path(mod_path)?;
+        }
+        return Ok((KeyKind::InnerAttrs, mod_path));
+    }
+    check_key_path(key)?;
+    if normalize_item_path(key, &[])?.is_empty() {
+        return Err("key refers to the crate root".to_owned());
+    }
+    Ok((KeyKind::Item, key))
+}
+
+/// Check that `snippet` is suitable for the normalized key `key` of kind `kind`.
+fn check_snippet(key: &str, kind: &KeyKind, snippet: &str) -> Result<(), String> {
+    match *kind {
+        KeyKind::Item => {
+            let file = syn::parse_str::<syn::File>(snippet)
+                .map_err(|e| format!("snippet doesn't parse as Rust items: {e}"))?;
+            if file.items.is_empty() {
+                return Err("snippet contains no items".to_owned());
+            }
+            check_snippet_name(key, snippet)
+                .map_err(|name| format!("snippet defines an item named {name:?} instead"))?;
+        },
+        KeyKind::Variant => {
+            let variant = syn::parse_str::<syn::Variant>(snippet)
+                .map_err(|e| format!("snippet doesn't parse as an enum variant: {e}"))?;
+            let expected = key.rsplit_once("::").map_or(key, |(_, name)| name);
+            if variant.ident.unraw() != expected {
+                return Err(format!("snippet defines a variant named {:?} instead",
+                    variant.ident.to_string()));
+            }
+        },
+        KeyKind::InnerAttrs => {
+            let only_attrs = syn::parse_file(snippet).is_ok_and(|file| file.items.is_empty());
+            if !only_attrs {
+                return Err("snippet should contain only inner attributes".to_owned());
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Check each `(key, value)` pair from a snippet file, returning the problems found as
+/// `(key, message)` pairs, in order.
+fn lint(snippets: &[(String, Value)]) -> Vec<(String, String)> {
+    let mut problems = Vec::new();
+    // Original key for each normalized key seen so far.
+    let mut seen = HashMap::<String, &str>::new();
+    for (key, value) in snippets {
+        let mut check = || -> Result<(), String> {
+            let content = snippet_content(value)?;
+            let (kind, item_path) = check_key(key)?;
+            let mut norm_key = normalize_item_path(item_path, &[])?.join("::");
+            norm_key.push_str(&key[item_path.len()..]);
+            if let Some(old_key) = seen.insert(norm_key.clone(), key) {
+                return Err(format!("key refers to the same item as {old_key:?}"));
+            }
+            // Names are checked against the normalized key, in which raw identifiers are unraw'd.
+            match content {
+                Some(snippet) => check_snippet(&norm_key, &kind, &snippet),
+                None => Ok(()),
+            }
+        };
+        if let Err(msg) = check() {
+            problems.push((key.clone(), msg));
+        }
+    }
+    problems
+}
+
+/// The `(key, value)` pairs of a JSON object, in order.  Unlike a map, this keeps every pair when
+/// a key appears more than once.
+struct Pairs(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for Pairs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Pairs, D::Error> {
+        struct PairsVisitor;
+
+        impl<'de> Visitor<'de> for PairsVisitor {
+            type Value = Pairs;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Pairs, A::Error> {
+                let mut pairs = Vec::new();
+                while let Some(pair) = map.next_entry()? {
+                    pairs.push(pair);
+                }
+                Ok(Pairs(pairs))
+            }
+        }
+
+        deserializer.deserialize_map(PairsVisitor)
+    }
+}
+
+/// Read the `(key, value)` pairs from the snippet file `json`, keeping duplicates and the original
+/// order.
+fn read_snippets(json: &str, jsonl: bool) -> Result<Vec<(String, Value)>, String> {
+    if !jsonl {
+        let Pairs(pairs) = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        return Ok(pairs);
+    }
+    let mut out = Vec::new();
+    for (i, line) in json.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut obj: serde_json::Map<String, Value> = serde_json::from_str(line)
+            .map_err(|e| format!("line {}: {}", i + 1, e))?;
+        let Some(Value::String(path)) = obj.remove("path") else {
+            return Err(format!("line {}: expected a string `path` field", i + 1));
+        };
+        out.push((path, Value::Object(obj)));
+    }
+    Ok(out)
+}
+
+fn main() {
+    let args = Args::parse();
+    let json = fs::read_to_string(&args.new_snippets_file).unwrap_or_else(|e| {
+        panic!("failed to read {:?}: {}", args.new_snippets_file, e);
+    });
+    let snippets = read_snippets(&json, args.jsonl).unwrap_or_else(|e| {
+        eprintln!("error: failed to parse {}: {}", args.new_snippets_file.display(), e);
+        process::exit(2);
+    });
+
+    let problems = lint(&snippets);
+    for (key, msg) in &problems {
+        eprintln!("error: {key:?}: {msg}");
+    }
+    if !problems.is_empty() {
+        eprintln!("found {} problems in {} snippets", problems.len(), snippets.len());
+        process::exit(1);
+    }
+    eprintln!("checked {} snippets", snippets.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint() {
+        let snippets = read_snippets(r##"{
+            "a::f": "fn f() {}",
+            "crate::a::f": "fn f() {}",
+            "g": "fn h() {}",
+            "h": "fn h( {",
+            "a b": null,
+            "super::i": null,
+            "a::crate::j": null,
+            "r#type": "struct r#type;",
+            "E::#variant::V": "V(i32)",
+            "E::#variant::W": "X",
+            "a::#inner_attrs": "fn k() {}",
+            "#inner_attrs": "#![allow(dead_code)]",
+            "m": {"content": "mod m;", "order": 1},
+            "n": {"mode": "delete"},
+            "o": 1,
+            "n": null
+        }"##, false).unwrap();
+        let problems = lint(&snippets);
+        let keys = problems.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>();
+        assert_eq!(keys, [
+            "crate::a::f", "g", "h", "a b", "super::i", "a::crate::j", "E::#variant::W",
+            "a::#inner_attrs", "o", "n",
+        ], "{problems:?}");
+        assert_eq!(problems[0].1, "key refers to the same item as \"a::f\"");
+        assert_eq!(problems[1].1, "snippet defines an item named \"h\" instead");
+
+        let snippets = read_snippets(
+            "{\"path\": \"f\", \"content\": \"fn f() {}\"}\n\n\
+            {\"path\": \"f\", \"content\": null}\n",
+            true,
+        ).unwrap();
+        assert_eq!(lint(&snippets), [
+            ("f".to_owned(), "key refers to the same item as \"f\"".to_owned()),
+        ]);
+    }
+}
@@ -15,7 +15,6 @@ use std::iter;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use syn;
 use syn::spanned::Spanned;
 use syn::visit_mut::{self, VisitMut};
 
@@ -200,6 +199,7 @@ fn span_to_text_range(span: Span) -> TextRange {
 // Helpers for dealing with nested meta items in attrs, like `#[unsafe(no_mangle)]`
 
 #[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
 enum ParsedMeta {
     Meta(syn::Meta),
     Unsafe(Box<ParsedMetaUnsafe>),
@@ -265,7 +265,7 @@ impl ParsedMeta {
                     _ => return Err(syn::Error::new(mnv.value.span(), "expected Lit")),
                 };
                 let syn::ExprLit { ref attrs, ref lit } = *expr_lit;
-                if attrs.len() > 0 {
+                if !attrs.is_empty() {
                     return Err(syn::Error::new(expr_lit.span(), "name must not have attrs"));
                 }
                 let name = match *lit {
@@ -407,10 +407,10 @@ fn main_impl(
                 sema.parse(editioned_file_id);
                 let file_id = editioned_file_id.file_id(db);
                 let vfs_path = vfs.file_path(file_id);
-                if let Some(path) = vfs_path.as_path() {
-                    if files_seen.insert(path) {
-                        files.push((path.to_path_buf(), node));
-                    }
+                if let Some(path) = vfs_path.as_path()
+                    && files_seen.insert(path)
+                {
+                    files.push((path.to_path_buf(), node));
                 }
             }
         }
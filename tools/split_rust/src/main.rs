@@ -1,13 +1,15 @@
 use clap::Parser;
-use rust_util::collect::FileCollector;
+use rust_util::collect::{self, FileCollector};
 use rust_util::item_span::item_spans;
-use serde_json;
 use std::collections::HashMap;
 use std::io::BufWriter;
-use std::fs::{self, File};
+use std::fs::File;
 use std::path::PathBuf;
 
 /// Split a Rust codebase into a JSON map from item paths to their source text.
+///
+/// Items declared inside function bodies get no entry of their own, since their text is already
+/// part of the enclosing function's.
 #[derive(Parser)]
 struct Args {
     /// Root Rust source file to split (`lib.rs` or `main.rs`).
@@ -23,9 +25,9 @@ fn main() {
     let mut fc = FileCollector::default();
     fc.parse(args.src_root_path, vec![], true).unwrap();
     let mut out = HashMap::new();
-    for &(ref name, ref mod_path, ref ast) in &fc.files {
+    for (name, mod_path, ast) in &fc.files {
         eprintln!("visit {:?}", name);
-        let src = fs::read_to_string(name).unwrap();
+        let src = collect::read_source(name).unwrap();
         for (item_path, lo, hi) in item_spans(mod_path.to_owned(), ast) {
             let snippet = &src[lo..hi];
             out.insert(item_path.join("::"), snippet.to_owned());